@@ -31,11 +31,44 @@ fn main() -> anyhow::Result<()> {
 
     cfg.output();
 
+    app_version(&cfg)?;
+
     build_protobuf();
 
     Ok(())
 }
 
+/// `CONFIG_APP_PROJECT_VER` is generated from the version in Cargo.toml, `ESP_IDF_SDKCONFIG_DEFAULTS` in
+/// `.cargo/config.toml` lists this file after `sdkconfig.defaults`.
+const VERSION_DEFAULTS: &str = "sdkconfig.defaults.version";
+
+/// Pull updates only boot an image whose app description has the signed version, so it has to be
+/// the crate's. ESP-IDF is configured before this script runs, after a version bump the new defaults
+/// only reach it with the next build, so the stale one fails instead of producing a wrong image.
+fn app_version(cfg: &CfgArgs) -> anyhow::Result<()> {
+    let version = std::env::var("CARGO_PKG_VERSION")?;
+    let defaults = format!(
+        "# Generated by build.rs from the version in Cargo.toml, don't edit\n\
+         CONFIG_APP_PROJECT_VER_FROM_CONFIG=y\n\
+         CONFIG_APP_PROJECT_VER=\"{}\"\n",
+        version
+    );
+    if std::fs::read_to_string(VERSION_DEFAULTS).ok().as_deref() != Some(defaults.as_str()) {
+        std::fs::write(VERSION_DEFAULTS, defaults)?;
+    }
+    cargo::track_file(VERSION_DEFAULTS);
+
+    match cfg.get("esp_idf_app_project_ver") {
+        Some(configured) if configured.trim_matches('"') == version => Ok(()),
+        configured => anyhow::bail!(
+            "ESP-IDF was configured with app version {:?} instead of {}, {} is updated now, build again",
+            configured,
+            version,
+            VERSION_DEFAULTS
+        ),
+    }
+}
+
 fn build_protobuf() {
     let out_dir = std::env::var("OUT_DIR").unwrap();
 
@@ -0,0 +1,37 @@
+#![no_main]
+
+// The firmware crate only builds for the ESP32, so pull in the (dependency free) frame module directly.
+#[path = "../../src/frame.rs"]
+#[allow(dead_code)]
+mod frame;
+
+use libfuzzer_sys::fuzz_target;
+
+use frame::{FrameDecoder, FrameError, MAX_MESSAGE_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    // feed the input in small pieces to exercise partial frames
+    let mut decoder = FrameDecoder::new();
+
+    for chunk in data.chunks(7) {
+        decoder.push(chunk);
+
+        loop {
+            match decoder.next_frame() {
+                Ok(Some((ty, payload))) => {
+                    assert_ne!(ty, 0);
+                    assert!(payload.len() <= MAX_MESSAGE_SIZE);
+
+                    // whatever we decode must survive a round trip
+                    let encoded = frame::encode_frame(ty, payload);
+                    let (header, decoded) = frame::parse_frame(&encoded).unwrap().unwrap();
+                    assert_eq!(header.ty, ty);
+                    assert_eq!(decoded, payload);
+                }
+                Ok(None) => break,
+                Err(FrameError::MessageTooLarge(_)) => continue,
+                Err(_) => return,
+            }
+        }
+    }
+});
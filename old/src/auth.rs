@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::*;
+
+/// Delay added before answering a failed login, multiplied by the number of consecutive failures
+const FAILED_LOGIN_DELAY: Duration = Duration::from_secs(1);
+/// Consecutive failed logins before locking out the peer
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(60);
+/// Upper bound for the remembered peers, the one with the oldest failure is forgotten first
+const MAX_TRACKED_PEERS: usize = 16;
+
+#[derive(Debug)]
+struct AuthState {
+    failed_logins: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Checks the password of a `ConnectRequest`
+///
+/// Failures are counted per peer address (not per connection, reconnecting does not reset them), so a lockout only
+/// affects the offending peer. The password itself is never logged.
+pub struct Authenticator {
+    password: String,
+    peers: Mutex<HashMap<IpAddr, AuthState>>,
+}
+
+impl Authenticator {
+    pub fn new(password: String) -> Self {
+        Authenticator {
+            password,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn uses_password(&self) -> bool {
+        !self.password.is_empty()
+    }
+
+    /// Returns `Err` with the delay to wait before answering the client when the login is rejected
+    pub fn check(&self, peer: IpAddr, password: &str) -> Result<(), Duration> {
+        self.check_at(peer, password, Instant::now())
+    }
+
+    fn check_at(&self, peer: IpAddr, password: &str, now: Instant) -> Result<(), Duration> {
+        let mut peers = self.peers.lock().expect("lock poisened!");
+
+        if let Some(state) = peers.get(&peer) {
+            match state.locked_until {
+                Some(until) if now < until => {
+                    warn!(
+                        "login from {peer} rejected, locked out for another {}s",
+                        (until - now).as_secs()
+                    );
+                    return Err(FAILED_LOGIN_DELAY * MAX_FAILED_LOGINS);
+                }
+                Some(_) => {
+                    info!("login lockout of {peer} expired");
+                    peers.remove(&peer);
+                }
+                None => {}
+            }
+        }
+
+        if !self.uses_password() || constant_time_eq(password.as_bytes(), self.password.as_bytes())
+        {
+            peers.remove(&peer);
+            return Ok(());
+        }
+
+        if !peers.contains_key(&peer) && peers.len() >= MAX_TRACKED_PEERS {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, state)| state.last_failure)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+
+        let state = peers.entry(peer).or_insert(AuthState {
+            failed_logins: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        state.failed_logins += 1;
+        state.last_failure = now;
+        warn!(
+            "invalid login attempt from {peer} ({}/{})",
+            state.failed_logins, MAX_FAILED_LOGINS
+        );
+
+        if state.failed_logins >= MAX_FAILED_LOGINS {
+            warn!(
+                "too many invalid login attempts from {peer}, locking out for {}s",
+                LOCKOUT_DURATION.as_secs()
+            );
+            state.locked_until = Some(now + LOCKOUT_DURATION);
+        }
+
+        Err(FAILED_LOGIN_DELAY * state.failed_logins)
+    }
+}
+
+/// Compares both slices without returning early on the first mismatch
+///
+/// Only the length of `expected` leaks through timing.
+pub(crate) fn constant_time_eq(given: &[u8], expected: &[u8]) -> bool {
+    let mut diff = (given.len() != expected.len()) as u8;
+
+    for (i, e) in expected.iter().enumerate() {
+        // compare against ourself when `given` is too short to keep the loop length constant
+        let g = given.get(i).unwrap_or(e);
+        diff |= g ^ e;
+    }
+
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 10));
+    const MALLORY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 66));
+
+    #[test]
+    fn lockout_is_per_peer() {
+        let auth = Authenticator::new(String::from("secret"));
+        let now = Instant::now();
+
+        for i in 1..=MAX_FAILED_LOGINS {
+            assert_eq!(
+                auth.check_at(MALLORY, "guess", now),
+                Err(FAILED_LOGIN_DELAY * i)
+            );
+        }
+        // locked out, even with the right password
+        assert!(auth.check_at(MALLORY, "secret", now).is_err());
+        // everybody else is not affected
+        assert_eq!(auth.check_at(ALICE, "secret", now), Ok(()));
+
+        let later = now + LOCKOUT_DURATION;
+        assert_eq!(auth.check_at(MALLORY, "secret", later), Ok(()));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let auth = Authenticator::new(String::from("secret"));
+        let now = Instant::now();
+
+        assert!(auth.check_at(ALICE, "typo", now).is_err());
+        assert_eq!(auth.check_at(ALICE, "secret", now), Ok(()));
+        assert_eq!(auth.check_at(ALICE, "typo", now), Err(FAILED_LOGIN_DELAY));
+    }
+
+    #[test]
+    fn tracked_peers_are_bounded() {
+        let auth = Authenticator::new(String::from("secret"));
+        let start = Instant::now();
+
+        for i in 0..=MAX_TRACKED_PEERS as u8 {
+            let peer = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, i));
+            let _ = auth.check_at(peer, "guess", start + Duration::from_secs(i.into()));
+        }
+        let peers = auth.peers.lock().unwrap();
+        assert_eq!(peers.len(), MAX_TRACKED_PEERS);
+        assert!(!peers.contains_key(&IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 0))));
+    }
+
+    #[test]
+    fn no_password() {
+        let auth = Authenticator::new(String::new());
+        assert!(!auth.uses_password());
+        assert_eq!(auth.check_at(ALICE, "anything", Instant::now()), Ok(()));
+    }
+
+    #[test]
+    fn compare() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secreT", b"secret"));
+        assert!(!constant_time_eq(b"secret!", b"secret"));
+        assert!(!constant_time_eq(b"secre", b"secret"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
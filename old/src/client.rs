@@ -1,20 +1,29 @@
 use std::{
-    net::TcpStream,
+    net::{IpAddr, TcpStream},
     sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, Result};
 use async_channel::{Receiver, Sender};
+use async_io::Timer;
 use futures_lite::{
     future,
     io::{ReadHalf, WriteHalf},
-    AsyncRead, AsyncWrite,
+    AsyncWrite,
 };
 use log::*;
-use protobuf::{Message, ProtobufEnum};
+use protobuf::Message;
 use smol::io::{split, AsyncWriteExt};
 
-use crate::{api::*, components::ComponentUpdate, consts::*, Device};
+use crate::{
+    api::*,
+    components::{entity::Command, logger::LogSubscriber, ComponentUpdate},
+    consts::*,
+    error::{AuthError, Error, ProtocolError, Result, TransportError},
+    frame::{encode_frame, FrameDecoder, FrameError},
+    keepalive::{Keepalive, KeepaliveAction},
+    Device,
+};
 
 // from ESPHome
 const API_MAX: u32 = 1;
@@ -36,22 +45,25 @@ enum ConnectionState {
 }
 
 impl ConnectionState {
+    /// Authorization is driven by the `needs_setup_connection` and `needs_authentication` options from `api.proto`
     pub fn is_call_legal(&self, ty: MessageTypes) -> bool {
         use ConnectionState::*;
 
+        if !ty.is_client_message() {
+            return false;
+        }
+
+        match ty {
+            // the handshake happens exactly once and in order
+            MessageTypes::HelloRequest => return matches!(self, Initalized),
+            MessageTypes::ConnectRequest => return matches!(self, Helloed),
+            _ => {}
+        }
+
         match self {
-            Initalized => {
-                // only expect HelloRequest (1)
-                return ty == MessageTypes::HelloRequest;
-            }
-            Helloed => {
-                // only expect ConnectRequest (3) and DeviceInfoRequest (9)
-                return ty == MessageTypes::ConnectRequest || ty == MessageTypes::DeviceInfoRequest;
-            }
-            Connected => {
-                // expect everything else
-                return ty != MessageTypes::HelloRequest && ty != MessageTypes::ConnectRequest;
-            }
+            Initalized => !ty.needs_setup_connection() && !ty.needs_authentication(),
+            Helloed => !ty.needs_authentication(),
+            Connected => true,
         }
     }
 }
@@ -73,25 +85,30 @@ impl EspHomeApiClient {
         //  1 recevies messages from the net, but does not send anything
         //  2 receives messages internally and sends to net
         // There is an internal message queue for things like `PingRequest` that do not need to go through the server
+        let peer = stream.get_ref().peer_addr()?.ip();
         let (stream_read, stream_send) = split(stream);
         let (int_send, int_recv) = async_channel::bounded(10);
-        let logs = Arc::new(Mutex::new(LogLevel::LOG_LEVEL_NONE));
+        let logs = Arc::new(Mutex::new(LogSubscriber::new()));
 
         // setup (net) sending half
         let logs_a = logs.clone();
-        smol::spawn(async {
+        let sender_a = sender.clone();
+        smol::spawn(async move {
             let res = handle_queue(logs_a, receiver, int_recv, stream_send).await;
             if let Err(err) = res {
                 warn!("Client queue returned: {err}");
             }
+
+            // our receiver is gone by now, let the server clean up
+            let _ = sender_a.send(ComponentUpdate::Closing).await;
         })
         .detach();
 
         // setup (net) receiving part
         let logs_b = logs.clone();
         let device_b = device.clone();
-        smol::spawn(async {
-            let res = handle_net(device_b, logs_b, int_send, sender, stream_read).await;
+        smol::spawn(async move {
+            let res = handle_net(device_b, peer, logs_b, int_send, sender, stream_read).await;
             if let Err(err) = res {
                 warn!("Client net returned: {err}");
             }
@@ -103,66 +120,142 @@ impl EspHomeApiClient {
 }
 
 async fn handle_queue(
-    log: Arc<Mutex<LogLevel>>,
+    log: Arc<Mutex<LogSubscriber>>,
     ext_recv: Receiver<ComponentUpdate>,
     int_recv: Receiver<ComponentUpdate>,
     mut stream_send: WriteHalf<smol::Async<TcpStream>>,
 ) -> Result<()> {
     loop {
-        // prefer internal queue over network
-        match future::or(int_recv.recv(), ext_recv.recv()).await {
-            Ok(msg) => {
-                match msg {
-                    ComponentUpdate::Request(..)
-                    | ComponentUpdate::Update
-                    | ComponentUpdate::LightRequest(..)
-                    | ComponentUpdate::Closing
-                    | ComponentUpdate::Connection(..) => {
-                        warn!("received unexpected message! This is likely a code bug!");
-                    }
-
-                    ComponentUpdate::Response((ty, msg)) => {
-                        send_packet(&mut stream_send, ty, msg.as_ref().as_ref()).await?;
-                    }
+        let logs = async {
+            // the lock must not be held while waiting
+            let listener = log.lock().expect("lock poisened!").listen();
+            if let Some(listener) = listener {
+                listener.await;
+            }
+            None
+        };
+        // prefer internal queue over network, logs come last
+        let msg = future::or(
+            async { Some(future::or(int_recv.recv(), ext_recv.recv()).await) },
+            logs,
+        )
+        .await;
+
+        match msg {
+            None => send_logs(&log, &mut stream_send).await?,
+            Some(Ok(msg)) => match msg {
+                ComponentUpdate::Request(..)
+                | ComponentUpdate::Command(..)
+                | ComponentUpdate::Closing
+                | ComponentUpdate::Shutdown
+                | ComponentUpdate::Connection(..) => {
+                    warn!("received unexpected message! This is likely a code bug!");
+                }
 
-                    ComponentUpdate::Log(msg) => {
-                        // DO NOT LOG ANYTHING IN HERE
-                        // It'll create a recursion
-
-                        // only send when requested
-                        if msg.level.value() <= log.lock().expect("lock poisened!").value() {
-                            send_packet(
-                                &mut stream_send,
-                                MessageTypes::SubscribeLogsResponse,
-                                msg.as_ref(),
-                            )
-                            .await?;
-                        }
+                ComponentUpdate::Response((ty, msg)) => {
+                    // the client closes the connection on a `DisconnectRequest`, the last lines would get lost
+                    if ty == MessageTypes::DisconnectRequest {
+                        send_logs(&log, &mut stream_send).await?;
                     }
+                    send_packet(&mut stream_send, ty, msg.as_ref().as_ref()).await?;
                 }
+            },
+            Some(Err(err)) => {
+                // let the client know that we are done, this also ends the receiving half
+                let _ = stream_send.close().await;
+                return Err(err.into());
             }
-            Err(err) => {
-                bail!("received error {err}, closing");
+        }
+    }
+}
+
+/// Sends all pending log lines
+async fn send_logs(
+    log: &Mutex<LogSubscriber>,
+    stream_send: &mut WriteHalf<smol::Async<TcpStream>>,
+) -> Result<()> {
+    // DO NOT LOG ANYTHING IN HERE
+    // every line would create another one
+
+    // the subscriber skips whatever the client did not ask for
+    loop {
+        let next = log.lock().expect("lock poisened!").next();
+        match next {
+            Some(msg) => {
+                send_packet(stream_send, MessageTypes::SubscribeLogsResponse, &msg).await?
             }
+            None => return Ok(()),
         }
     }
 }
 
 async fn handle_net(
     device: Arc<Device>,
-    log: Arc<Mutex<LogLevel>>,
+    peer: IpAddr,
+    log: Arc<Mutex<LogSubscriber>>,
     int_send: Sender<ComponentUpdate>,
     ext_send: Sender<ComponentUpdate>,
     mut stream_read: ReadHalf<smol::Async<TcpStream>>,
 ) -> Result<()> {
     let mut state = ConnectionState::Initalized;
+    let keepalive = Mutex::new(Keepalive::new(Instant::now()));
+    let mut decoder = FrameDecoder::new();
 
     loop {
-        // read available packets
-        let (ty, msg) = read_packet(&mut stream_read).await?;
+        // wait for the next complete packet, unless the client stopped answering
+        let packet = future::or(
+            async { Some(decoder.fill(&mut stream_read).await) },
+            async {
+                run_keepalive(&keepalive, &int_send).await;
+                None
+            },
+        )
+        .await;
+
+        match packet {
+            Some(Ok(())) => {}
+            Some(Err(FrameError::Closed)) => {
+                info!("Recevied shutdown signal");
+                return Ok(());
+            }
+            Some(Err(err @ FrameError::MessageTooLarge(_))) => {
+                // the decoder skips it, nothing else to do
+                warn!("{err}");
+                continue;
+            }
+            Some(Err(err)) => {
+                warn!("dropping client: {err}");
+                return Err(err.into());
+            }
+            None => {
+                warn!("client is unresponsive, disconnecting");
+
+                // best effort, the queue flushes this before shutting down
+                let req = DisconnectRequest::new();
+                let _ = int_send
+                    .send(ComponentUpdate::Response((
+                        MessageTypes::DisconnectRequest,
+                        Arc::new(Box::new(req)),
+                    )))
+                    .await;
+                return Err(Error::Transport(TransportError::KeepaliveTimeout));
+            }
+        }
+
+        let (raw_ty, msg) = match decoder.next_frame()? {
+            Some(frame) => frame,
+            // `fill` guarantees a complete frame, just try again
+            None => continue,
+        };
+        keepalive
+            .lock()
+            .expect("lock poisened!")
+            .on_traffic(Instant::now());
+
+        let ty = MessageTypes::from(raw_ty);
         if ty == MessageTypes::Unkown {
-            info!("Recevied shutdown signal");
-            return Ok(());
+            warn!("received unknown message type {raw_ty}, ignoring");
+            continue;
         }
         trace!("received type {}", ty);
 
@@ -187,7 +280,7 @@ async fn handle_net(
                 info!("DisconnectResponse");
                 expect_empty!(msg, "DisconnectResponse");
 
-                bail!("disconnected");
+                return Err(Error::Transport(TransportError::Disconnected));
             }
             MessageTypes::PingRequest => {
                 // PingRequest
@@ -203,20 +296,25 @@ async fn handle_net(
                     .await?;
                 continue;
             }
-            MessageTypes::PingResponse => {}
+            MessageTypes::PingResponse => {
+                // answer to our keepalive ping, traffic is already accounted for
+                trace!("PingResponse");
+                expect_empty!(msg, "PingResponse");
+                continue;
+            }
             _ => {}
         }
 
         // check if type is allowed
         if !state.is_call_legal(ty) {
             warn!("received illegal call! type: {ty}, state {state:?}");
-            return Ok(());
+            return Err(Error::Protocol(ProtocolError::IllegalCall(ty)));
         }
 
         match ty {
             MessageTypes::HelloRequest => {
                 // HelloRequest
-                let req = HelloRequest::parse_from_bytes(&msg)?;
+                let req = HelloRequest::parse_from_bytes(msg)?;
                 info!("HelloRequest");
                 info!(
                     " -> incoming connection from client {}",
@@ -242,18 +340,17 @@ async fn handle_net(
                 // ConnectRequest
                 info!("ConnectRequest");
 
-                let req = ConnectRequest::parse_from_bytes(&msg)?;
+                let req = ConnectRequest::parse_from_bytes(msg)?;
 
-                let valid_login =
-                    device.password.is_empty() || req.get_password() == device.password;
-
-                if !valid_login {
-                    // Shall we print it? Or better not?
-                    // (we don't support encryption, so anybody in the network can read it anyway)
-                    warn!("invalid login attempt: {}", req.get_password());
-
-                    // don't bail yet!
-                }
+                // never log the password, not even a wrong one
+                let valid_login = match device.auth.check(peer, req.get_password()) {
+                    Ok(()) => true,
+                    Err(delay) => {
+                        // slow down brute forcing, don't bail yet!
+                        Timer::after(delay).await;
+                        false
+                    }
+                };
 
                 let mut resp = ConnectResponse::new();
                 resp.set_invalid_password(!valid_login);
@@ -267,7 +364,7 @@ async fn handle_net(
 
                 if !valid_login {
                     // time to bail
-                    bail!("invalid login attempt!");
+                    return Err(Error::Auth(AuthError::InvalidPassword));
                 }
 
                 info!("connected");
@@ -292,9 +389,16 @@ async fn handle_net(
                 resp.set_model(device.model.to_owned());
                 resp.set_name(device.name.to_owned());
                 resp.set_project_name(device.project_name.to_owned());
-                resp.set_project_version(device.project_version.to_owned());
+                // there is no field for it, the version shows up in the dashboard and in Home Assistant
+                match &device.safe_mode {
+                    Some(reason) => resp.set_project_version(format!(
+                        "{} (safe mode: {})",
+                        device.project_version, reason
+                    )),
+                    None => resp.set_project_version(device.project_version.to_owned()),
+                }
 
-                resp.set_uses_password(!device.password.is_empty());
+                resp.set_uses_password(device.auth.uses_password());
 
                 int_send
                     .send(ComponentUpdate::Response((
@@ -309,10 +413,9 @@ async fn handle_net(
                 expect_empty!(msg, "ListEntitiesRequest");
 
                 // int_send.send(ComponentUpdate::ListEntitiesRequest).await?;
-                for comp in &device.component_description {
-                    // send_packet(&mut stream_send, comp.0, comp.1.as_ref()).await?;
+                for desc in &device.component_description {
                     int_send
-                        .send(ComponentUpdate::Response((comp.0, comp.1.to_owned())))
+                        .send(ComponentUpdate::Response((desc.ty, desc.msg.to_owned())))
                         .await?;
                 }
 
@@ -330,27 +433,60 @@ async fn handle_net(
                 expect_empty!(msg, "SubscribeStatesRequest");
 
                 // request state from all
-                ext_send
-                    .send(ComponentUpdate::Request(None))
-                    .await
-                    .expect("failed to send");
+                ext_send.send(ComponentUpdate::Request(None)).await?;
             }
             MessageTypes::SubscribeLogsRequest => {
                 // SubscribeLogsRequest
                 info!("SubscribeLogsRequest");
 
-                let msg = SubscribeLogsRequest::parse_from_bytes(&msg)?;
-                // update log state for client
-                *log.lock().expect("lock poisened!") = msg.level;
+                let msg = SubscribeLogsRequest::parse_from_bytes(msg)?;
+                // update log state for client, it gets the buffered lines first
+                log.lock().expect("lock poisened!").subscribe(msg.level);
             }
             MessageTypes::LightCommandRequest => {
                 // LightCommandRequest
                 info!("LightCommandRequest");
 
-                let msg = LightCommandRequest::parse_from_bytes(&msg)?;
-                let msg = ComponentUpdate::LightRequest(Box::new(msg));
+                let msg = LightCommandRequest::parse_from_bytes(msg)?;
+                let msg = ComponentUpdate::Command(Command::Light(Box::new(msg)));
+
+                ext_send.send(msg).await?;
+            }
+            MessageTypes::SwitchCommandRequest => {
+                // SwitchCommandRequest
+                info!("SwitchCommandRequest");
+
+                let msg = SwitchCommandRequest::parse_from_bytes(msg)?;
+                let msg = ComponentUpdate::Command(Command::Switch(Box::new(msg)));
+
+                ext_send.send(msg).await?;
+            }
+            MessageTypes::NumberCommandRequest => {
+                // NumberCommandRequest
+                info!("NumberCommandRequest");
+
+                let msg = NumberCommandRequest::parse_from_bytes(msg)?;
+                let msg = ComponentUpdate::Command(Command::Number(Box::new(msg)));
+
+                ext_send.send(msg).await?;
+            }
+            MessageTypes::SelectCommandRequest => {
+                // SelectCommandRequest
+                info!("SelectCommandRequest");
+
+                let msg = SelectCommandRequest::parse_from_bytes(msg)?;
+                let msg = ComponentUpdate::Command(Command::Select(Box::new(msg)));
+
+                ext_send.send(msg).await?;
+            }
+            MessageTypes::ButtonCommandRequest => {
+                // ButtonCommandRequest
+                info!("ButtonCommandRequest");
+
+                let msg = ButtonCommandRequest::parse_from_bytes(msg)?;
+                let msg = ComponentUpdate::Command(Command::Button(Box::new(msg)));
 
-                ext_send.send(msg).await.expect("failed to send");
+                ext_send.send(msg).await?;
             }
             MessageTypes::SubscribeHomeassistantServicesRequest => {
                 // SubscribeHomeassistantServicesRequest
@@ -364,6 +500,25 @@ async fn handle_net(
 
                 // none for now
             }
+            MessageTypes::GetTimeRequest => {
+                // GetTimeRequest
+                info!("GetTimeRequest");
+                expect_empty!(msg, "GetTimeRequest");
+
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+
+                let mut resp = GetTimeResponse::new();
+                resp.set_epoch_seconds(now.as_secs() as u32);
+
+                int_send
+                    .send(ComponentUpdate::Response((
+                        MessageTypes::GetTimeResponse,
+                        Arc::new(Box::new(resp)),
+                    )))
+                    .await?;
+            }
             _ => {
                 warn!("type {} is not implemted yet!", ty);
                 // break;
@@ -372,117 +527,38 @@ async fn handle_net(
     }
 }
 
-pub fn to_varuint(mut i: u32) -> Vec<u8> {
-    if i <= 0x7f {
-        return vec![i as u8];
-    }
-
-    let mut buffer = vec![];
-
-    while i > 0 {
-        let tmp = (i & 0x7f) as u8;
-        i >>= 7;
-        if i > 0 {
-            buffer.push(tmp | 0x80);
-        } else {
-            buffer.push(tmp)
-        }
-    }
-
-    buffer
-}
-
-pub fn from_varuint(buf: &Vec<u8>) -> u32 {
-    let mut i = 0 as u32;
-    let mut bitpos = 0;
-
-    for b in buf {
-        i |= ((b & 0x7f) as u32) << bitpos;
-        bitpos += 7;
-
-        if (b & 0x80) == 0 {
-            return i;
-        }
-    }
-    0
-}
+/// Sends a `PingRequest` whenever the client is idle for too long
+///
+/// Only returns when the client should be disconnected.
+async fn run_keepalive(keepalive: &Mutex<Keepalive>, int_send: &Sender<ComponentUpdate>) {
+    loop {
+        let action = keepalive
+            .lock()
+            .expect("lock poisened!")
+            .poll(Instant::now());
+
+        match action {
+            KeepaliveAction::Wait(deadline) => {
+                Timer::at(deadline).await;
+            }
+            KeepaliveAction::SendPing => {
+                trace!("client is idle, sending PingRequest");
 
-async fn read_packet<T: AsyncRead + Unpin>(stream: &mut T) -> Result<(MessageTypes, Vec<u8>)> {
-    match read_packet_inner(stream).await {
-        Ok((0, msg)) => {
-            assert!(msg.is_empty());
-            return Ok((MessageTypes::Unkown, vec![]));
-        }
-        Ok((ty, msg)) => Ok((ty.into(), msg)),
-        Err(err) => {
-            if let Some(err) = err.downcast_ref::<std::io::Error>() {
-                match err.kind() {
-                    std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::UnexpectedEof => {
-                        return Ok((MessageTypes::Unkown, vec![]));
-                    }
-                    _ => {
-                        warn!("read_packet recieved io error: {}", err);
-                        bail!("read_packet recieved io error: {}", err);
-                    }
+                let req = PingRequest::new();
+                let res = int_send
+                    .send(ComponentUpdate::Response((
+                        MessageTypes::PingRequest,
+                        Arc::new(Box::new(req)),
+                    )))
+                    .await;
+                if res.is_err() {
+                    // queue is gone, no point in waiting any longer
+                    return;
                 }
             }
-
-            bail!("unhandled error {}", err);
-        }
-    }
-}
-
-async fn read_packet_inner<T: AsyncRead + Unpin>(stream: &mut T) -> Result<(u32, Vec<u8>)> {
-    use smol::io::AsyncReadExt;
-
-    let mut buf_single: [u8; 1] = [!0];
-    let mut buf: Vec<u8> = vec![];
-
-    // recieve empty byte preamble
-    trace!("waiting for preamble");
-    let len = stream.read(&mut buf_single).await?;
-    if len == 0 {
-        bail!("nothing to read. Stream is closed");
-    }
-
-    if buf_single[0] != 0 {
-        bail!("invalid preamble");
-    }
-
-    // receive varuint len
-    buf.clear();
-    loop {
-        stream.read_exact(&mut buf_single).await?;
-        buf.push(buf_single[0]);
-
-        if (buf_single[0] & 0x80) == 0 {
-            break;
+            KeepaliveAction::Disconnect => return,
         }
     }
-    let len = from_varuint(&buf);
-    trace!("len {} (0x{:x})", len, len);
-
-    // receive varuint type
-    buf.clear();
-    loop {
-        stream.read_exact(&mut buf_single).await?;
-        buf.push(buf_single[0]);
-
-        if (buf_single[0] & 0x80) == 0 {
-            break;
-        }
-    }
-    let ty = from_varuint(&buf);
-    trace!("type {} (0x{:x})", ty, ty);
-
-    if len == 0 {
-        return Ok((ty, vec![]));
-    }
-
-    let mut msg: Vec<u8> = vec![0; len as usize];
-    stream.read_exact(&mut msg).await?;
-
-    Ok((ty, msg))
 }
 
 async fn send_packet<T>(stream: &mut T, ty: MessageTypes, msg: &dyn Message) -> Result<()>
@@ -490,12 +566,7 @@ where
     T: AsyncWrite + Unpin,
 {
     trace!("sending {}, {:?}", ty as u32, msg);
-    let len = msg.compute_size();
-
-    let mut packet = vec![0 as u8];
-    packet.append(&mut to_varuint(len));
-    packet.append(&mut to_varuint(ty as u32));
-    packet.append(&mut msg.write_to_bytes()?);
+    let packet = encode_frame(ty as u32, &msg.write_to_bytes()?);
 
     stream.write_all(&packet).await?;
 
@@ -0,0 +1,168 @@
+//! One channel of ADC1, for [`super::sensor::Sensor`]
+//!
+//! Voltages are corrected with the calibration burned into the eFuses, ADC2 is left alone because WiFi uses it.
+
+use esp_idf_sys::*;
+use log::*;
+
+/// Sample count used by ESPHome
+const DEFAULT_SAMPLES: u8 = 1;
+/// Only used when the chip has no calibration in its eFuses
+const DEFAULT_VREF_MV: u32 = 1100;
+
+/// Measurable range grows with the attenuation, the accuracy drops
+///
+/// Full scale is about 0.75 V, 1.05 V, 1.3 V and 2.5 V on the ESP32-C3 (about 0.1 V more on the ESP32).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attenuation {
+    Db0,
+    Db2_5,
+    Db6,
+    Db11,
+}
+
+impl Attenuation {
+    /// Smallest attenuation that still measures `max` V, the most accurate one for the range
+    pub fn for_voltage(max: f32) -> Self {
+        // ESP32-C3, the ESP32 reaches a bit further
+        match max {
+            max if max <= 0.75 => Attenuation::Db0,
+            max if max <= 1.05 => Attenuation::Db2_5,
+            max if max <= 1.3 => Attenuation::Db6,
+            _ => Attenuation::Db11,
+        }
+    }
+
+    fn raw(&self) -> adc_atten_t {
+        match self {
+            Attenuation::Db0 => adc_atten_t_ADC_ATTEN_DB_0,
+            Attenuation::Db2_5 => adc_atten_t_ADC_ATTEN_DB_2_5,
+            Attenuation::Db6 => adc_atten_t_ADC_ATTEN_DB_6,
+            Attenuation::Db11 => adc_atten_t_ADC_ATTEN_DB_11,
+        }
+    }
+}
+
+/// Where the voltage calibration comes from, best first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Calibration {
+    /// Two point values from the eFuses
+    EfuseTwoPoint,
+    /// Reference voltage from the eFuses
+    EfuseVref,
+    /// Nothing in the eFuses, assumes [`DEFAULT_VREF_MV`]
+    DefaultVref,
+}
+
+/// What [`AdcDriver`] measures, like ESPHome's `raw` option
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// Calibrated, in V
+    Voltage,
+    /// ADC counts, 0 to 4095
+    Raw,
+}
+
+pub struct AdcDriver {
+    channel: adc1_channel_t,
+    samples: u8,
+    output: Output,
+    characteristics: esp_adc_cal_characteristics_t,
+    calibration: Calibration,
+}
+
+impl AdcDriver {
+    /// `channel` is an ADC1 channel, e.g. `adc1_channel_t_ADC1_CHANNEL_1` for GPIO1 on the ESP32-C3
+    pub fn new(channel: adc1_channel_t, attenuation: Attenuation) -> Result<Self, EspError> {
+        esp!(unsafe { adc1_config_width(adc_bits_width_t_ADC_WIDTH_BIT_12) })?;
+        esp!(unsafe { adc1_config_channel_atten(channel, attenuation.raw()) })?;
+
+        let mut characteristics = esp_adc_cal_characteristics_t::default();
+        let source = unsafe {
+            esp_adc_cal_characterize(
+                adc_unit_t_ADC_UNIT_1,
+                attenuation.raw(),
+                adc_bits_width_t_ADC_WIDTH_BIT_12,
+                DEFAULT_VREF_MV,
+                &mut characteristics,
+            )
+        };
+        #[allow(non_upper_case_globals)]
+        let calibration = match source {
+            esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_TP => Calibration::EfuseTwoPoint,
+            esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_VREF => Calibration::EfuseVref,
+            _ => Calibration::DefaultVref,
+        };
+        if calibration == Calibration::DefaultVref {
+            warn!("ADC is not calibrated, voltages may be off by up to 10%");
+        }
+
+        Ok(AdcDriver {
+            channel,
+            samples: DEFAULT_SAMPLES,
+            output: Output::Voltage,
+            characteristics,
+            calibration,
+        })
+    }
+
+    /// Averages this many readings per measurement against noise
+    pub fn with_samples(mut self, samples: u8) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    pub fn with_output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    fn read_raw(&self) -> Result<u32, EspError> {
+        let mut sum = 0;
+        for _ in 0..self.samples {
+            match unsafe { adc1_get_raw(self.channel) } {
+                raw if raw < 0 => {
+                    return Err(EspError::from(ESP_FAIL).expect("ESP_FAIL is an error"))
+                }
+                raw => sum += raw as u32,
+            }
+        }
+        Ok(sum / self.samples as u32)
+    }
+}
+
+impl super::sensor::SensorDriver for AdcDriver {
+    type Error = EspError;
+
+    fn measure(&mut self) -> Result<Option<f32>, EspError> {
+        let raw = self.read_raw()?;
+        let value = match self.output {
+            Output::Raw => raw as f32,
+            Output::Voltage => {
+                let mv = unsafe { esp_adc_cal_raw_to_voltage(raw, &self.characteristics) };
+                mv as f32 / 1000.
+            }
+        };
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attenuation_for_voltage() {
+        assert_eq!(Attenuation::for_voltage(0.5), Attenuation::Db0);
+        assert_eq!(Attenuation::for_voltage(0.75), Attenuation::Db0);
+        assert_eq!(Attenuation::for_voltage(1.), Attenuation::Db2_5);
+        assert_eq!(Attenuation::for_voltage(1.2), Attenuation::Db6);
+        // a LiPo behind a 1:1 divider
+        assert_eq!(Attenuation::for_voltage(4.2 / 2.), Attenuation::Db11);
+        assert_eq!(Attenuation::for_voltage(3.3), Attenuation::Db11);
+    }
+}
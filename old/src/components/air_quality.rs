@@ -0,0 +1,337 @@
+//! eCO2 and TVOC component shared by the CCS811 and SGP30 drivers
+//!
+//! The sensors adapt to their surroundings over time, this state (the baseline) is lost on every power cycle.
+//! It is saved to the preferences once the sensor has settled and written back to the chip on the next boot.
+//! Temperature and humidity from a [`Compensation`] are passed to the chip whenever they change.
+
+use std::{
+    fmt::{Debug, Display, Formatter},
+    time::{Duration, Instant},
+};
+
+use log::*;
+
+use crate::{
+    api::{EntityCategory, ListEntitiesTextSensorResponse, TextSensorStateResponse},
+    components::{
+        compensation::{Compensation, Environment},
+        entity::{EntityDescription, EntityMeta, StatePublisher},
+        sensor::SensorEntity,
+        BaseComponent, Component,
+    },
+    error::{Error, Result},
+    preferences::SharedPreferences,
+    utils::name_to_hash,
+};
+
+#[cfg(feature = "has_ccs811")]
+use super::ccs811::SensorError;
+
+/// How often eCO2 and TVOC are published, sensors may sample more often
+pub(super) const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+/// Datasheets of both chips recommend storing the baseline about once an hour
+const BASELINE_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub enum DriverError<E> {
+    I2c(E),
+    /// ID register holds something else
+    UnknownChip(u8),
+    /// No valid application firmware, the chip is stuck in its boot loader
+    #[cfg(feature = "has_ccs811")]
+    NoFirmware,
+    /// The chip reported a problem
+    #[cfg(feature = "has_ccs811")]
+    Sensor(SensorError),
+    /// Data got corrupted on the bus
+    #[cfg(feature = "has_sgp30")]
+    Crc,
+}
+
+impl<E> From<E> for DriverError<E> {
+    fn from(err: E) -> Self {
+        DriverError::I2c(err)
+    }
+}
+
+impl<E: Debug> Display for DriverError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriverError::I2c(err) => write!(f, "i2c: {err:?}"),
+            DriverError::UnknownChip(id) => write!(f, "unknown chip 0x{id:02x}"),
+            #[cfg(feature = "has_ccs811")]
+            DriverError::NoFirmware => write!(f, "no valid firmware"),
+            #[cfg(feature = "has_ccs811")]
+            DriverError::Sensor(err) => write!(f, "{err}"),
+            #[cfg(feature = "has_sgp30")]
+            DriverError::Crc => write!(f, "CRC mismatch"),
+        }
+    }
+}
+
+/// eCO2 in ppm and TVOC in ppb
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AirQualityData {
+    pub eco2: u16,
+    pub tvoc: u16,
+}
+
+/// A driver the [`AirQuality`] component can use
+pub trait AirQualitySensor {
+    type Error: Debug;
+
+    fn chip_name(&self) -> &'static str;
+
+    /// Read once during initialisation
+    fn firmware_version(&self) -> String;
+
+    /// How often [`AirQualitySensor::measure`] has to be called
+    fn sample_interval(&self) -> Duration;
+
+    /// Time after the start before the baseline should be written
+    fn warm_up(&self) -> Duration;
+
+    /// `None` when there is no new data yet
+    fn measure(&mut self) -> std::result::Result<Option<AirQualityData>, DriverError<Self::Error>>;
+
+    /// Opaque to the caller, only meant to be written back with [`AirQualitySensor::set_baseline`]
+    fn baseline(&mut self) -> std::result::Result<u32, DriverError<Self::Error>>;
+
+    fn set_baseline(&mut self, baseline: u32) -> std::result::Result<(), DriverError<Self::Error>>;
+
+    fn set_environment(
+        &mut self,
+        environment: Environment,
+    ) -> std::result::Result<(), DriverError<Self::Error>>;
+}
+
+/// Where the baseline handling is at
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Baseline {
+    /// Waiting for the sensor to warm up before restoring the saved baseline
+    Restore(Instant),
+    Save(Instant),
+}
+
+/// eCO2 and TVOC sensors plus firmware version and status as diagnostic text sensors
+pub struct AirQuality<S> {
+    sensor: S,
+    eco2: SensorEntity,
+    tvoc: SensorEntity,
+    status: BaseComponent,
+    version: BaseComponent,
+
+    compensation: Option<Compensation>,
+    /// Last values sent to the chip
+    environment: Option<Environment>,
+
+    preferences: SharedPreferences,
+    baseline_key: u32,
+    baseline: Baseline,
+
+    latest: Option<AirQualityData>,
+    last_status: String,
+    /// Since the last publish
+    samples: u32,
+
+    publisher: StatePublisher,
+}
+
+impl<S: AirQualitySensor> AirQuality<S> {
+    /// Entity names are `name` followed by the reading, e.g. "Kitchen eCO2"
+    pub fn new(
+        sensor: S,
+        name: &str,
+        preferences: SharedPreferences,
+        publisher: StatePublisher,
+    ) -> AirQuality<S> {
+        let text_sensor = |suffix: &str, meta: EntityMeta| {
+            BaseComponent::new(String::from(name) + " " + suffix, "text_sensor").with_meta(meta)
+        };
+        let diagnostic =
+            EntityMeta::default().entity_category(EntityCategory::ENTITY_CATEGORY_DIAGNOSTIC);
+
+        // a baseline does not fit another chip
+        let baseline_key = name_to_hash(&format!("{} {} baseline", name, sensor.chip_name()));
+
+        AirQuality {
+            eco2: SensorEntity::new(String::from(name) + " eCO2")
+                .with_meta(
+                    EntityMeta::default()
+                        .device_class("carbon_dioxide")
+                        .icon("mdi:molecule-co2"),
+                )
+                .with_unit("ppm", 0),
+            tvoc: SensorEntity::new(String::from(name) + " Total Volatile Organic Compounds")
+                .with_meta(EntityMeta::default().device_class("volatile_organic_compounds"))
+                .with_unit("ppb", 0),
+            status: text_sensor(
+                "Status",
+                diagnostic.clone().icon("mdi:alert-circle-outline"),
+            ),
+            version: text_sensor("Firmware Version", diagnostic.icon("mdi:new-box")),
+            sensor,
+
+            compensation: None,
+            environment: None,
+
+            preferences,
+            baseline_key,
+            baseline: Baseline::Restore(Instant::now()),
+
+            latest: None,
+            last_status: String::from("OK"),
+            samples: 0,
+
+            publisher,
+        }
+    }
+
+    /// Temperature and humidity from a companion sensor, e.g. a BME280
+    pub fn with_compensation(mut self, compensation: Compensation) -> Self {
+        self.compensation = Some(compensation);
+        self
+    }
+
+    fn error(&self, err: DriverError<S::Error>) -> Error {
+        Error::component(
+            self.eco2.get_key(),
+            format!("{}: {}", self.sensor.chip_name(), err),
+        )
+    }
+
+    fn compensate(&mut self) -> std::result::Result<(), DriverError<S::Error>> {
+        let environment = match self.compensation.as_ref().and_then(|c| c.get()) {
+            Some(environment) if Some(environment) != self.environment => environment,
+            _ => return Ok(()),
+        };
+
+        self.sensor.set_environment(environment)?;
+        debug!(
+            "{} compensating for {:.1}°C and {:.1}%",
+            self.sensor.chip_name(),
+            environment.temperature,
+            environment.humidity
+        );
+        self.environment = Some(environment);
+        Ok(())
+    }
+
+    fn handle_baseline(&mut self, now: Instant) -> std::result::Result<(), DriverError<S::Error>> {
+        match self.baseline {
+            Baseline::Restore(at) if at <= now => {
+                let saved = self
+                    .preferences
+                    .lock()
+                    .expect("lock poisened!")
+                    .load::<u32>(self.baseline_key);
+                if let Some(baseline) = saved {
+                    self.sensor.set_baseline(baseline)?;
+                    info!(
+                        "{} baseline 0x{:x} restored",
+                        self.sensor.chip_name(),
+                        baseline
+                    );
+                }
+                self.baseline = Baseline::Save(now + BASELINE_SAVE_INTERVAL);
+            }
+            Baseline::Save(at) if at <= now => {
+                let baseline = self.sensor.baseline()?;
+                debug!("{} baseline is 0x{:x}", self.sensor.chip_name(), baseline);
+                // unchanged values are not written again
+                self.preferences.lock().expect("lock poisened!").save(
+                    self.baseline_key,
+                    &baseline,
+                    now,
+                );
+                self.baseline = Baseline::Save(now + BASELINE_SAVE_INTERVAL);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self, now: Instant) -> std::result::Result<(), DriverError<S::Error>> {
+        self.compensate()?;
+        if let Some(data) = self.sensor.measure()? {
+            self.latest = Some(data);
+        }
+        self.handle_baseline(now)
+    }
+
+    /// Publishes the status only when it changed
+    fn set_status(&mut self, status: String) {
+        if status != self.last_status {
+            self.last_status = status;
+            self.publish_status();
+        }
+    }
+
+    fn publish_status(&self) {
+        let mut resp = TextSensorStateResponse::new();
+        resp.set_key(self.status.get_object_id_hash());
+        resp.set_state(self.last_status.clone());
+        self.publisher.publish(resp);
+    }
+
+    fn publish_data(&self) {
+        let data = match self.latest {
+            Some(data) => data,
+            None => return,
+        };
+        trace!("eCO2: {}ppm, TVOC: {}ppb", data.eco2, data.tvoc);
+
+        self.eco2.publish(&self.publisher, data.eco2 as f32);
+        self.tvoc.publish(&self.publisher, data.tvoc as f32);
+    }
+}
+
+impl<S: AirQualitySensor + Send> Component for AirQuality<S> {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        vec![
+            self.eco2.describe(),
+            self.tvoc.describe(),
+            self.status.describe(ListEntitiesTextSensorResponse::new()),
+            self.version.describe(ListEntitiesTextSensorResponse::new()),
+        ]
+    }
+
+    fn setup(&mut self) -> Result<()> {
+        self.baseline = Baseline::Restore(Instant::now() + self.sensor.warm_up());
+        Ok(())
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let now = Instant::now();
+
+        if let Err(err) = self.sample(now) {
+            self.set_status(err.to_string());
+            return Err(self.error(err));
+        }
+        self.set_status(String::from("OK"));
+
+        // counted instead of timed, updates do not happen at exact intervals
+        self.samples += 1;
+        let interval = self.sensor.sample_interval().as_millis().max(1);
+        if self.samples as u128 >= PUBLISH_INTERVAL.as_millis() / interval {
+            self.samples = 0;
+            self.publish_data();
+        }
+        Ok(())
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        Some(self.sensor.sample_interval())
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.publish_data();
+        self.publish_status();
+
+        let mut resp = TextSensorStateResponse::new();
+        resp.set_key(self.version.get_object_id_hash());
+        resp.set_state(self.sensor.firmware_version());
+        self.publisher.publish(resp);
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+//! Bosch BME280/BMP280/BME680 sensor component, shared by the drivers in `bme280` and `bme680`
+//!
+//! The drivers only use embedded-hal 1.0, measurements run in forced mode so the sensor sleeps in between
+//! and does not heat itself up.
+
+use std::{
+    fmt::{Debug, Display, Formatter},
+    time::Duration,
+};
+
+use log::*;
+
+use crate::{
+    components::{
+        compensation::{Compensation, Environment},
+        entity::{EntityDescription, EntityMeta, StatePublisher},
+        sensor::SensorEntity,
+        Component,
+    },
+    error::{Error, Result},
+};
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const ADDRESS_PRIMARY: u8 = 0x76;
+pub const ADDRESS_SECONDARY: u8 = 0x77;
+
+// registers, the same on all chips
+pub(super) const REG_CHIP_ID: u8 = 0xd0;
+pub(super) const REG_RESET: u8 = 0xe0;
+pub(super) const RESET: u8 = 0xb6;
+
+/// Give up on the status bits after this many polls
+pub(super) const MAX_POLLS: u8 = 10;
+
+/// Number of samples averaged per measurement, `Skipped` disables a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Oversampling {
+    Skipped = 0,
+    X1 = 1,
+    X2 = 2,
+    X4 = 3,
+    X8 = 4,
+    X16 = 5,
+}
+
+impl Oversampling {
+    pub fn samples(&self) -> u32 {
+        match self {
+            Oversampling::Skipped => 0,
+            Oversampling::X1 => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+            Oversampling::X8 => 8,
+            Oversampling::X16 => 16,
+        }
+    }
+
+    /// ESPHome's names, e.g. `16x`, `NONE` disables the channel
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "NONE" => Some(Oversampling::Skipped),
+            "1x" => Some(Oversampling::X1),
+            "2x" => Some(Oversampling::X2),
+            "4x" => Some(Oversampling::X4),
+            "8x" => Some(Oversampling::X8),
+            "16x" => Some(Oversampling::X16),
+            _ => None,
+        }
+    }
+}
+
+/// IIR filter on temperature and pressure against short disturbances like a slammed door
+///
+/// Register values, the coefficient is `2^n` on the BME280 and `2^n - 1` on the BME680.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IirFilter {
+    Off = 0,
+    X2 = 1,
+    X4 = 2,
+    X8 = 3,
+    X16 = 4,
+}
+
+impl IirFilter {
+    /// ESPHome's names, e.g. `OFF` or `4x`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "OFF" => Some(IirFilter::Off),
+            "2x" => Some(IirFilter::X2),
+            "4x" => Some(IirFilter::X4),
+            "8x" => Some(IirFilter::X8),
+            "16x" => Some(IirFilter::X16),
+            _ => None,
+        }
+    }
+}
+
+/// Like ESPHome's `oversampling` and `iir_filter` options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub temperature: Oversampling,
+    pub pressure: Oversampling,
+    /// Ignored by chips without humidity sensor
+    pub humidity: Oversampling,
+    pub iir_filter: IirFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            temperature: Oversampling::X16,
+            pressure: Oversampling::X16,
+            humidity: Oversampling::X16,
+            iir_filter: IirFilter::Off,
+        }
+    }
+}
+
+impl Config {
+    pub fn temperature(mut self, oversampling: Oversampling) -> Self {
+        self.temperature = oversampling;
+        self
+    }
+
+    pub fn pressure(mut self, oversampling: Oversampling) -> Self {
+        self.pressure = oversampling;
+        self
+    }
+
+    pub fn humidity(mut self, oversampling: Oversampling) -> Self {
+        self.humidity = oversampling;
+        self
+    }
+
+    pub fn iir_filter(mut self, iir_filter: IirFilter) -> Self {
+        self.iir_filter = iir_filter;
+        self
+    }
+
+    /// Temperature is needed to compensate the others, it is measured even when it is not published
+    pub(super) fn temperature_measured(&self) -> Oversampling {
+        if self.pressure != Oversampling::Skipped || self.humidity != Oversampling::Skipped {
+            self.temperature.max(Oversampling::X1)
+        } else {
+            self.temperature
+        }
+    }
+}
+
+/// What a chip can measure, every reading becomes a sensor entity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    Temperature,
+    Pressure,
+    Humidity,
+    #[cfg(feature = "has_bme680")]
+    GasResistance,
+}
+
+impl Reading {
+    fn name(&self) -> &'static str {
+        match self {
+            Reading::Temperature => "Temperature",
+            Reading::Pressure => "Pressure",
+            Reading::Humidity => "Humidity",
+            #[cfg(feature = "has_bme680")]
+            Reading::GasResistance => "Gas Resistance",
+        }
+    }
+
+    fn device_class(&self) -> Option<&'static str> {
+        match self {
+            Reading::Temperature => Some("temperature"),
+            Reading::Pressure => Some("pressure"),
+            Reading::Humidity => Some("humidity"),
+            #[cfg(feature = "has_bme680")]
+            Reading::GasResistance => None,
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            Reading::Temperature => "°C",
+            Reading::Pressure => "hPa",
+            Reading::Humidity => "%",
+            #[cfg(feature = "has_bme680")]
+            Reading::GasResistance => "Ω",
+        }
+    }
+
+    fn accuracy_decimals(&self) -> i32 {
+        match self {
+            Reading::Temperature | Reading::Pressure | Reading::Humidity => 1,
+            #[cfg(feature = "has_bme680")]
+            Reading::GasResistance => 0,
+        }
+    }
+
+    fn icon(&self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "has_bme680")]
+            Reading::GasResistance => Some("mdi:gas-cylinder"),
+            Reading::Temperature | Reading::Pressure | Reading::Humidity => None,
+        }
+    }
+}
+
+/// One forced measurement, `None` for disabled (or unsupported) readings
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Measurement {
+    /// °C
+    pub temperature: Option<f32>,
+    /// hPa
+    pub pressure: Option<f32>,
+    /// %
+    pub humidity: Option<f32>,
+    /// Ω
+    #[cfg(feature = "has_bme680")]
+    pub gas_resistance: Option<f32>,
+}
+
+impl Measurement {
+    fn get(&self, reading: Reading) -> Option<f32> {
+        match reading {
+            Reading::Temperature => self.temperature,
+            Reading::Pressure => self.pressure,
+            Reading::Humidity => self.humidity,
+            #[cfg(feature = "has_bme680")]
+            Reading::GasResistance => self.gas_resistance,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DriverError<E> {
+    I2c(E),
+    /// Chip ID register holds something else
+    UnknownChip(u8),
+    /// Status bits never cleared
+    Timeout,
+}
+
+impl<E> From<E> for DriverError<E> {
+    fn from(err: E) -> Self {
+        DriverError::I2c(err)
+    }
+}
+
+impl<E: Debug> Display for DriverError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriverError::I2c(err) => write!(f, "i2c: {err:?}"),
+            DriverError::UnknownChip(id) => write!(f, "unknown chip 0x{id:02x}"),
+            DriverError::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+/// A driver the [`Bme`] component can use
+pub trait BmeSensor {
+    type Error: Debug;
+
+    fn chip_name(&self) -> &'static str;
+
+    /// Readings that are enabled and supported by the chip
+    fn readings(&self) -> Vec<Reading>;
+
+    /// Blocks until the measurement is done
+    fn measure(&mut self) -> std::result::Result<Measurement, DriverError<Self::Error>>;
+}
+
+/// Sensor entities of a BME280, BMP280 or BME680
+pub struct Bme<S> {
+    sensor: S,
+    entities: Vec<(Reading, SensorEntity)>,
+    compensation: Option<Compensation>,
+
+    publisher: StatePublisher,
+}
+
+impl<S: BmeSensor> Bme<S> {
+    /// Entity names are `name` followed by the reading, e.g. "Kitchen Temperature"
+    pub fn new(sensor: S, name: &str, publisher: StatePublisher) -> Bme<S> {
+        let entities = sensor
+            .readings()
+            .into_iter()
+            .map(|reading| {
+                let mut meta = EntityMeta::default();
+                if let Some(device_class) = reading.device_class() {
+                    meta = meta.device_class(device_class);
+                }
+                if let Some(icon) = reading.icon() {
+                    meta = meta.icon(icon);
+                }
+                let entity = SensorEntity::new(String::from(name) + " " + reading.name())
+                    .with_meta(meta)
+                    .with_unit(reading.unit(), reading.accuracy_decimals());
+                (reading, entity)
+            })
+            .collect();
+
+        Bme {
+            sensor,
+            entities,
+            compensation: None,
+            publisher,
+        }
+    }
+
+    /// Shares temperature and humidity with gas sensors, e.g. a CCS811 on the same board
+    pub fn with_compensation(mut self, compensation: Compensation) -> Self {
+        self.compensation = Some(compensation);
+        self
+    }
+
+    fn publish(&mut self) -> Result<()> {
+        let measurement = self.sensor.measure().map_err(|err| {
+            let key = self
+                .entities
+                .first()
+                .map(|(_, entity)| entity.get_key())
+                .unwrap_or_default();
+            Error::component(
+                key,
+                format!("{} failed to measure: {}", self.sensor.chip_name(), err),
+            )
+        })?;
+
+        if let (Some(compensation), Some(temperature), Some(humidity)) = (
+            &self.compensation,
+            measurement.temperature,
+            measurement.humidity,
+        ) {
+            compensation.set(Environment {
+                temperature,
+                humidity,
+            });
+        }
+
+        for (reading, entity) in &self.entities {
+            match measurement.get(*reading) {
+                Some(value) => {
+                    trace!("measured {:.1}{}", value, reading.unit());
+                    entity.publish(&self.publisher, value);
+                }
+                None => debug!("{} did not measure {:?}", self.sensor.chip_name(), reading),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: BmeSensor + Send> Component for Bme<S> {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        self.entities
+            .iter()
+            .map(|(_, entity)| entity.describe())
+            .collect()
+    }
+
+    fn update(&mut self) -> Result<()> {
+        self.publish()
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        Some(UPDATE_INTERVAL)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.publish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(Oversampling::from_name("NONE"), Some(Oversampling::Skipped));
+        assert_eq!(Oversampling::from_name("16x"), Some(Oversampling::X16));
+        assert_eq!(Oversampling::from_name("16"), None);
+        assert_eq!(IirFilter::from_name("OFF"), Some(IirFilter::Off));
+        assert_eq!(IirFilter::from_name("4x"), Some(IirFilter::X4));
+        assert_eq!(IirFilter::from_name("1x"), None);
+    }
+
+    #[test]
+    fn temperature_is_measured_for_compensation() {
+        let config = Config::default().temperature(Oversampling::Skipped);
+        assert_eq!(config.temperature_measured(), Oversampling::X1);
+
+        let config = config
+            .pressure(Oversampling::Skipped)
+            .humidity(Oversampling::Skipped);
+        assert_eq!(config.temperature_measured(), Oversampling::Skipped);
+    }
+}
@@ -1,167 +1,448 @@
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
+//! Bosch BME280 and BMP280
 
-use log::*;
-use protobuf::Message;
+use embedded_hal_1::{delay::DelayNs, i2c::I2c};
 
-use embedded_hal::blocking::{
-    delay::DelayMs,
-    i2c::{Read, Write, WriteRead},
+use super::bme::{
+    BmeSensor, Config, DriverError, Measurement, Oversampling, Reading, MAX_POLLS, REG_CHIP_ID,
+    REG_RESET, RESET,
 };
 
-use bme280::BME280;
+const REG_CALIB_TP: u8 = 0x88;
+const REG_CALIB_H1: u8 = 0xa1;
+const REG_CALIB_H2: u8 = 0xe1;
+const REG_CTRL_HUM: u8 = 0xf2;
+const REG_STATUS: u8 = 0xf3;
+const REG_CTRL_MEAS: u8 = 0xf4;
+const REG_CONFIG: u8 = 0xf5;
+const REG_DATA: u8 = 0xf7;
 
-use crate::{
-    api::{ListEntitiesSensorResponse, SensorStateResponse},
-    components::{Component, ComponentUpdate},
-    consts::MessageTypes,
-    utils::*,
-};
+const STATUS_MEASURING: u8 = 1 << 3;
+const STATUS_IM_UPDATE: u8 = 1 << 0;
+const MODE_FORCED: u8 = 0b01;
+
+/// Raw value of a skipped measurement
+const SKIPPED_20BIT: u32 = 0x80000;
+const SKIPPED_16BIT: u32 = 0x8000;
 
-const UPDATE_TICK: Duration = Duration::from_secs(60);
-const NAME: &str = "Rusty old BME280";
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip {
+    Bmp280,
+    Bme280,
+}
 
-pub struct Bme280<I2C, D> {
-    key: u32,
+/// Compensation parameters from the chip's NVM
+#[derive(Debug, Clone, Copy, Default)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
 
-    bme: BME280<I2C, D>,
+impl Calibration {
+    /// `tp` starts at 0x88, `h2` at 0xe1
+    fn parse(tp: &[u8; 24], h1: u8, h2: &[u8; 7]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]);
 
-    last_update: Instant,
+        Calibration {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            h1,
+            h2: i16::from_le_bytes([h2[0], h2[1]]),
+            h3: h2[2],
+            // 12 bit values sharing 0xe5, the upper bytes are signed
+            h4: ((h2[3] as i8 as i16) << 4) | (h2[4] & 0x0f) as i16,
+            h5: ((h2[5] as i8 as i16) << 4) | (h2[4] >> 4) as i16,
+            h6: h2[6] as i8,
+        }
+    }
+
+    /// Returns °C and `t_fine` for the other compensations, floating point formulas from the datasheet
+    fn temperature(&self, adc: u32) -> (f64, f64) {
+        let adc = adc as f64;
+        let t1 = self.t1 as f64;
+
+        let var1 = (adc / 16384. - t1 / 1024.) * self.t2 as f64;
+        let var2 = (adc / 131072. - t1 / 8192.).powi(2) * self.t3 as f64;
+        let t_fine = var1 + var2;
+
+        (t_fine / 5120., t_fine)
+    }
+
+    /// Pa
+    fn pressure(&self, adc: u32, t_fine: f64) -> f64 {
+        let mut var1 = t_fine / 2. - 64000.;
+        let mut var2 = var1 * var1 * self.p6 as f64 / 32768.;
+        var2 += var1 * self.p5 as f64 * 2.;
+        var2 = var2 / 4. + self.p4 as f64 * 65536.;
+        var1 = (self.p3 as f64 * var1 * var1 / 524288. + self.p2 as f64 * var1) / 524288.;
+        var1 = (1. + var1 / 32768.) * self.p1 as f64;
+        if var1 == 0. {
+            // avoid a division by zero
+            return 0.;
+        }
+
+        let mut p = 1048576. - adc as f64;
+        p = (p - var2 / 4096.) * 6250. / var1;
+        let var1 = self.p9 as f64 * p * p / 2147483648.;
+        let var2 = p * self.p8 as f64 / 32768.;
+        p + (var1 + var2 + self.p7 as f64) / 16.
+    }
+
+    /// %
+    fn humidity(&self, adc: u32, t_fine: f64) -> f64 {
+        let var = t_fine - 76800.;
+        let var = (adc as f64 - (self.h4 as f64 * 64. + self.h5 as f64 / 16384. * var))
+            * (self.h2 as f64 / 65536.
+                * (1.
+                    + self.h6 as f64 / 67108864. * var * (1. + self.h3 as f64 / 67108864. * var)));
+        let var = var * (1. - self.h1 as f64 * var / 524288.);
+
+        var.clamp(0., 100.)
+    }
 }
 
-impl<E, I2C, D> Bme280<I2C, D>
-where
-    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
-    D: DelayMs<u8>,
-{
-    pub fn new(bme: BME280<I2C, D>) -> Bme280<I2C, D> {
-        Bme280 {
-            key: name_to_hash(NAME),
-            bme,
-
-            last_update: Instant::now(),
+/// BME280 or BMP280, whatever [`Bme280Driver::new`] finds
+pub struct Bme280Driver<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    chip: Chip,
+    config: Config,
+    calibration: Calibration,
+}
+
+impl<I2C: I2c, D: DelayNs> Bme280Driver<I2C, D> {
+    /// Resets the chip and reads its calibration
+    pub fn new(
+        i2c: I2C,
+        delay: D,
+        address: u8,
+        config: Config,
+    ) -> std::result::Result<Self, DriverError<I2C::Error>> {
+        let mut driver = Bme280Driver {
+            i2c,
+            delay,
+            address,
+            chip: Chip::Bme280,
+            config,
+            calibration: Calibration::default(),
+        };
+
+        driver.chip = match driver.read_reg(REG_CHIP_ID)? {
+            0x60 => Chip::Bme280,
+            // samples, mass production
+            0x56..=0x58 => Chip::Bmp280,
+            id => return Err(DriverError::UnknownChip(id)),
+        };
+
+        driver.write_reg(REG_RESET, RESET)?;
+        driver.delay.delay_ms(2);
+        driver.wait_status(STATUS_IM_UPDATE)?;
+
+        let mut tp = [0; 24];
+        driver.read_regs(REG_CALIB_TP, &mut tp)?;
+        let (mut h1, mut h2) = (0, [0; 7]);
+        if driver.chip == Chip::Bme280 {
+            h1 = driver.read_reg(REG_CALIB_H1)?;
+            driver.read_regs(REG_CALIB_H2, &mut h2)?;
         }
+        driver.calibration = Calibration::parse(&tp, h1, &h2);
+
+        // filter settings are only taken in sleep mode, which we are in after the reset
+        driver.write_reg(REG_CONFIG, (driver.config.iir_filter as u8) << 2)?;
+
+        Ok(driver)
     }
 
-    fn get_key(&self) -> u32 {
-        self.key
-    }
-
-    fn gen_resp(&mut self) -> Vec<ComponentUpdate> {
-        match self.bme.measure() {
-            Ok(mes) => {
-                trace!("measured {:.1}°C", mes.temperature);
-                trace!("measured {:.0}hPa", mes.pressure / 100.);
-                trace!("measured {:.2}%", mes.humidity);
-
-                let mut resp = vec![];
-
-                let mut temp = SensorStateResponse::new();
-                temp.set_key(self.get_key());
-                temp.set_state(mes.temperature);
-                resp.push(ComponentUpdate::Response((
-                    MessageTypes::SensorStateResponse,
-                    Arc::new(Box::new(temp)),
-                )));
-
-                let mut humi = SensorStateResponse::new();
-                humi.set_key(self.get_key() + 1);
-                humi.set_state(mes.humidity);
-                resp.push(ComponentUpdate::Response((
-                    MessageTypes::SensorStateResponse,
-                    Arc::new(Box::new(humi)),
-                )));
-
-                let mut pres = SensorStateResponse::new();
-                pres.set_key(self.get_key() + 2);
-                pres.set_state(mes.pressure / 100.); // mes.pressure is in Pa
-                resp.push(ComponentUpdate::Response((
-                    MessageTypes::SensorStateResponse,
-                    Arc::new(Box::new(pres)),
-                )));
-
-                return resp;
+    fn humidity(&self) -> Oversampling {
+        match self.chip {
+            Chip::Bme280 => self.config.humidity,
+            Chip::Bmp280 => Oversampling::Skipped,
+        }
+    }
+
+    /// Maximum measurement time from the datasheet, in µs
+    fn measurement_time(&self) -> u32 {
+        let channel = |os: Oversampling, overhead: u32| match os {
+            Oversampling::Skipped => 0,
+            os => 2300 * os.samples() + overhead,
+        };
+
+        1250 + channel(self.config.temperature_measured(), 0)
+            + channel(self.config.pressure, 575)
+            + channel(self.humidity(), 575)
+    }
+
+    fn wait_status(&mut self, mask: u8) -> std::result::Result<(), DriverError<I2C::Error>> {
+        for _ in 0..MAX_POLLS {
+            if self.read_reg(REG_STATUS)? & mask == 0 {
+                return Ok(());
             }
-            _ => vec![],
+            self.delay.delay_ms(1);
         }
+        Err(DriverError::Timeout)
+    }
+
+    fn read_reg(&mut self, reg: u8) -> std::result::Result<u8, I2C::Error> {
+        let mut buf = [0];
+        self.i2c.write_read(self.address, &[reg], &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> std::result::Result<(), I2C::Error> {
+        self.i2c.write_read(self.address, &[reg], buf)
+    }
+
+    fn write_reg(&mut self, reg: u8, value: u8) -> std::result::Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[reg, value])
     }
 }
 
-impl<E, I2C, D> Component for Bme280<I2C, D>
-where
-    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
-    D: DelayMs<u8>,
-{
-    fn get_description(&self) -> Vec<(MessageTypes, Arc<Box<dyn Message>>)> {
-        let mut resps: Vec<(MessageTypes, Arc<Box<dyn Message>>)> = vec![];
-
-        // Temperatur
-        let name = String::from(NAME) + " Temperatur";
-        let mut resp = ListEntitiesSensorResponse::new();
-        resp.set_disabled_by_default(false);
-        resp.set_key(self.get_key());
-        resp.set_name(name.to_owned());
-        resp.set_object_id(name_to_object(&name));
-        resp.set_unique_id(name_to_unique(&name, "bme280"));
-        resp.set_unit_of_measurement(String::from("°C"));
-        resp.set_accuracy_decimals(1);
-
-        resps.push((
-            MessageTypes::ListEntitiesSensorResponse,
-            Arc::new(Box::new(resp)),
-        ));
+impl<I2C: I2c, D: DelayNs> BmeSensor for Bme280Driver<I2C, D> {
+    type Error = I2C::Error;
 
-        // Humidity
-        let name = String::from(NAME) + " Humidity";
-        let mut resp = ListEntitiesSensorResponse::new();
-        resp.set_disabled_by_default(false);
-        resp.set_key(self.get_key() + 1);
-        resp.set_name(name.to_owned());
-        resp.set_object_id(name_to_object(&name));
-        resp.set_unique_id(name_to_unique(&name, "bme280"));
-        resp.set_unit_of_measurement(String::from("%"));
-        resp.set_accuracy_decimals(1);
-
-        resps.push((
-            MessageTypes::ListEntitiesSensorResponse,
-            Arc::new(Box::new(resp)),
-        ));
+    fn chip_name(&self) -> &'static str {
+        match self.chip {
+            Chip::Bme280 => "BME280",
+            Chip::Bmp280 => "BMP280",
+        }
+    }
 
-        // Preasure
-        let name = String::from(NAME) + " Preasure";
-        let mut resp = ListEntitiesSensorResponse::new();
-        resp.set_disabled_by_default(false);
-        resp.set_key(self.get_key() + 2);
-        resp.set_name(name.to_owned());
-        resp.set_object_id(name_to_object(&name));
-        resp.set_unique_id(name_to_unique(&name, "bme280"));
-        resp.set_unit_of_measurement(String::from("hPa"));
-        resp.set_accuracy_decimals(1);
-
-        resps.push((
-            MessageTypes::ListEntitiesSensorResponse,
-            Arc::new(Box::new(resp)),
-        ));
+    fn readings(&self) -> Vec<Reading> {
+        [
+            (Reading::Temperature, self.config.temperature),
+            (Reading::Pressure, self.config.pressure),
+            (Reading::Humidity, self.humidity()),
+        ]
+        .into_iter()
+        .filter(|(_, os)| *os != Oversampling::Skipped)
+        .map(|(reading, _)| reading)
+        .collect()
+    }
+
+    fn measure(&mut self) -> std::result::Result<Measurement, DriverError<I2C::Error>> {
+        // humidity settings only take effect with the next write to ctrl_meas
+        if self.chip == Chip::Bme280 {
+            self.write_reg(REG_CTRL_HUM, self.config.humidity as u8)?;
+        }
+        let ctrl_meas = (self.config.temperature_measured() as u8) << 5
+            | (self.config.pressure as u8) << 2
+            | MODE_FORCED;
+        self.write_reg(REG_CTRL_MEAS, ctrl_meas)?;
+
+        self.delay.delay_us(self.measurement_time());
+        self.wait_status(STATUS_MEASURING)?;
+
+        let mut data = [0; 8];
+        let len = match self.chip {
+            Chip::Bme280 => 8,
+            Chip::Bmp280 => 6,
+        };
+        self.read_regs(REG_DATA, &mut data[..len])?;
+
+        let adc_20bit =
+            |d: &[u8]| ((d[0] as u32) << 12) | ((d[1] as u32) << 4) | ((d[2] as u32) >> 4);
+        let adc_p = adc_20bit(&data[0..3]);
+        let adc_t = adc_20bit(&data[3..6]);
+        let adc_h = ((data[6] as u32) << 8) | data[7] as u32;
 
-        resps
+        let mut measurement = Measurement::default();
+        if adc_t == SKIPPED_20BIT {
+            return Ok(measurement);
+        }
+
+        let cal = &self.calibration;
+        let (temperature, t_fine) = cal.temperature(adc_t);
+        if self.config.temperature != Oversampling::Skipped {
+            measurement.temperature = Some(temperature as f32);
+        }
+        if self.config.pressure != Oversampling::Skipped && adc_p != SKIPPED_20BIT {
+            measurement.pressure = Some((cal.pressure(adc_p, t_fine) / 100.) as f32);
+        }
+        if self.humidity() != Oversampling::Skipped && adc_h != SKIPPED_16BIT {
+            measurement.humidity = Some(cal.humidity(adc_h, t_fine) as f32);
+        }
+
+        Ok(measurement)
     }
+}
 
-    fn handle_update(&mut self, msg: &ComponentUpdate) -> Vec<ComponentUpdate> {
-        match msg {
-            ComponentUpdate::Request(key) if key.is_none() || key == &Some(self.key) => {
-                return self.gen_resp();
-            }
-            ComponentUpdate::Update => {
-                let now = Instant::now();
-                if now.duration_since(self.last_update) >= UPDATE_TICK {
-                    self.last_update = now;
-                    return self.gen_resp();
-                }
-            }
-            _ => {}
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::{
+        delay::NoopDelay,
+        i2c::{Mock, Transaction},
+    };
+
+    use super::*;
+    use crate::components::bme::{IirFilter, ADDRESS_PRIMARY};
+
+    const ADDR: u8 = ADDRESS_PRIMARY;
+
+    // example of the datasheet's compensation formulas
+    const T: [i32; 3] = [27504, 26435, -1000];
+    const P: [i32; 9] = [36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000];
+    const ADC_T: u32 = 519888;
+    const ADC_P: u32 = 415148;
+
+    fn calibration_tp() -> Vec<u8> {
+        T.iter()
+            .chain(P.iter())
+            .flat_map(|&value| (value as u16).to_le_bytes())
+            .collect()
+    }
+
+    fn adc_20bit(adc: u32) -> [u8; 3] {
+        [(adc >> 12) as u8, (adc >> 4) as u8, (adc << 4) as u8]
+    }
+
+    /// Chip ID, reset and calibration
+    fn init(chip_id: u8, config: u8) -> Vec<Transaction> {
+        let mut transactions = vec![
+            Transaction::write_read(ADDR, vec![REG_CHIP_ID], vec![chip_id]),
+            Transaction::write(ADDR, vec![REG_RESET, RESET]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![STATUS_IM_UPDATE]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![0]),
+            Transaction::write_read(ADDR, vec![REG_CALIB_TP], calibration_tp()),
+        ];
+        if chip_id == 0x60 {
+            transactions.push(Transaction::write_read(ADDR, vec![REG_CALIB_H1], vec![75]));
+            transactions.push(Transaction::write_read(
+                ADDR,
+                vec![REG_CALIB_H2],
+                vec![0x6a, 0x01, 0x00, 0x13, 0x22, 0x03, 0x1e],
+            ));
         }
-        vec![]
+        transactions.push(Transaction::write(ADDR, vec![REG_CONFIG, config]));
+        transactions
+    }
+
+    #[test]
+    fn probe() {
+        let mut i2c = Mock::new(&init(0x60, 0));
+        let driver = Bme280Driver::new(i2c.clone(), NoopDelay, ADDR, Config::default()).unwrap();
+        assert_eq!(driver.chip_name(), "BME280");
+        assert_eq!(
+            driver.readings(),
+            [Reading::Temperature, Reading::Pressure, Reading::Humidity]
+        );
+        i2c.done();
+
+        // no humidity calibration to read
+        let mut i2c = Mock::new(&init(0x58, 0));
+        let driver = Bme280Driver::new(i2c.clone(), NoopDelay, ADDR, Config::default()).unwrap();
+        assert_eq!(driver.chip_name(), "BMP280");
+        assert_eq!(driver.readings(), [Reading::Temperature, Reading::Pressure]);
+        i2c.done();
+
+        // a BME680, nothing is written
+        let mut i2c = Mock::new(&[Transaction::write_read(ADDR, vec![REG_CHIP_ID], vec![0x61])]);
+        let res = Bme280Driver::new(i2c.clone(), NoopDelay, ADDR, Config::default());
+        assert!(matches!(res, Err(DriverError::UnknownChip(0x61))));
+        i2c.done();
+    }
+
+    #[test]
+    fn calibration() {
+        let tp: [u8; 24] = calibration_tp().try_into().unwrap();
+        // h4 = 0x132, h5 = 0x032 sharing 0xe5, then negative ones
+        let cal = Calibration::parse(&tp, 75, &[0x6a, 0x01, 0x00, 0x13, 0x22, 0x03, 0x1e]);
+        assert_eq!((cal.t1, cal.t2, cal.t3), (27504, 26435, -1000));
+        assert_eq!((cal.p1, cal.p5, cal.p6, cal.p9), (36477, 140, -7, 6000));
+        assert_eq!((cal.h1, cal.h2, cal.h3), (75, 362, 0));
+        assert_eq!((cal.h4, cal.h5, cal.h6), (0x132, 0x032, 30));
+
+        let cal = Calibration::parse(&tp, 0, &[0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!((cal.h4, cal.h5, cal.h6), (-1, -1, -1));
+    }
+
+    #[test]
+    fn compensation() {
+        let tp: [u8; 24] = calibration_tp().try_into().unwrap();
+        let cal = Calibration::parse(&tp, 0, &[0; 7]);
+
+        let (temperature, t_fine) = cal.temperature(ADC_T);
+        assert!((temperature - 25.08).abs() < 0.01, "{}", temperature);
+        let pressure = cal.pressure(ADC_P, t_fine);
+        assert!((pressure - 100653.27).abs() < 0.1, "{}", pressure);
+
+        // way out of range
+        assert_eq!(cal.humidity(0, t_fine), 0.);
+    }
+
+    #[test]
+    fn measure() {
+        let mut transactions = init(0x60, (IirFilter::X4 as u8) << 2);
+        let data = [adc_20bit(ADC_P), adc_20bit(ADC_T)].concat();
+        transactions.extend([
+            // humidity is skipped, temperature is still measured for the pressure
+            Transaction::write(ADDR, vec![REG_CTRL_HUM, 0]),
+            Transaction::write(ADDR, vec![REG_CTRL_MEAS, 1 << 5 | 3 << 2 | MODE_FORCED]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![0]),
+            Transaction::write_read(ADDR, vec![REG_DATA], [data, vec![0x80, 0x00]].concat()),
+        ]);
+        let config = Config::default()
+            .temperature(Oversampling::Skipped)
+            .pressure(Oversampling::X4)
+            .humidity(Oversampling::Skipped)
+            .iir_filter(IirFilter::X4);
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Bme280Driver::new(i2c.clone(), NoopDelay, ADDR, config).unwrap();
+        assert_eq!(driver.readings(), [Reading::Pressure]);
+        let measurement = driver.measure().unwrap();
+        assert_eq!(measurement.temperature, None);
+        assert_eq!(measurement.humidity, None);
+        let pressure = measurement.pressure.unwrap();
+        assert!((pressure - 1006.53).abs() < 0.01, "{}", pressure);
+        i2c.done();
+    }
+
+    #[test]
+    fn timeout() {
+        let mut transactions = init(0x58, 0);
+        transactions.push(Transaction::write(
+            ADDR,
+            vec![REG_CTRL_MEAS, 5 << 5 | 5 << 2 | MODE_FORCED],
+        ));
+        for _ in 0..MAX_POLLS {
+            transactions.push(Transaction::write_read(
+                ADDR,
+                vec![REG_STATUS],
+                vec![STATUS_MEASURING],
+            ));
+        }
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver =
+            Bme280Driver::new(i2c.clone(), NoopDelay, ADDR, Config::default()).unwrap();
+        assert!(matches!(driver.measure(), Err(DriverError::Timeout)));
+        i2c.done();
     }
 }
@@ -0,0 +1,580 @@
+//! Bosch BME680, the BME280 plus a heated metal oxide gas sensor
+//!
+//! Publishing goes through [`super::bme::Bme`], only the driver lives here.
+
+use std::time::Duration;
+
+use embedded_hal_1::{delay::DelayNs, i2c::I2c};
+
+use super::bme::{
+    BmeSensor, Config, DriverError, Measurement, Oversampling, Reading, MAX_POLLS, REG_CHIP_ID,
+    REG_RESET, RESET,
+};
+
+const CHIP_ID: u8 = 0x61;
+
+const REG_RES_HEAT_VAL: u8 = 0x00;
+const REG_RES_HEAT_RANGE: u8 = 0x02;
+const REG_RANGE_SW_ERR: u8 = 0x04;
+const REG_FIELD_0: u8 = 0x1d;
+const REG_RES_HEAT_0: u8 = 0x5a;
+const REG_GAS_WAIT_0: u8 = 0x64;
+const REG_CTRL_GAS_0: u8 = 0x70;
+const REG_CTRL_GAS_1: u8 = 0x71;
+const REG_CTRL_HUM: u8 = 0x72;
+const REG_CTRL_MEAS: u8 = 0x74;
+const REG_CONFIG: u8 = 0x75;
+const REG_COEFF_1: u8 = 0x89;
+const REG_COEFF_2: u8 = 0xe1;
+
+const STATUS_NEW_DATA: u8 = 1 << 7;
+const GAS_VALID: u8 = 1 << 5;
+const HEAT_STAB: u8 = 1 << 4;
+const RUN_GAS: u8 = 1 << 4;
+const HEAT_OFF: u8 = 1 << 3;
+const MODE_FORCED: u8 = 0b01;
+
+/// Raw value of a skipped measurement
+const SKIPPED_20BIT: u32 = 0x80000;
+const SKIPPED_16BIT: u32 = 0x8000;
+
+/// Temperature used for the first heater setting, before there is a measurement
+const DEFAULT_AMBIENT: f64 = 25.;
+
+/// Gas range correction from Bosch's reference driver, in percent
+const GAS_RANGE_K1: [f64; 16] = [
+    0., 0., 0., 0., 0., -1., 0., -0.8, 0., 0., -0.2, -0.5, 0., -1., 0., 0.,
+];
+const GAS_RANGE_K2: [f64; 16] = [
+    0., 0., 0., 0., 0.1, 0.7, 0., -0.8, -0.1, 0., 0., 0., 0., 0., 0., 0.,
+];
+
+/// Hot plate profile for the gas measurement, like ESPHome's `heater` option
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasHeater {
+    /// °C, 200 to 400
+    pub temperature: u16,
+    /// Up to 4032 ms
+    pub duration: Duration,
+}
+
+impl Default for GasHeater {
+    fn default() -> Self {
+        GasHeater {
+            temperature: 320,
+            duration: Duration::from_millis(150),
+        }
+    }
+}
+
+impl GasHeater {
+    /// 6 bit value times a factor of 1, 4, 16 or 64
+    fn wait_register(&self) -> u8 {
+        let mut ms = self.duration.as_millis().min(u16::MAX as u128) as u16;
+        if ms >= 0xfc0 {
+            return 0xff;
+        }
+
+        let mut factor = 0;
+        while ms > 0x3f {
+            ms /= 4;
+            factor += 1;
+        }
+        (ms as u8) | (factor << 6)
+    }
+}
+
+/// Compensation parameters from the chip's NVM
+#[derive(Debug, Clone, Copy, Default)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i8,
+    p1: u16,
+    p2: i16,
+    p3: i8,
+    p4: i16,
+    p5: i16,
+    p6: i8,
+    p7: i8,
+    p8: i16,
+    p9: i16,
+    p10: u8,
+    h1: u16,
+    h2: u16,
+    h3: i8,
+    h4: i8,
+    h5: i8,
+    h6: u8,
+    h7: i8,
+    gh1: i8,
+    gh2: i16,
+    gh3: i8,
+    res_heat_range: u8,
+    res_heat_val: i8,
+    range_sw_err: i8,
+}
+
+impl Calibration {
+    /// `coeff` is 25 bytes from 0x89 followed by 16 bytes from 0xe1, indices as in Bosch's driver
+    fn parse(coeff: &[u8; 41], res_heat_range: u8, res_heat_val: u8, range_sw_err: u8) -> Self {
+        let u16_at = |lsb: usize, msb: usize| u16::from_le_bytes([coeff[lsb], coeff[msb]]);
+        let i16_at = |lsb: usize, msb: usize| i16::from_le_bytes([coeff[lsb], coeff[msb]]);
+
+        Calibration {
+            t1: u16_at(33, 34),
+            t2: i16_at(1, 2),
+            t3: coeff[3] as i8,
+            p1: u16_at(5, 6),
+            p2: i16_at(7, 8),
+            p3: coeff[9] as i8,
+            p4: i16_at(11, 12),
+            p5: i16_at(13, 14),
+            p6: coeff[16] as i8,
+            p7: coeff[15] as i8,
+            p8: i16_at(19, 20),
+            p9: i16_at(21, 22),
+            p10: coeff[23],
+            // 12 bit values sharing a byte
+            h1: ((coeff[27] as u16) << 4) | (coeff[26] & 0x0f) as u16,
+            h2: ((coeff[25] as u16) << 4) | (coeff[26] >> 4) as u16,
+            h3: coeff[28] as i8,
+            h4: coeff[29] as i8,
+            h5: coeff[30] as i8,
+            h6: coeff[31],
+            h7: coeff[32] as i8,
+            gh1: coeff[37] as i8,
+            gh2: i16_at(35, 36),
+            gh3: coeff[38] as i8,
+            res_heat_range: (res_heat_range & 0x30) >> 4,
+            res_heat_val: res_heat_val as i8,
+            // signed upper nibble
+            range_sw_err: (range_sw_err as i8) >> 4,
+        }
+    }
+
+    /// Returns °C and `t_fine` for the other compensations, floating point formulas of Bosch's driver
+    fn temperature(&self, adc: u32) -> (f64, f64) {
+        let adc = adc as f64;
+        let t1 = self.t1 as f64;
+
+        let var1 = (adc / 16384. - t1 / 1024.) * self.t2 as f64;
+        let var2 = (adc / 131072. - t1 / 8192.).powi(2) * (self.t3 as f64 * 16.);
+        let t_fine = var1 + var2;
+
+        (t_fine / 5120., t_fine)
+    }
+
+    /// Pa
+    fn pressure(&self, adc: u32, t_fine: f64) -> f64 {
+        let mut var1 = t_fine / 2. - 64000.;
+        let mut var2 = var1 * var1 * (self.p6 as f64 / 131072.);
+        var2 += var1 * self.p5 as f64 * 2.;
+        var2 = var2 / 4. + self.p4 as f64 * 65536.;
+        var1 = (self.p3 as f64 * var1 * var1 / 16384. + self.p2 as f64 * var1) / 524288.;
+        var1 = (1. + var1 / 32768.) * self.p1 as f64;
+        if var1 == 0. {
+            // avoid a division by zero
+            return 0.;
+        }
+
+        let mut p = 1048576. - adc as f64;
+        p = (p - var2 / 4096.) * 6250. / var1;
+        let var1 = self.p9 as f64 * p * p / 2147483648.;
+        let var2 = p * (self.p8 as f64 / 32768.);
+        let var3 = (p / 256.).powi(3) * (self.p10 as f64 / 131072.);
+        p + (var1 + var2 + var3 + self.p7 as f64 * 128.) / 16.
+    }
+
+    /// %
+    fn humidity(&self, adc: u32, t_fine: f64) -> f64 {
+        let temperature = t_fine / 5120.;
+
+        let var1 = adc as f64 - (self.h1 as f64 * 16. + self.h3 as f64 / 2. * temperature);
+        let var2 = var1
+            * (self.h2 as f64 / 262144.
+                * (1.
+                    + self.h4 as f64 / 16384. * temperature
+                    + self.h5 as f64 / 1048576. * temperature * temperature));
+        let var3 = self.h6 as f64 / 16384.;
+        let var4 = self.h7 as f64 / 2097152.;
+
+        (var2 + (var3 + var4 * temperature) * var2 * var2).clamp(0., 100.)
+    }
+
+    /// Ω
+    fn gas_resistance(&self, adc: u16, range: u8) -> f64 {
+        let range = (range & 0x0f) as usize;
+
+        let var1 = 1340. + 5. * self.range_sw_err as f64;
+        let var2 = var1 * (1. + GAS_RANGE_K1[range] / 100.);
+        let var3 = 1. + GAS_RANGE_K2[range] / 100.;
+
+        1. / (var3 * 0.000000125 * (1u32 << range) as f64 * ((adc as f64 - 512.) / var2 + 1.))
+    }
+
+    /// Register value of the heater resistance for `target` °C at `ambient` °C
+    fn heater_resistance(&self, target: u16, ambient: f64) -> u8 {
+        let target = target.min(400) as f64;
+
+        let var1 = self.gh1 as f64 / 16. + 49.;
+        let var2 = self.gh2 as f64 / 32768. * 0.0005 + 0.00235;
+        let var3 = self.gh3 as f64 / 1024.;
+        let var4 = var1 * (1. + var2 * target);
+        let var5 = var4 + var3 * ambient;
+
+        let res = 3.4
+            * (var5
+                * (4. / (4. + self.res_heat_range as f64))
+                * (1. / (1. + self.res_heat_val as f64 * 0.002))
+                - 25.);
+        res.clamp(0., u8::MAX as f64) as u8
+    }
+}
+
+pub struct Bme680Driver<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    config: Config,
+    /// `None` disables the gas measurement
+    heater: Option<GasHeater>,
+    calibration: Calibration,
+    /// Last temperature, the heater setting depends on it
+    ambient: f64,
+}
+
+impl<I2C: I2c, D: DelayNs> Bme680Driver<I2C, D> {
+    /// Resets the chip and reads its calibration
+    pub fn new(
+        i2c: I2C,
+        delay: D,
+        address: u8,
+        config: Config,
+        heater: Option<GasHeater>,
+    ) -> std::result::Result<Self, DriverError<I2C::Error>> {
+        let mut driver = Bme680Driver {
+            i2c,
+            delay,
+            address,
+            config,
+            heater,
+            calibration: Calibration::default(),
+            ambient: DEFAULT_AMBIENT,
+        };
+
+        match driver.read_reg(REG_CHIP_ID)? {
+            CHIP_ID => {}
+            id => return Err(DriverError::UnknownChip(id)),
+        }
+
+        driver.write_reg(REG_RESET, RESET)?;
+        driver.delay.delay_ms(10);
+
+        let mut coeff = [0; 41];
+        driver.read_regs(REG_COEFF_1, &mut coeff[..25])?;
+        driver.read_regs(REG_COEFF_2, &mut coeff[25..])?;
+        let res_heat_range = driver.read_reg(REG_RES_HEAT_RANGE)?;
+        let res_heat_val = driver.read_reg(REG_RES_HEAT_VAL)?;
+        let range_sw_err = driver.read_reg(REG_RANGE_SW_ERR)?;
+        driver.calibration = Calibration::parse(&coeff, res_heat_range, res_heat_val, range_sw_err);
+
+        driver.write_reg(REG_CONFIG, (driver.config.iir_filter as u8) << 2)?;
+
+        Ok(driver)
+    }
+
+    /// From Bosch's driver, in µs
+    fn measurement_time(&self) -> u32 {
+        let cycles = self.config.temperature_measured().samples()
+            + self.config.pressure.samples()
+            + self.config.humidity.samples();
+
+        // conversions, switching between them, the gas measurement and the wake up
+        let tph = cycles * 1963 + 477 * 4 + 477 * 5 + 500 + 1000;
+        let gas = self
+            .heater
+            .map(|heater| heater.duration.as_micros() as u32)
+            .unwrap_or_default();
+        tph + gas
+    }
+
+    fn read_reg(&mut self, reg: u8) -> std::result::Result<u8, I2C::Error> {
+        let mut buf = [0];
+        self.i2c.write_read(self.address, &[reg], &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> std::result::Result<(), I2C::Error> {
+        self.i2c.write_read(self.address, &[reg], buf)
+    }
+
+    fn write_reg(&mut self, reg: u8, value: u8) -> std::result::Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[reg, value])
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> BmeSensor for Bme680Driver<I2C, D> {
+    type Error = I2C::Error;
+
+    fn chip_name(&self) -> &'static str {
+        "BME680"
+    }
+
+    fn readings(&self) -> Vec<Reading> {
+        let mut readings: Vec<Reading> = [
+            (Reading::Temperature, self.config.temperature),
+            (Reading::Pressure, self.config.pressure),
+            (Reading::Humidity, self.config.humidity),
+        ]
+        .into_iter()
+        .filter(|(_, os)| *os != Oversampling::Skipped)
+        .map(|(reading, _)| reading)
+        .collect();
+
+        if self.heater.is_some() {
+            readings.push(Reading::GasResistance);
+        }
+        readings
+    }
+
+    fn measure(&mut self) -> std::result::Result<Measurement, DriverError<I2C::Error>> {
+        match self.heater {
+            Some(heater) => {
+                let res_heat = self
+                    .calibration
+                    .heater_resistance(heater.temperature, self.ambient);
+                self.write_reg(REG_RES_HEAT_0, res_heat)?;
+                self.write_reg(REG_GAS_WAIT_0, heater.wait_register())?;
+                self.write_reg(REG_CTRL_GAS_0, 0)?;
+                self.write_reg(REG_CTRL_GAS_1, RUN_GAS)?;
+            }
+            None => {
+                self.write_reg(REG_CTRL_GAS_0, HEAT_OFF)?;
+                self.write_reg(REG_CTRL_GAS_1, 0)?;
+            }
+        }
+
+        // humidity settings only take effect with the next write to ctrl_meas
+        self.write_reg(REG_CTRL_HUM, self.config.humidity as u8)?;
+        let ctrl_meas = (self.config.temperature_measured() as u8) << 5
+            | (self.config.pressure as u8) << 2
+            | MODE_FORCED;
+        self.write_reg(REG_CTRL_MEAS, ctrl_meas)?;
+
+        self.delay.delay_us(self.measurement_time());
+
+        let mut data = [0; 15];
+        let mut polls = 0;
+        loop {
+            self.read_regs(REG_FIELD_0, &mut data)?;
+            if data[0] & STATUS_NEW_DATA != 0 {
+                break;
+            }
+            polls += 1;
+            if polls >= MAX_POLLS {
+                return Err(DriverError::Timeout);
+            }
+            self.delay.delay_ms(5);
+        }
+
+        let adc_20bit =
+            |d: &[u8]| ((d[0] as u32) << 12) | ((d[1] as u32) << 4) | ((d[2] as u32) >> 4);
+        let adc_p = adc_20bit(&data[2..5]);
+        let adc_t = adc_20bit(&data[5..8]);
+        let adc_h = ((data[8] as u32) << 8) | data[9] as u32;
+        let adc_gas = ((data[13] as u16) << 2) | (data[14] >> 6) as u16;
+        let gas_range = data[14] & 0x0f;
+
+        let mut measurement = Measurement::default();
+        if adc_t == SKIPPED_20BIT {
+            return Ok(measurement);
+        }
+
+        let cal = &self.calibration;
+        let (temperature, t_fine) = cal.temperature(adc_t);
+        self.ambient = temperature;
+        if self.config.temperature != Oversampling::Skipped {
+            measurement.temperature = Some(temperature as f32);
+        }
+        if self.config.pressure != Oversampling::Skipped && adc_p != SKIPPED_20BIT {
+            measurement.pressure = Some((cal.pressure(adc_p, t_fine) / 100.) as f32);
+        }
+        if self.config.humidity != Oversampling::Skipped && adc_h != SKIPPED_16BIT {
+            measurement.humidity = Some(cal.humidity(adc_h, t_fine) as f32);
+        }
+        // an unstable heater reads garbage, e.g. when the duration is too short for the temperature
+        if self.heater.is_some() && data[14] & (GAS_VALID | HEAT_STAB) == GAS_VALID | HEAT_STAB {
+            measurement.gas_resistance = Some(cal.gas_resistance(adc_gas, gas_range) as f32);
+        }
+
+        Ok(measurement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::{
+        delay::NoopDelay,
+        i2c::{Mock, Transaction},
+    };
+
+    use super::*;
+    use crate::components::bme::ADDRESS_SECONDARY;
+
+    const ADDR: u8 = ADDRESS_SECONDARY;
+
+    /// Temperature calibration of the BME280 datasheet's example, `t3` is scaled by 16 on the BME680
+    fn coefficients() -> [u8; 41] {
+        let mut coeff = [0; 41];
+        coeff[33..35].copy_from_slice(&27504u16.to_le_bytes());
+        coeff[1..3].copy_from_slice(&26435i16.to_le_bytes());
+        coeff[3] = -63i8 as u8;
+        coeff
+    }
+
+    fn init() -> Vec<Transaction> {
+        let coeff = coefficients();
+        vec![
+            Transaction::write_read(ADDR, vec![REG_CHIP_ID], vec![CHIP_ID]),
+            Transaction::write(ADDR, vec![REG_RESET, RESET]),
+            Transaction::write_read(ADDR, vec![REG_COEFF_1], coeff[..25].to_vec()),
+            Transaction::write_read(ADDR, vec![REG_COEFF_2], coeff[25..].to_vec()),
+            Transaction::write_read(ADDR, vec![REG_RES_HEAT_RANGE], vec![0]),
+            Transaction::write_read(ADDR, vec![REG_RES_HEAT_VAL], vec![0]),
+            Transaction::write_read(ADDR, vec![REG_RANGE_SW_ERR], vec![0]),
+            Transaction::write(ADDR, vec![REG_CONFIG, 0]),
+        ]
+    }
+
+    #[test]
+    fn probe() {
+        let mut i2c = Mock::new(&init());
+        let driver =
+            Bme680Driver::new(i2c.clone(), NoopDelay, ADDR, Config::default(), None).unwrap();
+        assert_eq!(
+            driver.readings(),
+            [Reading::Temperature, Reading::Pressure, Reading::Humidity]
+        );
+        i2c.done();
+
+        // a BME280, nothing is written
+        let mut i2c = Mock::new(&[Transaction::write_read(ADDR, vec![REG_CHIP_ID], vec![0x60])]);
+        let res = Bme680Driver::new(i2c.clone(), NoopDelay, ADDR, Config::default(), None);
+        assert!(matches!(res, Err(DriverError::UnknownChip(0x60))));
+        i2c.done();
+    }
+
+    #[test]
+    fn calibration() {
+        let mut coeff = coefficients();
+        // h1 and h2 share 0xe2
+        coeff[25] = 0x3f;
+        coeff[26] = 0x2a;
+        coeff[27] = 0x5b;
+        coeff[37] = -3i8 as u8;
+
+        let cal = Calibration::parse(&coeff, 0x30, 0xfe, 0xf0);
+        assert_eq!((cal.t1, cal.t2, cal.t3), (27504, 26435, -63));
+        assert_eq!((cal.h1, cal.h2), (0x5ba, 0x3f2));
+        assert_eq!(cal.gh1, -3);
+        assert_eq!(cal.res_heat_range, 3);
+        assert_eq!(cal.res_heat_val, -2);
+        assert_eq!(cal.range_sw_err, -1);
+
+        let (temperature, _) = cal.temperature(519888);
+        assert!((temperature - 25.08).abs() < 0.01, "{}", temperature);
+    }
+
+    #[test]
+    fn gas_resistance() {
+        // the datasheet's resistance per range, for the ADC in the middle
+        const RANGES: [f64; 16] = [
+            8000000.,
+            4000000.,
+            2000000.,
+            1000000.,
+            499500.4995,
+            248262.1648,
+            125000.,
+            63004.03226,
+            31281.28128,
+            15625.,
+            7812.5,
+            3906.25,
+            1953.125,
+            976.5625,
+            488.28125,
+            244.140625,
+        ];
+
+        let cal = Calibration::default();
+        for (range, expected) in RANGES.into_iter().enumerate() {
+            let resistance = cal.gas_resistance(512, range as u8);
+            assert!((resistance - expected).abs() / expected < 1e-6, "{}", range);
+        }
+        // a higher ADC value is a lower resistance
+        assert!(cal.gas_resistance(1023, 3) < 1000000.);
+    }
+
+    #[test]
+    fn heater_wait() {
+        let wait = |ms| {
+            GasHeater {
+                temperature: 320,
+                duration: Duration::from_millis(ms),
+            }
+            .wait_register()
+        };
+        assert_eq!(wait(25), 25);
+        // 25 * 4
+        assert_eq!(wait(100), 0x59);
+        assert_eq!(wait(150), 0x65);
+        assert_eq!(wait(5000), 0xff);
+    }
+
+    #[test]
+    fn measure() {
+        let heater = GasHeater::default();
+        let res_heat = Calibration::parse(&coefficients(), 0, 0, 0)
+            .heater_resistance(heater.temperature, DEFAULT_AMBIENT);
+
+        let mut data = vec![STATUS_NEW_DATA, 0];
+        // skipped pressure, the temperature of the datasheet and skipped humidity
+        data.extend([0x80, 0x00, 0x00, 0x7e, 0xed, 0x00, 0x80, 0x00]);
+        // ADC of 512 in range 3
+        data.extend([0, 0, 0, 0x80, GAS_VALID | HEAT_STAB | 3]);
+
+        let mut transactions = init();
+        transactions.extend([
+            Transaction::write(ADDR, vec![REG_RES_HEAT_0, res_heat]),
+            Transaction::write(ADDR, vec![REG_GAS_WAIT_0, 0x65]),
+            Transaction::write(ADDR, vec![REG_CTRL_GAS_0, 0]),
+            Transaction::write(ADDR, vec![REG_CTRL_GAS_1, RUN_GAS]),
+            Transaction::write(ADDR, vec![REG_CTRL_HUM, Oversampling::X16 as u8]),
+            Transaction::write(ADDR, vec![REG_CTRL_MEAS, 5 << 5 | 5 << 2 | MODE_FORCED]),
+            // not done on the first poll
+            Transaction::write_read(ADDR, vec![REG_FIELD_0], vec![0; 15]),
+            Transaction::write_read(ADDR, vec![REG_FIELD_0], data),
+        ]);
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Bme680Driver::new(
+            i2c.clone(),
+            NoopDelay,
+            ADDR,
+            Config::default(),
+            Some(heater),
+        )
+        .unwrap();
+        assert_eq!(driver.readings().last(), Some(&Reading::GasResistance));
+
+        let measurement = driver.measure().unwrap();
+        let temperature = measurement.temperature.unwrap();
+        assert!((temperature - 25.08).abs() < 0.01, "{}", temperature);
+        assert_eq!(measurement.pressure, None);
+        assert_eq!(measurement.humidity, None);
+        assert_eq!(measurement.gas_resistance, Some(1000000.));
+        i2c.done();
+    }
+}
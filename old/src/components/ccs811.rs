@@ -1,137 +1,316 @@
-use std::time::{Duration, Instant};
+//! ams CCS811, published through [`super::air_quality::AirQuality`]
 
-// use log::*;
-use protobuf::Message;
-
-use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
 
-use embedded_ccs811::{mode::App, prelude::*, Ccs811Awake};
+use embedded_hal_1::{delay::DelayNs, i2c::I2c};
 
-use crate::{
-    api::{ListEntitiesSensorResponse, SensorStateResponse},
-    components::{Component, ComponentUpdate},
-    consts::MessageTypes,
-    utils::*,
+use super::{
+    air_quality::{AirQualityData, AirQualitySensor, DriverError, PUBLISH_INTERVAL},
+    compensation::Environment,
 };
 
-const UPDATE_TICK: Duration = Duration::from_secs(60);
-const NAME: &str = "Rusty old CCS811";
+pub const ADDRESS_PRIMARY: u8 = 0x5a;
+pub const ADDRESS_SECONDARY: u8 = 0x5b;
+
+const HW_ID: u8 = 0x81;
+
+// registers
+const REG_STATUS: u8 = 0x00;
+const REG_MEAS_MODE: u8 = 0x01;
+const REG_ALG_RESULT_DATA: u8 = 0x02;
+const REG_ENV_DATA: u8 = 0x05;
+const REG_BASELINE: u8 = 0x11;
+const REG_HW_ID: u8 = 0x20;
+const REG_FW_APP_VERSION: u8 = 0x24;
+const REG_ERROR_ID: u8 = 0xe0;
+const REG_APP_START: u8 = 0xf4;
+const REG_SW_RESET: u8 = 0xff;
+
+const SW_RESET: [u8; 4] = [0x11, 0xe5, 0x72, 0x8a];
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_READY: u8 = 1 << 3;
+const STATUS_APP_VALID: u8 = 1 << 4;
+const STATUS_FW_MODE: u8 = 1 << 7;
+
+/// Constant power mode, one measurement per second
+const DRIVE_MODE_1S: u8 = 1 << 4;
+
+/// Conditioning period before the baseline may be written, from the datasheet
+const WARM_UP: Duration = Duration::from_secs(20 * 60);
 
-pub struct CompCcs811<I2C> {
-    key: u32,
+/// Flags of the CCS811's ERROR_ID register
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorError(pub u8);
 
-    ccs811: Ccs811Awake<I2C, App>,
+impl Display for SensorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const FLAGS: [&str; 6] = [
+            "invalid register write",
+            "invalid register read",
+            "invalid measurement mode",
+            "resistance out of range",
+            "heater fault",
+            "heater supply fault",
+        ];
 
-    last_update: Instant,
+        let errors: Vec<&str> = FLAGS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, flag)| *flag)
+            .collect();
+        match errors.is_empty() {
+            true => write!(f, "unknown error 0x{:02x}", self.0),
+            false => write!(f, "{}", errors.join(", ")),
+        }
+    }
+}
+
+/// CCS811 with its nWAKE pin tied low
+pub struct Ccs811Driver<I2C> {
+    i2c: I2C,
+    address: u8,
+    firmware_version: String,
 }
 
-impl<E, I2C> CompCcs811<I2C>
-where
-    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
-{
-    pub fn new(ccs811: Ccs811Awake<I2C, App>) -> CompCcs811<I2C> {
-        // let ccs811 = ccs811.
-        CompCcs811 {
-            key: name_to_hash(NAME),
-            ccs811,
-
-            last_update: Instant::now(),
+impl<I2C: I2c> Ccs811Driver<I2C> {
+    /// Resets the chip, starts its application and measures once per second
+    pub fn new<D: DelayNs>(
+        i2c: I2C,
+        mut delay: D,
+        address: u8,
+    ) -> std::result::Result<Self, DriverError<I2C::Error>> {
+        let mut driver = Ccs811Driver {
+            i2c,
+            address,
+            firmware_version: String::new(),
+        };
+
+        match driver.read_reg(REG_HW_ID)? {
+            HW_ID => {}
+            id => return Err(DriverError::UnknownChip(id)),
+        }
+
+        driver.write_regs(REG_SW_RESET, &SW_RESET)?;
+        delay.delay_ms(2);
+
+        let status = driver.read_reg(REG_STATUS)?;
+        if status & STATUS_APP_VALID == 0 {
+            return Err(DriverError::NoFirmware);
+        }
+        let mut version = [0; 2];
+        driver.read_regs(REG_FW_APP_VERSION, &mut version)?;
+        driver.firmware_version =
+            format!("{}.{}.{}", version[0] >> 4, version[0] & 0x0f, version[1]);
+
+        // no data, only the register address
+        driver.i2c.write(driver.address, &[REG_APP_START])?;
+        delay.delay_ms(1);
+        let status = driver.check_status()?;
+        if status & STATUS_FW_MODE == 0 {
+            return Err(DriverError::NoFirmware);
         }
+
+        driver.write_regs(REG_MEAS_MODE, &[DRIVE_MODE_1S])?;
+        driver.check_status()?;
+
+        Ok(driver)
     }
 
-    fn get_key(&self) -> u32 {
-        self.key
+    /// Status register, the error flags are turned into [`DriverError::Sensor`]
+    fn check_status(&mut self) -> std::result::Result<u8, DriverError<I2C::Error>> {
+        let status = self.read_reg(REG_STATUS)?;
+        if status & STATUS_ERROR != 0 {
+            // reading clears the error
+            let error = self.read_reg(REG_ERROR_ID)?;
+            return Err(DriverError::Sensor(SensorError(error)));
+        }
+        Ok(status)
     }
 
-    fn gen_resp(&mut self) -> Vec<ComponentUpdate> {
-        match self.ccs811.data() {
-            Ok(data) => {
-                let mut resp = vec![];
+    fn read_reg(&mut self, reg: u8) -> std::result::Result<u8, I2C::Error> {
+        let mut buf = [0];
+        self.i2c.write_read(self.address, &[reg], &mut buf)?;
+        Ok(buf[0])
+    }
 
-                let mut eco2 = SensorStateResponse::new();
-                eco2.set_key(self.get_key());
-                eco2.set_state(data.eco2 as f32);
-                resp.push(ComponentUpdate::SensorResponse(Box::new(eco2)));
+    fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> std::result::Result<(), I2C::Error> {
+        self.i2c.write_read(self.address, &[reg], buf)
+    }
 
-                let mut etvoc = SensorStateResponse::new();
-                etvoc.set_key(self.get_key() + 1);
-                etvoc.set_state(data.etvoc as f32);
-                resp.push(ComponentUpdate::SensorResponse(Box::new(etvoc)));
+    fn write_regs(&mut self, reg: u8, data: &[u8]) -> std::result::Result<(), I2C::Error> {
+        let mut buf = vec![reg];
+        buf.extend_from_slice(data);
+        self.i2c.write(self.address, &buf)
+    }
+}
+
+impl<I2C: I2c> AirQualitySensor for Ccs811Driver<I2C> {
+    type Error = I2C::Error;
+
+    fn chip_name(&self) -> &'static str {
+        "CCS811"
+    }
+
+    fn firmware_version(&self) -> String {
+        self.firmware_version.clone()
+    }
 
-                return resp;
-            }
-            _ => {}
+    fn sample_interval(&self) -> Duration {
+        // keeps the latest result, no need to fetch every one
+        PUBLISH_INTERVAL
+    }
+
+    fn warm_up(&self) -> Duration {
+        WARM_UP
+    }
+
+    fn measure(&mut self) -> std::result::Result<Option<AirQualityData>, DriverError<I2C::Error>> {
+        if self.check_status()? & STATUS_DATA_READY == 0 {
+            return Ok(None);
         }
-        vec![]
+
+        let mut data = [0; 4];
+        self.read_regs(REG_ALG_RESULT_DATA, &mut data)?;
+        Ok(Some(AirQualityData {
+            eco2: u16::from_be_bytes([data[0], data[1]]),
+            tvoc: u16::from_be_bytes([data[2], data[3]]),
+        }))
+    }
+
+    fn baseline(&mut self) -> std::result::Result<u32, DriverError<I2C::Error>> {
+        let mut baseline = [0; 2];
+        self.read_regs(REG_BASELINE, &mut baseline)?;
+        Ok(u16::from_be_bytes(baseline) as u32)
+    }
+
+    fn set_baseline(&mut self, baseline: u32) -> std::result::Result<(), DriverError<I2C::Error>> {
+        self.write_regs(REG_BASELINE, &(baseline as u16).to_be_bytes())?;
+        self.check_status().map(|_| ())
+    }
+
+    fn set_environment(
+        &mut self,
+        environment: Environment,
+    ) -> std::result::Result<(), DriverError<I2C::Error>> {
+        // both in 1/512, the temperature with an offset of 25 °C
+        let encode = |value: f32| (value.max(0.) * 512.).round().min(u16::MAX as f32) as u16;
+        let humidity = encode(environment.humidity);
+        let temperature = encode(environment.temperature + 25.);
+
+        let mut data = [0; 4];
+        data[..2].copy_from_slice(&humidity.to_be_bytes());
+        data[2..].copy_from_slice(&temperature.to_be_bytes());
+        self.write_regs(REG_ENV_DATA, &data)?;
+        Ok(())
     }
 }
 
-impl<E, I2C> Component for CompCcs811<I2C>
-where
-    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
-{
-    fn get_description(&self) -> Vec<(MessageTypes, Arc<Box<dyn Message>>)> {
-        let mut resps: Vec<(MessageTypes, Arc<Box<dyn Message>>)> = vec![];
-
-        // eCO2
-        let name = String::from(NAME) + " eCO2";
-        let mut resp = ListEntitiesSensorResponse::new();
-        resp.set_disabled_by_default(false);
-        resp.set_key(self.get_key());
-        resp.set_name(name.to_owned());
-        resp.set_object_id(name_to_object(&name));
-        resp.set_unique_id(name_to_unique(&name, "ccs811"));
-        resp.set_unit_of_measurement(String::from("ppm"));
-
-        resps.push((
-            MessageTypes::ListEntitiesSensorResponse,
-            Arc::new(Box::new(resp)),
-        ));
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::{
+        delay::NoopDelay,
+        i2c::{Mock, Transaction},
+    };
 
-        // etvoc
-        let name = String::from(NAME) + " Total Volatile Organic Compound";
-        let mut resp = ListEntitiesSensorResponse::new();
-        resp.set_disabled_by_default(false);
-        resp.set_key(self.get_key() + 1);
-        resp.set_name(name.to_owned());
-        resp.set_object_id(name_to_object(&name));
-        resp.set_unique_id(name_to_unique(&name, "ccs811"));
-        resp.set_unit_of_measurement(String::from("ppb"));
-
-        resps.push((
-            MessageTypes::ListEntitiesSensorResponse,
-            Arc::new(Box::new(resp)),
-        ));
+    use super::*;
+
+    const ADDR: u8 = ADDRESS_PRIMARY;
+    const RUNNING: u8 = STATUS_FW_MODE | STATUS_APP_VALID;
 
-        resps
-    }
-
-    fn handle_update(&mut self, msg: &ComponentUpdate) -> Vec<ComponentUpdate> {
-        match msg {
-            ComponentUpdate::Request(key) if key.is_none() || key == &Some(self.key) => {
-                return self.gen_resp();
-            }
-            ComponentUpdate::Update => {
-                let now = Instant::now();
-                if now.duration_since(self.last_update) >= UPDATE_TICK {
-                    self.last_update = now;
-                    return self.gen_resp();
-                }
-            }
-            _ => {}
+    fn init() -> Vec<Transaction> {
+        vec![
+            Transaction::write_read(ADDR, vec![REG_HW_ID], vec![HW_ID]),
+            Transaction::write(ADDR, [&[REG_SW_RESET][..], &SW_RESET].concat()),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![STATUS_APP_VALID]),
+            Transaction::write_read(ADDR, vec![REG_FW_APP_VERSION], vec![0x12, 0x03]),
+            Transaction::write(ADDR, vec![REG_APP_START]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![RUNNING]),
+            Transaction::write(ADDR, vec![REG_MEAS_MODE, DRIVE_MODE_1S]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![RUNNING]),
+        ]
+    }
+
+    #[test]
+    fn probe() {
+        let mut i2c = Mock::new(&init());
+        let driver = Ccs811Driver::new(i2c.clone(), NoopDelay, ADDR).unwrap();
+        assert_eq!(driver.firmware_version(), "1.2.3");
+        i2c.done();
+
+        let mut i2c = Mock::new(&[Transaction::write_read(ADDR, vec![REG_HW_ID], vec![0x60])]);
+        let res = Ccs811Driver::new(i2c.clone(), NoopDelay, ADDR);
+        assert!(matches!(res, Err(DriverError::UnknownChip(0x60))));
+        i2c.done();
+
+        // stuck in the boot loader
+        let mut transactions = init();
+        transactions.truncate(2);
+        transactions.push(Transaction::write_read(ADDR, vec![REG_STATUS], vec![0]));
+        let mut i2c = Mock::new(&transactions);
+        let res = Ccs811Driver::new(i2c.clone(), NoopDelay, ADDR);
+        assert!(matches!(res, Err(DriverError::NoFirmware)));
+        i2c.done();
+    }
+
+    #[test]
+    fn measure() {
+        let mut transactions = init();
+        transactions.extend([
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![RUNNING]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![RUNNING | STATUS_DATA_READY]),
+            Transaction::write_read(ADDR, vec![REG_ALG_RESULT_DATA], vec![0x01, 0x90, 0, 0x2a]),
+            Transaction::write_read(ADDR, vec![REG_STATUS], vec![RUNNING | STATUS_ERROR]),
+            Transaction::write_read(ADDR, vec![REG_ERROR_ID], vec![1 << 4]),
+        ]);
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Ccs811Driver::new(i2c.clone(), NoopDelay, ADDR).unwrap();
+        assert_eq!(driver.measure().unwrap(), None);
+        assert_eq!(
+            driver.measure().unwrap(),
+            Some(AirQualityData {
+                eco2: 400,
+                tvoc: 42
+            })
+        );
+        match driver.measure() {
+            Err(DriverError::Sensor(err)) => assert_eq!(err.to_string(), "heater fault"),
+            res => panic!("{:?}", res),
         }
-        vec![]
+        i2c.done();
     }
-}
 
-// let dev = hal::I2cdev::new("/dev/i2c-1").unwrap();
-// let nwake = hal::Pin::new(17);
-// let delay = hal::Delay {};
-// let address = SlaveAddr::default();
-// let sensor = Ccs811::new(dev, address, nwake, delay);
-// let mut sensor = sensor.start_application().ok().unwrap();
-// sensor.set_mode(MeasurementMode::ConstantPower1s).unwrap();
-// loop {
-//     let data = block!(sensor.data()).unwrap();
-//     println!("eCO2: {}, eTVOC: {}", data.eco2, data.etvoc);
-// }
+    #[test]
+    fn environment() {
+        let mut transactions = init();
+        // 50.5 % and 0.25 °C plus the offset, both in 1/512
+        transactions.push(Transaction::write(
+            ADDR,
+            vec![REG_ENV_DATA, 0x65, 0x00, 0x32, 0x80],
+        ));
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Ccs811Driver::new(i2c.clone(), NoopDelay, ADDR).unwrap();
+        driver
+            .set_environment(Environment {
+                temperature: 0.25,
+                humidity: 50.5,
+            })
+            .unwrap();
+        i2c.done();
+    }
+
+    #[test]
+    fn sensor_errors() {
+        assert_eq!(
+            SensorError(0b110000).to_string(),
+            "heater fault, heater supply fault"
+        );
+        assert_eq!(SensorError(0x80).to_string(), "unknown error 0x80");
+    }
+}
@@ -0,0 +1,46 @@
+//! Temperature and humidity handed from one sensor to another
+//!
+//! Gas sensors report better values when they know the ambient conditions, like ESPHome's
+//! `compensation` option. A [`Compensation`] is cloned into the sensor that measures them and the ones that need them.
+
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Environment {
+    /// °C
+    pub temperature: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+}
+
+impl Environment {
+    /// g/m³, from the Magnus formula
+    #[cfg(feature = "has_sgp30")]
+    pub fn absolute_humidity(&self) -> f32 {
+        let t = self.temperature;
+        let saturation = 6.112 * (17.62 * t / (243.12 + t)).exp();
+        216.7 * (self.humidity / 100. * saturation) / (273.15 + t)
+    }
+}
+
+/// Latest [`Environment`], `None` until the source measured once
+#[derive(Debug, Clone, Default)]
+pub struct Compensation {
+    latest: Arc<Mutex<Option<Environment>>>,
+}
+
+impl Compensation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(any(feature = "has_bme280", feature = "has_bme680"))]
+    pub fn set(&self, environment: Environment) {
+        *self.latest.lock().expect("lock poisened!") = Some(environment);
+    }
+
+    #[cfg(any(feature = "has_ccs811", feature = "has_sgp30"))]
+    pub fn get(&self) -> Option<Environment> {
+        *self.latest.lock().expect("lock poisened!")
+    }
+}
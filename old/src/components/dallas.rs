@@ -0,0 +1,400 @@
+//! Dallas DS18B20 (and DS18S20/DS1822) temperature probes on a 1-Wire bus
+//!
+//! All probes convert at once and the conversion runs while the update interval passes, every update reads
+//! the conversion the previous one started. The first temperatures come one interval after boot.
+
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::{
+    components::{
+        entity::{EntityDescription, EntityMeta, StatePublisher},
+        one_wire::{crc8, Address, OneWire, OneWireError},
+        sensor::SensorEntity,
+        Component,
+    },
+    error::{Error, Result},
+};
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+// family codes
+const FAMILY_DS18S20: u8 = 0x10;
+const FAMILY_DS1822: u8 = 0x22;
+const FAMILY_DS18B20: u8 = 0x28;
+
+// function commands
+const CONVERT_T: u8 = 0x44;
+const WRITE_SCRATCHPAD: u8 = 0x4e;
+const READ_SCRATCHPAD: u8 = 0xbe;
+
+/// Conversion time and noise grow with the resolution, the DS18S20 always uses 9 bit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// 0.5 °C
+    Bits9 = 9,
+    /// 0.25 °C
+    Bits10 = 10,
+    /// 0.125 °C
+    Bits11 = 11,
+    /// 0.0625 °C
+    Bits12 = 12,
+}
+
+impl Resolution {
+    /// Like ESPHome's `resolution` option
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            9 => Some(Resolution::Bits9),
+            10 => Some(Resolution::Bits10),
+            11 => Some(Resolution::Bits11),
+            12 => Some(Resolution::Bits12),
+            _ => None,
+        }
+    }
+
+    fn conversion_time(&self) -> Duration {
+        Duration::from_micros(93_750 << (*self as u8 - 9))
+    }
+
+    /// Configuration register, the lower 5 bits always read 1
+    fn config(&self) -> u8 {
+        ((*self as u8 - 9) << 5) | 0x1f
+    }
+}
+
+pub fn is_supported(address: &Address) -> bool {
+    matches!(
+        address.family(),
+        FAMILY_DS18S20 | FAMILY_DS1822 | FAMILY_DS18B20
+    )
+}
+
+/// °C from a scratchpad with a valid CRC
+fn temperature(family: u8, scratchpad: &[u8; 9], resolution: Resolution) -> f32 {
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+
+    match family {
+        FAMILY_DS18S20 => {
+            // 9 bit, extended with COUNT_REMAIN and COUNT_PER_C
+            let count_remain = scratchpad[6] as f32;
+            let count_per_c = scratchpad[7] as f32;
+            (raw >> 1) as f32 - 0.25 + (count_per_c - count_remain) / count_per_c
+        }
+        _ => {
+            // bits below the resolution are undefined
+            let undefined = 12 - resolution as u8;
+            ((raw >> undefined) << undefined) as f32 / 16.
+        }
+    }
+}
+
+/// All temperature probes on one bus, each one its own sensor entity
+pub struct Dallas<B> {
+    bus: B,
+    resolution: Resolution,
+    sensors: Vec<(Address, SensorEntity)>,
+    /// Published again when a client subscribes, NaN for probes that failed
+    states: Vec<f32>,
+    /// When the conversion that the next update reads was started
+    converting_since: Option<Instant>,
+
+    publisher: StatePublisher,
+}
+
+impl<B: OneWire> Dallas<B> {
+    /// `sensors` are usually the supported addresses of [`OneWire::search`], each with its own name
+    pub fn new(
+        bus: B,
+        resolution: Resolution,
+        sensors: Vec<(Address, String)>,
+        publisher: StatePublisher,
+    ) -> Dallas<B> {
+        let sensors: Vec<(Address, SensorEntity)> = sensors
+            .into_iter()
+            .map(|(address, name)| {
+                let entity = SensorEntity::new(name)
+                    .with_meta(EntityMeta::default().device_class("temperature"))
+                    .with_unit("°C", 1);
+                (address, entity)
+            })
+            .collect();
+
+        Dallas {
+            bus,
+            resolution,
+            states: vec![f32::NAN; sensors.len()],
+            sensors,
+            converting_since: None,
+            publisher,
+        }
+    }
+
+    fn error(&self, reason: String) -> Error {
+        let key = self
+            .sensors
+            .first()
+            .map(|(_, entity)| entity.get_key())
+            .unwrap_or_default();
+        Error::component(key, reason)
+    }
+
+    fn configure(&mut self, address: Address) -> std::result::Result<(), OneWireError<B::Error>> {
+        if address.family() == FAMILY_DS18S20 {
+            return Ok(());
+        }
+
+        // alarm thresholds are not used, only the resolution matters
+        self.bus.select(Some(address))?;
+        self.bus
+            .write_bytes(&[WRITE_SCRATCHPAD, 0x7f, 0x80, self.resolution.config()])?;
+        Ok(())
+    }
+
+    fn start_conversion(&mut self) -> std::result::Result<(), OneWireError<B::Error>> {
+        self.bus.select(None)?;
+        self.bus.write_byte(CONVERT_T)?;
+        self.converting_since = Some(Instant::now());
+        Ok(())
+    }
+
+    fn read(&mut self, address: Address) -> std::result::Result<f32, OneWireError<B::Error>> {
+        let mut scratchpad = [0; 9];
+        self.bus.select(Some(address))?;
+        self.bus.write_byte(READ_SCRATCHPAD)?;
+        self.bus.read_bytes(&mut scratchpad)?;
+
+        // a bus stuck low reads all zeros, which passes the CRC
+        if crc8(&scratchpad) != 0 || scratchpad == [0; 9] {
+            return Err(OneWireError::Crc);
+        }
+        Ok(temperature(address.family(), &scratchpad, self.resolution))
+    }
+
+    /// Reads every probe and publishes them all, NaN for the ones that failed
+    fn read_all(&mut self, failed: &mut Vec<String>) {
+        for idx in 0..self.sensors.len() {
+            let address = self.sensors[idx].0;
+            self.states[idx] = match self.read(address) {
+                Ok(temperature) => temperature,
+                Err(err) => {
+                    failed.push(format!("{address}: {err}"));
+                    f32::NAN
+                }
+            };
+        }
+        self.publish();
+    }
+
+    fn publish(&self) {
+        for ((_, entity), state) in self.sensors.iter().zip(&self.states) {
+            entity.publish(&self.publisher, *state);
+        }
+    }
+}
+
+impl<B: OneWire + Send> Component for Dallas<B> {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        self.sensors
+            .iter()
+            .map(|(_, entity)| entity.describe())
+            .collect()
+    }
+
+    fn setup(&mut self) -> Result<()> {
+        for idx in 0..self.sensors.len() {
+            let address = self.sensors[idx].0;
+            if let Err(err) = self.configure(address) {
+                warn!("failed to configure {}: {}", address, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let mut failed = vec![];
+        match self.converting_since {
+            // nothing to read yet
+            None => {}
+            // an update interval shorter than the conversion, read it next time
+            Some(since) if since.elapsed() < self.resolution.conversion_time() => return Ok(()),
+            Some(_) => self.read_all(&mut failed),
+        }
+
+        // read by the next update
+        self.converting_since = None;
+        if let Err(err) = self.start_conversion() {
+            failed.push(format!("failed to start conversion: {err}"));
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(self.error(failed.join(", "))),
+        }
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        Some(UPDATE_INTERVAL)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.publish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_channel::Receiver;
+
+    use super::*;
+    use crate::api::SensorStateResponse;
+    use crate::components::{
+        one_wire::tests::{address, FakeBus},
+        ComponentUpdate,
+    };
+
+    // ROM commands
+    const MATCH_ROM: u8 = 0x55;
+    const SKIP_ROM: u8 = 0xcc;
+
+    /// Temperature register, `count_remain` and `count_per_c` only matter on the DS18S20
+    fn scratchpad(raw: u16, count_remain: u8, count_per_c: u8) -> [u8; 9] {
+        let [lsb, msb] = raw.to_le_bytes();
+        let mut scratchpad = [
+            lsb,
+            msb,
+            0x4b,
+            0x46,
+            0x7f,
+            0xff,
+            count_remain,
+            count_per_c,
+            0,
+        ];
+        scratchpad[8] = crc8(&scratchpad[..8]);
+        scratchpad
+    }
+
+    fn published(recv: &Receiver<ComponentUpdate>) -> Vec<f32> {
+        let mut states = vec![];
+        while let Ok(ComponentUpdate::Response((_, msg))) = recv.try_recv() {
+            let resp = msg.as_any().downcast_ref::<SensorStateResponse>().unwrap();
+            states.push(resp.get_state());
+        }
+        states
+    }
+
+    #[test]
+    fn ds18b20() {
+        use Resolution::*;
+
+        // from the datasheet
+        for (raw, celsius) in [
+            (0x07d0, 125.),
+            (0x0550, 85.),
+            (0x0191, 25.0625),
+            (0x00a2, 10.125),
+            (0x0008, 0.5),
+            (0x0000, 0.),
+            (0xfff8, -0.5),
+            (0xff5e, -10.125),
+            (0xfc90, -55.),
+        ] {
+            let scratchpad = scratchpad(raw, 0x0c, 0x10);
+            assert_eq!(temperature(FAMILY_DS18B20, &scratchpad, Bits12), celsius);
+        }
+
+        // the undefined bits are dropped, towards minus infinity
+        let scratchpad = scratchpad(0xff5e, 0x0c, 0x10);
+        let at = |resolution| temperature(FAMILY_DS18B20, &scratchpad, resolution);
+        assert_eq!(at(Bits11), -10.125);
+        assert_eq!(at(Bits10), -10.25);
+        assert_eq!(at(Bits9), -10.5);
+
+        let scratchpad = super::tests::scratchpad(0x0191, 0x0c, 0x10);
+        let at = |resolution| temperature(FAMILY_DS1822, &scratchpad, resolution);
+        assert_eq!(at(Bits11), 25.);
+        assert_eq!(at(Bits10), 25.);
+        assert_eq!(at(Bits9), 25.);
+    }
+
+    #[test]
+    fn ds18s20() {
+        // 9 bit value, the counters add the fraction
+        let at = |raw, count_remain| {
+            let scratchpad = scratchpad(raw, count_remain, 0x10);
+            temperature(FAMILY_DS18S20, &scratchpad, Resolution::Bits12)
+        };
+        assert_eq!(at(0x0032, 0x0c), 25.);
+        assert_eq!(at(0x0032, 0x08), 25.25);
+        assert_eq!(at(0x0033, 0x0c), 25.);
+        assert_eq!(at(0xffff, 0x0c), -1.);
+        assert_eq!(at(0xff92, 0x0c), -55.);
+    }
+
+    #[test]
+    fn resolution() {
+        assert_eq!(Resolution::from_bits(9), Some(Resolution::Bits9));
+        assert_eq!(Resolution::from_bits(12), Some(Resolution::Bits12));
+        assert_eq!(Resolution::from_bits(13), None);
+
+        assert_eq!(Resolution::Bits9.config(), 0x1f);
+        assert_eq!(Resolution::Bits12.config(), 0x7f);
+        assert_eq!(
+            Resolution::Bits9.conversion_time(),
+            Duration::from_micros(93_750)
+        );
+        assert_eq!(
+            Resolution::Bits12.conversion_time(),
+            Duration::from_millis(750)
+        );
+    }
+
+    #[test]
+    fn update() {
+        let probes = [address(FAMILY_DS18B20, 1), address(FAMILY_DS18S20, 2)];
+        let mut bad = scratchpad(0x0032, 0x0c, 0x10);
+        bad[0] ^= 1;
+        let bus = FakeBus::new(vec![
+            (probes[0], scratchpad(0x0191, 0x0c, 0x10).to_vec()),
+            (probes[1], bad.to_vec()),
+        ]);
+        let sensors = probes
+            .iter()
+            .map(|address| (*address, address.to_string()))
+            .collect();
+
+        let (send, recv) = async_channel::unbounded();
+        let mut dallas = Dallas::new(bus, Resolution::Bits11, sensors, StatePublisher::new(send));
+
+        // the DS18S20 has a fixed resolution
+        dallas.setup().unwrap();
+        let mut configure = vec![MATCH_ROM];
+        configure.extend(probes[0].0.to_le_bytes());
+        configure.extend([WRITE_SCRATCHPAD, 0x7f, 0x80, 0x5f]);
+        assert_eq!(dallas.bus.transactions, [configure]);
+        dallas.bus.transactions.clear();
+
+        // only starts the conversion
+        dallas.update().unwrap();
+        assert_eq!(dallas.bus.transactions, [[SKIP_ROM, CONVERT_T]]);
+        assert!(published(&recv).is_empty());
+        dallas.bus.transactions.clear();
+
+        // still converting
+        dallas.update().unwrap();
+        assert!(dallas.bus.transactions.is_empty());
+
+        // reads and starts the next one, the corrupted scratchpad is NaN
+        dallas.converting_since = Some(Instant::now() - Duration::from_secs(1));
+        assert!(dallas.update().is_err());
+        let states = published(&recv);
+        assert_eq!(states[0], 25.);
+        assert!(states[1].is_nan());
+        assert_eq!(dallas.bus.transactions.len(), 3);
+        assert_eq!(dallas.bus.transactions[2], [SKIP_ROM, CONVERT_T]);
+        assert!(dallas.converting_since.is_some());
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use async_channel::Sender;
+use protobuf::Message;
+
+use crate::{
+    api::*,
+    components::{BaseComponent, ComponentUpdate},
+    consts::MessageTypes,
+};
+
+/// A `ListEntities*Response` together with the key of the entity it describes
+#[derive(Debug, Clone)]
+pub struct EntityDescription {
+    pub key: u32,
+    pub ty: MessageTypes,
+    pub msg: Arc<Box<dyn Message>>,
+}
+
+impl EntityDescription {
+    pub fn new<D: EntityInfo>(key: u32, msg: D) -> Self {
+        EntityDescription {
+            key,
+            ty: D::TYPE,
+            msg: Arc::new(Box::new(msg)),
+        }
+    }
+}
+
+/// Fields every entity has besides its name, like ESPHome's `EntityBase`
+#[derive(Debug, Clone, Default)]
+pub struct EntityMeta {
+    /// e.g. `mdi:thermometer`, `None` lets Home Assistant pick one
+    pub icon: Option<String>,
+    pub entity_category: EntityCategory,
+    pub disabled_by_default: bool,
+    /// Only sent for entities that have one (binary sensor, button, sensor and switch)
+    pub device_class: Option<String>,
+}
+
+impl EntityMeta {
+    pub fn icon(mut self, icon: &str) -> Self {
+        self.icon = Some(icon.to_owned());
+        self
+    }
+
+    pub fn entity_category(mut self, entity_category: EntityCategory) -> Self {
+        self.entity_category = entity_category;
+        self
+    }
+
+    pub fn device_class(mut self, device_class: &str) -> Self {
+        self.device_class = Some(device_class.to_owned());
+        self
+    }
+}
+
+/// `ListEntities*Response` messages
+pub trait EntityInfo: Message {
+    const TYPE: MessageTypes;
+
+    /// Fills in the fields shared by all entities
+    fn set_base(&mut self, base: &BaseComponent);
+}
+
+/// `*StateResponse` messages that can be published by a component
+pub trait EntityState: Message {
+    const TYPE: MessageTypes;
+}
+
+macro_rules! message_type {
+    ($tr:ident, $($msg:ident),+) => {
+        $(
+            impl $tr for $msg {
+                const TYPE: MessageTypes = MessageTypes::$msg;
+            }
+        )+
+    };
+}
+
+macro_rules! entity_info {
+    (@base $msg:ident, $base:ident) => {
+        let meta = $base.get_meta();
+        $msg.set_key($base.get_object_id_hash());
+        $msg.set_name($base.get_name());
+        $msg.set_object_id($base.get_object_id());
+        $msg.set_unique_id($base.get_unique_id());
+        $msg.set_icon(meta.icon.clone().unwrap_or_default());
+        $msg.set_entity_category(meta.entity_category);
+        $msg.set_disabled_by_default(meta.disabled_by_default);
+    };
+    (device_class: $($msg:ident),+) => {
+        $(
+            impl EntityInfo for $msg {
+                const TYPE: MessageTypes = MessageTypes::$msg;
+
+                fn set_base(&mut self, base: &BaseComponent) {
+                    entity_info!(@base self, base);
+                    self.set_device_class(base.get_meta().device_class.clone().unwrap_or_default());
+                }
+            }
+        )+
+    };
+    ($($msg:ident),+) => {
+        $(
+            impl EntityInfo for $msg {
+                const TYPE: MessageTypes = MessageTypes::$msg;
+
+                fn set_base(&mut self, base: &BaseComponent) {
+                    entity_info!(@base self, base);
+                }
+            }
+        )+
+    };
+}
+
+entity_info!(
+    device_class: ListEntitiesBinarySensorResponse,
+    ListEntitiesButtonResponse,
+    ListEntitiesSensorResponse,
+    ListEntitiesSwitchResponse
+);
+
+entity_info!(
+    ListEntitiesLightResponse,
+    ListEntitiesNumberResponse,
+    ListEntitiesSelectResponse,
+    ListEntitiesTextSensorResponse
+);
+
+message_type!(
+    EntityState,
+    BinarySensorStateResponse,
+    LightStateResponse,
+    NumberStateResponse,
+    SelectStateResponse,
+    SensorStateResponse,
+    SwitchStateResponse,
+    TextSensorStateResponse
+);
+
+/// Hands states from components to the server
+///
+/// Can be cloned and used from any task or thread, publishing never blocks.
+#[derive(Debug, Clone)]
+pub struct StatePublisher {
+    send: Sender<ComponentUpdate>,
+}
+
+impl StatePublisher {
+    pub fn new(send: Sender<ComponentUpdate>) -> Self {
+        StatePublisher { send }
+    }
+
+    pub fn publish<S: EntityState>(&self, state: S) {
+        // the server channel is unbounded, this only fails once the server is gone
+        let _ = self.send.try_send(ComponentUpdate::Response((
+            S::TYPE,
+            Arc::new(Box::new(state)),
+        )));
+    }
+
+    /// Asks the server for an orderly shutdown, e.g. to reboot into an update
+    pub fn request_shutdown(&self) {
+        let _ = self.send.try_send(ComponentUpdate::Shutdown);
+    }
+}
+
+/// Commands from a client, each one addressed to a single entity
+#[derive(Debug, Clone)]
+pub enum Command {
+    Button(Box<ButtonCommandRequest>),
+    Light(Box<LightCommandRequest>),
+    Number(Box<NumberCommandRequest>),
+    Select(Box<SelectCommandRequest>),
+    Switch(Box<SwitchCommandRequest>),
+}
+
+impl Command {
+    pub fn key(&self) -> u32 {
+        match self {
+            Command::Button(cmd) => cmd.get_key(),
+            Command::Light(cmd) => cmd.get_key(),
+            Command::Number(cmd) => cmd.get_key(),
+            Command::Select(cmd) => cmd.get_key(),
+            Command::Switch(cmd) => cmd.get_key(),
+        }
+    }
+}
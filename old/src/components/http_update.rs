@@ -0,0 +1,731 @@
+//! Pull updates, the device checks a manifest and installs newer firmware on its own
+//!
+//! The manifest is plain `key = value` lines:
+//!
+//! ```text
+//! version = 0.0.8
+//! url = esphome-rs-poc-0.0.8.bin
+//! size = 1523712
+//! sha256 = 3b1f...
+//! signature = 9a0c...
+//! ```
+//!
+//! `url` may be relative to the manifest, `signature` is the Ed25519 signature (hex, 64 bytes) of just the `version`,
+//! `size` and `sha256` lines in this order, each ending with `\n` (see [`Manifest::signed`]). The signature is
+//! checked before the download, size and hash while the image streams into the inactive OTA partition. Only an image
+//! whose app description has the signed version, newer than the running one, is set to boot.
+
+use std::{
+    cmp::Ordering,
+    fmt::{Debug, Display, Formatter},
+    io::Read,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use ed25519_compact::{PublicKey, Signature};
+use log::*;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{
+        EntityCategory, ListEntitiesButtonResponse, ListEntitiesSensorResponse,
+        ListEntitiesTextSensorResponse, SensorStateClass, SensorStateResponse,
+        TextSensorStateResponse,
+    },
+    components::{
+        entity::{Command, EntityDescription, EntityMeta, StatePublisher},
+        BaseComponent, Component,
+    },
+    error::{Error, Result},
+    http::{self, HttpError, Url},
+    ota::{self, EspOtaTarget, OtaError, OtaTarget},
+    VERSION,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// How often the schedule is looked at
+const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+/// Updating is refused until the running image was marked valid
+const FIRST_CHECK_AFTER: Duration = Duration::from_secs(2 * 60);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_MANIFEST_SIZE: u64 = 4096;
+const CHUNK_SIZE: usize = 1024;
+/// Progress is published in steps of this many percent
+const PROGRESS_STEP: usize = 5;
+/// Signature verification needs more than the default 4k
+const STACK_SIZE: usize = 12 * 1024;
+
+#[derive(Debug)]
+pub enum UpdateError<E> {
+    Http(HttpError),
+    Manifest(String),
+    Target(OtaError<E>),
+    /// The server sent a different amount than the manifest announced
+    Size {
+        expected: usize,
+        received: usize,
+    },
+    Sha256Mismatch,
+    Signature,
+    /// The image's app description doesn't have the signed version or it isn't newer
+    Version(String),
+}
+
+impl<E> From<HttpError> for UpdateError<E> {
+    fn from(err: HttpError) -> Self {
+        UpdateError::Http(err)
+    }
+}
+
+impl<E> From<OtaError<E>> for UpdateError<E> {
+    fn from(err: OtaError<E>) -> Self {
+        UpdateError::Target(err)
+    }
+}
+
+impl<E: Debug> Display for UpdateError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateError::Http(err) => write!(f, "download failed: {err}"),
+            UpdateError::Manifest(reason) => write!(f, "invalid manifest: {reason}"),
+            UpdateError::Target(err) => write!(f, "{err}"),
+            UpdateError::Size { expected, received } => {
+                write!(f, "received {received} of {expected} bytes")
+            }
+            UpdateError::Sha256Mismatch => write!(f, "SHA-256 mismatch"),
+            UpdateError::Signature => write!(f, "invalid signature"),
+            UpdateError::Version(version) => write!(f, "image has version {version}"),
+        }
+    }
+}
+
+/// `major.minor.patch`, missing parts count as 0
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    let parse = |version: &str| -> Option<Vec<u32>> {
+        version
+            .trim()
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect()
+    };
+    let (mut a, mut b) = (parse(a)?, parse(b)?);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    Some(a.cmp(&b))
+}
+
+pub fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    pub size: usize,
+    pub sha256: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> std::result::Result<Manifest, String> {
+        let (mut version, mut url, mut size, mut sha256, mut signature) =
+            (None, None, None, None, None);
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| format!("line {line:?}"))?;
+            match key {
+                "version" => version = Some(value.to_owned()),
+                "url" => url = Some(value.to_owned()),
+                "size" => size = Some(value.parse().map_err(|_| format!("size {value:?}"))?),
+                "sha256" => sha256 = Some(from_hex(value).ok_or("sha256 is not 32 hex bytes")?),
+                "signature" => {
+                    signature = Some(from_hex(value).ok_or("signature is not 64 hex bytes")?)
+                }
+                // room for additions
+                key => debug!("ignoring manifest key {}", key),
+            }
+        }
+
+        let missing = |key: &str| format!("{key} is missing");
+        Ok(Manifest {
+            version: version.ok_or_else(|| missing("version"))?,
+            url: url.ok_or_else(|| missing("url"))?,
+            size: size.ok_or_else(|| missing("size"))?,
+            sha256: sha256.ok_or_else(|| missing("sha256"))?,
+            signature: signature.ok_or_else(|| missing("signature"))?,
+        })
+    }
+
+    /// What the signature covers
+    pub fn signed(&self) -> String {
+        let sha256: String = self
+            .sha256
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!(
+            "version = {}\nsize = {}\nsha256 = {}\n",
+            self.version, self.size, sha256
+        )
+    }
+
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        public_key
+            .verify(self.signed(), &Signature::new(self.signature))
+            .is_ok()
+    }
+}
+
+pub fn fetch_manifest<E>(url: &Url) -> std::result::Result<Manifest, UpdateError<E>> {
+    let mut text = String::new();
+    http::get(url, HTTP_TIMEOUT)?
+        .take(MAX_MANIFEST_SIZE)
+        .read_to_string(&mut text)
+        .map_err(HttpError::Io)?;
+    Manifest::parse(&text).map_err(UpdateError::Manifest)
+}
+
+/// Streams the image into `target`, it only boots next if signature, size, hash and version match
+///
+/// `progress` gets the bytes received so far.
+pub fn install<T: OtaTarget>(
+    manifest: &Manifest,
+    image: &Url,
+    public_key: &PublicKey,
+    target: &mut T,
+    mut progress: impl FnMut(usize),
+) -> std::result::Result<(), UpdateError<T::Error>> {
+    if !manifest.verify(public_key) {
+        return Err(UpdateError::Signature);
+    }
+    let mut sha256 = Sha256::new();
+
+    let mut response = http::get(image, HTTP_TIMEOUT)?;
+    if let Some(length) = response.content_length {
+        if length != manifest.size {
+            return Err(UpdateError::Size {
+                expected: manifest.size,
+                received: length,
+            });
+        }
+    }
+
+    target.begin(manifest.size)?;
+    let result = (|| {
+        let mut buf = [0; CHUNK_SIZE];
+        let mut received = 0;
+        loop {
+            let read = response.read(&mut buf).map_err(HttpError::Io)?;
+            if read == 0 {
+                break;
+            }
+            received += read;
+            if received > manifest.size {
+                break;
+            }
+            let chunk = &buf[..read];
+            sha256.update(chunk);
+            target
+                .write(chunk)
+                .map_err(|err| UpdateError::Target(OtaError::Write(err)))?;
+            progress(received);
+        }
+
+        if received != manifest.size {
+            return Err(UpdateError::Size {
+                expected: manifest.size,
+                received,
+            });
+        }
+        if sha256.finalize().as_slice() != manifest.sha256 {
+            return Err(UpdateError::Sha256Mismatch);
+        }
+        target
+            .finish()
+            .map_err(|err| UpdateError::Target(OtaError::End(err)))?;
+
+        // the hash only ties the image to the manifest, the image itself has to agree on the version
+        let version = target
+            .version()
+            .map_err(|err| UpdateError::Target(OtaError::End(err)))?;
+        if version != manifest.version
+            || compare_versions(&version, VERSION) != Some(Ordering::Greater)
+        {
+            return Err(UpdateError::Version(version));
+        }
+        target
+            .set_boot()
+            .map_err(|err| UpdateError::Target(OtaError::End(err)))
+    })();
+
+    if result.is_err() {
+        target.abort();
+    }
+    result
+}
+
+/// Last states, published again when a client subscribes
+#[derive(Debug, Default)]
+struct Report {
+    status: String,
+    progress: Option<f32>,
+    latest: Option<String>,
+}
+
+/// Publishes from the worker thread
+#[derive(Clone)]
+struct Reporter {
+    status_key: u32,
+    progress_key: u32,
+    latest_key: u32,
+    report: Arc<Mutex<Report>>,
+    publisher: StatePublisher,
+}
+
+impl Reporter {
+    fn status(&self, status: String) {
+        info!("firmware update: {}", status);
+        self.report.lock().expect("lock poisened!").status = status;
+        self.publish();
+    }
+
+    fn progress(&self, progress: f32) {
+        self.report.lock().expect("lock poisened!").progress = Some(progress);
+        self.publish();
+    }
+
+    fn latest(&self, latest: String) {
+        self.report.lock().expect("lock poisened!").latest = Some(latest);
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let report = self.report.lock().expect("lock poisened!");
+
+        let mut resp = TextSensorStateResponse::new();
+        resp.set_key(self.status_key);
+        resp.set_state(report.status.clone());
+        self.publisher.publish(resp);
+
+        let mut resp = SensorStateResponse::new();
+        resp.set_key(self.progress_key);
+        match report.progress {
+            Some(progress) => resp.set_state(progress),
+            None => resp.set_missing_state(true),
+        }
+        self.publisher.publish(resp);
+
+        let mut resp = TextSensorStateResponse::new();
+        resp.set_key(self.latest_key);
+        match &report.latest {
+            Some(latest) => resp.set_state(latest.clone()),
+            None => resp.set_missing_state(true),
+        }
+        self.publisher.publish(resp);
+    }
+}
+
+/// Runs in its own thread, erasing and writing the flash takes a while
+fn check(manifest_url: &Url, public_key: &PublicKey, reporter: &Reporter) {
+    reporter.status(String::from("Checking"));
+    let manifest = match fetch_manifest::<()>(manifest_url) {
+        Ok(manifest) => manifest,
+        Err(err) => return reporter.status(format!("Check failed: {err}")),
+    };
+    reporter.latest(manifest.version.clone());
+
+    match compare_versions(&manifest.version, VERSION) {
+        Some(Ordering::Greater) => {}
+        Some(_) => return reporter.status(String::from("Up to date")),
+        None => return reporter.status(format!("Invalid version {}", manifest.version)),
+    }
+
+    let guard = match ota::UpdateGuard::take() {
+        Some(guard) => guard,
+        None => return reporter.status(String::from("Another update is in progress")),
+    };
+    let result = (|| {
+        let image = manifest_url.join(&manifest.url)?;
+        reporter.status(format!("Installing {}", manifest.version));
+
+        let mut target = EspOtaTarget::new()?;
+        let mut last_step = 0;
+        install(&manifest, &image, public_key, &mut target, |received| {
+            let percent = received * 100 / manifest.size.max(1);
+            if percent / PROGRESS_STEP != last_step {
+                last_step = percent / PROGRESS_STEP;
+                reporter.progress(percent as f32);
+            }
+        })
+    })();
+
+    match result {
+        Ok(()) => {
+            guard.keep();
+            reporter.status(format!("Rebooting into {}", manifest.version));
+            // main reboots once the server is down
+            reporter.publisher.request_shutdown();
+        }
+        Err(err) => reporter.status(format!("Update failed: {err}")),
+    }
+}
+
+/// Checks `manifest_url` on a schedule (and on the button press) and installs newer firmware
+pub struct HttpUpdate {
+    manifest_url: Url,
+    public_key: PublicKey,
+    next_check: Instant,
+
+    status: BaseComponent,
+    progress: BaseComponent,
+    latest: BaseComponent,
+    button: BaseComponent,
+
+    reporter: Reporter,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl HttpUpdate {
+    /// `public_key` is the raw Ed25519 key the images are signed with
+    pub fn new(
+        name: &str,
+        manifest_url: &str,
+        public_key: [u8; 32],
+        publisher: StatePublisher,
+    ) -> std::result::Result<Self, HttpError> {
+        let diagnostic =
+            || EntityMeta::default().entity_category(EntityCategory::ENTITY_CATEGORY_DIAGNOSTIC);
+        let status = BaseComponent::new(String::from(name) + " Status", "text_sensor")
+            .with_meta(diagnostic().icon("mdi:update"));
+        let progress = BaseComponent::new(String::from(name) + " Progress", "sensor")
+            .with_meta(diagnostic().icon("mdi:progress-download"));
+        let latest = BaseComponent::new(String::from(name) + " Latest Version", "text_sensor")
+            .with_meta(diagnostic().icon("mdi:new-box"));
+        let button = BaseComponent::new(String::from(name) + " Check", "button").with_meta(
+            EntityMeta::default()
+                .icon("mdi:cloud-sync")
+                .entity_category(EntityCategory::ENTITY_CATEGORY_CONFIG),
+        );
+
+        let reporter = Reporter {
+            status_key: status.get_object_id_hash(),
+            progress_key: progress.get_object_id_hash(),
+            latest_key: latest.get_object_id_hash(),
+            report: Arc::new(Mutex::new(Report {
+                status: String::from("Idle"),
+                ..Default::default()
+            })),
+            publisher,
+        };
+
+        Ok(HttpUpdate {
+            manifest_url: Url::parse(manifest_url)?,
+            public_key: PublicKey::new(public_key),
+            next_check: Instant::now() + FIRST_CHECK_AFTER.max(ota::CONFIRM_AFTER),
+
+            status,
+            progress,
+            latest,
+            button,
+
+            reporter,
+            worker: None,
+        })
+    }
+
+    fn start_check(&mut self) -> Result<()> {
+        if self
+            .worker
+            .as_ref()
+            .is_some_and(|worker| !worker.is_finished())
+        {
+            debug!("firmware update check is already running");
+            return Ok(());
+        }
+        self.next_check = Instant::now() + CHECK_INTERVAL;
+
+        let manifest_url = self.manifest_url.clone();
+        let public_key = self.public_key;
+        let reporter = self.reporter.clone();
+        let worker = thread::Builder::new()
+            .name(String::from("http_update"))
+            .stack_size(STACK_SIZE)
+            .spawn(move || check(&manifest_url, &public_key, &reporter))
+            .map_err(|err| {
+                Error::component(
+                    self.status.get_object_id_hash(),
+                    format!("failed to start update check: {err}"),
+                )
+            })?;
+        self.worker = Some(worker);
+        Ok(())
+    }
+}
+
+impl Component for HttpUpdate {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        let mut progress = ListEntitiesSensorResponse::new();
+        progress.set_unit_of_measurement(String::from("%"));
+        progress.set_accuracy_decimals(0);
+        progress.set_state_class(SensorStateClass::STATE_CLASS_MEASUREMENT);
+
+        vec![
+            self.status.describe(ListEntitiesTextSensorResponse::new()),
+            self.progress.describe(progress),
+            self.latest.describe(ListEntitiesTextSensorResponse::new()),
+            self.button.describe(ListEntitiesButtonResponse::new()),
+        ]
+    }
+
+    fn update(&mut self) -> Result<()> {
+        match Instant::now() >= self.next_check {
+            true => self.start_check(),
+            false => Ok(()),
+        }
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        Some(UPDATE_INTERVAL)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.reporter.publish();
+        Ok(())
+    }
+
+    fn handle_command(&mut self, cmd: &Command) -> Result<()> {
+        match cmd {
+            Command::Button(_) => self.start_check(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_compact::{KeyPair, Seed};
+
+    use super::*;
+    use crate::{
+        http::tests::{response, serve},
+        ota::tests::FakeTarget,
+    };
+
+    const NEWER: &str = "99.0.0";
+
+    fn image() -> Vec<u8> {
+        // more than a chunk
+        (0..3000).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn key_pair() -> KeyPair {
+        KeyPair::from_seed(Seed::new([0x42; 32]))
+    }
+
+    fn manifest(image: &[u8]) -> Manifest {
+        let mut manifest = Manifest {
+            version: String::from(NEWER),
+            url: String::from("image.bin"),
+            size: image.len(),
+            sha256: Sha256::digest(image).into(),
+            signature: [0; 64],
+        };
+        manifest.signature = *key_pair().sk.sign(manifest.signed(), None);
+        manifest
+    }
+
+    /// Serves `served` for the image of `manifest`, the target reports `version`
+    fn install_from(
+        manifest: &Manifest,
+        served: Vec<u8>,
+        version: &str,
+    ) -> (std::result::Result<(), UpdateError<()>>, FakeTarget) {
+        let (url, server) = serve(vec![served]);
+        let mut target = FakeTarget {
+            version: String::from(version),
+            ..Default::default()
+        };
+        let mut progress = vec![];
+        let result = install(
+            manifest,
+            &url.join(&manifest.url).unwrap(),
+            &key_pair().pk,
+            &mut target,
+            |received| progress.push(received),
+        );
+        if result.is_ok() {
+            assert_eq!(progress.last(), Some(&manifest.size));
+        }
+        // only a bad signature stops before the download
+        if !matches!(result, Err(UpdateError::Signature)) {
+            let requests = server.join().unwrap();
+            assert!(requests[0].starts_with("GET /image.bin HTTP/1.0\r\n"));
+        }
+        (result, target)
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("0.0.8", "0.0.7"), Some(Ordering::Greater));
+        assert_eq!(compare_versions("0.10.0", "0.9.9"), Some(Ordering::Greater));
+        assert_eq!(compare_versions("v1.2", "1.2.0"), Some(Ordering::Equal));
+        assert_eq!(compare_versions(" 1.2.0\n", "1.2.1"), Some(Ordering::Less));
+        assert_eq!(compare_versions("1.2.0-rc1", "1.2.0"), None);
+        assert_eq!(compare_versions("", "1.2.0"), None);
+    }
+
+    #[test]
+    fn parse() {
+        let text = format!(
+            "# nightly\n\nversion = 0.0.8\nurl=fw/image.bin\n  size = 1523712\nsha256 = {}\nsignature = {}\n\
+             channel = beta\n",
+            "ab".repeat(32),
+            "0f".repeat(64)
+        );
+        let manifest = Manifest::parse(&text).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                version: String::from("0.0.8"),
+                url: String::from("fw/image.bin"),
+                size: 1523712,
+                sha256: [0xab; 32],
+                signature: [0x0f; 64],
+            }
+        );
+        assert_eq!(
+            manifest.signed(),
+            format!(
+                "version = 0.0.8\nsize = 1523712\nsha256 = {}\n",
+                "ab".repeat(32)
+            )
+        );
+
+        let without = |key: &str| {
+            let text: Vec<&str> = text
+                .lines()
+                .filter(|line| !line.trim().starts_with(key))
+                .collect();
+            Manifest::parse(&text.join("\n"))
+        };
+        assert_eq!(without("version"), Err(String::from("version is missing")));
+        assert_eq!(
+            without("signature"),
+            Err(String::from("signature is missing"))
+        );
+
+        let broken = text.replace("1523712", "1.5 MB");
+        assert_eq!(
+            Manifest::parse(&broken),
+            Err(String::from("size \"1.5 MB\""))
+        );
+        let broken = text.replace(&"ab".repeat(32), "abab");
+        assert!(Manifest::parse(&broken).is_err());
+        assert!(Manifest::parse("version 0.0.8").is_err());
+    }
+
+    #[test]
+    fn install_image() {
+        let manifest = manifest(&image());
+        let (result, target) = install_from(&manifest, response("200 OK", &image()), NEWER);
+        result.unwrap();
+        assert_eq!(target.size, Some(image().len()));
+        assert_eq!(target.written, image());
+        assert!(target.finished && target.booted);
+        assert!(!target.aborted);
+    }
+
+    #[test]
+    fn install_signature() {
+        // signed with another key
+        let mut manifest = manifest(&image());
+        manifest.signature = *KeyPair::from_seed(Seed::new([0x24; 32]))
+            .sk
+            .sign(manifest.signed(), None);
+        let (result, target) = install_from(&manifest, vec![], NEWER);
+        assert!(matches!(result, Err(UpdateError::Signature)));
+        assert_eq!(target.size, None);
+
+        // the signature covers the version
+        let mut manifest = self::manifest(&image());
+        manifest.version = String::from("99.0.1");
+        let (result, target) = install_from(&manifest, vec![], NEWER);
+        assert!(matches!(result, Err(UpdateError::Signature)));
+        assert_eq!(target.size, None);
+    }
+
+    #[test]
+    fn install_sha256() {
+        let manifest = manifest(&image());
+        let mut tampered = image();
+        tampered[100] ^= 0x01;
+        let (result, target) = install_from(&manifest, response("200 OK", &tampered), NEWER);
+        assert!(matches!(result, Err(UpdateError::Sha256Mismatch)));
+        assert!(!target.booted);
+        assert!(target.aborted);
+    }
+
+    #[test]
+    fn install_size() {
+        let manifest = manifest(&image());
+
+        // announced by the server
+        let (result, target) = install_from(&manifest, response("200 OK", &image()[..2000]), NEWER);
+        assert!(matches!(
+            result,
+            Err(UpdateError::Size {
+                expected: 3000,
+                received: 2000
+            })
+        ));
+        assert_eq!(target.size, None);
+
+        // the connection closed early
+        let mut served = response("200 OK", &image());
+        served.truncate(served.len() - 1000);
+        let (result, target) = install_from(&manifest, served, NEWER);
+        assert!(matches!(
+            result,
+            Err(UpdateError::Size {
+                expected: 3000,
+                received: 2000
+            })
+        ));
+        assert!(!target.booted);
+        assert!(target.aborted);
+    }
+
+    #[test]
+    fn install_version() {
+        let manifest = manifest(&image());
+        let (result, target) = install_from(&manifest, response("200 OK", &image()), "98.0.0");
+        assert!(matches!(result, Err(UpdateError::Version(version)) if version == "98.0.0"));
+        assert!(target.finished && !target.booted);
+
+        // signed, but not newer than the running one
+        let mut manifest = self::manifest(&image());
+        manifest.version = String::from(VERSION);
+        manifest.signature = *key_pair().sk.sign(manifest.signed(), None);
+        let (result, target) = install_from(&manifest, response("200 OK", &image()), VERSION);
+        assert!(matches!(result, Err(UpdateError::Version(_))));
+        assert!(!target.booted);
+    }
+}
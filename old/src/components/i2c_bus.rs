@@ -0,0 +1,129 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use embedded_hal_1::i2c::{self as i2c_1, Operation};
+
+/// Addresses outside of this range are reserved
+const SCAN_FIRST: u8 = 0x08;
+const SCAN_LAST: u8 = 0x77;
+
+/// One I²C master shared by all devices on the bus
+///
+/// Every device gets its own [`I2cDevice`] handle, a transaction holds the bus only as long as it takes.
+/// Errors are returned to the device that caused them, the other devices keep working.
+pub struct I2cBus<I2C> {
+    bus: Arc<Mutex<I2C>>,
+}
+
+impl<I2C> I2cBus<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        I2cBus {
+            bus: Arc::new(Mutex::new(i2c)),
+        }
+    }
+
+    pub fn device(&self) -> I2cDevice<I2C> {
+        I2cDevice {
+            bus: self.bus.clone(),
+        }
+    }
+}
+
+impl<I2C: Write> I2cBus<I2C> {
+    /// Addresses that acknowledge an empty write, like ESPHome's `scan: true`
+    pub fn scan(&self) -> Vec<u8> {
+        let mut bus = lock(&self.bus);
+        (SCAN_FIRST..=SCAN_LAST)
+            .filter(|addr| bus.write(*addr, &[]).is_ok())
+            .collect()
+    }
+}
+
+/// Handle of a single device on an [`I2cBus`], usable wherever a driver wants an I²C master
+///
+/// Implements embedded-hal 0.2 and 1.0, so drivers written for either can share the bus.
+pub struct I2cDevice<I2C> {
+    bus: Arc<Mutex<I2C>>,
+}
+
+impl<I2C: Read> Read for I2cDevice<I2C> {
+    type Error = I2C::Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        lock(&self.bus).read(address, buffer)
+    }
+}
+
+impl<I2C: Write> Write for I2cDevice<I2C> {
+    type Error = I2C::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        lock(&self.bus).write(address, bytes)
+    }
+}
+
+impl<I2C: WriteRead> WriteRead for I2cDevice<I2C> {
+    type Error = I2C::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        lock(&self.bus).write_read(address, bytes, buffer)
+    }
+}
+
+/// Bus error for embedded-hal 1.0 drivers, the 0.2 error does not tell what went wrong
+#[derive(Debug)]
+pub struct I2cError<E>(pub E);
+
+impl<E: Debug> i2c_1::Error for I2cError<E> {
+    fn kind(&self) -> i2c_1::ErrorKind {
+        i2c_1::ErrorKind::Other
+    }
+}
+
+impl<I2C: Write> i2c_1::ErrorType for I2cDevice<I2C>
+where
+    I2C::Error: Debug,
+{
+    type Error = I2cError<I2C::Error>;
+}
+
+impl<E, I2C> i2c_1::I2c for I2cDevice<I2C>
+where
+    E: Debug,
+    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
+{
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut bus = lock(&self.bus);
+
+        // register reads, keep the repeated start between the two
+        if let [Operation::Write(bytes), Operation::Read(buffer)] = operations {
+            return bus.write_read(address, bytes, buffer).map_err(I2cError);
+        }
+
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => bus.write(address, bytes),
+                Operation::Read(buffer) => bus.read(address, buffer),
+            }
+            .map_err(I2cError)?;
+        }
+        Ok(())
+    }
+}
+
+/// A driver panicking mid transaction must not take the other devices down with it
+fn lock<I2C>(bus: &Mutex<I2C>) -> MutexGuard<'_, I2C> {
+    bus.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,449 @@
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys::*;
+
+use crate::{
+    api::{ColorMode, LightCommandRequest, LightStateResponse, ListEntitiesLightResponse},
+    components::{
+        entity::{Command, EntityDescription, StatePublisher},
+        BaseComponent, Component,
+    },
+    error::{Error, Result},
+    utils::{
+        addressable::{AddressableEffect, Color, EffectRenderer, GammaTable},
+        light_color::{LightTraits, DEFAULT_GAMMA},
+        light_engine::{LightEffect, LightEngine, ANIMATION_INTERVAL, EFFECT_NONE},
+    },
+};
+
+/// RMT runs at 80 MHz / 2, one tick is 25 ns
+const RMT_CLK_DIV: u8 = 2;
+const RMT_TICK_NS: u32 = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chipset {
+    /// GRB
+    Ws2812,
+    /// GRBW
+    Sk6812,
+}
+
+impl Chipset {
+    /// ESPHome's names, e.g. `WS2812`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "WS2812" => Some(Chipset::Ws2812),
+            "SK6812" => Some(Chipset::Sk6812),
+            _ => None,
+        }
+    }
+
+    /// High and low time in ns of a 0 and a 1 bit
+    fn timings(&self) -> ((u32, u32), (u32, u32)) {
+        match self {
+            Chipset::Ws2812 => ((400, 850), (800, 450)),
+            Chipset::Sk6812 => ((300, 900), (600, 600)),
+        }
+    }
+
+    fn has_white(&self) -> bool {
+        *self == Chipset::Sk6812
+    }
+
+    fn color_mode(&self) -> ColorMode {
+        if self.has_white() {
+            ColorMode::COLOR_MODE_RGB_WHITE
+        } else {
+            ColorMode::COLOR_MODE_RGB
+        }
+    }
+}
+
+/// Legacy RMT driver of ESP-IDF 4.x in TX mode
+pub struct RmtDriver {
+    channel: rmt_channel_t,
+    chipset: Chipset,
+    /// `rmt_item32_t` for a 0 and a 1 bit
+    bits: (u32, u32),
+    items: Vec<u32>,
+}
+
+impl RmtDriver {
+    pub fn new(channel: rmt_channel_t, gpio: i32, chipset: Chipset) -> Result<Self> {
+        let mut config = rmt_config_t {
+            rmt_mode: rmt_mode_t_RMT_MODE_TX,
+            channel,
+            gpio_num: gpio,
+            clk_div: RMT_CLK_DIV,
+            mem_block_num: 1,
+            ..Default::default()
+        };
+        config.__bindgen_anon_1.tx_config = rmt_tx_config_t {
+            idle_level: rmt_idle_level_t_RMT_IDLE_LEVEL_LOW,
+            idle_output_en: true,
+            ..Default::default()
+        };
+
+        esp!(unsafe { rmt_config(&config) })?;
+        esp!(unsafe { rmt_driver_install(channel, 0, 0) })?;
+
+        let (zero, one) = chipset.timings();
+        Ok(RmtDriver {
+            channel,
+            chipset,
+            bits: (rmt_item(zero), rmt_item(one)),
+            items: vec![],
+        })
+    }
+
+    /// Blocks until the whole strip was sent
+    pub fn write(&mut self, pixels: &[Color]) -> Result<()> {
+        let (zero, one) = self.bits;
+
+        self.items.clear();
+        for pixel in pixels {
+            let mut bytes = vec![pixel.g, pixel.r, pixel.b];
+            if self.chipset.has_white() {
+                bytes.push(pixel.w);
+            }
+            for byte in bytes {
+                for bit in (0..8).rev() {
+                    self.items
+                        .push(if byte & (1 << bit) != 0 { one } else { zero });
+                }
+            }
+        }
+
+        // `rmt_item32_t` is a single `u32` bit field
+        esp!(unsafe {
+            rmt_write_items(
+                self.channel,
+                self.items.as_ptr() as *const rmt_item32_t,
+                self.items.len() as i32,
+                true,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for RmtDriver {
+    fn drop(&mut self) {
+        unsafe { rmt_driver_uninstall(self.channel) };
+    }
+}
+
+/// `duration0 | level0 | duration1 | level1`, high first
+fn rmt_item((high, low): (u32, u32)) -> u32 {
+    let high = high / RMT_TICK_NS;
+    let low = low / RMT_TICK_NS;
+    (high & 0x7fff) | (1 << 15) | ((low & 0x7fff) << 16)
+}
+
+/// Part of the strip that is exposed as its own light entity
+struct Segment {
+    base: BaseComponent,
+    range: Range<usize>,
+    engine: LightEngine,
+    effect: Option<EffectRenderer>,
+}
+
+impl Segment {
+    fn effect_name(&self) -> &'static str {
+        match &self.effect {
+            Some(renderer) => renderer.effect().name(),
+            None => self.engine.effect_name(),
+        }
+    }
+
+    fn handle_command(&mut self, req: &LightCommandRequest, now: Instant) {
+        let mut req = req.clone();
+
+        if req.get_has_effect() {
+            match AddressableEffect::from_name(req.get_effect()) {
+                Some(effect) => {
+                    self.effect = Some(EffectRenderer::new(
+                        effect,
+                        self.range.len(),
+                        now,
+                        self.base.get_object_id_hash(),
+                    ));
+                    // only one effect at a time
+                    req.set_effect(EFFECT_NONE.to_owned());
+                }
+                None => self.effect = None,
+            }
+        }
+
+        self.engine.apply_command(&req, now);
+        if !self.engine.remote_values().is_on() {
+            self.effect = None;
+        }
+    }
+
+    /// Draws into `frame` and writes the gamma corrected result to `output`, both are the whole strip
+    fn render(
+        &mut self,
+        frame: &mut [Color],
+        output: &mut [Color],
+        gamma: &GammaTable,
+        now: Instant,
+    ) {
+        let values = self.engine.poll(now);
+        let (r, g, b, w) = values.as_rgbw();
+        let frame = &mut frame[self.range.clone()];
+
+        let brightness = match &mut self.effect {
+            Some(renderer) => {
+                // effects draw at full brightness, stepped ones build on their last frame
+                let brightness = values.as_brightness();
+                let scale = 1. / brightness.max(f32::EPSILON);
+                let base = Color::from_levels(r * scale, g * scale, b * scale, w * scale);
+                renderer.render(frame, base, now);
+                brightness
+            }
+            None => {
+                frame.fill(Color::from_levels(r, g, b, w));
+                1.
+            }
+        };
+
+        for (out, pixel) in output[self.range.clone()].iter_mut().zip(frame.iter()) {
+            *out = gamma.apply(*pixel, brightness);
+        }
+    }
+
+    fn is_animating(&self) -> bool {
+        self.effect.is_some() || self.engine.is_animating()
+    }
+}
+
+/// WS2812/SK6812 strip on an RMT channel, split into one or more segments
+///
+/// Every segment is a light entity of its own, they share the framebuffer and are sent out together.
+pub struct LedStrip {
+    driver: RmtDriver,
+    segments: Vec<Segment>,
+    /// What the effects draw into
+    frame: Vec<Color>,
+    /// What gets sent, gamma corrected and scaled
+    output: Vec<Color>,
+    gamma: GammaTable,
+    publisher: StatePublisher,
+}
+
+impl LedStrip {
+    /// `segments` are (name, pixel range), ranges outside of `num_leds` are cut off
+    pub fn new(
+        driver: RmtDriver,
+        num_leds: usize,
+        segments: Vec<(String, Range<usize>)>,
+        publisher: StatePublisher,
+    ) -> LedStrip {
+        let traits = LightTraits::new(vec![driver.chipset.color_mode()]);
+
+        let segments = segments
+            .into_iter()
+            .map(|(name, range)| {
+                let range = range.start.min(num_leds)..range.end.min(num_leds);
+                let base = BaseComponent::new(name, "light");
+                Segment {
+                    // the addressable rainbow replaces the plain one
+                    engine: LightEngine::new(traits.clone(), base.get_object_id_hash())
+                        .with_effects(&[
+                            LightEffect::Pulse,
+                            LightEffect::Strobe,
+                            LightEffect::Random,
+                            LightEffect::Flicker,
+                        ]),
+                    base,
+                    range,
+                    effect: None,
+                }
+            })
+            .collect();
+
+        LedStrip {
+            driver,
+            segments,
+            frame: vec![Color::BLACK; num_leds],
+            output: vec![Color::BLACK; num_leds],
+            gamma: GammaTable::new(DEFAULT_GAMMA),
+            publisher,
+        }
+    }
+
+    fn show(&mut self) -> Result<()> {
+        let now = Instant::now();
+        for segment in &mut self.segments {
+            segment.render(&mut self.frame, &mut self.output, &self.gamma, now);
+        }
+        self.driver.write(&self.output)
+    }
+
+    fn publish(&self, segment: &Segment) {
+        let mut resp = LightStateResponse::new();
+        resp.set_key(segment.base.get_object_id_hash());
+        segment
+            .engine
+            .remote_values()
+            .fill_state_response(&mut resp);
+        resp.set_effect(segment.effect_name().to_owned());
+        self.publisher.publish(resp);
+    }
+}
+
+impl Component for LedStrip {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let traits = segment.engine.traits();
+
+                let mut resp = ListEntitiesLightResponse::new();
+                resp.set_supported_color_modes(traits.supported_color_modes.clone());
+                resp.set_legacy_supports_brightness(true);
+                resp.set_legacy_supports_rgb(true);
+                resp.set_legacy_supports_white_value(self.driver.chipset.has_white());
+
+                let mut effects = vec![EFFECT_NONE.to_owned()];
+                effects.extend(
+                    segment
+                        .engine
+                        .effects()
+                        .iter()
+                        .map(|effect| effect.name().to_owned()),
+                );
+                effects.extend(
+                    AddressableEffect::ALL
+                        .iter()
+                        .map(|effect| effect.name().to_owned()),
+                );
+                resp.set_effects(effects.into());
+
+                segment.base.describe(resp)
+            })
+            .collect()
+    }
+
+    fn setup(&mut self) -> Result<()> {
+        self.show()
+    }
+
+    fn update(&mut self) -> Result<()> {
+        self.show()
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        self.segments
+            .iter()
+            .any(Segment::is_animating)
+            .then_some(ANIMATION_INTERVAL)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        for segment in &self.segments {
+            self.publish(segment);
+        }
+        Ok(())
+    }
+
+    fn handle_command(&mut self, cmd: &Command) -> Result<()> {
+        if let Command::Light(req) = cmd {
+            let now = Instant::now();
+            let idx = self
+                .segments
+                .iter()
+                .position(|segment| segment.base.get_object_id_hash() == req.get_key())
+                .ok_or_else(|| Error::component(req.get_key(), "no such segment"))?;
+
+            self.segments[idx].handle_command(req, now);
+            self.show()?;
+            self.publish(&self.segments[idx]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::LightCommandRequest, utils::addressable::GammaTable};
+
+    fn segment(range: Range<usize>) -> Segment {
+        let traits = LightTraits::new(vec![ColorMode::COLOR_MODE_RGB]);
+        Segment {
+            base: BaseComponent::new("strip".to_owned(), "light"),
+            range,
+            engine: LightEngine::new(traits, 1),
+            effect: None,
+        }
+    }
+
+    fn command(effect: &str) -> LightCommandRequest {
+        let mut req = LightCommandRequest::new();
+        req.set_has_state(true);
+        req.set_state(true);
+        req.set_has_rgb(true);
+        req.set_red(1.);
+        req.set_has_transition_length(true);
+        req.set_transition_length(0);
+        req.set_has_effect(true);
+        req.set_effect(effect.to_owned());
+        req
+    }
+
+    #[test]
+    fn chipset_names() {
+        assert_eq!(Chipset::from_name("WS2812"), Some(Chipset::Ws2812));
+        assert_eq!(Chipset::from_name("SK6812"), Some(Chipset::Sk6812));
+        assert_eq!(Chipset::from_name("ws2812"), None);
+    }
+
+    #[test]
+    fn rmt_items() {
+        // 400 ns high, 850 ns low
+        assert_eq!(rmt_item((400, 850)), 16 | 1 << 15 | 34 << 16);
+    }
+
+    #[test]
+    fn segments() {
+        let now = Instant::now();
+        let gamma = GammaTable::new(1.);
+        let mut frame = vec![Color::BLACK; 10];
+        let mut output = vec![Color::BLACK; 10];
+
+        let mut left = segment(0..5);
+        left.handle_command(&command(EFFECT_NONE), now);
+        left.render(&mut frame, &mut output, &gamma, now);
+
+        let red = Color::new(255, 0, 0, 0);
+        assert!(output[..5].iter().all(|pixel| *pixel == red));
+        assert!(output[5..].iter().all(|pixel| *pixel == Color::BLACK));
+    }
+
+    #[test]
+    fn effects() {
+        let now = Instant::now();
+        let gamma = GammaTable::new(1.);
+        let mut frame = vec![Color::BLACK; 10];
+        let mut output = vec![Color::BLACK; 10];
+
+        let mut right = segment(5..10);
+        right.handle_command(&command("Rainbow"), now);
+        assert_eq!(right.effect_name(), "Rainbow");
+        assert!(right.is_animating());
+        right.render(&mut frame, &mut output, &gamma, now);
+        assert!(output[..5].iter().all(|pixel| *pixel == Color::BLACK));
+        assert_ne!(output[5], output[9]);
+
+        // plain effects replace addressable ones
+        right.handle_command(&command("Strobe"), now);
+        assert_eq!(right.effect_name(), "Strobe");
+        right.handle_command(&command(EFFECT_NONE), now);
+        assert!(!right.is_animating());
+    }
+}
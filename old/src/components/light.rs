@@ -1,220 +1,526 @@
-use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use embedded_hal::{digital::v2::OutputPin, PwmPin};
-use esp_idf_sys::EspError;
+use embedded_hal::digital::v2::OutputPin;
 
 use crate::{
     api::{ColorMode, LightCommandRequest, LightStateResponse, ListEntitiesLightResponse},
-    components::{BaseComponent, Component, ComponentUpdate},
-    consts::MessageTypes,
-    utils::{light_color::LightColor, *},
+    components::{
+        entity::{Command, EntityDescription, StatePublisher},
+        output::FloatOutput,
+        BaseComponent, Component,
+    },
+    error::Result,
+    preferences::SharedPreferences,
+    utils::{
+        light_color::{capability, gamma_correct, LightColorValues, LightTraits, DEFAULT_GAMMA},
+        light_engine::{LightEngine, ANIMATION_INTERVAL, EFFECT_NONE},
+    },
 };
 
-enum LightPlatform {
+/// What a light does after boot, like ESPHome's `restore_mode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreMode {
+    AlwaysOff,
+    AlwaysOn,
+    /// Restore the saved state, off if there is none
+    RestoreDefaultOff,
+    /// Restore the saved state, on if there is none
+    RestoreDefaultOn,
+    /// Restore the saved state inverted, off if there is none
+    RestoreInvertedDefaultOff,
+    /// Restore the saved state inverted, on if there is none
+    RestoreInvertedDefaultOn,
+}
+
+impl RestoreMode {
+    /// ESPHome's names, e.g. `RESTORE_DEFAULT_OFF`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ALWAYS_OFF" => Some(RestoreMode::AlwaysOff),
+            "ALWAYS_ON" => Some(RestoreMode::AlwaysOn),
+            "RESTORE_DEFAULT_OFF" => Some(RestoreMode::RestoreDefaultOff),
+            "RESTORE_DEFAULT_ON" => Some(RestoreMode::RestoreDefaultOn),
+            "RESTORE_INVERTED_DEFAULT_OFF" => Some(RestoreMode::RestoreInvertedDefaultOff),
+            "RESTORE_INVERTED_DEFAULT_ON" => Some(RestoreMode::RestoreInvertedDefaultOn),
+            _ => None,
+        }
+    }
+
+    /// Whether the state needs to be saved at all
+    fn persists(&self) -> bool {
+        !matches!(self, RestoreMode::AlwaysOff | RestoreMode::AlwaysOn)
+    }
+
+    /// Values after boot, `saved` is what was saved before, `defaults` are the fallback for everything else
+    fn initial_values(
+        &self,
+        saved: Option<LightColorValues>,
+        defaults: LightColorValues,
+    ) -> LightColorValues {
+        let on = |mut values: LightColorValues, on: bool| {
+            values.state = if on { 1. } else { 0. };
+            values
+        };
+
+        match (self, saved) {
+            (RestoreMode::AlwaysOff, _) => on(defaults, false),
+            (RestoreMode::AlwaysOn, _) => on(defaults, true),
+            (RestoreMode::RestoreDefaultOff | RestoreMode::RestoreDefaultOn, Some(saved)) => saved,
+            (
+                RestoreMode::RestoreInvertedDefaultOff | RestoreMode::RestoreInvertedDefaultOn,
+                Some(saved),
+            ) => on(saved, !saved.is_on()),
+            (RestoreMode::RestoreDefaultOff | RestoreMode::RestoreInvertedDefaultOff, None) => {
+                on(defaults, false)
+            }
+            (RestoreMode::RestoreDefaultOn | RestoreMode::RestoreInvertedDefaultOn, None) => {
+                on(defaults, true)
+            }
+        }
+    }
+}
+
+/// The hardware behind a light, named after ESPHome's light platforms
+///
+/// The board only wires the platforms of the enabled light features.
+#[allow(dead_code)]
+enum LightOutput {
     Binary {
-        pin: Box<dyn OutputPin<Error = EspError>>,
+        pin: Box<dyn OutputPin<Error = esp_idf_sys::EspError> + Send>,
     },
     Monochromatic {
-        pin: Box<dyn PwmPin<Duty = u32>>,
-        brightness: f32,
+        pin: FloatOutput,
+    },
+    Rgb {
+        red: FloatOutput,
+        green: FloatOutput,
+        blue: FloatOutput,
     },
-    RGB {
-        pin_r: Box<dyn PwmPin<Duty = u32>>,
-        pin_g: Box<dyn PwmPin<Duty = u32>>,
-        pin_b: Box<dyn PwmPin<Duty = u32>>,
-        brightness: f32,
-        color: LightColor,
+    Rgbw {
+        red: FloatOutput,
+        green: FloatOutput,
+        blue: FloatOutput,
+        white: FloatOutput,
+    },
+    Rgbww {
+        red: FloatOutput,
+        green: FloatOutput,
+        blue: FloatOutput,
+        cold_white: FloatOutput,
+        warm_white: FloatOutput,
+        constant_brightness: bool,
+    },
+    Cwww {
+        cold_white: FloatOutput,
+        warm_white: FloatOutput,
+        constant_brightness: bool,
+    },
+    ColorTemperature {
+        brightness: FloatOutput,
+        color_temperature: FloatOutput,
+        /// Colour temperatures at `0.0` and `1.0` of the colour temperature output
+        mireds: (f32, f32),
     },
 }
 
+impl LightOutput {
+    /// `gamma` is applied to all brightness channels, but not to the colour temperature
+    fn write(&mut self, values: &LightColorValues, gamma: f32) -> Result<()> {
+        let set = |output: &mut FloatOutput, level: f32| {
+            output.set_level(gamma_correct(level, gamma));
+        };
+
+        match self {
+            LightOutput::Binary { pin } => {
+                if values.as_binary() {
+                    pin.set_high()?;
+                } else {
+                    pin.set_low()?;
+                }
+            }
+            LightOutput::Monochromatic { pin } => set(pin, values.as_brightness()),
+            LightOutput::Rgb { red, green, blue } => {
+                let (r, g, b) = values.as_rgb();
+                set(red, r);
+                set(green, g);
+                set(blue, b);
+            }
+            LightOutput::Rgbw {
+                red,
+                green,
+                blue,
+                white,
+            } => {
+                let (r, g, b, w) = values.as_rgbw();
+                set(red, r);
+                set(green, g);
+                set(blue, b);
+                set(white, w);
+            }
+            LightOutput::Rgbww {
+                red,
+                green,
+                blue,
+                cold_white,
+                warm_white,
+                constant_brightness,
+            } => {
+                let (r, g, b, cw, ww) = values.as_rgbww(*constant_brightness);
+                set(red, r);
+                set(green, g);
+                set(blue, b);
+                set(cold_white, cw);
+                set(warm_white, ww);
+            }
+            LightOutput::Cwww {
+                cold_white,
+                warm_white,
+                constant_brightness,
+            } => {
+                let (cw, ww) = values.as_cwww(*constant_brightness);
+                set(cold_white, cw);
+                set(warm_white, ww);
+            }
+            LightOutput::ColorTemperature {
+                brightness,
+                color_temperature,
+                mireds,
+            } => {
+                let (ct, br) = values.as_ct(*mireds);
+                set(brightness, br);
+                color_temperature.set_level(ct);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub struct Light {
     base: BaseComponent,
-    state: bool,
-    platform: LightPlatform,
+    engine: LightEngine,
+    output: LightOutput,
+    gamma: f32,
+    publisher: StatePublisher,
+
+    restore_mode: RestoreMode,
+    preferences: Option<SharedPreferences>,
 }
 
 impl Light {
-    #[allow(dead_code)]
-    pub fn new_binary(name: String, pin: Box<dyn OutputPin<Error = EspError>>) -> Light {
+    fn new(
+        name: String,
+        output: LightOutput,
+        traits: LightTraits,
+        publisher: StatePublisher,
+    ) -> Light {
+        let base = BaseComponent::new(name, "light");
         Light {
-            base: BaseComponent::new(name),
-            state: false,
-            platform: LightPlatform::Binary { pin },
+            engine: LightEngine::new(traits, base.get_object_id_hash()),
+            base,
+            output,
+            gamma: DEFAULT_GAMMA,
+            publisher,
+            restore_mode: RestoreMode::AlwaysOff,
+            preferences: None,
         }
     }
 
     #[allow(dead_code)]
-    pub fn new_monochromatic(name: String, pin: Box<dyn PwmPin<Duty = u32>>) -> Light {
-        Light {
-            base: BaseComponent::new(name),
-            state: false,
-            platform: LightPlatform::Monochromatic {
-                pin,
-                brightness: 1.,
-            },
-        }
+    pub fn new_binary(
+        name: String,
+        pin: Box<dyn OutputPin<Error = esp_idf_sys::EspError> + Send>,
+        publisher: StatePublisher,
+    ) -> Light {
+        Light::new(
+            name,
+            LightOutput::Binary { pin },
+            LightTraits::new(vec![ColorMode::COLOR_MODE_ON_OFF]),
+            publisher,
+        )
+    }
+
+    pub fn new_monochromatic(name: String, pin: FloatOutput, publisher: StatePublisher) -> Light {
+        Light::new(
+            name,
+            LightOutput::Monochromatic { pin },
+            LightTraits::new(vec![ColorMode::COLOR_MODE_BRIGHTNESS]),
+            publisher,
+        )
     }
 
+    #[allow(dead_code)]
     pub fn new_rgb(
+        name: String,
+        pins: (FloatOutput, FloatOutput, FloatOutput),
+        publisher: StatePublisher,
+    ) -> Light {
+        Light::new(
+            name,
+            LightOutput::Rgb {
+                red: pins.0,
+                green: pins.1,
+                blue: pins.2,
+            },
+            LightTraits::new(vec![ColorMode::COLOR_MODE_RGB]),
+            publisher,
+        )
+    }
+
+    #[allow(dead_code)]
+    pub fn new_rgbw(
+        name: String,
+        pins: (FloatOutput, FloatOutput, FloatOutput, FloatOutput),
+        publisher: StatePublisher,
+    ) -> Light {
+        Light::new(
+            name,
+            LightOutput::Rgbw {
+                red: pins.0,
+                green: pins.1,
+                blue: pins.2,
+                white: pins.3,
+            },
+            LightTraits::new(vec![ColorMode::COLOR_MODE_RGB_WHITE]),
+            publisher,
+        )
+    }
+
+    /// `mireds` are the colour temperatures of the cold and the warm white LEDs
+    #[allow(dead_code)]
+    pub fn new_rgbww(
         name: String,
         pins: (
-            Box<dyn PwmPin<Duty = u32>>,
-            Box<dyn PwmPin<Duty = u32>>,
-            Box<dyn PwmPin<Duty = u32>>,
+            FloatOutput,
+            FloatOutput,
+            FloatOutput,
+            FloatOutput,
+            FloatOutput,
         ),
+        mireds: (f32, f32),
+        constant_brightness: bool,
+        publisher: StatePublisher,
     ) -> Light {
-        Light {
-            base: BaseComponent::new(name),
-            state: false,
-            platform: LightPlatform::RGB {
-                pin_r: pins.0,
-                pin_g: pins.1,
-                pin_b: pins.2,
-                brightness: 1.,
-                color: (1., 1., 1.).into(),
+        Light::new(
+            name,
+            LightOutput::Rgbww {
+                red: pins.0,
+                green: pins.1,
+                blue: pins.2,
+                cold_white: pins.3,
+                warm_white: pins.4,
+                constant_brightness,
             },
-        }
+            LightTraits::new(vec![ColorMode::COLOR_MODE_RGB_COLD_WARM_WHITE])
+                .with_mireds(mireds.0, mireds.1),
+            publisher,
+        )
+    }
+
+    /// `mireds` are the colour temperatures of the cold and the warm white LEDs
+    #[allow(dead_code)]
+    pub fn new_cwww(
+        name: String,
+        pins: (FloatOutput, FloatOutput),
+        mireds: (f32, f32),
+        constant_brightness: bool,
+        publisher: StatePublisher,
+    ) -> Light {
+        Light::new(
+            name,
+            LightOutput::Cwww {
+                cold_white: pins.0,
+                warm_white: pins.1,
+                constant_brightness,
+            },
+            LightTraits::new(vec![ColorMode::COLOR_MODE_COLD_WARM_WHITE])
+                .with_mireds(mireds.0, mireds.1),
+            publisher,
+        )
+    }
+
+    /// For drivers with one brightness and one colour temperature input, `pins` in that order
+    #[allow(dead_code)]
+    pub fn new_color_temperature(
+        name: String,
+        pins: (FloatOutput, FloatOutput),
+        mireds: (f32, f32),
+        publisher: StatePublisher,
+    ) -> Light {
+        Light::new(
+            name,
+            LightOutput::ColorTemperature {
+                brightness: pins.0,
+                color_temperature: pins.1,
+                mireds,
+            },
+            LightTraits::new(vec![ColorMode::COLOR_MODE_COLOR_TEMPERATURE])
+                .with_mireds(mireds.0, mireds.1),
+            publisher,
+        )
+    }
+
+    /// `1.0` disables the gamma correction
+    pub fn with_gamma(mut self, gamma: f32) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    /// Restores the state from before the reboot, changes are saved to `preferences` from now on
+    pub fn with_restore_mode(mut self, mode: RestoreMode, preferences: SharedPreferences) -> Self {
+        let traits = self.engine.traits();
+
+        let saved = if mode.persists() {
+            preferences
+                .lock()
+                .expect("lock poisened!")
+                .load::<LightColorValues>(self.get_key())
+                .filter(|values| traits.supports(values.color_mode))
+        } else {
+            None
+        };
+        let values = mode.initial_values(saved, LightColorValues::new(traits));
+        self.engine.restore(values);
+
+        self.restore_mode = mode;
+        self.preferences = Some(preferences);
+        self
     }
 
     fn get_key(&self) -> u32 {
         self.base.get_object_id_hash()
     }
 
-    fn as_response(&self) -> Vec<ComponentUpdate> {
+    fn as_response(&self) -> LightStateResponse {
         let mut resp = LightStateResponse::new();
         resp.set_key(self.get_key());
-        resp.set_state(self.state);
-        match self.platform {
-            LightPlatform::Binary { .. } => (),
-            LightPlatform::Monochromatic { brightness, .. }
-            | LightPlatform::RGB { brightness, .. } => resp.set_brightness(brightness),
-        }
-        match self.platform {
-            LightPlatform::Binary { .. } | LightPlatform::Monochromatic { .. } => (),
-            LightPlatform::RGB { color, .. } => {
-                resp.set_red(color.get_red());
-                resp.set_green(color.get_green());
-                resp.set_blue(color.get_blue());
-            }
-        }
-        vec![ComponentUpdate::Response((
-            MessageTypes::LightStateResponse,
-            Arc::new(Box::new(resp)),
-        ))]
+        self.engine.remote_values().fill_state_response(&mut resp);
+        resp.set_effect(self.engine.effect_name().to_owned());
+        resp
     }
 
-    fn update_state(&mut self, req: &Box<LightCommandRequest>) {
-        // update state
-        if req.get_has_state() {
-            self.state = req.get_state();
-        }
-
-        // brightness
-        if req.get_has_brightness() {
-            match &mut self.platform {
-                LightPlatform::Binary { .. } => unreachable!("light has no brightness"),
-                LightPlatform::Monochromatic { brightness, .. }
-                | LightPlatform::RGB { brightness, .. } => *brightness = req.get_brightness(),
-            }
-        }
-
-        // update colors
-        if req.get_has_rgb() {
-            match &mut self.platform {
-                LightPlatform::Binary { .. } | LightPlatform::Monochromatic { .. } => {
-                    unreachable!("light has no color")
-                }
-                LightPlatform::RGB { color, .. } => {
-                    color.set_red(req.get_red());
-                    color.set_green(req.get_green());
-                    color.set_blue(req.get_blue());
-                }
-            }
-        }
-
-        // set new values
-        match &mut self.platform {
-            LightPlatform::Binary { pin } => {
-                if self.state {
-                    pin.set_high().unwrap();
-                } else {
-                    pin.set_low().unwrap();
-                }
-            }
-            LightPlatform::Monochromatic { pin, brightness } => {
-                if self.state {
-                    set_pwm(pin, *brightness);
-                } else {
-                    set_pwm(pin, 0.);
-                }
-            }
-            LightPlatform::RGB {
-                pin_r,
-                pin_g,
-                pin_b,
-                brightness,
-                color,
-            } => {
-                let color = if self.state {
-                    color.scale(*brightness)
-                } else {
-                    (0., 0., 0.).into()
-                };
+    fn update_state(&mut self, req: &LightCommandRequest) -> Result<()> {
+        let now = Instant::now();
+        self.engine.apply_command(req, now);
+        self.save(now);
+        self.apply(now)
+    }
 
-                set_pwm(pin_r, color.get_red());
-                set_pwm(pin_g, color.get_green());
-                set_pwm(pin_b, color.get_blue());
-            }
+    /// Writes are coalesced by the preferences, saving after every command is fine
+    fn save(&self, now: Instant) {
+        if let (Some(preferences), true) = (&self.preferences, self.restore_mode.persists()) {
+            preferences.lock().expect("lock poisened!").save(
+                self.get_key(),
+                self.engine.remote_values(),
+                now,
+            );
         }
     }
-}
 
-fn set_pwm(pin: &mut Box<dyn PwmPin<Duty = u32>>, brightness: f32) {
-    let max_duty = pin.get_max_duty();
-    let duty: u32 = (max_duty as f32 * brightness) as u32;
-    pin.set_duty(duty);
+    /// Writes the current (possibly transitioning) state to the hardware
+    fn apply(&mut self, now: Instant) -> Result<()> {
+        let values = self.engine.poll(now);
+        self.output.write(&values, self.gamma)
+    }
 }
 
 impl Component for Light {
-    fn get_description(&self) -> Vec<(MessageTypes, Arc<Box<dyn protobuf::Message>>)> {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        let traits = self.engine.traits();
+
         let mut resp = ListEntitiesLightResponse::new();
-        resp.set_disabled_by_default(false);
-        resp.set_key(self.get_key());
-        resp.set_name(self.base.get_name());
-        resp.set_object_id(self.base.get_object_id());
-        resp.set_unique_id(name_to_unique(&self.base.name, "light"));
-        match self.platform {
-            LightPlatform::Binary { .. } => {
-                resp.set_supported_color_modes(vec![ColorMode::COLOR_MODE_ON_OFF])
-            }
-            LightPlatform::Monochromatic { .. } => {
-                resp.set_supported_color_modes(vec![ColorMode::COLOR_MODE_BRIGHTNESS])
-            }
-            LightPlatform::RGB { .. } => resp.set_supported_color_modes(vec![
-                ColorMode::COLOR_MODE_BRIGHTNESS,
-                ColorMode::COLOR_MODE_RGB,
-            ]),
+        resp.set_supported_color_modes(traits.supported_color_modes.clone());
+        resp.set_legacy_supports_brightness(traits.supports_capability(capability::BRIGHTNESS));
+        resp.set_legacy_supports_rgb(traits.supports_capability(capability::RGB));
+        resp.set_legacy_supports_white_value(traits.supports_capability(capability::WHITE));
+        resp.set_legacy_supports_color_temperature(
+            traits.supports_capability(capability::COLOR_TEMPERATURE)
+                || traits.supports_capability(capability::COLD_WARM_WHITE),
+        );
+        if resp.get_legacy_supports_color_temperature() {
+            resp.set_min_mireds(traits.min_mireds);
+            resp.set_max_mireds(traits.max_mireds);
+        }
+        if !self.engine.effects().is_empty() {
+            let mut effects = vec![EFFECT_NONE.to_owned()];
+            effects.extend(
+                self.engine
+                    .effects()
+                    .iter()
+                    .map(|effect| effect.name().to_owned()),
+            );
+            resp.set_effects(effects.into());
         }
 
-        vec![(
-            MessageTypes::ListEntitiesLightResponse,
-            Arc::new(Box::new(resp)),
-        )]
+        vec![self.base.describe(resp)]
     }
 
-    fn handle_update(&mut self, msg: &ComponentUpdate) -> Vec<ComponentUpdate> {
-        match msg {
-            ComponentUpdate::Request(key) if key.is_none() || key == &Some(self.get_key()) => {
-                return self.as_response();
-            }
-            ComponentUpdate::LightRequest(req) if req.get_key() == self.get_key() => {
-                self.update_state(req);
+    fn setup(&mut self) -> Result<()> {
+        // bring the hardware in line with our (initial) state
+        self.apply(Instant::now())
+    }
 
-                // return vec![ComponentUpdate::LightResponse(self.as_response())];
-                return self.as_response();
-            }
-            _ => {}
+    /// Advances transitions and effects
+    fn update(&mut self) -> Result<()> {
+        self.apply(Instant::now())
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        self.engine.is_animating().then_some(ANIMATION_INTERVAL)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.publisher.publish(self.as_response());
+        Ok(())
+    }
+
+    fn handle_command(&mut self, cmd: &Command) -> Result<()> {
+        if let Command::Light(req) = cmd {
+            self.update_state(req)?;
+            self.publish_state()?;
         }
-        vec![]
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_mode_names() {
+        assert_eq!(
+            RestoreMode::from_name("RESTORE_INVERTED_DEFAULT_ON"),
+            Some(RestoreMode::RestoreInvertedDefaultOn)
+        );
+        assert_eq!(RestoreMode::from_name("restore_default_on"), None);
+        assert!(!RestoreMode::AlwaysOn.persists());
+        assert!(RestoreMode::RestoreDefaultOff.persists());
+    }
+
+    #[test]
+    fn initial_values() {
+        let traits = LightTraits::new(vec![ColorMode::COLOR_MODE_BRIGHTNESS]);
+        let defaults = LightColorValues::new(&traits);
+        let mut saved = defaults;
+        saved.state = 1.;
+        saved.brightness = 0.5;
+
+        let initial = |mode: RestoreMode, saved| mode.initial_values(saved, defaults);
+
+        assert!(!initial(RestoreMode::AlwaysOff, Some(saved)).is_on());
+        assert_eq!(initial(RestoreMode::AlwaysOn, Some(saved)).brightness, 1.);
+        assert!(initial(RestoreMode::AlwaysOn, None).is_on());
+
+        assert_eq!(initial(RestoreMode::RestoreDefaultOff, Some(saved)), saved);
+        assert!(!initial(RestoreMode::RestoreDefaultOff, None).is_on());
+        assert!(initial(RestoreMode::RestoreDefaultOn, None).is_on());
+
+        // inverted keeps everything but the state
+        let inverted = initial(RestoreMode::RestoreInvertedDefaultOn, Some(saved));
+        assert!(!inverted.is_on());
+        assert_eq!(inverted.brightness, 0.5);
+        assert!(!initial(RestoreMode::RestoreInvertedDefaultOff, None).is_on());
+        assert!(initial(RestoreMode::RestoreInvertedDefaultOn, None).is_on());
     }
 }
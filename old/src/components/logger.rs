@@ -1,24 +1,43 @@
-use async_channel::Sender;
+//! Logs to the ESP-IDF console and to every API client that subscribed
+//!
+//! `log()` can be called from any thread and never blocks: lines are formatted ESPHome style
+//! (`[I][tag:line]: message` with ANSI colours) into a fixed size ring buffer, without allocating. The oldest lines
+//! get overwritten. Every API client follows the ring with its own [`LogSubscriber`], a client that subscribes late
+//! still gets the recent (boot) logs and one that can't keep up loses lines instead of holding up everybody else.
+
+use std::{
+    fmt::{self, Write},
+    mem,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+use event_listener::{Event, EventListener};
 use log::{Level, LevelFilter, Log, Metadata, Record};
+use protobuf::ProtobufEnum;
+
+use crate::api::*;
 
-use crate::{api::*, components::ComponentUpdate};
+/// Lines kept for late subscribers
+const LOG_LINES: u32 = 64;
+/// Longer lines are cut, including the colour codes
+const LINE_SIZE: usize = 160;
+const LINE_WORDS: usize = LINE_SIZE / 4;
 
-static mut LOGGER: EspHomeLogger = EspHomeLogger { send: None };
+const COLOR_RESET: &str = "\x1b[0m";
+
+static LOGGER: EspHomeLogger = EspHomeLogger {
+    lines: LogBuffer::new(),
+};
 
 pub struct EspHomeLogger {
-    send: Option<Sender<ComponentUpdate>>,
+    lines: LogBuffer,
 }
 
-unsafe impl Send for EspHomeLogger {}
-unsafe impl Sync for EspHomeLogger {}
-
 impl EspHomeLogger {
     pub fn initialize_default() {
-        unsafe {
-            ::log::set_logger(&LOGGER)
-                .map(|()| LOGGER.initialize())
-                .unwrap();
-        }
+        ::log::set_logger(&LOGGER)
+            .map(|()| LOGGER.initialize())
+            .unwrap();
     }
 
     pub fn initialize(&self) {
@@ -29,13 +48,6 @@ impl EspHomeLogger {
         LevelFilter::Debug
     }
 
-    pub fn set_send(send: Sender<ComponentUpdate>) {
-        unsafe {
-            LOGGER.send = Some(send);
-        }
-    }
-
-    #[allow(dead_code)]
     fn get_marker(level: Level) -> &'static str {
         // static const char *const LOG_LEVEL_LETTERS[] = {
         //     "",    // NONE
@@ -56,27 +68,20 @@ impl EspHomeLogger {
         }
     }
 
-    #[allow(dead_code)]
-    fn get_color(level: Level) -> u8 {
-        // #define ESPHOME_LOG_COLOR_BLACK "30"
-        // #define ESPHOME_LOG_COLOR_RED "31"     // ERROR
-        // #define ESPHOME_LOG_COLOR_GREEN "32"   // INFO
-        // #define ESPHOME_LOG_COLOR_YELLOW "33"  // WARNING
-        // #define ESPHOME_LOG_COLOR_BLUE "34"
-        // #define ESPHOME_LOG_COLOR_MAGENTA "35"  // CONFIG
-        // #define ESPHOME_LOG_COLOR_WHITE "38"
-
+    fn get_color(level: Level) -> &'static str {
+        // #define ESPHOME_LOG_COLOR(COLOR) "\033[0;" COLOR "m"
+        // #define ESPHOME_LOG_BOLD(COLOR) "\033[1;" COLOR "m"
         match level {
-            // #define ESPHOME_LOG_COLOR_CYAN "36"     // DEBUG
-            Level::Debug => 36,
             // #define ESPHOME_LOG_COLOR_RED "31"     // ERROR
-            Level::Error => 31, // LOG_COLOR_RED
+            Level::Error => "\x1b[1;31m",
+            // #define ESPHOME_LOG_COLOR_YELLOW "33"  // WARNING
+            Level::Warn => "\x1b[0;33m",
             // #define ESPHOME_LOG_COLOR_GREEN "32"   // INFO
-            Level::Info => 32, // LOG_COLOR_GREEN,
+            Level::Info => "\x1b[0;32m",
+            // #define ESPHOME_LOG_COLOR_CYAN "36"     // DEBUG
+            Level::Debug => "\x1b[0;36m",
             // #define ESPHOME_LOG_COLOR_GRAY "37"     // VERBOSE
-            Level::Trace => 37,
-            // #define ESPHOME_LOG_COLOR_YELLOW "33"  // WARNING
-            Level::Warn => 33, // LOG_COLOR_BROWN
+            Level::Trace => "\x1b[0;37m",
         }
     }
 }
@@ -87,66 +92,301 @@ impl Log for EspHomeLogger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            // server running?
-            if let Some(send) = &self.send {
-                // let color = match record.level() {
-                //     Level::Debug => "36",
-                //     Level::Error => "31", // LOG_COLOR_RED
-                //     Level::Info => "32",  // LOG_COLOR_GREEN,
-                //     Level::Trace => "37",
-                //     Level::Warn => "33", // LOG_COLOR_BROWN
-                // };
-                // let marker = match record.level() {
-                //     Level::Error => "E",
-                //     Level::Warn => "W",
-                //     Level::Info => "I",
-                //     Level::Debug => "D",
-                //     Level::Trace => "V",
-                // };
-
-                // this results in a stack overflow ...
-                // let output = format!(
-                //     "\x1b[0;{}m[{}] {}: {}\x1b[0m",
-                //     Self::get_color(record.level()),
-                //     Self::get_marker(record.level()),
-                //     record.target(),
-                //     record.args()
-                // );
-                // let output = format!(
-                //     "\x1b[0;{}m[{}] {}\x1b[0m",
-                //     Self::get_color(record.level()),
-                //     Self::get_marker(record.level()),
-                //     record.args()
-                // );
-
-                let output = format!("[{}] {}", Self::get_marker(record.level()), record.args());
-
-                let mut resp = SubscribeLogsResponse::new();
-                resp.set_level(record.level().into());
-                resp.set_message(output);
-
-                smol::block_on(async {
-                    send.send(ComponentUpdate::Log(Box::new(resp)))
-                        .await
-                        .expect("failed to send");
-                    // send.send(ComponentUpdate::Generic((
-                    //     MessageTypes::SubscribeLogsResponse,
-                    //     Arc::new(Box::new(resp)),
-                    // )))
-                    // .await
-                    // .expect("failed to send");
-                })
-            }
-
-            // forward to ESP-IDF
-            esp_idf_svc::log::EspLogger.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
         }
+
+        // the module, like ESPHome's TAG
+        let tag = record.target().rsplit("::").next().unwrap_or_default();
+        let mut line = Line::new();
+        // a full line ends the formatting early, that's fine
+        let _ = write!(
+            line,
+            "{}[{}][{}:{:03}]: {}",
+            Self::get_color(record.level()),
+            Self::get_marker(record.level()),
+            tag,
+            record.line().unwrap_or_default(),
+            record.args()
+        );
+        self.lines.push(record.level().into(), line.finish());
+
+        // forward to ESP-IDF
+        esp_idf_svc::log::EspLogger.log(record);
     }
 
     fn flush(&self) {}
 }
 
+/// Formats into a fixed buffer, cut at a char boundary and always closed by [`COLOR_RESET`]
+struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            buf: [0; LINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        self.buf[self.len..self.len + COLOR_RESET.len()].copy_from_slice(COLOR_RESET.as_bytes());
+        &self.buf[..self.len + COLOR_RESET.len()]
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = LINE_SIZE - COLOR_RESET.len() - self.len;
+        let mut len = s.len().min(room);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+
+        match len == s.len() {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
+
+/// One line of the ring, a seqlock: readers check the stamp before and after copying
+struct Slot {
+    /// `2 * pos + 1` while line `pos` is written, `2 * pos + 2` once it is complete
+    stamp: AtomicU32,
+    /// `2 * pos + 2` when line `pos` was dropped, the slot was still being written one round earlier
+    dropped: AtomicU32,
+    level: AtomicU32,
+    len: AtomicU32,
+    words: [AtomicU32; LINE_WORDS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+impl Slot {
+    const EMPTY: Slot = Slot::new();
+
+    const fn new() -> Self {
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+        Slot {
+            stamp: ZERO,
+            dropped: ZERO,
+            level: ZERO,
+            len: ZERO,
+            words: [ZERO; LINE_WORDS],
+        }
+    }
+}
+
+fn writing(pos: u32) -> u32 {
+    pos.wrapping_mul(2).wrapping_add(1)
+}
+
+fn written(pos: u32) -> u32 {
+    pos.wrapping_mul(2).wrapping_add(2)
+}
+
+#[derive(Debug, PartialEq)]
+enum ReadLine {
+    Line(LogLevel, String),
+    /// Not written yet
+    Pending,
+    /// Overwritten already
+    Lost,
+}
+
+/// Lock-free ring of the latest [`LOG_LINES`] lines, any number of writers and readers
+///
+/// Positions count up forever (wrapping), every reader keeps its own. A writer that finds its slot still being written
+/// one round earlier drops its line and marks it as dropped, readers skip it like an overwritten one.
+pub struct LogBuffer {
+    /// Position of the next line
+    head: AtomicU32,
+    slots: [Slot; LOG_LINES as usize],
+    /// Wakes up the readers
+    event: Event,
+}
+
+impl LogBuffer {
+    pub const fn new() -> Self {
+        LogBuffer {
+            head: AtomicU32::new(0),
+            slots: [Slot::EMPTY; LOG_LINES as usize],
+            event: Event::new(),
+        }
+    }
+
+    pub fn push(&self, level: LogLevel, line: &[u8]) {
+        let pos = self.head.fetch_add(1, Ordering::AcqRel);
+        let slot = &self.slots[(pos % LOG_LINES) as usize];
+
+        let stamp = slot.stamp.load(Ordering::Acquire);
+        if stamp & 1 == 1
+            || slot
+                .stamp
+                .compare_exchange(stamp, writing(pos), Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+        {
+            // the slot isn't ours, only the marker tells the readers not to wait for this line
+            slot.dropped.store(written(pos), Ordering::Release);
+            self.event.notify(usize::MAX);
+            return;
+        }
+        // readers must not see the new line with the old stamp
+        fence(Ordering::Release);
+
+        let line = &line[..line.len().min(LINE_SIZE)];
+        slot.level.store(level.value() as u32, Ordering::Relaxed);
+        slot.len.store(line.len() as u32, Ordering::Relaxed);
+        for (word, chunk) in slot.words.iter().zip(line.chunks(4)) {
+            let mut bytes = [0; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            word.store(u32::from_le_bytes(bytes), Ordering::Relaxed);
+        }
+        slot.stamp.store(written(pos), Ordering::Release);
+
+        self.event.notify(usize::MAX);
+    }
+
+    fn head(&self) -> u32 {
+        self.head.load(Ordering::Acquire)
+    }
+
+    /// Position of the oldest line that is (most likely) still there
+    fn oldest(&self) -> u32 {
+        self.head().saturating_sub(LOG_LINES - 1)
+    }
+
+    fn lapped(&self, pos: u32) -> bool {
+        self.head().wrapping_sub(pos) > LOG_LINES
+    }
+
+    fn available(&self, pos: u32) -> bool {
+        let slot = &self.slots[(pos % LOG_LINES) as usize];
+        self.lapped(pos)
+            || slot.stamp.load(Ordering::Acquire) == written(pos)
+            || slot.dropped.load(Ordering::Acquire) == written(pos)
+    }
+
+    fn read(&self, pos: u32) -> ReadLine {
+        if self.lapped(pos) {
+            return ReadLine::Lost;
+        }
+        let slot = &self.slots[(pos % LOG_LINES) as usize];
+        let stamp = slot.stamp.load(Ordering::Acquire);
+        if stamp != written(pos) {
+            return match slot.dropped.load(Ordering::Acquire) == written(pos) {
+                true => ReadLine::Lost,
+                false => ReadLine::Pending,
+            };
+        }
+
+        let level = slot.level.load(Ordering::Relaxed);
+        let len = (slot.len.load(Ordering::Relaxed) as usize).min(LINE_SIZE);
+        let mut line = Vec::with_capacity(LINE_SIZE);
+        for word in &slot.words {
+            line.extend_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+        }
+        line.truncate(len);
+
+        // a writer came by while copying
+        fence(Ordering::Acquire);
+        if slot.stamp.load(Ordering::Relaxed) != stamp {
+            return ReadLine::Lost;
+        }
+
+        let level = LogLevel::from_i32(level as i32).unwrap_or(LogLevel::LOG_LEVEL_NONE);
+        ReadLine::Line(level, String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Follows the log for one API client
+pub struct LogSubscriber {
+    lines: &'static LogBuffer,
+    cursor: u32,
+    /// `LOG_LEVEL_NONE` until the client subscribed
+    level: LogLevel,
+    /// Lines were lost since the last one that was sent
+    lost: bool,
+}
+
+impl LogSubscriber {
+    pub fn new() -> Self {
+        Self::on(&LOGGER.lines)
+    }
+
+    pub fn on(lines: &'static LogBuffer) -> Self {
+        LogSubscriber {
+            lines,
+            cursor: lines.head(),
+            level: LogLevel::LOG_LEVEL_NONE,
+            lost: false,
+        }
+    }
+
+    /// Starts over with the oldest line that is still buffered
+    pub fn subscribe(&mut self, level: LogLevel) {
+        self.level = level;
+        self.cursor = self.lines.oldest();
+        self.lost = false;
+        // whoever waits in `listen` has the old position
+        self.lines.event.notify(usize::MAX);
+    }
+
+    /// `None` when there is something to read already, otherwise a listener that fires with the next line
+    pub fn listen(&self) -> Option<EventListener> {
+        let listener = self.lines.event.listen();
+        match self.lines.available(self.cursor) {
+            true => None,
+            false => Some(listener),
+        }
+    }
+
+    /// Next line for this client, `None` when it is up to date
+    pub fn next(&mut self) -> Option<SubscribeLogsResponse> {
+        loop {
+            match self.lines.read(self.cursor) {
+                ReadLine::Pending => return None,
+                ReadLine::Lost => {
+                    self.cursor = match self.lines.lapped(self.cursor) {
+                        true => self.lines.oldest(),
+                        false => self.cursor.wrapping_add(1),
+                    };
+                    self.lost = self.level != LogLevel::LOG_LEVEL_NONE;
+                }
+                ReadLine::Line(level, message) => {
+                    self.cursor = self.cursor.wrapping_add(1);
+                    if level == LogLevel::LOG_LEVEL_NONE || level.value() > self.level.value() {
+                        continue;
+                    }
+
+                    let mut resp = SubscribeLogsResponse::new();
+                    resp.set_level(level);
+                    resp.set_message(message);
+                    resp.set_send_failed(mem::take(&mut self.lost));
+                    return Some(resp);
+                }
+            }
+        }
+    }
+}
+
+impl Default for LogSubscriber {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<LogLevel> for LevelFilter {
     fn from(ll: LogLevel) -> Self {
         match ll {
@@ -154,7 +394,8 @@ impl From<LogLevel> for LevelFilter {
             LogLevel::LOG_LEVEL_ERROR => LevelFilter::Error,
             LogLevel::LOG_LEVEL_WARN => LevelFilter::Warn,
             LogLevel::LOG_LEVEL_INFO => LevelFilter::Info,
-            LogLevel::LOG_LEVEL_CONFIG => LevelFilter::Off,
+            // between info and debug, `log` has nothing like it
+            LogLevel::LOG_LEVEL_CONFIG => LevelFilter::Info,
             LogLevel::LOG_LEVEL_DEBUG => LevelFilter::Debug,
             LogLevel::LOG_LEVEL_VERBOSE => LevelFilter::Trace,
             LogLevel::LOG_LEVEL_VERY_VERBOSE => LevelFilter::Trace,
@@ -186,3 +427,177 @@ impl From<Level> for LogLevel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaked() -> &'static LogBuffer {
+        Box::leak(Box::new(LogBuffer::new()))
+    }
+
+    fn push(lines: &LogBuffer, range: std::ops::Range<u32>) {
+        for i in range {
+            lines.push(LogLevel::LOG_LEVEL_INFO, format!("line {i}").as_bytes());
+        }
+    }
+
+    fn line(level: LogLevel, message: &str) -> ReadLine {
+        ReadLine::Line(level, String::from(message))
+    }
+
+    #[test]
+    fn format() {
+        let mut line = Line::new();
+        let (tag, number) = ("wifi", 42);
+        write!(line, "\x1b[0;32m[I][{tag}:{number:03}]: up").unwrap();
+        assert_eq!(line.finish(), b"\x1b[0;32m[I][wifi:042]: up\x1b[0m");
+
+        // cut before the multi byte char that doesn't fit, still closed by the reset
+        let mut line = Line::new();
+        let long = "a".repeat(LINE_SIZE - COLOR_RESET.len() - 1) + "ä";
+        assert!(write!(line, "{long}").is_err());
+        let finished = line.finish();
+        assert_eq!(finished.len(), LINE_SIZE - 1);
+        assert!(finished.ends_with(COLOR_RESET.as_bytes()));
+        assert!(std::str::from_utf8(finished).is_ok());
+    }
+
+    #[test]
+    fn read() {
+        let lines = LogBuffer::new();
+        assert_eq!(lines.read(0), ReadLine::Pending);
+        assert!(!lines.available(0));
+
+        lines.push(LogLevel::LOG_LEVEL_WARN, b"first");
+        lines.push(LogLevel::LOG_LEVEL_DEBUG, "ümlaut".as_bytes());
+        assert!(lines.available(0));
+        assert_eq!(lines.read(0), line(LogLevel::LOG_LEVEL_WARN, "first"));
+        assert_eq!(lines.read(1), line(LogLevel::LOG_LEVEL_DEBUG, "ümlaut"));
+        assert_eq!(lines.read(2), ReadLine::Pending);
+
+        // longer lines are cut
+        lines.push(LogLevel::LOG_LEVEL_INFO, &[b'x'; LINE_SIZE + 10]);
+        assert_eq!(
+            lines.read(2),
+            line(LogLevel::LOG_LEVEL_INFO, &"x".repeat(LINE_SIZE))
+        );
+    }
+
+    #[test]
+    fn overwritten() {
+        let lines = LogBuffer::new();
+        push(&lines, 0..LOG_LINES + 2);
+        assert_eq!(lines.read(0), ReadLine::Lost);
+        assert_eq!(lines.read(1), ReadLine::Lost);
+        // the oldest one still there
+        assert_eq!(lines.oldest(), 3);
+        assert_eq!(lines.read(3), line(LogLevel::LOG_LEVEL_INFO, "line 3"));
+        assert_eq!(
+            lines.read(LOG_LINES + 1),
+            line(LogLevel::LOG_LEVEL_INFO, &format!("line {}", LOG_LINES + 1))
+        );
+    }
+
+    #[test]
+    fn dropped() {
+        let lines = LogBuffer::new();
+        // the writer of line 0 stalls in the middle
+        lines.head.store(1, Ordering::Relaxed);
+        lines.slots[0].stamp.store(writing(0), Ordering::Relaxed);
+        push(&lines, 1..LOG_LINES + 2);
+
+        // its slot is still taken one round later, that line is gone instead of pending forever
+        assert!(lines.available(LOG_LINES));
+        assert_eq!(lines.read(LOG_LINES), ReadLine::Lost);
+        assert_eq!(
+            lines.read(LOG_LINES + 1),
+            line(LogLevel::LOG_LEVEL_INFO, &format!("line {}", LOG_LINES + 1))
+        );
+
+        // the next round writes it again
+        lines.slots[0].stamp.store(written(0), Ordering::Relaxed);
+        push(&lines, LOG_LINES + 2..2 * LOG_LINES + 1);
+        assert_eq!(
+            lines.read(2 * LOG_LINES),
+            line(LogLevel::LOG_LEVEL_INFO, &format!("line {}", 2 * LOG_LINES))
+        );
+    }
+
+    fn messages(subscriber: &mut LogSubscriber) -> Vec<(String, bool)> {
+        std::iter::from_fn(|| subscriber.next())
+            .map(|resp| (resp.get_message().to_owned(), resp.get_send_failed()))
+            .collect()
+    }
+
+    #[test]
+    fn subscribe() {
+        let lines = leaked();
+        lines.push(LogLevel::LOG_LEVEL_INFO, b"boot");
+        lines.push(LogLevel::LOG_LEVEL_DEBUG, b"details");
+
+        // nothing before subscribing
+        let mut subscriber = LogSubscriber::on(lines);
+        assert!(subscriber.listen().is_some());
+        lines.push(LogLevel::LOG_LEVEL_ERROR, b"oops");
+        assert!(subscriber.listen().is_none());
+        assert!(subscriber.next().is_none());
+
+        // the boot logs up to its level
+        subscriber.subscribe(LogLevel::LOG_LEVEL_INFO);
+        assert_eq!(
+            messages(&mut subscriber),
+            [(String::from("boot"), false), (String::from("oops"), false)]
+        );
+        assert!(subscriber.listen().is_some());
+    }
+
+    #[test]
+    fn slow_subscriber() {
+        let lines = leaked();
+        let mut subscriber = LogSubscriber::on(lines);
+        subscriber.subscribe(LogLevel::LOG_LEVEL_INFO);
+        push(lines, 0..LOG_LINES + 10);
+
+        // continues with the oldest line and tells about the loss once
+        let messages = messages(&mut subscriber);
+        assert_eq!(messages.len() as u32, LOG_LINES - 1);
+        assert_eq!(messages[0], (String::from("line 11"), true));
+        assert!(messages[1..].iter().all(|(_, lost)| !lost));
+    }
+
+    #[test]
+    fn dropped_line_subscriber() {
+        let lines = leaked();
+        lines.head.store(1, Ordering::Relaxed);
+        lines.slots[0].stamp.store(writing(0), Ordering::Relaxed);
+        push(lines, 1..LOG_LINES + 2);
+
+        let mut subscriber = LogSubscriber::on(lines);
+        subscriber.subscribe(LogLevel::LOG_LEVEL_INFO);
+        let messages = messages(&mut subscriber);
+        // doesn't wait for the dropped line
+        assert_eq!(
+            messages.last(),
+            Some(&(format!("line {}", LOG_LINES + 1), true))
+        );
+        assert_eq!(messages.len() as u32, LOG_LINES - 2);
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(
+            LevelFilter::from(LogLevel::LOG_LEVEL_CONFIG),
+            LevelFilter::Info
+        );
+        assert_eq!(
+            LevelFilter::from(LogLevel::LOG_LEVEL_NONE),
+            LevelFilter::Off
+        );
+        assert_eq!(
+            LogLevel::from(LevelFilter::Trace),
+            LogLevel::LOG_LEVEL_VERY_VERBOSE
+        );
+        assert_eq!(LogLevel::from(Level::Warn), LogLevel::LOG_LEVEL_WARN);
+    }
+}
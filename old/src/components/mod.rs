@@ -1,54 +1,170 @@
 #[allow(unused_imports)]
 use log::*;
-use std::{net::TcpStream, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    net::TcpStream,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_channel::{Receiver, Sender};
+use async_io::Timer;
+use futures_lite::future;
 
 use esp_idf_hal::prelude::*;
 #[allow(unused_imports)]
 use esp_idf_hal::{
+    gpio::Pin,
     i2c,
-    ledc::{config::TimerConfig, Channel, Timer},
-};
-
-#[cfg(feature = "has_ccs811")]
-use embedded_ccs811::{
-    nb::block, Ccs811AppMode, Ccs811Awake, Ccs811BootMode, MeasurementMode, SlaveAddr,
+    ledc::{config::TimerConfig, Channel, Timer as LedcTimer},
 };
 
 use protobuf::Message;
 
-use crate::{api::*, consts::MessageTypes, utils::*};
+use crate::{
+    consts::MessageTypes,
+    error::{Error, Recovery, Result},
+    preferences::SharedPreferences,
+    utils::*,
+};
+
+use entity::{Command, EntityDescription, EntityInfo, EntityMeta, StatePublisher};
 
-// crate is broken
+// the drivers share the component and configuration
+#[cfg(any(feature = "has_bme280", feature = "has_bme680"))]
+pub mod bme;
 #[cfg(feature = "has_bme280")]
 pub mod bme280;
+#[cfg(feature = "has_bme680")]
+pub mod bme680;
 
-// never worked
+// the drivers share the component
+#[cfg(any(feature = "has_ccs811", feature = "has_sgp30"))]
+pub mod air_quality;
 #[cfg(feature = "has_ccs811")]
 pub mod ccs811;
+#[cfg(feature = "has_sgp30")]
+pub mod sgp30;
+
+// only the sensors use the I²C bus
+#[cfg(any(
+    feature = "has_bme280",
+    feature = "has_bme680",
+    feature = "has_ccs811",
+    feature = "has_sgp30"
+))]
+pub mod i2c_bus;
+// handed from the BME280/BME680 to the gas sensors
+#[cfg(any(
+    feature = "has_bme280",
+    feature = "has_bme680",
+    feature = "has_ccs811",
+    feature = "has_sgp30"
+))]
+pub mod compensation;
 
+#[cfg(feature = "has_led_strip")]
+pub mod led_strip;
+
+#[cfg(feature = "has_adc")]
+pub mod adc;
+// the entity is shared by every sensor component
+#[cfg(any(
+    feature = "has_bme280",
+    feature = "has_bme680",
+    feature = "has_ccs811",
+    feature = "has_sgp30",
+    feature = "has_adc",
+    feature = "has_dallas",
+    feature = "has_pulse_counter"
+))]
+pub mod sensor;
+
+#[cfg(feature = "has_dallas")]
+pub mod dallas;
+#[cfg(feature = "has_dallas")]
+pub mod one_wire;
+
+#[cfg(feature = "has_pulse_counter")]
+pub mod pulse_counter;
+
+// Modbus RTU on UART1
+#[cfg(feature = "has_modbus")]
+pub mod modbus;
+#[cfg(feature = "has_modbus")]
+pub mod uart;
+
+pub mod entity;
+pub mod http_update;
 pub mod light;
 pub mod logger;
+pub mod output;
+
+// all of them take over GPIO19
+#[cfg(any(
+    all(
+        feature = "has_rgbw_light",
+        any(
+            feature = "has_rgbww_light",
+            feature = "has_cwww_light",
+            feature = "has_ct_light"
+        )
+    ),
+    all(
+        feature = "has_rgbww_light",
+        any(feature = "has_cwww_light", feature = "has_ct_light")
+    ),
+    all(feature = "has_cwww_light", feature = "has_ct_light"),
+))]
+compile_error!(
+    "only one of has_rgbw_light, has_rgbww_light, has_cwww_light and has_ct_light can be enabled"
+);
+
+// both need GPIO8
+#[cfg(all(feature = "has_modbus", feature = "has_led_strip"))]
+compile_error!("has_modbus and has_led_strip can't be enabled at the same time");
+
+/// ESPHome's `restore_mode` of all lights, set at build time, e.g. `LIGHT_RESTORE_MODE=ALWAYS_ON`
+const LIGHT_RESTORE_MODE: Option<&str> = option_env!("LIGHT_RESTORE_MODE");
+/// ESPHome's `gamma_correct` of all PWM lights, set at build time, e.g. `LIGHT_GAMMA=1.0` to disable it
+const LIGHT_GAMMA: Option<&str> = option_env!("LIGHT_GAMMA");
+/// Calibration of the red, green and blue output, white balances the RGB LED, set at build time,
+/// e.g. `RGB_CALIBRATION=1.0,0.6,0.8`
+const RGB_CALIBRATION: Option<&str> = option_env!("RGB_CALIBRATION");
+/// Like ESPHome's `inverted` output option for the RGB LED, e.g. `RGB_INVERTED=1` for a common anode one
+const RGB_INVERTED: Option<&str> = option_env!("RGB_INVERTED");
 
 pub struct BaseComponent {
     name: String,
     key: u32,
     object_id: String,
-    // unique_id: String,
+    unique_id: String,
+    meta: EntityMeta,
 }
 
 #[allow(dead_code)]
 impl BaseComponent {
-    pub fn new(name: String) -> Self {
+    /// `component_type` is ESPHome's domain, e.g. "light" or "sensor"
+    pub fn new(name: String, component_type: &str) -> Self {
         let key = name_to_hash(&name);
         let object_id = name_to_object(&name);
+        let unique_id = name_to_unique(&name, component_type);
         BaseComponent {
             key,
             name,
             object_id,
-            // unique_id: (),
+            unique_id,
+            meta: EntityMeta::default(),
         }
     }
 
+    pub fn with_meta(mut self, meta: EntityMeta) -> Self {
+        self.meta = meta;
+        self
+    }
+
     // https://github.com/esphome/esphome/blob/3c0414c42027d8cc3cab8e59c878116f62d8fac7/esphome/core/entity_base.h#L21
     pub fn get_name(&self) -> String {
         self.name.to_owned()
@@ -63,217 +179,827 @@ impl BaseComponent {
     pub fn get_object_id_hash(&self) -> u32 {
         self.key
     }
+
+    pub fn get_unique_id(&self) -> String {
+        self.unique_id.to_owned()
+    }
+
+    pub fn get_meta(&self) -> &EntityMeta {
+        &self.meta
+    }
+
+    /// Completes a `ListEntities*Response` with everything that is common to all entities
+    pub fn describe<D: EntityInfo>(&self, mut msg: D) -> EntityDescription {
+        msg.set_base(self);
+        EntityDescription::new(self.key, msg)
+    }
 }
 
-pub trait Component {
-    fn handle_update(&mut self, msg: &ComponentUpdate) -> Vec<ComponentUpdate>;
+/// A (hardware) component providing one or more entities
+///
+/// Lifecycle: `setup` once, then `update` every `update_interval` and `shutdown` before the device stops.
+/// Components publish their states through the [`StatePublisher`] they are created with, which can happen at any time.
+pub trait Component: Send {
+    /// `ListEntities*Response` for every entity of this component
+    fn get_description(&self) -> Vec<EntityDescription>;
+
+    fn setup(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Periodic work, like ESPHome's `loop()`/`update()`
+    fn update(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// `None` when the component does not need periodic updates
+    fn update_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Publish the current state of all entities, e.g. when a client subscribes
+    fn publish_state(&mut self) -> Result<()>;
+
+    /// Only called with commands addressed to one of our entities
+    fn handle_command(&mut self, _cmd: &Command) -> Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
 
-    fn get_description(&self) -> Vec<(MessageTypes, Arc<Box<dyn Message>>)>;
+    /// Called when an error asks for [`Recovery::RestartComponent`]
+    fn restart(&mut self) -> Result<()> {
+        self.setup()
+    }
 }
 
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub enum ComponentUpdate {
-    /// Request an update of a specific or all components
+    /// Request the state of a specific or all entities
     Request(Option<u32>),
-    /// Send a tick to all modules that can then decide whether to generate an update or not
-    Update,
 
     /// Client is connecting, `Arc` is required for `Clone`, thoguh is should not be used
     Connection(Arc<smol::Async<TcpStream>>),
     /// Client is closing the connection
     Closing,
+    /// Server should disconnect all clients and stop
+    Shutdown,
 
-    /// Component related values
-    LightRequest(Box<LightCommandRequest>),
+    /// Command for a single entity
+    Command(Command),
 
     Response((MessageTypes, Arc<Box<dyn Message>>)),
+}
 
-    /// Value to log (send to client)
-    Log(Box<SubscribeLogsResponse>),
+/// Drivers (and the float maths of some) need more than the default pthread stack
+const STACK_SIZE: usize = 8 * 1024;
+
+struct ComponentSlot {
+    component: Box<dyn Component>,
+    next_update: Option<Instant>,
 }
 
 /// Owner and manager of all (hardware) components
 ///
-/// Takes care of forwaring incoming requests as well as regularly ticks all components.
+/// Runs on its own thread, takes care of the component lifecycle, schedules updates and routes commands by entity key.
+/// Drivers block (I2C transfers, conversion delays, UART reads), this keeps them away from the executor serving the
+/// API clients.
 pub struct ComponentManager {
-    components: Vec<Box<dyn Component>>,
+    components: Vec<ComponentSlot>,
+    /// entity key -> index into `components`
+    keys: HashMap<u32, usize>,
+    /// Flushed in between updates and once all components are shut down
+    preferences: SharedPreferences,
 }
 
-/// Small helper for getting a GPIO as output
+/// Small helper for getting a GPIO as output, evaluates to a `Result`
 macro_rules! gpio_out {
     ($peripherals:expr, $gpio:ident) => {
-        $peripherals
-            .pins
-            .$gpio
-            .into_output()
-            .expect("failed to acquire pin")
+        $peripherals.pins.$gpio.into_output()
     };
 }
 
-/// Small helper for getting a GPIO as input_output
-#[allow(unused_macros)]
+/// Small helper for getting a GPIO as input_output, evaluates to a `Result`
+#[cfg(any(
+    feature = "has_bme280",
+    feature = "has_bme680",
+    feature = "has_ccs811",
+    feature = "has_sgp30",
+    feature = "has_modbus"
+))]
 macro_rules! gpio_in_out {
     ($peripherals:expr, $gpio:ident) => {
-        $peripherals
-            .pins
-            .$gpio
-            .into_input_output()
-            .expect("failed to acquire pin")
+        $peripherals.pins.$gpio.into_input_output()
     };
 }
 
 #[allow(unused_macros)]
 macro_rules! make_light_binbary {
-    ($name: expr, $peripherals:expr, $gpio:ident, $components:expr) => {
-        // get pin, boxed
-        let pin = Box::new(gpio_out!($peripherals, $gpio));
-        // create light, boxed
-        let light = Box::new(light::Light::new_binary($name, pin));
-        // add to components
-        $components.push(light);
+    ($name: expr, $peripherals:expr, $gpio:ident, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
+        match gpio_out!($peripherals, $gpio) {
+            Ok(pin) => {
+                // create light, boxed
+                let light = light::Light::new_binary(name, Box::new(pin), $publisher.clone())
+                    .with_restore_mode($restore_mode, $preferences.clone());
+                // add to components
+                $components.push(Box::new(light));
+            }
+            Err(err) => error!("failed to setup {}: {}", name, Error::from(err)),
+        }
     };
 }
 
-macro_rules! ledc_channel {
-    ($peripherals:expr, $gpio:ident, $channel:ident, $timer: expr) => {
-        Channel::new(
-            $peripherals.ledc.$channel,
-            $timer.clone(),
-            gpio_out!($peripherals, $gpio),
-        )
-        .expect("failed to setup chhannel")
+/// LEDC channel wrapped into a [`output::FloatOutput`], evaluates to a `Result`
+macro_rules! pwm_output {
+    ($peripherals:expr, $gpio:ident, $channel:ident, $timer: expr, $config: expr) => {
+        gpio_out!($peripherals, $gpio)
+            .and_then(|pin| Channel::new($peripherals.ledc.$channel, $timer.clone(), pin))
+            .map(|channel| output::FloatOutput::new(Box::new(channel), $config))
     };
 }
 
-#[allow(unused_macros)]
 macro_rules! make_light_monochromatic {
-    ($name: expr, $peripherals:expr, $gpio:ident, $channel:ident, $timer: expr, $components:expr) => {
-        let channel = Box::new(ledc_channel!($peripherals, $gpio, $channel, $timer));
-        // create light, boxed
-        let light = Box::new(light::Light::new_monochromatic($name, channel));
-        // add to components
-        $components.push(light);
+    ($name: expr, $peripherals:expr, $gpio:ident, $channel:ident, $timer: expr, $config: expr, $gamma: expr, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
+        match pwm_output!($peripherals, $gpio, $channel, $timer, $config) {
+            Ok(channel) => {
+                // create light, boxed
+                let light = light::Light::new_monochromatic(name, channel, $publisher.clone())
+                    .with_gamma($gamma)
+                    .with_restore_mode($restore_mode, $preferences.clone());
+                // add to components
+                $components.push(Box::new(light));
+            }
+            Err(err) => error!("failed to setup {}: {}", name, Error::from(err)),
+        }
     };
 }
 
-#[allow(unused_macros)]
+#[cfg(not(any(feature = "has_rgbw_light", feature = "has_rgbww_light")))]
 macro_rules! make_light_rgb {
-    ($name: expr, $peripherals:expr, $gpio_r:ident, $gpio_g:ident, $gpio_b:ident, $channel_r:ident, $channel_g:ident, $channel_b:ident, $timer: expr, $components:expr) => {
+    ($name: expr, $peripherals:expr, ($gpio_r:ident, $gpio_g:ident, $gpio_b:ident), ($channel_r:ident, $channel_g:ident, $channel_b:ident), $timer: expr, ($config_r: expr, $config_g: expr, $config_b: expr), $gamma: expr, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
         // get channels
-        let channel_r = Box::new(ledc_channel!($peripherals, $gpio_r, $channel_r, $timer));
-        let channel_g = Box::new(ledc_channel!($peripherals, $gpio_g, $channel_g, $timer));
-        let channel_b = Box::new(ledc_channel!($peripherals, $gpio_b, $channel_b, $timer));
-
-        // create light, boxed
-        let light = Box::new(light::Light::new_rgb(
-            $name,
-            (channel_r, channel_g, channel_b),
-        ));
-        // add to components
-        $components.push(light);
+        match (
+            pwm_output!($peripherals, $gpio_r, $channel_r, $timer, $config_r),
+            pwm_output!($peripherals, $gpio_g, $channel_g, $timer, $config_g),
+            pwm_output!($peripherals, $gpio_b, $channel_b, $timer, $config_b),
+        ) {
+            (Ok(channel_r), Ok(channel_g), Ok(channel_b)) => {
+                // create light, boxed
+                let light = light::Light::new_rgb(
+                    name,
+                    (channel_r, channel_g, channel_b),
+                    $publisher.clone(),
+                )
+                .with_gamma($gamma)
+                .with_restore_mode($restore_mode, $preferences.clone());
+                // add to components
+                $components.push(Box::new(light));
+            }
+            (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+                error!("failed to setup {}: {}", name, Error::from(err))
+            }
+        }
+    };
+}
+
+#[cfg(feature = "has_rgbw_light")]
+macro_rules! make_light_rgbw {
+    ($name: expr, $peripherals:expr, ($gpio_r:ident, $gpio_g:ident, $gpio_b:ident, $gpio_w:ident), ($channel_r:ident, $channel_g:ident, $channel_b:ident, $channel_w:ident), $timer: expr, ($config_r: expr, $config_g: expr, $config_b: expr, $config_w: expr), $gamma: expr, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
+        match (
+            pwm_output!($peripherals, $gpio_r, $channel_r, $timer, $config_r),
+            pwm_output!($peripherals, $gpio_g, $channel_g, $timer, $config_g),
+            pwm_output!($peripherals, $gpio_b, $channel_b, $timer, $config_b),
+            pwm_output!($peripherals, $gpio_w, $channel_w, $timer, $config_w),
+        ) {
+            (Ok(red), Ok(green), Ok(blue), Ok(white)) => {
+                let light =
+                    light::Light::new_rgbw(name, (red, green, blue, white), $publisher.clone())
+                        .with_gamma($gamma)
+                        .with_restore_mode($restore_mode, $preferences.clone());
+                $components.push(Box::new(light));
+            }
+            (Err(err), _, _, _)
+            | (_, Err(err), _, _)
+            | (_, _, Err(err), _)
+            | (_, _, _, Err(err)) => {
+                error!("failed to setup {}: {}", name, Error::from(err))
+            }
+        }
+    };
+}
+
+/// `$mireds` are the colour temperatures of the cold and the warm white LEDs
+#[cfg(feature = "has_rgbww_light")]
+macro_rules! make_light_rgbww {
+    ($name: expr, $peripherals:expr, ($gpio_r:ident, $gpio_g:ident, $gpio_b:ident, $gpio_cw:ident, $gpio_ww:ident), ($channel_r:ident, $channel_g:ident, $channel_b:ident, $channel_cw:ident, $channel_ww:ident), $timer: expr, ($config_r: expr, $config_g: expr, $config_b: expr, $config_cw: expr, $config_ww: expr), $mireds: expr, $gamma: expr, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
+        match (
+            pwm_output!($peripherals, $gpio_r, $channel_r, $timer, $config_r),
+            pwm_output!($peripherals, $gpio_g, $channel_g, $timer, $config_g),
+            pwm_output!($peripherals, $gpio_b, $channel_b, $timer, $config_b),
+            pwm_output!($peripherals, $gpio_cw, $channel_cw, $timer, $config_cw),
+            pwm_output!($peripherals, $gpio_ww, $channel_ww, $timer, $config_ww),
+        ) {
+            (Ok(red), Ok(green), Ok(blue), Ok(cold_white), Ok(warm_white)) => {
+                let light = light::Light::new_rgbww(
+                    name,
+                    (red, green, blue, cold_white, warm_white),
+                    $mireds,
+                    true,
+                    $publisher.clone(),
+                )
+                .with_gamma($gamma)
+                .with_restore_mode($restore_mode, $preferences.clone());
+                $components.push(Box::new(light));
+            }
+            (Err(err), _, _, _, _)
+            | (_, Err(err), _, _, _)
+            | (_, _, Err(err), _, _)
+            | (_, _, _, Err(err), _)
+            | (_, _, _, _, Err(err)) => {
+                error!("failed to setup {}: {}", name, Error::from(err))
+            }
+        }
+    };
+}
+
+/// `$mireds` are the colour temperatures of the cold and the warm white LEDs
+#[cfg(feature = "has_cwww_light")]
+macro_rules! make_light_cwww {
+    ($name: expr, $peripherals:expr, ($gpio_cw:ident, $gpio_ww:ident), ($channel_cw:ident, $channel_ww:ident), $timer: expr, ($config_cw: expr, $config_ww: expr), $mireds: expr, $gamma: expr, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
+        match (
+            pwm_output!($peripherals, $gpio_cw, $channel_cw, $timer, $config_cw),
+            pwm_output!($peripherals, $gpio_ww, $channel_ww, $timer, $config_ww),
+        ) {
+            (Ok(cold_white), Ok(warm_white)) => {
+                let light = light::Light::new_cwww(
+                    name,
+                    (cold_white, warm_white),
+                    $mireds,
+                    true,
+                    $publisher.clone(),
+                )
+                .with_gamma($gamma)
+                .with_restore_mode($restore_mode, $preferences.clone());
+                $components.push(Box::new(light));
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                error!("failed to setup {}: {}", name, Error::from(err))
+            }
+        }
+    };
+}
+
+/// `$mireds` are the colour temperatures at `0.0` and `1.0` of the colour temperature output,
+/// the gamma is not applied to it
+#[cfg(feature = "has_ct_light")]
+macro_rules! make_light_ct {
+    ($name: expr, $peripherals:expr, ($gpio_br:ident, $gpio_ct:ident), ($channel_br:ident, $channel_ct:ident), $timer: expr, ($config_br: expr, $config_ct: expr), $mireds: expr, $gamma: expr, $components:expr, $publisher:expr, $preferences:expr, $restore_mode:expr) => {
+        let name = $name;
+        match (
+            pwm_output!($peripherals, $gpio_br, $channel_br, $timer, $config_br),
+            pwm_output!($peripherals, $gpio_ct, $channel_ct, $timer, $config_ct),
+        ) {
+            (Ok(brightness), Ok(color_temperature)) => {
+                let light = light::Light::new_color_temperature(
+                    name,
+                    (brightness, color_temperature),
+                    $mireds,
+                    $publisher.clone(),
+                )
+                .with_gamma($gamma)
+                .with_restore_mode($restore_mode, $preferences.clone());
+                $components.push(Box::new(light));
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                error!("failed to setup {}: {}", name, Error::from(err))
+            }
+        }
     };
 }
 
 impl ComponentManager {
-    pub fn new() -> ComponentManager {
+    pub fn new(publisher: StatePublisher, preferences: SharedPreferences) -> ComponentManager {
         // Preripherals live here
-        let peripherals =
-            esp_idf_hal::peripherals::Peripherals::take().expect("Failed to obtain Peripherals");
+        let peripherals = match esp_idf_hal::peripherals::Peripherals::take() {
+            Some(peripherals) => peripherals,
+            None => {
+                error!("peripherals are already taken, running without components");
+                return Self::with_components(vec![], preferences);
+            }
+        };
         // time for whoever needs it
-        let timer = Arc::new(
-            Timer::new(
-                peripherals.ledc.timer0,
-                &TimerConfig::default().frequency(25.kHz().into()),
-            )
-            .expect("failed to setup timer"),
-        );
+        let timer = match LedcTimer::new(
+            peripherals.ledc.timer0,
+            &TimerConfig::default().frequency(25.kHz().into()),
+        ) {
+            Ok(timer) => Some(Arc::new(timer)),
+            Err(err) => {
+                error!("failed to setup LEDC timer: {}", Error::from(err));
+                None
+            }
+        };
+
+        // what the lights do after boot
+        let restore_mode = match LIGHT_RESTORE_MODE.map(light::RestoreMode::from_name) {
+            Some(Some(mode)) => mode,
+            Some(None) => {
+                error!("unknown light restore mode {:?}", LIGHT_RESTORE_MODE);
+                light::RestoreMode::RestoreDefaultOff
+            }
+            None => light::RestoreMode::RestoreDefaultOff,
+        };
+        let gamma =
+            match LIGHT_GAMMA.map(|gamma| gamma.parse::<f32>().ok().filter(|gamma| *gamma > 0.)) {
+                Some(Some(gamma)) => gamma,
+                Some(None) => {
+                    error!("invalid light gamma {:?}", LIGHT_GAMMA);
+                    light_color::DEFAULT_GAMMA
+                }
+                None => light_color::DEFAULT_GAMMA,
+            };
+        // red, green and blue output of the RGB LED
+        let rgb_config = {
+            let calibration = match RGB_CALIBRATION.map(|calibration| {
+                calibration
+                    .split(',')
+                    .map(|channel| channel.trim().parse::<f32>().ok())
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|channels| <[f32; 3]>::try_from(channels).ok())
+            }) {
+                Some(Some(calibration)) => calibration,
+                Some(None) => {
+                    error!("invalid RGB calibration {:?}", RGB_CALIBRATION);
+                    [1.; 3]
+                }
+                None => [1.; 3],
+            };
+            calibration.map(|calibration| {
+                output::OutputConfig::default()
+                    .calibration(calibration)
+                    .inverted(RGB_INVERTED.is_some())
+            })
+        };
 
         let mut components: Vec<Box<dyn Component>> = vec![];
 
         // #######################################
         // # I2C - GPIO0 + GPIO2
         // #######################################
-        let pins = i2c::MasterPins {
-            scl: gpio_in_out!(peripherals, gpio0),
-            sda: gpio_in_out!(peripherals, gpio2),
+        #[cfg(any(
+            feature = "has_bme280",
+            feature = "has_bme680",
+            feature = "has_ccs811",
+            feature = "has_sgp30"
+        ))]
+        let i2c_master = match (
+            gpio_in_out!(peripherals, gpio0),
+            gpio_in_out!(peripherals, gpio2),
+        ) {
+            (Ok(scl), Ok(sda)) => i2c::Master::new(
+                peripherals.i2c0,
+                i2c::MasterPins { scl, sda },
+                i2c::config::MasterConfig::default(),
+            ),
+            (Err(err), _) | (_, Err(err)) => Err(err),
         };
-        let config = i2c::config::MasterConfig::default();
+        // shared by all sensors below, each one gets its own device handle
         #[allow(unused_variables)]
-        // cannot be shared at the moment
-        let i2c_bus =
-            i2c::Master::new(peripherals.i2c0, pins, config).expect("failed to aquire i2c");
+        #[cfg(any(
+            feature = "has_bme280",
+            feature = "has_bme680",
+            feature = "has_ccs811",
+            feature = "has_sgp30"
+        ))]
+        let i2c_bus = match i2c_master {
+            Ok(master) => {
+                let i2c_bus = i2c_bus::I2cBus::new(master);
+                let found = i2c_bus.scan();
+                if found.is_empty() {
+                    warn!("found no i2c devices");
+                }
+                for addr in found {
+                    info!("found i2c device at address 0x{:02x}", addr);
+                }
+                Some(i2c_bus)
+            }
+            Err(err) => {
+                error!("failed to setup I2C: {}", Error::from(err));
+                None
+            }
+        };
+        // temperature and humidity for the gas sensors
+        #[cfg(any(
+            feature = "has_bme280",
+            feature = "has_bme680",
+            feature = "has_ccs811",
+            feature = "has_sgp30"
+        ))]
+        let compensation = compensation::Compensation::new();
+
+        // like ESPHome's `oversampling` and `iir_filter` options, set at build time,
+        // e.g. `BME_HUMIDITY_OVERSAMPLING=NONE` or `BME_IIR_FILTER=4x`
+        #[cfg(any(feature = "has_bme280", feature = "has_bme680"))]
+        let bme_config = {
+            const TEMPERATURE: Option<&str> = option_env!("BME_TEMPERATURE_OVERSAMPLING");
+            const PRESSURE: Option<&str> = option_env!("BME_PRESSURE_OVERSAMPLING");
+            const HUMIDITY: Option<&str> = option_env!("BME_HUMIDITY_OVERSAMPLING");
+            const IIR_FILTER: Option<&str> = option_env!("BME_IIR_FILTER");
+
+            let oversampling =
+                |name: Option<&str>, default| match name.map(bme::Oversampling::from_name) {
+                    Some(Some(oversampling)) => oversampling,
+                    Some(None) => {
+                        error!("unknown BME oversampling {:?}", name);
+                        default
+                    }
+                    None => default,
+                };
+            let config = bme::Config::default();
+            let iir_filter = match IIR_FILTER.map(bme::IirFilter::from_name) {
+                Some(Some(iir_filter)) => iir_filter,
+                Some(None) => {
+                    error!("unknown BME IIR filter {:?}", IIR_FILTER);
+                    config.iir_filter
+                }
+                None => config.iir_filter,
+            };
+            config
+                .temperature(oversampling(TEMPERATURE, config.temperature))
+                .pressure(oversampling(PRESSURE, config.pressure))
+                .humidity(oversampling(HUMIDITY, config.humidity))
+                .iir_filter(iir_filter)
+        };
 
         // #######################################
-        // # BME280
+        // # BME280 / BMP280
         // #######################################
         #[cfg(feature = "has_bme280")]
+        if let Some(i2c_bus) = &i2c_bus {
+            use bme::BmeSensor;
+
+            // the chip ID is checked first, a BME680 on the other address is left alone
+            let new = |address| {
+                bme280::Bme280Driver::new(i2c_bus.device(), ThreadDelay, address, bme_config)
+            };
+            match new(bme::ADDRESS_PRIMARY).or_else(|_| new(bme::ADDRESS_SECONDARY)) {
+                Ok(driver) => {
+                    info!("{} initialized", driver.chip_name());
+                    let bme280 = bme::Bme::new(driver, "Rusty old BME280", publisher.clone())
+                        .with_compensation(compensation.clone());
+                    components.push(Box::new(bme280));
+                }
+                Err(err) => info!("BME280 failed to initialize: {}", err),
+            }
+        }
+
+        // #######################################
+        // # BME680
+        // #######################################
+        #[cfg(feature = "has_bme680")]
+        if let Some(i2c_bus) = &i2c_bus {
+            let new = |address| {
+                bme680::Bme680Driver::new(
+                    i2c_bus.device(),
+                    ThreadDelay,
+                    address,
+                    bme_config,
+                    Some(bme680::GasHeater::default()),
+                )
+            };
+            match new(bme::ADDRESS_SECONDARY).or_else(|_| new(bme::ADDRESS_PRIMARY)) {
+                Ok(driver) => {
+                    info!("BME680 initialized");
+                    let bme680 = bme::Bme::new(driver, "Rusty old BME680", publisher.clone())
+                        .with_compensation(compensation.clone());
+                    components.push(Box::new(bme680));
+                }
+                Err(err) => info!("BME680 failed to initialize: {}", err),
+            }
+        }
+
+        // #######################################
+        // # CCS811
+        // #######################################
+        #[cfg(feature = "has_ccs811")]
+        if let Some(i2c_bus) = &i2c_bus {
+            let new = |address| ccs811::Ccs811Driver::new(i2c_bus.device(), ThreadDelay, address);
+            match new(ccs811::ADDRESS_PRIMARY).or_else(|_| new(ccs811::ADDRESS_SECONDARY)) {
+                Ok(driver) => {
+                    info!("CCS811 initialized");
+                    let ccs811 = air_quality::AirQuality::new(
+                        driver,
+                        "Rusty old CCS811",
+                        preferences.clone(),
+                        publisher.clone(),
+                    )
+                    .with_compensation(compensation.clone());
+                    components.push(Box::new(ccs811));
+                }
+                Err(err) => info!("CCS811 failed to initialize: {}", err),
+            }
+        }
+
+        // #######################################
+        // # SGP30
+        // #######################################
+        #[cfg(feature = "has_sgp30")]
+        if let Some(i2c_bus) = &i2c_bus {
+            match sgp30::Sgp30Driver::new(i2c_bus.device(), ThreadDelay, sgp30::ADDRESS) {
+                Ok(driver) => {
+                    info!("SGP30 initialized, serial {:012x}", driver.serial());
+                    let sgp30 = air_quality::AirQuality::new(
+                        driver,
+                        "Rusty old SGP30",
+                        preferences.clone(),
+                        publisher.clone(),
+                    )
+                    .with_compensation(compensation.clone());
+                    components.push(Box::new(sgp30));
+                }
+                Err(err) => info!("SGP30 failed to initialize: {}", err),
+            }
+        }
+
+        // #######################################
+        // # ADC - GPIO1
+        // #######################################
+        #[cfg(feature = "has_adc")]
         {
-            // initialize the BME280 using the primary I2C address 0x76
-            let mut bme280 = ::bme280::BME280::new_primary(i2c_bus, esp_idf_hal::delay::Ets);
-            match bme280.init() {
-                Ok(()) => {
-                    info!("BME280 initialized");
-
-                    match bme280.measure() {
-                        Ok(mes) => {
-                            info!("measured {:.1}°C", mes.temperature);
-                            info!("measured {:.0}hPa", mes.pressure / 100.);
-                            info!("measured {:.2}%", mes.humidity);
-
-                            let bme280 = bme280::Bme280::new(bme280);
-                            let bme280 = Box::new(bme280);
-                            components.push(bme280);
-                        }
-                        Err(err) => {
-                            info!("failed to measure: {:?}", err);
-                            match err {
-                                ::bme280::Error::CompensationFailed
-                                | ::bme280::Error::InvalidData
-                                | ::bme280::Error::NoCalibrationData
-                                | ::bme280::Error::UnsupportedChip => {}
-                                ::bme280::Error::I2c(err) => info!("I2c error: {}", err),
+            const NAME: &str = "Rusty old Battery";
+            // like ESPHome's `raw` option, set at build time, e.g. `ADC_RAW=1` for a soil moisture sensor
+            const RAW: Option<&str> = option_env!("ADC_RAW");
+
+            // a LiPo behind a 1:1 voltage divider
+            match adc::AdcDriver::new(
+                esp_idf_sys::adc1_channel_t_ADC1_CHANNEL_1,
+                adc::Attenuation::for_voltage(4.2 / 2.),
+            ) {
+                Ok(driver) => {
+                    info!("ADC calibration: {:?}", driver.calibration());
+                    let driver = driver.with_samples(16);
+                    let battery = match RAW {
+                        Some(_) => sensor::Sensor::new(
+                            driver.with_output(adc::Output::Raw),
+                            NAME.to_owned() + " " + "Raw",
+                            publisher.clone(),
+                        )
+                        .with_unit("", 0),
+                        None => sensor::Sensor::new(
+                            driver.with_output(adc::Output::Voltage),
+                            NAME.to_owned() + " " + "Voltage",
+                            publisher.clone(),
+                        )
+                        .with_meta(EntityMeta::default().device_class("voltage"))
+                        .with_unit("V", 2)
+                        .with_filter(sensor::Filter::Multiply(2.)),
+                    };
+                    components.push(Box::new(battery));
+                }
+                Err(err) => error!("failed to setup ADC: {}", err),
+            }
+        }
+
+        // #######################################
+        // # 1-Wire - GPIO6
+        // #######################################
+        #[cfg(feature = "has_dallas")]
+        {
+            use one_wire::OneWire;
+
+            const NAME: &str = "Rusty old Temperature";
+            // like ESPHome's `resolution` option, set at build time, e.g. `DALLAS_RESOLUTION=10`
+            const RESOLUTION: Option<&str> = option_env!("DALLAS_RESOLUTION");
+
+            let resolution = match RESOLUTION
+                .map(|bits| bits.parse().ok().and_then(dallas::Resolution::from_bits))
+            {
+                Some(Some(resolution)) => resolution,
+                Some(None) => {
+                    error!("unknown Dallas resolution {:?}", RESOLUTION);
+                    dallas::Resolution::Bits12
+                }
+                None => dallas::Resolution::Bits12,
+            };
+
+            let bus = peripherals
+                .pins
+                .gpio6
+                .into_input_output_od()
+                .and_then(one_wire::BitBang::new);
+            match bus {
+                Ok(mut bus) => match bus.search() {
+                    Ok(found) => {
+                        let mut sensors = vec![];
+                        for address in found {
+                            info!("found 1-Wire device {}", address);
+                            if dallas::is_supported(&address) {
+                                sensors.push((address, format!("{} {}", NAME, address)));
                             }
                         }
+                        if !sensors.is_empty() {
+                            let dallas =
+                                dallas::Dallas::new(bus, resolution, sensors, publisher.clone());
+                            components.push(Box::new(dallas));
+                        }
                     }
+                    Err(err) => error!("1-Wire search failed: {}", err),
+                },
+                Err(err) => error!("failed to setup 1-Wire: {}", Error::from(err)),
+            }
+        }
+
+        // #######################################
+        // # Pulse counter - GPIO7
+        // #######################################
+        #[cfg(feature = "has_pulse_counter")]
+        {
+            const NAME: &str = "Rusty old Water";
+            // like ESPHome's `pulse_meter` instead of `pulse_counter` and its `count_mode`, set at build time,
+            // e.g. `PULSE_METER=1` or `PULSE_RISING_EDGE=1`
+            const METER: Option<&str> = option_env!("PULSE_METER");
+            const RISING_EDGE: Option<&str> = option_env!("PULSE_RISING_EDGE");
+
+            let mode = match METER {
+                Some(_) => pulse::RateMode::Meter {
+                    timeout: pulse_counter::DEFAULT_METER_TIMEOUT,
+                },
+                None => pulse::RateMode::Counter,
+            };
+            // by default a reed contact to ground, one pulse per litre
+            let edge = match RISING_EDGE {
+                Some(_) => pulse_counter::Edge::Rising,
+                None => pulse_counter::Edge::Falling,
+            };
+            match pulse_counter::PcntUnit::new(
+                esp_idf_sys::pcnt_unit_t_PCNT_UNIT_0,
+                7,
+                edge,
+                Duration::from_micros(10),
+                Duration::from_millis(50),
+            ) {
+                Ok(unit) => {
+                    let water = pulse_counter::PulseCounter::new(
+                        unit,
+                        NAME,
+                        mode,
+                        preferences.clone(),
+                        publisher.clone(),
+                    )
+                    .with_rate("L/min", 1., 0)
+                    .with_total("m³", 0.001, 3);
+                    components.push(Box::new(water));
                 }
-                Err(err) => {
-                    info!("BME280 failed to initialized: {:?}", err);
-                    match err {
-                        ::bme280::Error::CompensationFailed
-                        | ::bme280::Error::InvalidData
-                        | ::bme280::Error::NoCalibrationData
-                        | ::bme280::Error::UnsupportedChip => {}
-                        ::bme280::Error::I2c(err) => info!("I2c error: {}", err),
-                    }
-                }
+                Err(err) => error!("failed to setup PCNT: {}", err),
             }
         }
 
         // #######################################
-        // # CCS811
+        // # Modbus RTU - GPIO10 (TX) + GPIO8 (RX)
         // #######################################
-        #[cfg(feature = "has_ccs811")]
+        #[cfg(feature = "has_modbus")]
         {
-            let ccs811 = Ccs811Awake::new(i2c_bus, SlaveAddr::Default);
-            let mut ccs811 = ccs811.start_application().ok().unwrap();
-            ccs811.set_mode(MeasurementMode::ConstantPower1s).unwrap();
+            const NAME: &str = "Rusty old Heat Pump";
+            const SLAVE: u8 = 1;
+            // like ESPHome's `uart` options, set at build time, e.g. `MODBUS_BAUD_RATE=19200`, `MODBUS_PARITY=NONE`
+            // or `MODBUS_STOP_BITS=2`
+            const BAUD_RATE: Option<&str> = option_env!("MODBUS_BAUD_RATE");
+            const PARITY: Option<&str> = option_env!("MODBUS_PARITY");
+            const STOP_BITS: Option<&str> = option_env!("MODBUS_STOP_BITS");
 
-            // std::thread::sleep(Duration::from_millis(100));
+            let baud_rate = match BAUD_RATE.map(|baud_rate| baud_rate.parse().ok()) {
+                Some(Some(baud_rate)) => baud_rate,
+                Some(None) => {
+                    error!("invalid Modbus baud rate {:?}", BAUD_RATE);
+                    9600
+                }
+                None => 9600,
+            };
+            let parity = match PARITY.map(uart::Parity::from_name) {
+                Some(Some(parity)) => parity,
+                Some(None) => {
+                    error!("unknown Modbus parity {:?}", PARITY);
+                    uart::Parity::Even
+                }
+                None => uart::Parity::Even,
+            };
+            let stop_bits = match STOP_BITS
+                .map(|bits| bits.parse().ok().filter(|bits| matches!(bits, 1 | 2)))
+            {
+                Some(Some(stop_bits)) => stop_bits,
+                Some(None) => {
+                    error!("invalid Modbus stop bits {:?}", STOP_BITS);
+                    1
+                }
+                None => 1,
+            };
 
-            let res = block!(ccs811.data());
-            match res {
-                Ok(data) => {
-                    info!("CCS811 initialized");
-                    info!("eCO2: {}, eTVOC: {}", data.eco2, data.etvoc);
+            // RS485 transceiver with automatic direction control, so no RTS, GPIO20 and GPIO21 are the console
+            let config = uart::UartConfig::default()
+                .baud_rate(baud_rate)
+                .parity(parity)
+                .stop_bits(stop_bits);
+            let uart = match (
+                gpio_out!(peripherals, gpio10),
+                gpio_in_out!(peripherals, gpio8),
+            ) {
+                (Ok(tx), Ok(rx)) => uart::EspUart::new(1, tx.pin(), rx.pin(), None, config),
+                (Err(err), _) | (_, Err(err)) => Err(err),
+            };
+            match uart {
+                Ok(uart) => {
+                    use modbus::{DataType, ModbusItem, Register, WordOrder};
 
-                    let ccs811 = ccs811::CompCcs811::new(ccs811);
-                    let ccs811 = Box::new(ccs811);
-                    components.push(ccs811);
+                    let master = modbus::ModbusMaster::new(uart, config.char_bits());
+                    let items = vec![
+                        ModbusItem::sensor(
+                            NAME.to_owned() + " " + "Flow Temperature",
+                            Register::input(SLAVE, 0, DataType::I16),
+                            "°C",
+                            1,
+                        )
+                        .with_scale(0.1)
+                        .with_meta(EntityMeta::default().device_class("temperature")),
+                        ModbusItem::sensor(
+                            NAME.to_owned() + " " + "Power",
+                            Register::input(SLAVE, 2, DataType::F32),
+                            "W",
+                            0,
+                        )
+                        .with_meta(EntityMeta::default().device_class("power")),
+                        ModbusItem::sensor(
+                            NAME.to_owned() + " " + "Energy",
+                            Register::input(SLAVE, 4, DataType::U32)
+                                .with_word_order(WordOrder::LowFirst),
+                            "kWh",
+                            1,
+                        )
+                        .with_scale(0.001)
+                        .with_meta(EntityMeta::default().device_class("energy")),
+                        // negative while cooling
+                        ModbusItem::sensor(
+                            NAME.to_owned() + " " + "Thermal Power",
+                            Register::input(SLAVE, 6, DataType::I32),
+                            "W",
+                            0,
+                        )
+                        .with_meta(EntityMeta::default().device_class("power")),
+                        ModbusItem::number(
+                            NAME.to_owned() + " " + "Target Temperature",
+                            Register::holding(SLAVE, 10, DataType::I16),
+                            "°C",
+                            20.,
+                            60.,
+                            0.5,
+                        )
+                        .with_scale(0.1),
+                        ModbusItem::switch(
+                            NAME.to_owned() + " " + "Heating",
+                            Register::holding(SLAVE, 11, DataType::U16),
+                            0x0001,
+                        ),
+                    ];
+                    let heat_pump = modbus::Modbus::new(master, items, publisher.clone())
+                        .with_update_interval(Duration::from_secs(30));
+                    components.push(Box::new(heat_pump));
                 }
-                Err(err) => {
-                    info!("CCS811 failed to initialized: {:?}", err);
+                Err(err) => error!("failed to setup UART: {}", Error::from(err)),
+            }
+        }
+
+        // #######################################
+        // # Firmware updates
+        // #######################################
+        {
+            const NAME: &str = "Rusty old Firmware";
+            // set at build time, e.g. `UPDATE_MANIFEST_URL=http://192.168.1.2/esphome-rs-poc/manifest.txt` and the
+            // Ed25519 public key the images are signed with as `UPDATE_PUBLIC_KEY=<64 hex digits>`
+            const MANIFEST_URL: Option<&str> = option_env!("UPDATE_MANIFEST_URL");
+            const PUBLIC_KEY: Option<&str> = option_env!("UPDATE_PUBLIC_KEY");
+
+            match (MANIFEST_URL, PUBLIC_KEY.map(http_update::from_hex)) {
+                (Some(manifest_url), Some(Some(public_key))) => {
+                    match http_update::HttpUpdate::new(
+                        NAME,
+                        manifest_url,
+                        public_key,
+                        publisher.clone(),
+                    ) {
+                        Ok(update) => components.push(Box::new(update)),
+                        Err(err) => error!("failed to setup firmware updates: {}", err),
+                    }
                 }
+                (Some(_), Some(None)) => error!("invalid update public key {:?}", PUBLIC_KEY),
+                (Some(_), None) => error!("firmware updates need a public key"),
+                (None, _) => debug!("no manifest URL, firmware updates are disabled"),
             }
         }
 
@@ -284,28 +1010,100 @@ impl ComponentManager {
             const NAME: &str = "Rusty old LED";
 
             // GPIO LED (blue)
-            make_light_monochromatic!(
-                NAME.to_owned() + " " + "blue",
-                peripherals,
-                gpio9,
-                channel3,
-                timer,
-                components
-            );
+            if let Some(timer) = &timer {
+                // barely visible below 2%, off stays off
+                let config = output::OutputConfig::default()
+                    .power_range(0.02, 1.)
+                    .zero_means_zero(true);
+                make_light_monochromatic!(
+                    NAME.to_owned() + " " + "blue",
+                    peripherals,
+                    gpio9,
+                    channel3,
+                    timer,
+                    config,
+                    gamma,
+                    components,
+                    publisher,
+                    preferences,
+                    restore_mode
+                );
+            }
             // LED Warm (yellow)
+            #[cfg(not(any(
+                feature = "has_rgbww_light",
+                feature = "has_cwww_light",
+                feature = "has_ct_light"
+            )))]
             make_light_binbary!(
                 NAME.to_owned() + " " + "yellow",
                 peripherals,
                 gpio18,
-                components
+                components,
+                publisher,
+                preferences,
+                restore_mode
             );
             // LED Cold (white)
+            #[cfg(not(any(
+                feature = "has_rgbw_light",
+                feature = "has_rgbww_light",
+                feature = "has_cwww_light",
+                feature = "has_ct_light"
+            )))]
             make_light_binbary!(
                 NAME.to_owned() + " " + "white",
                 peripherals,
                 gpio19,
-                components
+                components,
+                publisher,
+                preferences,
+                restore_mode
             );
+            // both LEDs as one cold / warm white light
+            #[cfg(feature = "has_cwww_light")]
+            if let Some(timer) = &timer {
+                // 6500K and 2700K
+                make_light_cwww!(
+                    NAME.to_owned() + " " + "white",
+                    peripherals,
+                    (gpio19, gpio18),
+                    (channel4, channel5),
+                    timer,
+                    (
+                        output::OutputConfig::default(),
+                        output::OutputConfig::default()
+                    ),
+                    (153., 370.),
+                    gamma,
+                    components,
+                    publisher,
+                    preferences,
+                    restore_mode
+                );
+            }
+            // external driver with a brightness (GPIO18) and a colour temperature (GPIO19) input
+            #[cfg(feature = "has_ct_light")]
+            if let Some(timer) = &timer {
+                // 6500K and 2700K
+                make_light_ct!(
+                    NAME.to_owned() + " " + "white",
+                    peripherals,
+                    (gpio18, gpio19),
+                    (channel5, channel4),
+                    timer,
+                    (
+                        output::OutputConfig::default(),
+                        output::OutputConfig::default()
+                    ),
+                    (153., 370.),
+                    gamma,
+                    components,
+                    publisher,
+                    preferences,
+                    restore_mode
+                );
+            }
         }
 
         // #######################################
@@ -315,42 +1113,318 @@ impl ComponentManager {
             const NAME: &str = "Rusty old RGB Light";
 
             // build in RGB LED
-            make_light_rgb!(
-                NAME.to_owned() + " " + "onboard",
-                peripherals,
-                gpio3,
-                gpio4,
-                gpio5,
-                channel0,
-                channel1,
-                channel2,
-                timer,
-                components
-            );
+            #[cfg(not(any(feature = "has_rgbw_light", feature = "has_rgbww_light")))]
+            if let Some(timer) = &timer {
+                make_light_rgb!(
+                    NAME.to_owned() + " " + "onboard",
+                    peripherals,
+                    (gpio3, gpio4, gpio5),
+                    (channel0, channel1, channel2),
+                    timer,
+                    (rgb_config[0], rgb_config[1], rgb_config[2]),
+                    gamma,
+                    components,
+                    publisher,
+                    preferences,
+                    restore_mode
+                );
+            }
+            // build in RGB LED with the white LED (GPIO19)
+            #[cfg(feature = "has_rgbw_light")]
+            if let Some(timer) = &timer {
+                make_light_rgbw!(
+                    NAME.to_owned() + " " + "onboard",
+                    peripherals,
+                    (gpio3, gpio4, gpio5, gpio19),
+                    (channel0, channel1, channel2, channel4),
+                    timer,
+                    (
+                        rgb_config[0],
+                        rgb_config[1],
+                        rgb_config[2],
+                        output::OutputConfig::default()
+                    ),
+                    gamma,
+                    components,
+                    publisher,
+                    preferences,
+                    restore_mode
+                );
+            }
+            // build in RGB LED with the white (GPIO19) and the yellow (GPIO18) LED
+            #[cfg(feature = "has_rgbww_light")]
+            if let Some(timer) = &timer {
+                // 6500K and 2700K
+                make_light_rgbww!(
+                    NAME.to_owned() + " " + "onboard",
+                    peripherals,
+                    (gpio3, gpio4, gpio5, gpio19, gpio18),
+                    (channel0, channel1, channel2, channel4, channel5),
+                    timer,
+                    (
+                        rgb_config[0],
+                        rgb_config[1],
+                        rgb_config[2],
+                        output::OutputConfig::default(),
+                        output::OutputConfig::default()
+                    ),
+                    (153., 370.),
+                    gamma,
+                    components,
+                    publisher,
+                    preferences,
+                    restore_mode
+                );
+            }
+        }
+
+        // #######################################
+        // # LED strip - GPIO8
+        // #######################################
+        #[cfg(feature = "has_led_strip")]
+        {
+            const NAME: &str = "Rusty old LED strip";
+            const NUM_LEDS: usize = 60;
+            // like ESPHome's `chipset` option, set at build time, e.g. `LED_STRIP_CHIPSET=SK6812` for RGBW LEDs
+            const CHIPSET: Option<&str> = option_env!("LED_STRIP_CHIPSET");
+
+            let chipset = match CHIPSET.map(led_strip::Chipset::from_name) {
+                Some(Some(chipset)) => chipset,
+                Some(None) => {
+                    error!("unknown LED strip chipset {:?}", CHIPSET);
+                    led_strip::Chipset::Ws2812
+                }
+                None => led_strip::Chipset::Ws2812,
+            };
+
+            let driver = gpio_out!(peripherals, gpio8)
+                .map_err(Error::from)
+                .and_then(|pin| {
+                    led_strip::RmtDriver::new(
+                        esp_idf_sys::rmt_channel_t_RMT_CHANNEL_0,
+                        pin.pin(),
+                        chipset,
+                    )
+                });
+            match driver {
+                Ok(driver) => {
+                    let strip = led_strip::LedStrip::new(
+                        driver,
+                        NUM_LEDS,
+                        vec![
+                            (NAME.to_owned() + " " + "left", 0..NUM_LEDS / 2),
+                            (NAME.to_owned() + " " + "right", NUM_LEDS / 2..NUM_LEDS),
+                        ],
+                        publisher.clone(),
+                    );
+                    components.push(Box::new(strip));
+                }
+                Err(err) => error!("failed to setup RMT: {}", err),
+            }
         }
 
-        ComponentManager { components }
+        Self::with_components(components, preferences)
     }
 
-    pub fn hanlde(&mut self, msg: &ComponentUpdate) -> Vec<ComponentUpdate> {
-        let mut resp = vec![];
+    /// Without any components, for safe mode
+    pub fn empty(preferences: SharedPreferences) -> ComponentManager {
+        Self::with_components(vec![], preferences)
+    }
+
+    fn with_components(
+        components: Vec<Box<dyn Component>>,
+        preferences: SharedPreferences,
+    ) -> ComponentManager {
+        // keys are hashes of the object_id, two entities with similar names can collide
+        let mut keys = HashMap::new();
+        let mut slots = vec![];
+        'components: for component in components {
+            let descs = component.get_description();
+            for (pos, desc) in descs.iter().enumerate() {
+                let collides = keys.contains_key(&desc.key)
+                    || descs[..pos].iter().any(|other| other.key == desc.key);
+                if collides {
+                    error!(
+                        "entity key {} ({}) is used more than once, dropping component",
+                        desc.key, desc.ty
+                    );
+                    continue 'components;
+                }
+            }
 
-        for comp in &mut self.components {
-            for comp_resp in comp.handle_update(&msg) {
-                resp.push(comp_resp);
+            for desc in descs {
+                keys.insert(desc.key, slots.len());
             }
+            slots.push(ComponentSlot {
+                component,
+                next_update: None,
+            });
         }
 
-        resp
+        ComponentManager {
+            components: slots,
+            keys,
+            preferences,
+        }
     }
 
-    pub fn get_descriptions(&mut self) -> Vec<(MessageTypes, Arc<Box<dyn Message>>)> {
+    pub fn get_descriptions(&self) -> Vec<EntityDescription> {
         let mut ret = vec![];
 
-        for comp in &self.components {
-            ret.append(&mut comp.get_description());
+        for slot in &self.components {
+            ret.append(&mut slot.component.get_description());
         }
 
         ret
     }
+
+    /// Runs [`ComponentManager::run`] on a dedicated thread
+    ///
+    /// The returned receiver is closed once all components are shut down.
+    pub fn spawn(self, recv: Receiver<ComponentUpdate>) -> io::Result<Receiver<()>> {
+        let (done_send, done_recv) = async_channel::bounded(1);
+        thread::Builder::new()
+            .name(String::from("components"))
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                // closes the channel when dropped, a panic included
+                let _done: Sender<()> = done_send;
+                smol::block_on(self.run(recv));
+            })?;
+        Ok(done_recv)
+    }
+
+    /// Drives all components until a [`ComponentUpdate::Shutdown`] arrives (or the server is gone)
+    async fn run(mut self, recv: Receiver<ComponentUpdate>) {
+        let now = Instant::now();
+        for slot in &mut self.components {
+            let res = slot.component.setup();
+            Self::handle_result(slot, res);
+            slot.next_update = slot.component.update_interval().map(|_| now);
+        }
+
+        loop {
+            let next_flush = self
+                .preferences
+                .lock()
+                .expect("lock poisened!")
+                .next_flush();
+            let next_update = self
+                .components
+                .iter()
+                .filter_map(|s| s.next_update)
+                .chain(next_flush)
+                .min();
+            let timer = async {
+                match next_update {
+                    Some(at) => {
+                        Timer::at(at).await;
+                    }
+                    None => future::pending::<()>().await,
+                }
+                None
+            };
+
+            match future::or(async { Some(recv.recv().await) }, timer).await {
+                // time for updates
+                None => {
+                    self.update_due();
+                    self.flush_preferences();
+                }
+                Some(Ok(ComponentUpdate::Request(None))) => {
+                    for slot in &mut self.components {
+                        let res = slot.component.publish_state();
+                        Self::handle_result(slot, res);
+                    }
+                }
+                Some(Ok(ComponentUpdate::Request(Some(key)))) => {
+                    if let Some(slot) = self.get_slot(key) {
+                        let res = slot.component.publish_state();
+                        Self::handle_result(slot, res);
+                    }
+                }
+                Some(Ok(ComponentUpdate::Command(cmd))) => match self.get_slot(cmd.key()) {
+                    Some(slot) => {
+                        let res = slot.component.handle_command(&cmd);
+                        Self::handle_result(slot, res);
+                        // a command can start an animation, e.g. a light transition
+                        Self::reschedule(slot, Instant::now());
+                    }
+                    None => warn!("received command for unknown entity {}", cmd.key()),
+                },
+                Some(Ok(ComponentUpdate::Shutdown)) | Some(Err(_)) => break,
+                Some(Ok(upd)) => warn!("component manager received unexpected {upd:?}"),
+            }
+        }
+
+        for slot in &mut self.components {
+            if let Err(err) = slot.component.shutdown() {
+                warn!("failed to shut down component: {err}");
+            }
+        }
+        if let Err(err) = self.preferences.lock().expect("lock poisened!").flush() {
+            error!("failed to flush preferences: {err}");
+        }
+        info!("components stopped");
+    }
+
+    fn get_slot(&mut self, key: u32) -> Option<&mut ComponentSlot> {
+        let idx = *self.keys.get(&key)?;
+        self.components.get_mut(idx)
+    }
+
+    fn update_due(&mut self) {
+        let now = Instant::now();
+
+        for slot in &mut self.components {
+            match (slot.next_update, slot.component.update_interval()) {
+                (Some(at), Some(interval)) if at <= now => {
+                    // skip missed updates instead of bursting
+                    let next = at + interval;
+                    slot.next_update = Some(if next <= now { now + interval } else { next });
+
+                    let res = slot.component.update();
+                    Self::handle_result(slot, res);
+                    Self::reschedule(slot, now);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn flush_preferences(&mut self) {
+        let mut preferences = self.preferences.lock().expect("lock poisened!");
+        if let Err(err) = preferences.flush_due(Instant::now()) {
+            warn!("failed to flush preferences: {err}, will retry");
+        }
+    }
+
+    /// `update_interval` may change at runtime, e.g. lights only need updates while animating
+    fn reschedule(slot: &mut ComponentSlot, now: Instant) {
+        slot.next_update = match (slot.next_update, slot.component.update_interval()) {
+            (_, None) => None,
+            (None, Some(_)) => Some(now),
+            (next, Some(_)) => next,
+        };
+    }
+
+    /// A failing component must not affect the others
+    fn handle_result(slot: &mut ComponentSlot, res: Result<()>) {
+        let err: Error = match res {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+
+        match err.recovery() {
+            Recovery::RestartComponent => {
+                warn!("{err}, restarting component");
+                if let Err(err) = slot.component.restart() {
+                    error!("failed to restart component: {err}");
+                }
+            }
+            Recovery::Retry | Recovery::DropClient => {
+                warn!("{err}, will retry");
+            }
+        }
+    }
 }
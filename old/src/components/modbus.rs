@@ -0,0 +1,1045 @@
+//! Modbus RTU master, like ESPHome's `modbus_controller`
+//!
+//! Registers are polled every update interval, adjacent ones of the same slave and type are read in a single
+//! request. Each [`ModbusItem`] maps a register (or two for 32 bit values) to a sensor, number or switch entity.
+//! Framing, CRC and value decoding don't touch the hardware, [`ModbusMaster`] only needs a [`Uart`].
+
+use std::{
+    fmt::{Debug, Display, Formatter},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::*;
+
+use crate::{
+    api::{
+        ListEntitiesNumberResponse, ListEntitiesSensorResponse, ListEntitiesSwitchResponse,
+        NumberMode, NumberStateResponse, SensorStateClass, SensorStateResponse,
+        SwitchStateResponse,
+    },
+    components::{
+        entity::{Command, EntityDescription, EntityMeta, StatePublisher},
+        uart::Uart,
+        BaseComponent, Component,
+    },
+    error::{Error, Result},
+};
+
+const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a slave may take to answer
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most registers a single read may ask for
+pub const MAX_READ_REGISTERS: u16 = 125;
+
+// function codes
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+const EXCEPTION: u8 = 0x80;
+
+#[derive(Debug)]
+pub enum ModbusError<E> {
+    Uart(E),
+    /// The slave didn't answer (completely) in time
+    Timeout,
+    Crc,
+    /// The slave rejected the request, e.g. 2 for an illegal data address
+    Exception(u8),
+    /// A valid frame that doesn't answer the request
+    UnexpectedResponse,
+}
+
+impl<E: Debug> Display for ModbusError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusError::Uart(err) => write!(f, "UART: {err:?}"),
+            ModbusError::Timeout => write!(f, "timeout"),
+            ModbusError::Crc => write!(f, "CRC mismatch"),
+            ModbusError::Exception(code) => write!(f, "exception {code:#04x}"),
+            ModbusError::UnexpectedResponse => write!(f, "unexpected response"),
+        }
+    }
+}
+
+/// CRC-16/MODBUS (polynomial 0x8005 reflected, initial 0xffff), sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xa001,
+            };
+        }
+    }
+    crc
+}
+
+/// Appends the CRC to a PDU addressed to `slave`
+pub fn frame(slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(slave);
+    frame.extend_from_slice(pdu);
+    frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+    frame
+}
+
+/// The PDU of a frame from `slave`, exceptions are turned into errors
+pub fn unframe<E>(slave: u8, frame: &[u8]) -> std::result::Result<&[u8], ModbusError<E>> {
+    if frame.len() < 4 {
+        return Err(ModbusError::UnexpectedResponse);
+    }
+    // the CRC over a frame including its CRC is 0
+    if crc16(frame) != 0 {
+        return Err(ModbusError::Crc);
+    }
+    if frame[0] != slave {
+        return Err(ModbusError::UnexpectedResponse);
+    }
+    let pdu = &frame[1..frame.len() - 2];
+    match pdu {
+        [function, code] if function & EXCEPTION != 0 => Err(ModbusError::Exception(*code)),
+        pdu => Ok(pdu),
+    }
+}
+
+/// Length of the whole response frame, known once its first 3 bytes arrived
+fn response_len(header: &[u8; 3]) -> usize {
+    match header[1] {
+        function if function & EXCEPTION != 0 => 5,
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => 3 + header[2] as usize + 2,
+        // writes echo address and value or count
+        _ => 8,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RegisterType {
+    /// Read/write, function codes 0x03, 0x06 and 0x10
+    Holding,
+    /// Read only, function code 0x04
+    Input,
+}
+
+/// How a value is encoded in its registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl DataType {
+    pub fn registers(&self) -> u16 {
+        match self {
+            DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 | DataType::F32 => 2,
+        }
+    }
+}
+
+/// Order of the two registers of a 32 bit value, the bytes within a register are always big endian
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WordOrder {
+    /// High word first, e.g. `0x1234 0x5678` for `0x12345678`
+    HighFirst,
+    /// Low word first, common on energy meters
+    LowFirst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Register {
+    pub slave: u8,
+    pub register_type: RegisterType,
+    pub address: u16,
+    pub data_type: DataType,
+    pub word_order: WordOrder,
+}
+
+impl Register {
+    pub fn holding(slave: u8, address: u16, data_type: DataType) -> Self {
+        Register {
+            slave,
+            register_type: RegisterType::Holding,
+            address,
+            data_type,
+            word_order: WordOrder::HighFirst,
+        }
+    }
+
+    pub fn input(slave: u8, address: u16, data_type: DataType) -> Self {
+        Register {
+            register_type: RegisterType::Input,
+            ..Register::holding(slave, address, data_type)
+        }
+    }
+
+    pub fn with_word_order(mut self, word_order: WordOrder) -> Self {
+        self.word_order = word_order;
+        self
+    }
+
+    fn end(&self) -> u16 {
+        self.address + self.data_type.registers()
+    }
+
+    /// `words` holds exactly the register's words
+    pub fn decode(&self, words: &[u16]) -> f64 {
+        let high_low = |words: &[u16]| match self.word_order {
+            WordOrder::HighFirst => (words[0] as u32) << 16 | words[1] as u32,
+            WordOrder::LowFirst => (words[1] as u32) << 16 | words[0] as u32,
+        };
+        match self.data_type {
+            DataType::U16 => words[0] as f64,
+            DataType::I16 => words[0] as i16 as f64,
+            DataType::U32 => high_low(words) as f64,
+            DataType::I32 => high_low(words) as i32 as f64,
+            DataType::F32 => f32::from_bits(high_low(words)) as f64,
+        }
+    }
+
+    /// Integers are rounded and saturate at their limits
+    pub fn encode(&self, value: f64) -> Vec<u16> {
+        let bits = match self.data_type {
+            DataType::U16 => return vec![value.round() as u16],
+            DataType::I16 => return vec![value.round() as i16 as u16],
+            DataType::U32 => value.round() as u32,
+            DataType::I32 => value.round() as i32 as u32,
+            DataType::F32 => (value as f32).to_bits(),
+        };
+        let (high, low) = ((bits >> 16) as u16, bits as u16);
+        match self.word_order {
+            WordOrder::HighFirst => vec![high, low],
+            WordOrder::LowFirst => vec![low, high],
+        }
+    }
+}
+
+/// Registers read with a single request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadRange {
+    pub slave: u8,
+    pub register_type: RegisterType,
+    pub start: u16,
+    pub count: u16,
+}
+
+impl ReadRange {
+    fn contains(&self, register: &Register) -> bool {
+        self.slave == register.slave
+            && self.register_type == register.register_type
+            && register.address >= self.start
+            && register.end() <= self.start + self.count
+    }
+
+    /// The words of `register` from what this range read
+    fn words<'a>(&self, register: &Register, words: &'a [u16]) -> &'a [u16] {
+        let offset = (register.address - self.start) as usize;
+        &words[offset..offset + register.data_type.registers() as usize]
+    }
+}
+
+/// Merges adjacent and overlapping registers of the same slave and type into as few reads as possible
+pub fn plan_reads<'a>(registers: impl IntoIterator<Item = &'a Register>) -> Vec<ReadRange> {
+    let mut registers: Vec<&Register> = registers.into_iter().collect();
+    registers.sort_by_key(|register| (register.slave, register.register_type, register.address));
+
+    let mut ranges: Vec<ReadRange> = vec![];
+    for register in registers {
+        match ranges.last_mut() {
+            Some(range)
+                if range.slave == register.slave
+                    && range.register_type == register.register_type
+                    && register.address <= range.start + range.count
+                    && register.end().max(range.start + range.count) - range.start
+                        <= MAX_READ_REGISTERS =>
+            {
+                range.count = register.end().max(range.start + range.count) - range.start;
+            }
+            _ => ranges.push(ReadRange {
+                slave: register.slave,
+                register_type: register.register_type,
+                start: register.address,
+                count: register.data_type.registers(),
+            }),
+        }
+    }
+    ranges
+}
+
+/// Request/response transactions over a UART, one at a time
+pub struct ModbusMaster<U> {
+    uart: U,
+    /// 3.5 characters of silence end a frame
+    frame_gap: Duration,
+    last_frame: Option<Instant>,
+}
+
+impl<U: Uart> ModbusMaster<U> {
+    /// `char_bits` are the bits of a character on the line, 11 for the usual 8N2 or 8E1
+    pub fn new(uart: U, char_bits: u32) -> Self {
+        // the spec fixes the gap to 1.75 ms above 19200 baud
+        let frame_gap = match uart.baud_rate() {
+            baud_rate if baud_rate > 19200 => Duration::from_micros(1750),
+            baud_rate => Duration::from_micros(3_500_000 * char_bits as u64 / baud_rate as u64),
+        };
+        ModbusMaster {
+            uart,
+            frame_gap,
+            last_frame: None,
+        }
+    }
+
+    fn transaction(
+        &mut self,
+        slave: u8,
+        request: &[u8],
+    ) -> std::result::Result<Vec<u8>, ModbusError<U::Error>> {
+        if let Some(last) = self.last_frame {
+            thread::sleep(self.frame_gap.saturating_sub(last.elapsed()));
+        }
+        self.uart.clear_input().map_err(ModbusError::Uart)?;
+        self.uart
+            .write(&frame(slave, request))
+            .map_err(ModbusError::Uart)?;
+
+        let result = self.receive();
+        self.last_frame = Some(Instant::now());
+        let response = result?;
+
+        let pdu = unframe(slave, &response)?;
+        if pdu[0] != request[0] {
+            return Err(ModbusError::UnexpectedResponse);
+        }
+        Ok(pdu.to_vec())
+    }
+
+    fn receive(&mut self) -> std::result::Result<Vec<u8>, ModbusError<U::Error>> {
+        let mut header = [0; 3];
+        let read = self
+            .uart
+            .read(&mut header, TIMEOUT)
+            .map_err(ModbusError::Uart)?;
+        if read < header.len() {
+            return Err(ModbusError::Timeout);
+        }
+
+        let mut response = vec![0; response_len(&header)];
+        response[..3].copy_from_slice(&header);
+        // the rest follows without gaps, give it as long as a whole frame may take
+        let read = self
+            .uart
+            .read(&mut response[3..], TIMEOUT)
+            .map_err(ModbusError::Uart)?;
+        if read < response.len() - 3 {
+            return Err(ModbusError::Timeout);
+        }
+        Ok(response)
+    }
+
+    pub fn read_registers(
+        &mut self,
+        slave: u8,
+        register_type: RegisterType,
+        start: u16,
+        count: u16,
+    ) -> std::result::Result<Vec<u16>, ModbusError<U::Error>> {
+        let function = match register_type {
+            RegisterType::Holding => READ_HOLDING_REGISTERS,
+            RegisterType::Input => READ_INPUT_REGISTERS,
+        };
+        let mut request = vec![function];
+        request.extend_from_slice(&start.to_be_bytes());
+        request.extend_from_slice(&count.to_be_bytes());
+
+        let pdu = self.transaction(slave, &request)?;
+        if pdu.len() != 2 + 2 * count as usize || pdu[1] as usize != 2 * count as usize {
+            return Err(ModbusError::UnexpectedResponse);
+        }
+        Ok(pdu[2..]
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect())
+    }
+
+    /// Single registers use function code 0x06, which some slaves require
+    pub fn write_registers(
+        &mut self,
+        slave: u8,
+        start: u16,
+        words: &[u16],
+    ) -> std::result::Result<(), ModbusError<U::Error>> {
+        let mut request = match words {
+            [word] => {
+                let mut request = vec![WRITE_SINGLE_REGISTER];
+                request.extend_from_slice(&start.to_be_bytes());
+                request.extend_from_slice(&word.to_be_bytes());
+                request
+            }
+            words => {
+                let mut request = vec![WRITE_MULTIPLE_REGISTERS];
+                request.extend_from_slice(&start.to_be_bytes());
+                request.extend_from_slice(&(words.len() as u16).to_be_bytes());
+                request.push(2 * words.len() as u8);
+                words
+                    .iter()
+                    .for_each(|word| request.extend_from_slice(&word.to_be_bytes()));
+                request
+            }
+        };
+
+        let pdu = self.transaction(slave, &request)?;
+        // both echo the address, 0x06 the value and 0x10 the count
+        request.truncate(5);
+        match pdu == request {
+            true => Ok(()),
+            false => Err(ModbusError::UnexpectedResponse),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Sensor {
+        unit: String,
+        accuracy_decimals: i32,
+    },
+    Number {
+        unit: String,
+        min: f32,
+        max: f32,
+        step: f32,
+    },
+    /// On while any bit of `bitmask` is set
+    Switch { bitmask: u16 },
+}
+
+/// A register as one entity, values are multiplied by `scale` after decoding and divided by it before writing
+pub struct ModbusItem {
+    base: BaseComponent,
+    register: Register,
+    kind: Kind,
+    scale: f64,
+    /// Last words read or written
+    words: Option<Vec<u16>>,
+}
+
+impl ModbusItem {
+    pub fn sensor(name: String, register: Register, unit: &str, accuracy_decimals: i32) -> Self {
+        ModbusItem {
+            base: BaseComponent::new(name, "sensor"),
+            register,
+            kind: Kind::Sensor {
+                unit: unit.to_owned(),
+                accuracy_decimals,
+            },
+            scale: 1.,
+            words: None,
+        }
+    }
+
+    /// Only holding registers can be written
+    pub fn number(
+        name: String,
+        register: Register,
+        unit: &str,
+        min: f32,
+        max: f32,
+        step: f32,
+    ) -> Self {
+        ModbusItem {
+            base: BaseComponent::new(name, "number"),
+            register,
+            kind: Kind::Number {
+                unit: unit.to_owned(),
+                min,
+                max,
+                step,
+            },
+            scale: 1.,
+            words: None,
+        }
+    }
+
+    /// Writing sets or clears the bits of `bitmask` in the last value read, other bits are kept
+    pub fn switch(name: String, register: Register, bitmask: u16) -> Self {
+        ModbusItem {
+            base: BaseComponent::new(name, "switch"),
+            register: Register {
+                data_type: DataType::U16,
+                ..register
+            },
+            kind: Kind::Switch { bitmask },
+            scale: 1.,
+            words: None,
+        }
+    }
+
+    pub fn with_meta(mut self, meta: EntityMeta) -> Self {
+        self.base = self.base.with_meta(meta);
+        self
+    }
+
+    /// E.g. 0.1 for a register in tenths of a degree
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    fn describe(&self) -> EntityDescription {
+        match &self.kind {
+            Kind::Sensor {
+                unit,
+                accuracy_decimals,
+            } => {
+                let mut resp = ListEntitiesSensorResponse::new();
+                resp.set_unit_of_measurement(unit.clone());
+                resp.set_accuracy_decimals(*accuracy_decimals);
+                resp.set_state_class(SensorStateClass::STATE_CLASS_MEASUREMENT);
+                self.base.describe(resp)
+            }
+            Kind::Number {
+                unit,
+                min,
+                max,
+                step,
+            } => {
+                let mut resp = ListEntitiesNumberResponse::new();
+                resp.set_unit_of_measurement(unit.clone());
+                resp.set_min_value(*min);
+                resp.set_max_value(*max);
+                resp.set_step(*step);
+                resp.set_mode(NumberMode::NUMBER_MODE_BOX);
+                self.base.describe(resp)
+            }
+            Kind::Switch { .. } => self.base.describe(ListEntitiesSwitchResponse::new()),
+        }
+    }
+
+    fn value(&self) -> Option<f32> {
+        let words = self.words.as_ref()?;
+        Some((self.register.decode(words) * self.scale) as f32)
+    }
+
+    fn publish(&self, publisher: &StatePublisher) {
+        let key = self.base.get_object_id_hash();
+        match (&self.kind, self.value()) {
+            (Kind::Sensor { .. }, value) => {
+                let mut resp = SensorStateResponse::new();
+                resp.set_key(key);
+                match value {
+                    Some(value) => resp.set_state(value),
+                    None => resp.set_missing_state(true),
+                }
+                publisher.publish(resp);
+            }
+            (Kind::Number { .. }, value) => {
+                let mut resp = NumberStateResponse::new();
+                resp.set_key(key);
+                match value {
+                    Some(value) => resp.set_state(value),
+                    None => resp.set_missing_state(true),
+                }
+                publisher.publish(resp);
+            }
+            (Kind::Switch { bitmask }, Some(_)) => {
+                let word = self
+                    .words
+                    .as_ref()
+                    .map(|words| words[0])
+                    .unwrap_or_default();
+                let mut resp = SwitchStateResponse::new();
+                resp.set_key(key);
+                resp.set_state(word & bitmask != 0);
+                publisher.publish(resp);
+            }
+            // a switch has no unknown state
+            (Kind::Switch { .. }, None) => {}
+        }
+    }
+
+    /// Words to write for a command, `None` if it doesn't apply to this item
+    fn command_words(&self, cmd: &Command) -> Option<Vec<u16>> {
+        match (&self.kind, cmd) {
+            (Kind::Number { min, max, .. }, Command::Number(req)) => {
+                let value = req.get_state().clamp(*min, *max) as f64;
+                Some(self.register.encode(value / self.scale))
+            }
+            (Kind::Switch { bitmask }, Command::Switch(req)) => {
+                let word = self
+                    .words
+                    .as_ref()
+                    .map(|words| words[0])
+                    .unwrap_or_default();
+                Some(vec![match req.get_state() {
+                    true => word | bitmask,
+                    false => word & !bitmask,
+                }])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// All items on one bus, every update reads them all
+pub struct Modbus<U> {
+    master: ModbusMaster<U>,
+    items: Vec<ModbusItem>,
+    ranges: Vec<ReadRange>,
+    update_interval: Duration,
+
+    publisher: StatePublisher,
+}
+
+impl<U: Uart> Modbus<U> {
+    pub fn new(master: ModbusMaster<U>, items: Vec<ModbusItem>, publisher: StatePublisher) -> Self {
+        let ranges = plan_reads(items.iter().map(|item| &item.register));
+        debug!("polling {} items with {} reads", items.len(), ranges.len());
+        Modbus {
+            master,
+            items,
+            ranges,
+            update_interval: DEFAULT_UPDATE_INTERVAL,
+            publisher,
+        }
+    }
+
+    pub fn with_update_interval(mut self, update_interval: Duration) -> Self {
+        self.update_interval = update_interval;
+        self
+    }
+
+    fn error(&self, reason: String) -> Error {
+        let key = self
+            .items
+            .first()
+            .map(|item| item.base.get_object_id_hash())
+            .unwrap_or_default();
+        Error::component(key, reason)
+    }
+
+    fn publish(&self) {
+        self.items
+            .iter()
+            .for_each(|item| item.publish(&self.publisher));
+    }
+}
+
+impl<U: Uart + Send> Component for Modbus<U> {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        self.items.iter().map(ModbusItem::describe).collect()
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let mut failed = vec![];
+        for range in self.ranges.clone() {
+            let words = self.master.read_registers(
+                range.slave,
+                range.register_type,
+                range.start,
+                range.count,
+            );
+            let words = match words {
+                Ok(words) => Some(words),
+                Err(err) => {
+                    failed.push(format!(
+                        "slave {} {:?} {}+{}: {}",
+                        range.slave, range.register_type, range.start, range.count, err
+                    ));
+                    None
+                }
+            };
+
+            // items of a failed read turn unknown, switches keep their last state
+            for item in self
+                .items
+                .iter_mut()
+                .filter(|item| range.contains(&item.register))
+            {
+                match &words {
+                    Some(words) => item.words = Some(range.words(&item.register, words).to_vec()),
+                    None if matches!(item.kind, Kind::Switch { .. }) => continue,
+                    None => item.words = None,
+                }
+                item.publish(&self.publisher);
+            }
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(self.error(failed.join(", "))),
+        }
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        Some(self.update_interval)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.publish();
+        Ok(())
+    }
+
+    fn handle_command(&mut self, cmd: &Command) -> Result<()> {
+        let idx = match self
+            .items
+            .iter()
+            .position(|item| item.base.get_object_id_hash() == cmd.key())
+        {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        let item = &self.items[idx];
+        let key = item.base.get_object_id_hash();
+        let register = item.register;
+
+        if register.register_type != RegisterType::Holding {
+            return Err(Error::component(
+                key,
+                format!("{} is read only", item.base.get_name()),
+            ));
+        }
+        let words = match item.command_words(cmd) {
+            Some(words) => words,
+            None => return Ok(()),
+        };
+
+        self.master
+            .write_registers(register.slave, register.address, &words)
+            .map_err(|err| {
+                Error::component(key, format!("failed to write {}: {err}", register.address))
+            })?;
+        let item = &mut self.items[idx];
+        item.words = Some(words);
+        item.publish(&self.publisher);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_channel::Receiver;
+
+    use super::*;
+    use crate::{api::SwitchCommandRequest, components::ComponentUpdate};
+
+    /// Answers every request with the next of `responses`, `None` stays silent
+    #[derive(Default)]
+    struct FakeUart {
+        responses: VecDeque<Option<Vec<u8>>>,
+        written: Vec<Vec<u8>>,
+        input: VecDeque<u8>,
+    }
+
+    impl Uart for FakeUart {
+        type Error = ();
+
+        fn write(&mut self, data: &[u8]) -> std::result::Result<(), ()> {
+            self.written.push(data.to_vec());
+            if let Some(Some(response)) = self.responses.pop_front() {
+                self.input.extend(response);
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> std::result::Result<usize, ()> {
+            let read = buf.len().min(self.input.len());
+            buf[..read]
+                .iter_mut()
+                .for_each(|byte| *byte = self.input.pop_front().unwrap());
+            Ok(read)
+        }
+
+        fn clear_input(&mut self) -> std::result::Result<(), ()> {
+            self.input.clear();
+            Ok(())
+        }
+
+        fn baud_rate(&self) -> u32 {
+            115200
+        }
+    }
+
+    fn master(responses: Vec<Option<Vec<u8>>>) -> ModbusMaster<FakeUart> {
+        let uart = FakeUart {
+            responses: responses.into(),
+            ..Default::default()
+        };
+        ModbusMaster::new(uart, 11)
+    }
+
+    #[test]
+    fn crc() {
+        // the check value of CRC-16/MODBUS
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+        // read 10 holding registers from slave 1, the usual example
+        assert_eq!(
+            frame(1, &[0x03, 0x00, 0x00, 0x00, 0x0a]),
+            [0x01, 0x03, 0x00, 0x00, 0x00, 0x0a, 0xc5, 0xcd]
+        );
+    }
+
+    #[test]
+    fn unframe_response() {
+        let response = [0x01, 0x04, 0x04, 0x00, 0xea, 0xff, 0xfe, 0x1a, 0x00];
+        assert_eq!(
+            unframe::<()>(1, &response).unwrap(),
+            [0x04, 0x04, 0x00, 0xea, 0xff, 0xfe]
+        );
+
+        let mut corrupted = response;
+        corrupted[4] ^= 0x01;
+        assert!(matches!(
+            unframe::<()>(1, &corrupted),
+            Err(ModbusError::Crc)
+        ));
+        assert!(matches!(
+            unframe::<()>(2, &response),
+            Err(ModbusError::UnexpectedResponse)
+        ));
+        assert!(matches!(
+            unframe::<()>(1, &response[..3]),
+            Err(ModbusError::UnexpectedResponse)
+        ));
+
+        // illegal data address
+        let exception = [0x01, 0x83, 0x02, 0xc0, 0xf1];
+        assert!(matches!(
+            unframe::<()>(1, &exception),
+            Err(ModbusError::Exception(0x02))
+        ));
+    }
+
+    #[test]
+    fn response_length() {
+        assert_eq!(response_len(&[0x01, 0x03, 0x04]), 9);
+        assert_eq!(response_len(&[0x01, 0x04, 0x02]), 7);
+        assert_eq!(response_len(&[0x01, 0x83, 0x02]), 5);
+        assert_eq!(response_len(&[0x01, 0x06, 0x00]), 8);
+        assert_eq!(response_len(&[0x01, 0x10, 0x00]), 8);
+    }
+
+    #[test]
+    fn read_registers() {
+        let mut master = master(vec![Some(frame(1, &[0x04, 0x04, 0x00, 0xea, 0xff, 0xfe]))]);
+        // a late answer to an earlier request is dropped
+        master.uart.input.extend([0x01, 0x04]);
+
+        let words = master.read_registers(1, RegisterType::Input, 0, 2).unwrap();
+        assert_eq!(words, [0x00ea, 0xfffe]);
+        assert_eq!(
+            master.uart.written,
+            [frame(1, &[0x04, 0x00, 0x00, 0x00, 0x02])]
+        );
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut master = master(vec![
+            Some(frame(1, &[0x83, 0x02])),
+            None,
+            // only a part of the frame
+            Some(frame(1, &[0x03, 0x04, 0x00, 0x01])[..5].to_vec()),
+            // a register less than asked for
+            Some(frame(1, &[0x03, 0x02, 0x00, 0x01])),
+            // answers another function
+            Some(frame(1, &[0x04, 0x04, 0x00, 0x01, 0x00, 0x02])),
+            Some(frame(2, &[0x03, 0x04, 0x00, 0x01, 0x00, 0x02])),
+        ]);
+        let mut read = || master.read_registers(1, RegisterType::Holding, 10, 2);
+
+        assert!(matches!(read(), Err(ModbusError::Exception(0x02))));
+        assert!(matches!(read(), Err(ModbusError::Timeout)));
+        assert!(matches!(read(), Err(ModbusError::Timeout)));
+        assert!(matches!(read(), Err(ModbusError::UnexpectedResponse)));
+        assert!(matches!(read(), Err(ModbusError::UnexpectedResponse)));
+        assert!(matches!(read(), Err(ModbusError::UnexpectedResponse)));
+    }
+
+    #[test]
+    fn write_registers() {
+        let single = [0x06, 0x00, 0x0a, 0x00, 0xc8];
+        let multiple = [0x10, 0x00, 0x04, 0x00, 0x02, 0x04, 0x12, 0x34, 0x56, 0x78];
+        let mut master = master(vec![
+            // the slave echoes the request
+            Some(frame(1, &single)),
+            Some(frame(1, &multiple[..5])),
+            // but not the value
+            Some(frame(1, &[0x06, 0x00, 0x0a, 0x00, 0xc9])),
+            Some(frame(1, &[0x86, 0x03])),
+        ]);
+
+        master.write_registers(1, 10, &[200]).unwrap();
+        master.write_registers(1, 4, &[0x1234, 0x5678]).unwrap();
+        assert_eq!(
+            master.uart.written,
+            [frame(1, &single), frame(1, &multiple)]
+        );
+
+        assert!(matches!(
+            master.write_registers(1, 10, &[200]),
+            Err(ModbusError::UnexpectedResponse)
+        ));
+        assert!(matches!(
+            master.write_registers(1, 10, &[200]),
+            Err(ModbusError::Exception(0x03))
+        ));
+    }
+
+    #[test]
+    fn decode_encode() {
+        let register =
+            |data_type, word_order| Register::holding(1, 0, data_type).with_word_order(word_order);
+        use DataType::*;
+        use WordOrder::*;
+
+        assert_eq!(register(U16, HighFirst).decode(&[0xfffe]), 65534.);
+        assert_eq!(register(I16, HighFirst).decode(&[0xfffe]), -2.);
+        assert_eq!(
+            register(U32, HighFirst).decode(&[0x1234, 0x5678]),
+            0x12345678 as f64
+        );
+        assert_eq!(
+            register(U32, LowFirst).decode(&[0x5678, 0x1234]),
+            0x12345678 as f64
+        );
+        assert_eq!(register(I32, HighFirst).decode(&[0xffff, 0xff38]), -200.);
+        // 1.5
+        assert_eq!(register(F32, HighFirst).decode(&[0x3fc0, 0x0000]), 1.5);
+        assert_eq!(register(F32, LowFirst).decode(&[0x0000, 0x3fc0]), 1.5);
+
+        assert_eq!(register(U16, HighFirst).encode(199.6), [200]);
+        assert_eq!(register(U16, HighFirst).encode(-1.), [0]);
+        assert_eq!(register(I16, HighFirst).encode(-2.), [0xfffe]);
+        assert_eq!(register(I16, HighFirst).encode(40000.), [0x7fff]);
+        assert_eq!(
+            register(U32, LowFirst).encode(0x12345678 as f64),
+            [0x5678, 0x1234]
+        );
+        assert_eq!(register(I32, HighFirst).encode(-200.), [0xffff, 0xff38]);
+        assert_eq!(register(F32, HighFirst).encode(1.5), [0x3fc0, 0x0000]);
+    }
+
+    #[test]
+    fn read_plan() {
+        let registers = [
+            Register::input(1, 2, DataType::F32),
+            Register::input(1, 0, DataType::I16),
+            // overlaps the F32
+            Register::input(1, 3, DataType::U16),
+            // a gap
+            Register::input(1, 6, DataType::U16),
+            Register::holding(1, 4, DataType::U16),
+            Register::input(2, 4, DataType::U16),
+        ];
+        let range = |slave, register_type, start, count| ReadRange {
+            slave,
+            register_type,
+            start,
+            count,
+        };
+        assert_eq!(
+            plan_reads(&registers),
+            [
+                range(1, RegisterType::Holding, 4, 1),
+                range(1, RegisterType::Input, 0, 1),
+                range(1, RegisterType::Input, 2, 2),
+                range(1, RegisterType::Input, 6, 1),
+                range(2, RegisterType::Input, 4, 1),
+            ]
+        );
+
+        // split at the most a request may read
+        let registers = [
+            Register::holding(1, 0, DataType::U16),
+            Register::holding(1, MAX_READ_REGISTERS - 1, DataType::U32),
+        ];
+        assert_eq!(
+            plan_reads(&registers),
+            [
+                range(1, RegisterType::Holding, 0, 1),
+                range(1, RegisterType::Holding, MAX_READ_REGISTERS - 1, 2),
+            ]
+        );
+    }
+
+    /// Sensor states as `Some`, switch states as `Some(0.)` or `Some(1.)`
+    fn published(recv: &Receiver<ComponentUpdate>) -> Vec<Option<f32>> {
+        let mut states = vec![];
+        while let Ok(ComponentUpdate::Response((_, msg))) = recv.try_recv() {
+            if let Some(resp) = msg.as_any().downcast_ref::<SensorStateResponse>() {
+                states.push(Some(resp.get_state()).filter(|_| !resp.get_missing_state()));
+            }
+            if let Some(resp) = msg.as_any().downcast_ref::<SwitchStateResponse>() {
+                states.push(Some(resp.get_state() as u8 as f32));
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn update() {
+        let items = vec![
+            ModbusItem::sensor(
+                "Flow".to_owned(),
+                Register::input(1, 0, DataType::I16),
+                "°C",
+                1,
+            )
+            .with_scale(0.1),
+            ModbusItem::sensor(
+                "Offset".to_owned(),
+                Register::input(1, 1, DataType::I16),
+                "°C",
+                1,
+            ),
+            ModbusItem::switch(
+                "Heating".to_owned(),
+                Register::holding(1, 11, DataType::U16),
+                0x0004,
+            ),
+        ];
+        // holding registers are read first
+        let master = master(vec![
+            Some(frame(1, &[0x03, 0x02, 0x01, 0x01])),
+            Some(frame(1, &[0x04, 0x04, 0x00, 0xea, 0xff, 0xfe])),
+            None,
+            None,
+            Some(frame(1, &[0x06, 0x00, 0x0b, 0x01, 0x05])),
+        ]);
+        let (send, recv) = async_channel::unbounded();
+        let mut modbus = Modbus::new(master, items, StatePublisher::new(send));
+
+        modbus.update().unwrap();
+        let states = published(&recv);
+        assert_eq!(states.len(), 3);
+        assert_eq!(states[0], Some(0.));
+        assert!((states[1].unwrap() - 23.4).abs() < 1e-5);
+        assert_eq!(states[2], Some(-2.));
+
+        // the sensors turn unknown, the switch keeps its state
+        assert!(modbus.update().is_err());
+        assert_eq!(published(&recv), [None, None]);
+
+        // sets the bit and keeps the others
+        let mut req = SwitchCommandRequest::new();
+        req.set_key(modbus.items[2].base.get_object_id_hash());
+        req.set_state(true);
+        modbus
+            .handle_command(&Command::Switch(Box::new(req)))
+            .unwrap();
+        assert_eq!(
+            modbus.master.uart.written.last().unwrap(),
+            &frame(1, &[0x06, 0x00, 0x0b, 0x01, 0x05])
+        );
+        assert_eq!(published(&recv), [Some(1.)]);
+    }
+}
@@ -0,0 +1,475 @@
+//! Dallas/Maxim 1-Wire bus
+//!
+//! [`OneWire`] only needs the three time slots (reset, write bit, read bit), bytes, ROM commands and the
+//! device search are built on top of them. [`BitBang`] drives the slots on an open drain GPIO.
+
+use std::{
+    cmp::Ordering,
+    fmt::{Debug, Display, Formatter},
+};
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use esp_idf_sys::{ets_delay_us, vPortClearInterruptMask, vPortSetInterruptMask};
+
+// ROM commands
+const SEARCH_ROM: u8 = 0xf0;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xcc;
+
+#[derive(Debug)]
+pub enum OneWireError<E> {
+    Bus(E),
+    /// Nobody answered the reset pulse
+    NoPresence,
+    /// Data got corrupted on the bus, e.g. by a bad cable
+    Crc,
+}
+
+impl<E> From<E> for OneWireError<E> {
+    fn from(err: E) -> Self {
+        OneWireError::Bus(err)
+    }
+}
+
+impl<E: Debug> Display for OneWireError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OneWireError::Bus(err) => write!(f, "bus: {err:?}"),
+            OneWireError::NoPresence => write!(f, "no device present"),
+            OneWireError::Crc => write!(f, "CRC mismatch"),
+        }
+    }
+}
+
+/// 64 bit ROM code, family code in the lowest byte and the CRC in the highest, as sent on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address(pub u64);
+
+impl Address {
+    pub fn family(&self) -> u8 {
+        self.0 as u8
+    }
+
+    pub fn is_valid(&self) -> bool {
+        crc8(&self.0.to_le_bytes()) == 0
+    }
+}
+
+/// Big endian like ESPHome prints them, e.g. `0x1c0000031edd2a28`
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:016x}", self.0)
+    }
+}
+
+/// Dallas/Maxim CRC-8 (polynomial x⁸ + x⁵ + x⁴ + 1, reflected), 0 over data including its CRC
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0x8c,
+            };
+        }
+    }
+    crc
+}
+
+/// A 1-Wire master, bits go out least significant first
+pub trait OneWire {
+    type Error: Debug;
+
+    /// Reset pulse, `true` when at least one device answered with a presence pulse
+    fn reset(&mut self) -> Result<bool, Self::Error>;
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), Self::Error>;
+
+    fn read_bit(&mut self) -> Result<bool, Self::Error>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::Error> {
+        (0..8).try_for_each(|i| self.write_bit(byte & (1 << i) != 0))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Self::Error> {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit()? {
+                byte |= 1 << i;
+            }
+        }
+        Ok(byte)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        bytes.iter().try_for_each(|byte| self.write_byte(*byte))
+    }
+
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        for byte in bytes {
+            *byte = self.read_byte()?;
+        }
+        Ok(())
+    }
+
+    /// Reset and address a single device (or all with `None`), a function command has to follow
+    fn select(&mut self, address: Option<Address>) -> Result<(), OneWireError<Self::Error>> {
+        if !self.reset()? {
+            return Err(OneWireError::NoPresence);
+        }
+        match address {
+            Some(address) => {
+                self.write_byte(MATCH_ROM)?;
+                self.write_bytes(&address.0.to_le_bytes())?;
+            }
+            None => self.write_byte(SKIP_ROM)?,
+        }
+        Ok(())
+    }
+
+    /// ROM codes of all devices on the bus, with the search algorithm from Maxim's AN187
+    fn search(&mut self) -> Result<Vec<Address>, OneWireError<Self::Error>> {
+        let mut found = vec![];
+        // bit position of the last unexplored 0 branch, 1 based
+        let mut last_discrepancy = 0;
+        let mut rom = 0u64;
+
+        loop {
+            if !self.reset()? {
+                return match found.is_empty() {
+                    true => Ok(found),
+                    false => Err(OneWireError::NoPresence),
+                };
+            }
+            self.write_byte(SEARCH_ROM)?;
+
+            let mut discrepancy = 0;
+            for position in 1..=64 {
+                let bit = self.read_bit()?;
+                let complement = self.read_bit()?;
+
+                let direction = match (bit, complement) {
+                    // nobody is left, someone dropped off the bus during the search
+                    (true, true) => return Err(OneWireError::NoPresence),
+                    // all remaining devices agree
+                    (true, false) => true,
+                    (false, true) => false,
+                    // both values present, repeat the last path up to its discrepancy, then take the 1 branch
+                    // there and 0 at new ones
+                    (false, false) => {
+                        let direction = match position.cmp(&last_discrepancy) {
+                            Ordering::Less => rom & (1 << (position - 1)) != 0,
+                            Ordering::Equal => true,
+                            Ordering::Greater => false,
+                        };
+                        if !direction {
+                            discrepancy = position;
+                        }
+                        direction
+                    }
+                };
+
+                match direction {
+                    true => rom |= 1 << (position - 1),
+                    false => rom &= !(1 << (position - 1)),
+                }
+                self.write_bit(direction)?;
+            }
+
+            let address = Address(rom);
+            if !address.is_valid() {
+                return Err(OneWireError::Crc);
+            }
+            found.push(address);
+
+            if discrepancy == 0 {
+                return Ok(found);
+            }
+            last_discrepancy = discrepancy;
+        }
+    }
+}
+
+/// Bit-banged master on an open drain pin with a pull-up (4.7 kΩ, the internal one is too weak)
+///
+/// Slot timings are the standard speed ones from Maxim's AN126. A stretched slot silently turns into another
+/// bit, so every slot runs with interrupts disabled, the reset keeps them off for about a millisecond.
+pub struct BitBang<P> {
+    pin: P,
+}
+
+impl<P, E> BitBang<P>
+where
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+{
+    pub fn new(mut pin: P) -> Result<Self, E> {
+        pin.set_high()?;
+        Ok(BitBang { pin })
+    }
+
+    fn low_for(&mut self, us: u32) -> Result<(), E> {
+        self.pin.set_low()?;
+        unsafe { ets_delay_us(us) };
+        self.pin.set_high()
+    }
+}
+
+impl<P, E> OneWire for BitBang<P>
+where
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+    E: Debug,
+{
+    type Error = E;
+
+    fn reset(&mut self) -> Result<bool, E> {
+        let _lock = InterruptLock::new();
+        self.low_for(480)?;
+        unsafe { ets_delay_us(70) };
+        let present = self.pin.is_low()?;
+        unsafe { ets_delay_us(410) };
+        Ok(present)
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), E> {
+        let (low, high) = match bit {
+            true => (6, 64),
+            false => (60, 10),
+        };
+        let _lock = InterruptLock::new();
+        self.low_for(low)?;
+        unsafe { ets_delay_us(high) };
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, E> {
+        let _lock = InterruptLock::new();
+        self.low_for(6)?;
+        unsafe { ets_delay_us(9) };
+        let bit = self.pin.is_high()?;
+        unsafe { ets_delay_us(55) };
+        Ok(bit)
+    }
+}
+
+/// Interrupts of this core are disabled while it lives, like ESPHome's `InterruptLock`
+struct InterruptLock(i32);
+
+impl InterruptLock {
+    fn new() -> Self {
+        InterruptLock(unsafe { vPortSetInterruptMask() })
+    }
+}
+
+impl Drop for InterruptLock {
+    fn drop(&mut self) {
+        unsafe { vPortClearInterruptMask(self.0) };
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Bit level stand-in for the devices on a bus, answers the ROM commands like the real ones
+    #[derive(Default)]
+    pub struct FakeBus {
+        /// ROM code and what the devices send after a function command
+        pub devices: Vec<(Address, Vec<u8>)>,
+        /// Bytes after each reset, ROM commands included
+        pub transactions: Vec<Vec<u8>>,
+        /// Devices still taking part in the current search or selected by the ROM command
+        active: Vec<bool>,
+        search: Option<Search>,
+        bits: Vec<bool>,
+        reply: Vec<bool>,
+    }
+
+    #[derive(Debug, Clone, Copy, Default)]
+    struct Search {
+        position: u32,
+        /// Bit and complement read, the master writes the direction next
+        reads: u8,
+    }
+
+    impl FakeBus {
+        pub fn new(devices: Vec<(Address, Vec<u8>)>) -> Self {
+            FakeBus {
+                devices,
+                ..Default::default()
+            }
+        }
+
+        fn rom_bit(&self, idx: usize, position: u32) -> bool {
+            self.devices[idx].0 .0 & (1 << position) != 0
+        }
+
+        fn received(&mut self, byte: u8) {
+            let transaction = self.transactions.last_mut().expect("reset first");
+            transaction.push(byte);
+
+            match (transaction[0], transaction.len()) {
+                (SEARCH_ROM, 1) => self.search = Some(Search::default()),
+                // the address is complete
+                (MATCH_ROM, 9) => {
+                    let address = u64::from_le_bytes(transaction[1..9].try_into().unwrap());
+                    for (idx, active) in self.active.iter_mut().enumerate() {
+                        *active = self.devices[idx].0 .0 == address;
+                    }
+                }
+                // the function command of the selected devices, only one may answer
+                (MATCH_ROM, 10) | (SKIP_ROM, 2) => {
+                    let selected: Vec<usize> = (0..self.devices.len())
+                        .filter(|idx| self.active[*idx])
+                        .collect();
+                    if let [idx] = selected[..] {
+                        self.reply = self.devices[idx]
+                            .1
+                            .iter()
+                            .flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0))
+                            .rev()
+                            .collect();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    impl OneWire for FakeBus {
+        type Error = ();
+
+        fn reset(&mut self) -> Result<bool, ()> {
+            self.active = vec![true; self.devices.len()];
+            self.search = None;
+            self.bits.clear();
+            self.reply.clear();
+            self.transactions.push(vec![]);
+            Ok(!self.devices.is_empty())
+        }
+
+        fn write_bit(&mut self, bit: bool) -> Result<(), ()> {
+            if let Some(search) = self.search.as_mut() {
+                // devices with the other bit drop out
+                let position = search.position;
+                *search = Search {
+                    position: position + 1,
+                    reads: 0,
+                };
+                for idx in 0..self.devices.len() {
+                    if self.rom_bit(idx, position) != bit {
+                        self.active[idx] = false;
+                    }
+                }
+                return Ok(());
+            }
+
+            self.bits.push(bit);
+            if self.bits.len() == 8 {
+                let byte = self
+                    .bits
+                    .drain(..)
+                    .enumerate()
+                    .fold(0, |byte, (i, bit)| byte | (bit as u8) << i);
+                self.received(byte);
+            }
+            Ok(())
+        }
+
+        fn read_bit(&mut self) -> Result<bool, ()> {
+            if let Some(search) = self.search.as_mut() {
+                // wired AND, a device sending 0 wins
+                let (position, complement) = (search.position, search.reads == 1);
+                search.reads += 1;
+                let bit = (0..self.devices.len())
+                    .filter(|idx| self.active[*idx])
+                    .all(|idx| self.rom_bit(idx, position) != complement);
+                return Ok(bit);
+            }
+            // nobody pulls the line low
+            Ok(self.reply.pop().unwrap_or(true))
+        }
+    }
+
+    /// ROM code with a valid CRC
+    pub fn address(family: u8, serial: u64) -> Address {
+        let mut bytes = (serial << 8 | family as u64).to_le_bytes();
+        bytes[7] = crc8(&bytes[..7]);
+        Address(u64::from_le_bytes(bytes))
+    }
+
+    #[test]
+    fn crc() {
+        // example ROM of Maxim's application note 27
+        let rom = [0x02, 0x1c, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xa2];
+        assert_eq!(crc8(&rom[..7]), 0xa2);
+        assert_eq!(crc8(&rom), 0);
+        assert!(Address(u64::from_le_bytes(rom)).is_valid());
+        assert!(!Address(u64::from_le_bytes(rom) ^ 1 << 20).is_valid());
+    }
+
+    #[test]
+    fn display() {
+        let address = address(0x28, 0x031edd2a);
+        assert_eq!(address.family(), 0x28);
+        assert!(address.is_valid());
+        assert_eq!(address.to_string(), "0x1c0000031edd2a28");
+    }
+
+    #[test]
+    fn search() {
+        // differing in the family code, the serial and only in the last serial bit
+        let mut addresses = vec![
+            address(0x28, 0x031edd2a),
+            address(0x10, 0x031edd2a),
+            address(0x28, 0x031edd2b),
+        ];
+        let devices = addresses.iter().map(|address| (*address, vec![])).collect();
+        let mut bus = FakeBus::new(devices);
+
+        let mut found = bus.search().unwrap();
+        // one pass per device
+        assert_eq!(bus.transactions.len(), 3);
+        assert!(bus.transactions.iter().all(|t| t == &[SEARCH_ROM]));
+
+        found.sort();
+        addresses.sort();
+        assert_eq!(found, addresses);
+    }
+
+    #[test]
+    fn search_single_and_empty() {
+        let address = address(0x28, 1);
+        let mut bus = FakeBus::new(vec![(address, vec![])]);
+        assert_eq!(bus.search().unwrap(), [address]);
+
+        let mut bus = FakeBus::new(vec![]);
+        assert_eq!(bus.search().unwrap(), []);
+    }
+
+    #[test]
+    fn search_crc() {
+        let address = Address(address(0x28, 1).0 ^ 1 << 40);
+        let mut bus = FakeBus::new(vec![(address, vec![])]);
+        assert!(matches!(bus.search(), Err(OneWireError::Crc)));
+    }
+
+    #[test]
+    fn select() {
+        let address = address(0x28, 1);
+        let mut bus = FakeBus::new(vec![(address, vec![0x42])]);
+
+        bus.select(Some(address)).unwrap();
+        bus.write_byte(0xbe).unwrap();
+        assert_eq!(bus.read_byte().unwrap(), 0x42);
+        bus.select(None).unwrap();
+
+        let mut matched = vec![MATCH_ROM];
+        matched.extend(address.0.to_le_bytes());
+        matched.push(0xbe);
+        assert_eq!(bus.transactions, [matched, vec![SKIP_ROM]]);
+
+        let mut bus = FakeBus::new(vec![]);
+        assert!(matches!(bus.select(None), Err(OneWireError::NoPresence)));
+    }
+}
@@ -0,0 +1,178 @@
+use embedded_hal::PwmPin;
+
+/// How a level in `0.0..=1.0` is mapped onto the hardware, like ESPHome's `FloatOutput` options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputConfig {
+    /// Level that `0.0` (or the smallest level above it) maps to
+    pub min_power: f32,
+    /// Level that `1.0` maps to
+    pub max_power: f32,
+    /// Keep `0.0` at off instead of mapping it to `min_power`
+    pub zero_means_zero: bool,
+    /// For active low wiring, e.g. a common anode RGB LED
+    pub inverted: bool,
+    /// Applied before the power range, e.g. to white balance the channels of an RGB light
+    pub calibration: f32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            min_power: 0.,
+            max_power: 1.,
+            zero_means_zero: false,
+            inverted: false,
+            calibration: 1.,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn power_range(mut self, min_power: f32, max_power: f32) -> Self {
+        self.min_power = min_power.clamp(0., 1.);
+        self.max_power = max_power.clamp(self.min_power, 1.);
+        self
+    }
+
+    pub fn zero_means_zero(mut self, zero_means_zero: bool) -> Self {
+        self.zero_means_zero = zero_means_zero;
+        self
+    }
+
+    pub fn inverted(mut self, inverted: bool) -> Self {
+        self.inverted = inverted;
+        self
+    }
+
+    pub fn calibration(mut self, calibration: f32) -> Self {
+        self.calibration = calibration.clamp(0., 1.);
+        self
+    }
+
+    /// Level that is written to the hardware for the requested `level`
+    pub fn map(&self, level: f32) -> f32 {
+        let level = level.clamp(0., 1.) * self.calibration;
+
+        let level = if level == 0. && self.zero_means_zero {
+            0.
+        } else {
+            self.min_power + level * (self.max_power - self.min_power)
+        };
+
+        if self.inverted {
+            1. - level
+        } else {
+            level
+        }
+    }
+}
+
+/// A PWM channel (LEDC, ...) driven by a level in `0.0..=1.0`
+///
+/// Shared by everything that dims something: lights, fans, buzzers, ...
+pub struct FloatOutput {
+    pin: Box<dyn PwmPin<Duty = u32> + Send>,
+    config: OutputConfig,
+}
+
+impl FloatOutput {
+    pub fn new(pin: Box<dyn PwmPin<Duty = u32> + Send>, config: OutputConfig) -> Self {
+        FloatOutput { pin, config }
+    }
+
+    pub fn set_level(&mut self, level: f32) {
+        let max_duty = self.pin.get_max_duty();
+        let duty = (max_duty as f32 * self.config.map(level)).round() as u32;
+        self.pin.set_duty(duty.min(max_duty));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn power_range() {
+        let config = OutputConfig::default().power_range(0.1, 0.8);
+
+        assert_eq!(config.map(0.), 0.1);
+        assert!((config.map(0.5) - 0.45).abs() < 1e-6);
+        assert_eq!(config.map(1.), 0.8);
+        // clamped
+        assert_eq!(config.map(2.), 0.8);
+        assert_eq!(config.map(-1.), 0.1);
+    }
+
+    #[test]
+    fn zero_means_zero() {
+        let config = OutputConfig::default()
+            .power_range(0.1, 1.)
+            .zero_means_zero(true);
+
+        assert_eq!(config.map(0.), 0.);
+        assert!((config.map(0.01) - 0.109).abs() < 1e-6);
+    }
+
+    #[test]
+    fn calibration() {
+        let config = OutputConfig::default()
+            .power_range(0.1, 1.)
+            .calibration(0.5);
+
+        assert!((config.map(1.) - 0.55).abs() < 1e-6);
+        assert_eq!(config.map(0.), 0.1);
+        // clamped
+        assert_eq!(OutputConfig::default().calibration(2.).calibration, 1.);
+    }
+
+    #[test]
+    fn inverted() {
+        let config = OutputConfig::default().zero_means_zero(true).inverted(true);
+
+        assert_eq!(config.map(0.), 1.);
+        assert!((config.map(0.25) - 0.75).abs() < 1e-6);
+        assert_eq!(config.map(1.), 0.);
+    }
+
+    #[test]
+    fn invalid_range() {
+        let config = OutputConfig::default().power_range(0.5, 0.2);
+        assert_eq!(config.min_power, 0.5);
+        assert_eq!(config.max_power, 0.5);
+    }
+
+    struct Pwm(Arc<AtomicU32>);
+
+    impl PwmPin for Pwm {
+        type Duty = u32;
+
+        fn disable(&mut self) {}
+        fn enable(&mut self) {}
+        fn get_duty(&self) -> u32 {
+            self.0.load(Ordering::Relaxed)
+        }
+        fn get_max_duty(&self) -> u32 {
+            1023
+        }
+        fn set_duty(&mut self, duty: u32) {
+            self.0.store(duty, Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn duty() {
+        let duty = Arc::new(AtomicU32::new(0));
+        let config = OutputConfig::default().power_range(0., 0.5);
+        let mut output = FloatOutput::new(Box::new(Pwm(duty.clone())), config);
+
+        output.set_level(1.);
+        assert_eq!(duty.load(Ordering::Relaxed), 512);
+        output.set_level(0.);
+        assert_eq!(duty.load(Ordering::Relaxed), 0);
+    }
+}
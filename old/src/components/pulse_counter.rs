@@ -0,0 +1,327 @@
+//! Pulse counter and pulse meter on the PCNT peripheral, e.g. for water meters with a reed contact or S0 outputs
+//!
+//! Every pulse raises an interrupt (the unit's high limit is 1) that timestamps it. The PCNT glitch filter only
+//! covers a few µs, contact bounce lasts milliseconds, so pulses closer than `debounce` are dropped there as well.
+//! The total survives reboots through the preferences.
+
+use std::{
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys::*;
+use log::*;
+
+use crate::{
+    api::{ListEntitiesSensorResponse, SensorStateClass, SensorStateResponse},
+    components::{
+        entity::{EntityDescription, EntityMeta, StatePublisher},
+        BaseComponent, Component,
+    },
+    error::Result,
+    preferences::SharedPreferences,
+    utils::pulse::{debounced, PulseMath, Pulses, RateMode},
+};
+
+/// ESPHome's `pulse_counter` default
+const COUNTER_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+/// The meter publishes changes as they come
+const METER_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// ESPHome's `pulse_meter` default
+pub const DEFAULT_METER_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The glitch filter counts APB clock cycles in 10 bits
+const APB_CLK_MHZ: u64 = 80;
+const MAX_FILTER_CYCLES: u64 = 1023;
+
+/// Which edge counts as a pulse
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Rising,
+    /// Contacts and S0 outputs pulling the input to ground
+    Falling,
+}
+
+/// Written by the interrupt, read by the component
+#[derive(Debug, Default)]
+struct PulseState {
+    count: AtomicU32,
+    /// `esp_timer` µs of the last pulse, truncated
+    last_us: AtomicU32,
+    period_us: AtomicU32,
+    debounce_us: u32,
+}
+
+/// Runs in the PCNT ISR service, `arg` is the unit's [`PulseState`]
+unsafe extern "C" fn on_pulse(arg: *mut c_void) {
+    let state = &*(arg as *const PulseState);
+    let now = esp_timer_get_time() as u32;
+
+    let count = state.count.load(Ordering::Relaxed);
+    let last = state.last_us.load(Ordering::Relaxed);
+    if count > 0 {
+        if !debounced(last, now, state.debounce_us) {
+            return;
+        }
+        state
+            .period_us
+            .store(now.wrapping_sub(last), Ordering::Relaxed);
+    }
+    state.last_us.store(now, Ordering::Relaxed);
+    state.count.store(count.wrapping_add(1), Ordering::Release);
+}
+
+/// One PCNT unit counting a GPIO
+pub struct PcntUnit {
+    unit: pcnt_unit_t,
+    state: Arc<PulseState>,
+}
+
+impl PcntUnit {
+    /// Also enables the pin's pull-up, `glitch_filter` is capped at about 12.8 µs
+    pub fn new(
+        unit: pcnt_unit_t,
+        gpio: i32,
+        edge: Edge,
+        glitch_filter: Duration,
+        debounce: Duration,
+    ) -> std::result::Result<Self, EspError> {
+        let (pos_mode, neg_mode) = match edge {
+            Edge::Rising => (
+                pcnt_count_mode_t_PCNT_COUNT_INC,
+                pcnt_count_mode_t_PCNT_COUNT_DIS,
+            ),
+            Edge::Falling => (
+                pcnt_count_mode_t_PCNT_COUNT_DIS,
+                pcnt_count_mode_t_PCNT_COUNT_INC,
+            ),
+        };
+        let config = pcnt_config_t {
+            pulse_gpio_num: gpio,
+            ctrl_gpio_num: PCNT_PIN_NOT_USED,
+            lctrl_mode: pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+            hctrl_mode: pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+            pos_mode,
+            neg_mode,
+            // every pulse hits the limit, which raises the interrupt and resets the counter
+            counter_h_lim: 1,
+            counter_l_lim: 0,
+            unit,
+            channel: pcnt_channel_t_PCNT_CHANNEL_0,
+        };
+        esp!(unsafe { pcnt_unit_config(&config) })?;
+
+        let cycles = (glitch_filter.as_nanos() as u64 * APB_CLK_MHZ / 1000).min(MAX_FILTER_CYCLES);
+        if cycles > 0 {
+            esp!(unsafe { pcnt_set_filter_value(unit, cycles as u16) })?;
+            esp!(unsafe { pcnt_filter_enable(unit) })?;
+        }
+
+        let state = Arc::new(PulseState {
+            debounce_us: debounce.as_micros().min(u32::MAX as u128) as u32,
+            ..Default::default()
+        });
+
+        // shared by all units, only the first one installs it
+        match unsafe { pcnt_isr_service_install(0) } {
+            err if err == ESP_ERR_INVALID_STATE as esp_err_t => {}
+            err => esp!(err)?,
+        }
+        esp!(unsafe { pcnt_event_enable(unit, pcnt_evt_type_t_PCNT_EVT_H_LIM) })?;
+        esp!(unsafe {
+            pcnt_isr_handler_add(unit, Some(on_pulse), Arc::as_ptr(&state) as *mut c_void)
+        })?;
+
+        esp!(unsafe { pcnt_counter_pause(unit) })?;
+        esp!(unsafe { pcnt_counter_clear(unit) })?;
+        esp!(unsafe { pcnt_counter_resume(unit) })?;
+
+        Ok(PcntUnit { unit, state })
+    }
+
+    pub fn pulses(&self) -> Pulses {
+        Pulses {
+            count: self.state.count.load(Ordering::Acquire),
+            period_us: self.state.period_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for PcntUnit {
+    fn drop(&mut self) {
+        // the handler must be gone before its state is
+        unsafe {
+            pcnt_counter_pause(self.unit);
+            pcnt_isr_handler_remove(self.unit);
+        }
+    }
+}
+
+/// Rate in pulses/min and the total, each as its own sensor entity
+pub struct PulseCounter {
+    unit: PcntUnit,
+    math: PulseMath,
+    update_interval: Duration,
+
+    rate: BaseComponent,
+    rate_unit: String,
+    rate_multiply: f32,
+    rate_accuracy: i32,
+    total: BaseComponent,
+    total_unit: String,
+    total_multiply: f32,
+    total_accuracy: i32,
+
+    preferences: SharedPreferences,
+    /// Only changes are saved and published
+    last_total: Option<u64>,
+    last_rate: Option<f32>,
+
+    publisher: StatePublisher,
+}
+
+impl PulseCounter {
+    /// Entity names are `name` followed by "Rate" and "Total", the total is restored from `preferences`
+    pub fn new(
+        unit: PcntUnit,
+        name: &str,
+        mode: RateMode,
+        preferences: SharedPreferences,
+        publisher: StatePublisher,
+    ) -> PulseCounter {
+        let rate = BaseComponent::new(String::from(name) + " Rate", "sensor")
+            .with_meta(EntityMeta::default().icon("mdi:pulse"));
+        let total = BaseComponent::new(String::from(name) + " Total", "sensor")
+            .with_meta(EntityMeta::default().icon("mdi:counter"));
+
+        let offset = preferences
+            .lock()
+            .expect("lock poisened!")
+            .load::<u64>(total.get_object_id_hash())
+            .unwrap_or_default();
+        info!("{} restored {} pulses", name, offset);
+
+        let update_interval = match mode {
+            RateMode::Counter => COUNTER_UPDATE_INTERVAL,
+            RateMode::Meter { .. } => METER_UPDATE_INTERVAL,
+        };
+
+        PulseCounter {
+            unit,
+            math: PulseMath::new(mode, offset),
+            update_interval,
+
+            rate,
+            rate_unit: String::from("pulses/min"),
+            rate_multiply: 1.,
+            rate_accuracy: 0,
+            total,
+            total_unit: String::from("pulses"),
+            total_multiply: 1.,
+            total_accuracy: 0,
+
+            preferences,
+            last_total: None,
+            last_rate: None,
+
+            publisher,
+        }
+    }
+
+    /// Converts pulses/min, e.g. `("kW", 0.06, 3)` for a meter with 1000 pulses per kWh
+    pub fn with_rate(mut self, unit: &str, multiply: f32, accuracy_decimals: i32) -> Self {
+        self.rate_unit = unit.to_owned();
+        self.rate_multiply = multiply;
+        self.rate_accuracy = accuracy_decimals;
+        self
+    }
+
+    /// Converts pulses, e.g. `("kWh", 0.001, 3)`
+    pub fn with_total(mut self, unit: &str, multiply: f32, accuracy_decimals: i32) -> Self {
+        self.total_unit = unit.to_owned();
+        self.total_multiply = multiply;
+        self.total_accuracy = accuracy_decimals;
+        self
+    }
+
+    fn publish_value(&self, base: &BaseComponent, value: f32) {
+        let mut resp = SensorStateResponse::new();
+        resp.set_key(base.get_object_id_hash());
+        resp.set_state(value);
+        self.publisher.publish(resp);
+    }
+
+    fn publish(&self) {
+        if let Some(total) = self.last_total {
+            self.publish_value(&self.total, total as f32 * self.total_multiply);
+        }
+        if let Some(rate) = self.last_rate {
+            self.publish_value(&self.rate, rate * self.rate_multiply);
+        }
+    }
+}
+
+impl Component for PulseCounter {
+    fn get_description(&self) -> Vec<EntityDescription> {
+        let sensor = |base: &BaseComponent, unit: &String, accuracy_decimals, state_class| {
+            let mut resp = ListEntitiesSensorResponse::new();
+            resp.set_unit_of_measurement(String::from(unit));
+            resp.set_accuracy_decimals(accuracy_decimals);
+            resp.set_state_class(state_class);
+            base.describe(resp)
+        };
+
+        vec![
+            sensor(
+                &self.rate,
+                &self.rate_unit,
+                self.rate_accuracy,
+                SensorStateClass::STATE_CLASS_MEASUREMENT,
+            ),
+            sensor(
+                &self.total,
+                &self.total_unit,
+                self.total_accuracy,
+                SensorStateClass::STATE_CLASS_TOTAL_INCREASING,
+            ),
+        ]
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let pulses = self.unit.pulses();
+
+        let total = self.math.total(pulses);
+        if self.last_total != Some(total) {
+            self.last_total = Some(total);
+            self.publish_value(&self.total, total as f32 * self.total_multiply);
+            self.preferences.lock().expect("lock poisened!").save(
+                self.total.get_object_id_hash(),
+                &total,
+                now,
+            );
+        }
+
+        if let Some(rate) = self.math.rate(pulses, now) {
+            if self.last_rate != Some(rate) {
+                trace!("{} pulses/min", rate);
+                self.last_rate = Some(rate);
+                self.publish_value(&self.rate, rate * self.rate_multiply);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        Some(self.update_interval)
+    }
+
+    fn publish_state(&mut self) -> Result<()> {
+        self.publish();
+        Ok(())
+    }
+}
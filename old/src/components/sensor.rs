@@ -0,0 +1,183 @@
+//! Sensor entities, like ESPHome's `sensor` platforms
+//!
+//! [`SensorEntity`] is the part every sensor component shares. [`Sensor`] is the component for drivers that
+//! measure a single value, the driver only measures, unit, device class and filters are configured on the [`Sensor`].
+
+use crate::{
+    api::{ListEntitiesSensorResponse, SensorStateClass, SensorStateResponse},
+    components::{
+        entity::{EntityDescription, EntityMeta, StatePublisher},
+        BaseComponent,
+    },
+};
+
+// only the ADC uses the single value component so far
+#[cfg(feature = "has_adc")]
+pub use single::{Filter, Sensor, SensorDriver};
+
+/// One `sensor` entity: its description and its state
+pub struct SensorEntity {
+    base: BaseComponent,
+    unit: String,
+    accuracy_decimals: i32,
+}
+
+impl SensorEntity {
+    pub fn new(name: String) -> Self {
+        SensorEntity {
+            base: BaseComponent::new(name, "sensor"),
+            unit: String::new(),
+            accuracy_decimals: 1,
+        }
+    }
+
+    pub fn with_meta(mut self, meta: EntityMeta) -> Self {
+        self.base = self.base.with_meta(meta);
+        self
+    }
+
+    pub fn with_unit(mut self, unit: &str, accuracy_decimals: i32) -> Self {
+        self.unit = unit.to_owned();
+        self.accuracy_decimals = accuracy_decimals;
+        self
+    }
+
+    pub fn get_key(&self) -> u32 {
+        self.base.get_object_id_hash()
+    }
+
+    pub fn describe(&self) -> EntityDescription {
+        let mut resp = ListEntitiesSensorResponse::new();
+        resp.set_unit_of_measurement(self.unit.clone());
+        resp.set_accuracy_decimals(self.accuracy_decimals);
+        resp.set_state_class(SensorStateClass::STATE_CLASS_MEASUREMENT);
+        self.base.describe(resp)
+    }
+
+    pub fn publish(&self, publisher: &StatePublisher, state: f32) {
+        let mut resp = SensorStateResponse::new();
+        resp.set_key(self.get_key());
+        resp.set_state(state);
+        publisher.publish(resp);
+    }
+}
+
+#[cfg(feature = "has_adc")]
+mod single {
+    use std::{fmt::Debug, time::Duration};
+
+    use log::*;
+
+    use super::SensorEntity;
+    use crate::{
+        components::{
+            entity::{EntityDescription, EntityMeta, StatePublisher},
+            Component,
+        },
+        error::{Error, Result},
+    };
+
+    const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// A driver the [`Sensor`] component can use
+    pub trait SensorDriver {
+        type Error: Debug;
+
+        /// Blocks until the measurement is done, `None` when there is nothing to publish (yet)
+        fn measure(&mut self) -> std::result::Result<Option<f32>, Self::Error>;
+    }
+
+    /// Applied to every measurement in order, like ESPHome's sensor filters
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Filter {
+        Multiply(f32),
+    }
+
+    impl Filter {
+        pub(in crate::components) fn apply(&self, value: f32) -> f32 {
+            match self {
+                Filter::Multiply(factor) => value * factor,
+            }
+        }
+    }
+
+    pub struct Sensor<D> {
+        driver: D,
+        entity: SensorEntity,
+        filters: Vec<Filter>,
+
+        publisher: StatePublisher,
+    }
+
+    impl<D: SensorDriver> Sensor<D> {
+        pub fn new(driver: D, name: String, publisher: StatePublisher) -> Sensor<D> {
+            Sensor {
+                driver,
+                entity: SensorEntity::new(name),
+                filters: vec![],
+
+                publisher,
+            }
+        }
+
+        pub fn with_meta(mut self, meta: EntityMeta) -> Self {
+            self.entity = self.entity.with_meta(meta);
+            self
+        }
+
+        pub fn with_unit(mut self, unit: &str, accuracy_decimals: i32) -> Self {
+            self.entity = self.entity.with_unit(unit, accuracy_decimals);
+            self
+        }
+
+        pub fn with_filter(mut self, filter: Filter) -> Self {
+            self.filters.push(filter);
+            self
+        }
+
+        fn publish(&mut self) -> Result<()> {
+            let value = self.driver.measure().map_err(|err| {
+                Error::component(
+                    self.entity.get_key(),
+                    format!(
+                        "{} failed to measure: {:?}",
+                        self.entity.base.get_name(),
+                        err
+                    ),
+                )
+            })?;
+
+            let value = value.map(|value| {
+                self.filters
+                    .iter()
+                    .fold(value, |value, filter| filter.apply(value))
+            });
+            match value {
+                Some(value) => {
+                    trace!("measured {:.1}{}", value, self.entity.unit);
+                    self.entity.publish(&self.publisher, value);
+                }
+                None => debug!("{} has no value to publish", self.entity.base.get_name()),
+            }
+            Ok(())
+        }
+    }
+
+    impl<D: SensorDriver + Send> Component for Sensor<D> {
+        fn get_description(&self) -> Vec<EntityDescription> {
+            vec![self.entity.describe()]
+        }
+
+        fn update(&mut self) -> Result<()> {
+            self.publish()
+        }
+
+        fn update_interval(&self) -> Option<Duration> {
+            Some(UPDATE_INTERVAL)
+        }
+
+        fn publish_state(&mut self) -> Result<()> {
+            self.publish()
+        }
+    }
+}
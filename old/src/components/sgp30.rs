@@ -0,0 +1,297 @@
+//! Sensirion SGP30, published through [`super::air_quality::AirQuality`]
+//!
+//! Commands are 16 bit, every 16 bit word the chip returns is followed by a CRC-8.
+
+use std::time::Duration;
+
+use embedded_hal_1::{delay::DelayNs, i2c::I2c};
+
+use super::{
+    air_quality::{AirQualityData, AirQualitySensor, DriverError},
+    compensation::Environment,
+};
+
+pub const ADDRESS: u8 = 0x58;
+
+// commands with the time they take in ms
+const CMD_GET_SERIAL_ID: (u16, u32) = (0x3682, 1);
+const CMD_GET_FEATURE_SET: (u16, u32) = (0x202f, 10);
+const CMD_IAQ_INIT: (u16, u32) = (0x2003, 10);
+const CMD_MEASURE_IAQ: (u16, u32) = (0x2008, 12);
+const CMD_GET_IAQ_BASELINE: (u16, u32) = (0x2015, 10);
+const CMD_SET_IAQ_BASELINE: (u16, u32) = (0x201e, 10);
+const CMD_SET_ABSOLUTE_HUMIDITY: (u16, u32) = (0x2061, 10);
+
+/// Upper nibble of the feature set
+const PRODUCT_TYPE_SGP30: u16 = 0;
+
+/// The on-chip algorithm expects a measurement every second
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Sgp30Driver<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    serial: u64,
+    product_version: u8,
+}
+
+impl<I2C: I2c, D: DelayNs> Sgp30Driver<I2C, D> {
+    /// Checks the chip and starts the air quality algorithm
+    pub fn new(
+        i2c: I2C,
+        delay: D,
+        address: u8,
+    ) -> std::result::Result<Self, DriverError<I2C::Error>> {
+        let mut driver = Sgp30Driver {
+            i2c,
+            delay,
+            address,
+            serial: 0,
+            product_version: 0,
+        };
+
+        let mut serial = [0; 3];
+        driver.read(CMD_GET_SERIAL_ID, &mut serial)?;
+        driver.serial = serial.iter().fold(0, |acc, word| acc << 16 | *word as u64);
+
+        let mut feature_set = [0];
+        driver.read(CMD_GET_FEATURE_SET, &mut feature_set)?;
+        if feature_set[0] >> 12 != PRODUCT_TYPE_SGP30 {
+            return Err(DriverError::UnknownChip((feature_set[0] >> 12) as u8));
+        }
+        driver.product_version = feature_set[0] as u8;
+
+        // the first 15 s only return 400 ppm and 0 ppb
+        driver.write(CMD_IAQ_INIT, &[])?;
+
+        Ok(driver)
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    fn write(
+        &mut self,
+        (cmd, ms): (u16, u32),
+        words: &[u16],
+    ) -> std::result::Result<(), DriverError<I2C::Error>> {
+        let mut buf = cmd.to_be_bytes().to_vec();
+        for word in words {
+            let bytes = word.to_be_bytes();
+            buf.extend_from_slice(&bytes);
+            buf.push(crc8(&bytes));
+        }
+        self.i2c.write(self.address, &buf)?;
+        self.delay.delay_ms(ms);
+        Ok(())
+    }
+
+    /// The chip needs time between command and reply, no repeated start
+    fn read(
+        &mut self,
+        cmd: (u16, u32),
+        words: &mut [u16],
+    ) -> std::result::Result<(), DriverError<I2C::Error>> {
+        self.write(cmd, &[])?;
+
+        let mut buf = vec![0; words.len() * 3];
+        self.i2c.read(self.address, &mut buf)?;
+        for (word, chunk) in words.iter_mut().zip(buf.chunks(3)) {
+            if crc8(&chunk[..2]) != chunk[2] {
+                return Err(DriverError::Crc);
+            }
+            *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(())
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> AirQualitySensor for Sgp30Driver<I2C, D> {
+    type Error = I2C::Error;
+
+    fn chip_name(&self) -> &'static str {
+        "SGP30"
+    }
+
+    fn firmware_version(&self) -> String {
+        format!("0x{:02x}", self.product_version)
+    }
+
+    fn sample_interval(&self) -> Duration {
+        SAMPLE_INTERVAL
+    }
+
+    fn warm_up(&self) -> Duration {
+        // the baseline belongs right after the init
+        Duration::ZERO
+    }
+
+    fn measure(&mut self) -> std::result::Result<Option<AirQualityData>, DriverError<I2C::Error>> {
+        let mut data = [0; 2];
+        self.read(CMD_MEASURE_IAQ, &mut data)?;
+        Ok(Some(AirQualityData {
+            eco2: data[0],
+            tvoc: data[1],
+        }))
+    }
+
+    fn baseline(&mut self) -> std::result::Result<u32, DriverError<I2C::Error>> {
+        let mut baseline = [0; 2];
+        self.read(CMD_GET_IAQ_BASELINE, &mut baseline)?;
+        Ok((baseline[0] as u32) << 16 | baseline[1] as u32)
+    }
+
+    fn set_baseline(&mut self, baseline: u32) -> std::result::Result<(), DriverError<I2C::Error>> {
+        // TVOC first, the reverse of reading it
+        let (eco2, tvoc) = ((baseline >> 16) as u16, baseline as u16);
+        self.write(CMD_SET_IAQ_BASELINE, &[tvoc, eco2])
+    }
+
+    fn set_environment(
+        &mut self,
+        environment: Environment,
+    ) -> std::result::Result<(), DriverError<I2C::Error>> {
+        // g/m³ as 8.8 fixed point, 0 would turn the compensation off
+        let humidity = (environment.absolute_humidity() * 256.).round();
+        let humidity = humidity.clamp(1., u16::MAX as f32) as u16;
+        self.write(CMD_SET_ABSOLUTE_HUMIDITY, &[humidity])
+    }
+}
+
+/// Sensirion's CRC-8, polynomial 0x31 starting at 0xff
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xff;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x31,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::{
+        delay::NoopDelay,
+        i2c::{Mock, Transaction},
+    };
+
+    use super::*;
+
+    /// Words as the chip sends them, each followed by its CRC
+    fn reply(words: &[u16]) -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|word| {
+                let bytes = word.to_be_bytes();
+                [bytes[0], bytes[1], crc8(&bytes)]
+            })
+            .collect()
+    }
+
+    fn init() -> Vec<Transaction> {
+        vec![
+            Transaction::write(ADDRESS, vec![0x36, 0x82]),
+            Transaction::read(ADDRESS, reply(&[0x0000, 0x0123, 0x4567])),
+            Transaction::write(ADDRESS, vec![0x20, 0x2f]),
+            Transaction::read(ADDRESS, reply(&[0x0022])),
+            Transaction::write(ADDRESS, vec![0x20, 0x03]),
+        ]
+    }
+
+    #[test]
+    fn crc() {
+        // example of the datasheet
+        assert_eq!(crc8(&[0xbe, 0xef]), 0x92);
+        assert_eq!(crc8(&[0x00, 0x00]), 0x81);
+    }
+
+    #[test]
+    fn probe() {
+        let mut i2c = Mock::new(&init());
+        let driver = Sgp30Driver::new(i2c.clone(), NoopDelay, ADDRESS).unwrap();
+        assert_eq!(driver.serial(), 0x0123_4567);
+        assert_eq!(driver.firmware_version(), "0x22");
+        i2c.done();
+
+        // an SGPC3
+        let mut transactions = init();
+        transactions.truncate(3);
+        transactions.push(Transaction::read(ADDRESS, reply(&[0x1006])));
+        let mut i2c = Mock::new(&transactions);
+        let res = Sgp30Driver::new(i2c.clone(), NoopDelay, ADDRESS);
+        assert!(matches!(res, Err(DriverError::UnknownChip(1))));
+        i2c.done();
+    }
+
+    #[test]
+    fn measure() {
+        let mut transactions = init();
+        transactions.extend([
+            Transaction::write(ADDRESS, vec![0x20, 0x08]),
+            Transaction::read(ADDRESS, reply(&[400, 42])),
+            Transaction::write(ADDRESS, vec![0x20, 0x08]),
+            Transaction::read(ADDRESS, vec![0x01, 0x90, 0x00, 0x00, 0x2a, 0x00]),
+        ]);
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Sgp30Driver::new(i2c.clone(), NoopDelay, ADDRESS).unwrap();
+        assert_eq!(
+            driver.measure().unwrap(),
+            Some(AirQualityData {
+                eco2: 400,
+                tvoc: 42
+            })
+        );
+        assert!(matches!(driver.measure(), Err(DriverError::Crc)));
+        i2c.done();
+    }
+
+    #[test]
+    fn baseline() {
+        let mut transactions = init();
+        transactions.extend([
+            Transaction::write(ADDRESS, vec![0x20, 0x15]),
+            Transaction::read(ADDRESS, reply(&[0x8a3c, 0x8f1e])),
+            // TVOC first
+            Transaction::write(
+                ADDRESS,
+                [vec![0x20, 0x1e], reply(&[0x8f1e, 0x8a3c])].concat(),
+            ),
+        ]);
+
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Sgp30Driver::new(i2c.clone(), NoopDelay, ADDRESS).unwrap();
+        let baseline = driver.baseline().unwrap();
+        assert_eq!(baseline, 0x8a3c_8f1e);
+        driver.set_baseline(baseline).unwrap();
+        i2c.done();
+    }
+
+    #[test]
+    fn absolute_humidity() {
+        let environment = Environment {
+            temperature: 25.,
+            humidity: 50.,
+        };
+        // 11.5 g/m³ in 8.8 fixed point
+        let humidity = (environment.absolute_humidity() * 256.).round() as u16;
+        assert!((2940..2950).contains(&humidity), "{}", humidity);
+
+        let mut transactions = init();
+        transactions.push(Transaction::write(
+            ADDRESS,
+            [vec![0x20, 0x61], reply(&[humidity])].concat(),
+        ));
+        let mut i2c = Mock::new(&transactions);
+        let mut driver = Sgp30Driver::new(i2c.clone(), NoopDelay, ADDRESS).unwrap();
+        driver.set_environment(environment).unwrap();
+        i2c.done();
+    }
+}
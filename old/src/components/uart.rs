@@ -0,0 +1,197 @@
+//! UART bus for serial protocols like Modbus RTU
+//!
+//! Protocols only see the [`Uart`] trait, [`EspUart`] runs it on one of the chip's UARTs, optionally as RS485 with
+//! the RTS pin driving the transceiver's DE/RE.
+
+use std::{ffi::c_void, fmt::Debug, ptr, time::Duration};
+
+use esp_idf_sys::*;
+
+/// Driver buffers, the RX buffer must be larger than the hardware FIFO (128 bytes)
+const RX_BUFFER_SIZE: i32 = 256;
+const TX_BUFFER_SIZE: i32 = 0;
+
+/// Write, wait, read
+pub trait Uart {
+    type Error: Debug;
+
+    /// Blocks until everything was sent
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Waits until `buf` is full or `timeout` passed, returns the number of bytes read
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Self::Error>;
+
+    /// Drops whatever was received so far, e.g. a late answer to an earlier request
+    fn clear_input(&mut self) -> Result<(), Self::Error>;
+
+    fn baud_rate(&self) -> u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl Parity {
+    /// ESPHome's names
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "NONE" => Some(Parity::None),
+            "EVEN" => Some(Parity::Even),
+            "ODD" => Some(Parity::Odd),
+            _ => None,
+        }
+    }
+}
+
+/// Like ESPHome's `uart` options, defaults to 9600 8N1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UartConfig {
+    pub baud_rate: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        UartConfig {
+            baud_rate: 9600,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+}
+
+impl UartConfig {
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: u8) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    /// Bits per character including start, parity and stop bits
+    pub fn char_bits(&self) -> u32 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            _ => 1,
+        };
+        1 + self.data_bits as u32 + parity + self.stop_bits as u32
+    }
+}
+
+pub struct EspUart {
+    port: uart_port_t,
+    config: UartConfig,
+}
+
+impl EspUart {
+    /// `rts` switches an RS485 transceiver between sending and receiving, `None` for plain UART
+    pub fn new(
+        port: uart_port_t,
+        tx: i32,
+        rx: i32,
+        rts: Option<i32>,
+        config: UartConfig,
+    ) -> Result<Self, EspError> {
+        let uart_config = uart_config_t {
+            baud_rate: config.baud_rate as i32,
+            data_bits: match config.data_bits {
+                5 => uart_word_length_t_UART_DATA_5_BITS,
+                6 => uart_word_length_t_UART_DATA_6_BITS,
+                7 => uart_word_length_t_UART_DATA_7_BITS,
+                _ => uart_word_length_t_UART_DATA_8_BITS,
+            },
+            parity: match config.parity {
+                Parity::None => uart_parity_t_UART_PARITY_DISABLE,
+                Parity::Even => uart_parity_t_UART_PARITY_EVEN,
+                Parity::Odd => uart_parity_t_UART_PARITY_ODD,
+            },
+            stop_bits: match config.stop_bits {
+                2 => uart_stop_bits_t_UART_STOP_BITS_2,
+                _ => uart_stop_bits_t_UART_STOP_BITS_1,
+            },
+            flow_ctrl: uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE,
+            ..Default::default()
+        };
+
+        esp!(unsafe {
+            uart_driver_install(port, RX_BUFFER_SIZE, TX_BUFFER_SIZE, 0, ptr::null_mut(), 0)
+        })?;
+        let uart = EspUart { port, config };
+
+        esp!(unsafe { uart_param_config(port, &uart_config) })?;
+        esp!(unsafe {
+            uart_set_pin(
+                port,
+                tx,
+                rx,
+                rts.unwrap_or(UART_PIN_NO_CHANGE),
+                UART_PIN_NO_CHANGE,
+            )
+        })?;
+        if rts.is_some() {
+            esp!(unsafe { uart_set_mode(port, uart_mode_t_UART_MODE_RS485_HALF_DUPLEX) })?;
+        }
+
+        Ok(uart)
+    }
+}
+
+impl Uart for EspUart {
+    type Error = EspError;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), EspError> {
+        let written =
+            unsafe { uart_write_bytes(self.port, data.as_ptr() as *const c_void, data.len() as _) };
+        if written < 0 {
+            return Err(EspError::from(ESP_FAIL).expect("ESP_FAIL is an error"));
+        }
+        esp!(unsafe { uart_wait_tx_done(self.port, TickType_t::MAX) })
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, EspError> {
+        // at least one tick, rounded up
+        let ticks = (timeout.as_millis() as u32 * configTICK_RATE_HZ).div_ceil(1000);
+        let read = unsafe {
+            uart_read_bytes(
+                self.port,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u32,
+                ticks.max(1),
+            )
+        };
+        match read {
+            read if read < 0 => Err(EspError::from(ESP_FAIL).expect("ESP_FAIL is an error")),
+            read => Ok(read as usize),
+        }
+    }
+
+    fn clear_input(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { uart_flush_input(self.port) })
+    }
+
+    fn baud_rate(&self) -> u32 {
+        self.config.baud_rate
+    }
+}
+
+impl Drop for EspUart {
+    fn drop(&mut self) {
+        unsafe { uart_driver_delete(self.port) };
+    }
+}
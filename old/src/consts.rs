@@ -44,6 +44,26 @@ pub enum MessageTypes {
     SubscribeHomeAssistantStatesRequest,
     SubscribeHomeAssistantStateResponse,
     HomeAssistantStateResponse, // = 40
+    ListEntitiesServicesResponse,
+    ExecuteServiceRequest,
+    ListEntitiesCameraResponse,
+    CameraImageResponse,
+    CameraImageRequest,
+    ListEntitiesClimateResponse,
+    ClimateStateResponse,
+    ClimateCommandRequest,
+    ListEntitiesNumberResponse,
+    NumberStateResponse, // = 50
+    NumberCommandRequest,
+    ListEntitiesSelectResponse,
+    SelectStateResponse,
+    SelectCommandRequest,
+    // 55 - 57 are not part of our api.proto
+    ListEntitiesLockResponse = 58,
+    LockStateResponse,
+    LockCommandRequest, // = 60
+    ListEntitiesButtonResponse,
+    ButtonCommandRequest,
 }
 
 impl From<u32> for MessageTypes {
@@ -89,11 +109,98 @@ impl From<u32> for MessageTypes {
             38 => Self::SubscribeHomeAssistantStatesRequest,
             39 => Self::SubscribeHomeAssistantStateResponse,
             40 => Self::HomeAssistantStateResponse,
+            41 => Self::ListEntitiesServicesResponse,
+            42 => Self::ExecuteServiceRequest,
+            43 => Self::ListEntitiesCameraResponse,
+            44 => Self::CameraImageResponse,
+            45 => Self::CameraImageRequest,
+            46 => Self::ListEntitiesClimateResponse,
+            47 => Self::ClimateStateResponse,
+            48 => Self::ClimateCommandRequest,
+            49 => Self::ListEntitiesNumberResponse,
+            50 => Self::NumberStateResponse,
+            51 => Self::NumberCommandRequest,
+            52 => Self::ListEntitiesSelectResponse,
+            53 => Self::SelectStateResponse,
+            54 => Self::SelectCommandRequest,
+            58 => Self::ListEntitiesLockResponse,
+            59 => Self::LockStateResponse,
+            60 => Self::LockCommandRequest,
+            61 => Self::ListEntitiesButtonResponse,
+            62 => Self::ButtonCommandRequest,
             _ => Self::Unkown,
         }
     }
 }
 
+impl MessageTypes {
+    /// Mirrors the `needs_setup_connection` method option of the `APIConnection` service in `api.proto`.
+    ///
+    /// Only messages handled by the service (i.e. sent by the client) are relevant here,
+    /// everything else is rejected anyway.
+    pub fn needs_setup_connection(&self) -> bool {
+        use MessageTypes::*;
+
+        !matches!(
+            self,
+            HelloRequest | ConnectRequest | DisconnectRequest | PingRequest
+        )
+    }
+
+    /// Mirrors the `needs_authentication` method option of the `APIConnection` service in `api.proto`.
+    pub fn needs_authentication(&self) -> bool {
+        use MessageTypes::*;
+
+        !matches!(
+            self,
+            HelloRequest
+                | ConnectRequest
+                | DisconnectRequest
+                | PingRequest
+                | DeviceInfoRequest
+                | GetTimeRequest
+        )
+    }
+
+    /// Whether the message is part of the `APIConnection` service and can be sent by a client.
+    ///
+    /// Responses to server initiated requests (`DisconnectResponse`, `PingResponse`, `GetTimeResponse`)
+    /// are accepted as well.
+    pub fn is_client_message(&self) -> bool {
+        use MessageTypes::*;
+
+        matches!(
+            self,
+            HelloRequest
+                | ConnectRequest
+                | DisconnectRequest
+                | DisconnectResponse
+                | PingRequest
+                | PingResponse
+                | DeviceInfoRequest
+                | ListEntitiesRequest
+                | SubscribeStatesRequest
+                | SubscribeLogsRequest
+                | CoverCommandRequest
+                | FanCommandRequest
+                | LightCommandRequest
+                | SwitchCommandRequest
+                | SubscribeHomeassistantServicesRequest
+                | GetTimeRequest
+                | GetTimeResponse
+                | SubscribeHomeAssistantStatesRequest
+                | HomeAssistantStateResponse
+                | ExecuteServiceRequest
+                | CameraImageRequest
+                | ClimateCommandRequest
+                | NumberCommandRequest
+                | SelectCommandRequest
+                | LockCommandRequest
+                | ButtonCommandRequest
+        )
+    }
+}
+
 impl Display for MessageTypes {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(f, "{} [{:?}]", *self as u32, self)
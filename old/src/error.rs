@@ -0,0 +1,156 @@
+use std::fmt::{Display, Formatter};
+
+use esp_idf_sys::EspError;
+
+use crate::{consts::MessageTypes, frame::FrameError};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What to do when an [`Error`] reaches the top of a task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// Close the offending connection, everything else keeps running
+    DropClient,
+    /// Transient problem, try again later (e.g. next tick)
+    Retry,
+    /// Re-initialise the component that raised the error
+    RestartComponent,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(TransportError),
+    Protocol(ProtocolError),
+    Auth(AuthError),
+    /// A component failed to produce or apply a state
+    Component { key: u32, reason: String },
+    /// A peripheral (GPIO, LEDC, I2C, ...) returned an error
+    Hardware(EspError),
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(std::io::Error),
+    /// Binding the listening socket failed
+    Bind(std::io::Error),
+    /// An internal channel was closed, the other side is gone
+    ChannelClosed,
+    /// Client did not answer our keepalive pings
+    KeepaliveTimeout,
+    /// Client acknowledged a disconnect
+    Disconnected,
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Frame(FrameError),
+    Protobuf(protobuf::ProtobufError),
+    /// Message is not allowed in the current connection state
+    IllegalCall(MessageTypes),
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidPassword,
+}
+
+impl Error {
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Error::Transport(TransportError::Bind(_)) => Recovery::Retry,
+            Error::Transport(_) | Error::Protocol(_) | Error::Auth(_) => Recovery::DropClient,
+            Error::Component { .. } => Recovery::Retry,
+            Error::Hardware(_) => Recovery::RestartComponent,
+        }
+    }
+
+    pub fn component(key: u32, reason: impl Into<String>) -> Self {
+        Error::Component {
+            key,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(err) => write!(f, "transport: {err}"),
+            Error::Protocol(err) => write!(f, "protocol: {err}"),
+            Error::Auth(err) => write!(f, "auth: {err}"),
+            Error::Component { key, reason } => write!(f, "component {key}: {reason}"),
+            Error::Hardware(err) => write!(f, "hardware: {err}"),
+        }
+    }
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Io(err) => write!(f, "{err}"),
+            TransportError::Bind(err) => write!(f, "failed to bind socket: {err}"),
+            TransportError::ChannelClosed => write!(f, "channel closed"),
+            TransportError::KeepaliveTimeout => write!(f, "keepalive timeout"),
+            TransportError::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Frame(err) => write!(f, "{err}"),
+            ProtocolError::Protobuf(err) => write!(f, "{err}"),
+            ProtocolError::IllegalCall(ty) => write!(f, "illegal call {ty}"),
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidPassword => write!(f, "invalid password"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Transport(TransportError::Io(err))
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::Io(err) => Error::Transport(TransportError::Io(err)),
+            err => Error::Protocol(ProtocolError::Frame(err)),
+        }
+    }
+}
+
+impl From<protobuf::ProtobufError> for Error {
+    fn from(err: protobuf::ProtobufError) -> Self {
+        Error::Protocol(ProtocolError::Protobuf(err))
+    }
+}
+
+impl<T> From<async_channel::SendError<T>> for Error {
+    fn from(_: async_channel::SendError<T>) -> Self {
+        Error::Transport(TransportError::ChannelClosed)
+    }
+}
+
+impl From<async_channel::RecvError> for Error {
+    fn from(_: async_channel::RecvError) -> Self {
+        Error::Transport(TransportError::ChannelClosed)
+    }
+}
+
+impl From<EspError> for Error {
+    fn from(err: EspError) -> Self {
+        Error::Hardware(err)
+    }
+}
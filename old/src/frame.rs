@@ -0,0 +1,376 @@
+//! Framing of the plaintext ESPHome API protocol
+//!
+//! Every frame looks like `0x00 | varuint(len) | varuint(type) | payload[len]`.
+//!
+//! This module only depends on `std` and `futures-lite`, so it can be pulled into the fuzz targets in `fuzz/` as is.
+
+use std::fmt::{Display, Formatter};
+
+use futures_lite::{AsyncRead, AsyncReadExt};
+
+/// Largest payload we are willing to buffer, anything bigger is treated as a protocol violation
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+/// A `u32` never needs more than 5 bytes
+const MAX_VARUINT_LEN: usize = 5;
+/// Bytes requested from the socket per read
+const READ_CHUNK: usize = 256;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// Peer closed the connection between two frames
+    Closed,
+    /// Peer closed the connection in the middle of a frame
+    UnexpectedEof,
+    Io(std::io::Error),
+
+    InvalidPreamble(u8),
+    /// Varuint is longer than 5 bytes or does not fit into a `u32`
+    InvalidVaruint,
+    /// Payload exceeds [`MAX_MESSAGE_SIZE`], [`FrameDecoder`] skips it and stays in sync
+    MessageTooLarge(FrameHeader),
+    /// Type 0 is not used by the protocol
+    InvalidType(u32),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "connection closed"),
+            FrameError::UnexpectedEof => write!(f, "connection closed mid frame"),
+            FrameError::Io(err) => write!(f, "io error: {err}"),
+            FrameError::InvalidPreamble(b) => write!(f, "invalid preamble 0x{b:02x}"),
+            FrameError::InvalidVaruint => write!(f, "invalid varuint"),
+            FrameError::MessageTooLarge(header) => write!(
+                f,
+                "message type {} with {} bytes exceeds {MAX_MESSAGE_SIZE} bytes",
+                header.ty, header.payload_len
+            ),
+            FrameError::InvalidType(ty) => write!(f, "invalid message type {ty}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted => {
+                FrameError::Closed
+            }
+            std::io::ErrorKind::UnexpectedEof => FrameError::UnexpectedEof,
+            _ => FrameError::Io(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub ty: u32,
+    /// Bytes used by preamble, length and type
+    pub header_len: usize,
+    pub payload_len: usize,
+}
+
+impl FrameHeader {
+    /// `u64` as the length of an oversized frame does not fit into a 32 bit `usize`
+    pub fn frame_len(&self) -> u64 {
+        self.header_len as u64 + self.payload_len as u64
+    }
+}
+
+pub fn encode_varuint(mut i: u32, buf: &mut Vec<u8>) {
+    loop {
+        let tmp = (i & 0x7f) as u8;
+        i >>= 7;
+        if i > 0 {
+            buf.push(tmp | 0x80);
+        } else {
+            buf.push(tmp);
+            return;
+        }
+    }
+}
+
+/// Returns the value and the number of bytes used, `None` when more input is needed
+pub fn decode_varuint(buf: &[u8]) -> Result<Option<(u32, usize)>, FrameError> {
+    let mut i = 0u32;
+
+    for (pos, b) in buf.iter().enumerate() {
+        if pos == MAX_VARUINT_LEN - 1 && *b > 0x0f {
+            // either a 6th byte follows or the value does not fit into 32 bits
+            return Err(FrameError::InvalidVaruint);
+        }
+
+        i |= ((b & 0x7f) as u32) << (7 * pos);
+
+        if (b & 0x80) == 0 {
+            return Ok(Some((i, pos + 1)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Parses the header at the start of `buf`, `None` when more input is needed
+///
+/// Fails as early as possible, i.e. an oversized length is rejected before its payload arrives.
+/// The size limit is only checked once the type is known, so the whole frame can be skipped.
+pub fn parse_header(buf: &[u8]) -> Result<Option<FrameHeader>, FrameError> {
+    let preamble = match buf.first() {
+        Some(b) => *b,
+        None => return Ok(None),
+    };
+    if preamble != 0 {
+        return Err(FrameError::InvalidPreamble(preamble));
+    }
+    let mut pos = 1;
+
+    let (len, used) = match decode_varuint(&buf[pos..])? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    pos += used;
+
+    let (ty, used) = match decode_varuint(&buf[pos..])? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    if ty == 0 {
+        return Err(FrameError::InvalidType(ty));
+    }
+    pos += used;
+
+    let header = FrameHeader {
+        ty,
+        header_len: pos,
+        payload_len: len as usize,
+    };
+    if header.payload_len > MAX_MESSAGE_SIZE {
+        return Err(FrameError::MessageTooLarge(header));
+    }
+
+    Ok(Some(header))
+}
+
+/// Parses one complete frame from the start of `buf` without copying the payload
+pub fn parse_frame(buf: &[u8]) -> Result<Option<(FrameHeader, &[u8])>, FrameError> {
+    match parse_header(buf)? {
+        Some(header) if buf.len() as u64 >= header.frame_len() => {
+            // the header is checked against `MAX_MESSAGE_SIZE`, so the frame fits into `buf`
+            let end = header.header_len + header.payload_len;
+            Ok(Some((header, &buf[header.header_len..end])))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn encode_frame(ty: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + 2 * MAX_VARUINT_LEN + payload.len());
+    frame.push(0);
+    encode_varuint(payload.len() as u32, &mut frame);
+    encode_varuint(ty, &mut frame);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Buffered frame reader
+///
+/// Reads the socket in chunks instead of byte by byte and hands out payloads as slices into its buffer.
+/// `fill` is cancel safe: data is only added to the buffer once a read completed.
+///
+/// Oversized messages are reported once and then skipped without buffering them, every other error is fatal for the connection.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Bytes to drop before the next frame starts, either the frame handed out last or an oversized one
+    consumed: u64,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends raw bytes, e.g. from a fuzzer or a test
+    pub fn push(&mut self, data: &[u8]) {
+        self.discard_consumed();
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next frame if it is already buffered completely
+    pub fn next_frame(&mut self) -> Result<Option<(u32, &[u8])>, FrameError> {
+        match self.poll_frame()? {
+            Some(header) => {
+                self.consumed = header.frame_len();
+                Ok(Some((
+                    header.ty,
+                    &self.buf[header.header_len..header.header_len + header.payload_len],
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads from `stream` until a complete frame is buffered, fetch it with [`FrameDecoder::next_frame`]
+    pub async fn fill<T: AsyncRead + Unpin>(&mut self, stream: &mut T) -> Result<(), FrameError> {
+        while self.poll_frame()?.is_none() {
+            let mut chunk = [0u8; READ_CHUNK];
+            let len = stream.read(&mut chunk).await?;
+            if len == 0 {
+                return Err(if self.buf.is_empty() && self.consumed == 0 {
+                    FrameError::Closed
+                } else {
+                    FrameError::UnexpectedEof
+                });
+            }
+            self.push(&chunk[..len]);
+        }
+
+        Ok(())
+    }
+
+    fn poll_frame(&mut self) -> Result<Option<FrameHeader>, FrameError> {
+        self.discard_consumed();
+        if self.consumed > 0 {
+            // still skipping an oversized frame
+            return Ok(None);
+        }
+
+        match parse_frame(&self.buf) {
+            Ok(frame) => Ok(frame.map(|(header, _)| header)),
+            Err(FrameError::MessageTooLarge(header)) => {
+                self.consumed = header.frame_len();
+                self.discard_consumed();
+                Err(FrameError::MessageTooLarge(header))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn discard_consumed(&mut self) {
+        let len = self.consumed.min(self.buf.len() as u64);
+        self.buf.drain(..len as usize);
+        self.consumed -= len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varuint() {
+        for (value, encoded) in [
+            (0u32, &[0x00][..]),
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (u32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ] {
+            let mut buf = vec![];
+            encode_varuint(value, &mut buf);
+            assert_eq!(buf, encoded);
+            assert_eq!(
+                decode_varuint(encoded).unwrap(),
+                Some((value, encoded.len()))
+            );
+        }
+
+        assert_eq!(decode_varuint(&[0x80]).unwrap(), None);
+        assert!(matches!(
+            decode_varuint(&[0xff, 0xff, 0xff, 0xff, 0x10]),
+            Err(FrameError::InvalidVaruint)
+        ));
+    }
+
+    #[test]
+    fn header_errors() {
+        assert!(matches!(
+            parse_header(&[0x01, 0x00, 0x01]),
+            Err(FrameError::InvalidPreamble(0x01))
+        ));
+        assert!(matches!(
+            parse_header(&[0x00, 0x00, 0x00]),
+            Err(FrameError::InvalidType(0))
+        ));
+        // rejected before the payload arrives
+        assert!(matches!(
+            parse_header(&[0x00, 0x80, 0x80, 0x04, 0x07]),
+            Err(FrameError::MessageTooLarge(FrameHeader {
+                ty: 7,
+                header_len: 5,
+                payload_len: 65536,
+            }))
+        ));
+    }
+
+    #[test]
+    fn decoder_partial_frames() {
+        let mut stream = encode_frame(7, b"hello");
+        stream.extend(encode_frame(300, b""));
+
+        let mut decoder = FrameDecoder::new();
+        for b in &stream[..4] {
+            decoder.push(&[*b]);
+            assert_eq!(decoder.next_frame().unwrap(), None);
+        }
+        decoder.push(&stream[4..]);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some((7, &b"hello"[..])));
+        assert_eq!(decoder.next_frame().unwrap(), Some((300, &b""[..])));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn decoder_skips_oversized() {
+        let mut decoder = FrameDecoder::new();
+        let oversized = encode_frame(7, &[0xaa; MAX_MESSAGE_SIZE + 1]);
+
+        decoder.push(&oversized[..100]);
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::MessageTooLarge(_))
+        ));
+        decoder.push(&oversized[100..]);
+        decoder.push(&encode_frame(8, b"ok"));
+
+        assert_eq!(decoder.next_frame().unwrap(), Some((8, &b"ok"[..])));
+    }
+
+    #[test]
+    fn decoder_skips_largest_length() {
+        // u32::MAX, the frame is longer than a 32 bit usize can count
+        let header = [0x00, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x07];
+        let mut decoder = FrameDecoder::new();
+
+        decoder.push(&header);
+        match decoder.next_frame() {
+            Err(FrameError::MessageTooLarge(header)) => {
+                assert_eq!(header.payload_len, u32::MAX as usize);
+                assert_eq!(header.frame_len(), u32::MAX as u64 + 7);
+            }
+            res => panic!("unexpected {res:?}"),
+        }
+
+        // still skipping
+        decoder.push(&encode_frame(8, b"ok"));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        assert_eq!(decoder.consumed, u32::MAX as u64 - 5);
+    }
+
+    #[test]
+    fn fill_from_stream() {
+        let mut stream = encode_frame(7, b"hello");
+        stream.push(0x00);
+        let mut reader = futures_lite::io::Cursor::new(stream);
+        let mut decoder = FrameDecoder::new();
+
+        futures_lite::future::block_on(decoder.fill(&mut reader)).unwrap();
+        assert_eq!(decoder.next_frame().unwrap(), Some((7, &b"hello"[..])));
+
+        let res = futures_lite::future::block_on(decoder.fill(&mut reader));
+        assert!(matches!(res, Err(FrameError::UnexpectedEof)));
+    }
+}
@@ -0,0 +1,327 @@
+//! Minimal HTTP/1.0 client for plain `http://` GET requests, e.g. firmware manifests and images
+//!
+//! HTTP/1.0 keeps it simple, servers answer with a plain body (no chunked encoding) and close the connection when
+//! done. There is no TLS, whatever is downloaded has to be verified on its own.
+
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use log::*;
+
+use crate::{NAME, VERSION};
+
+/// Longest status line or header
+const MAX_LINE: usize = 1024;
+const MAX_HEADERS: usize = 32;
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// Not an `http://` URL
+    Url(String),
+    Io(io::Error),
+    /// Anything but 2xx
+    Status(u16),
+    Response(String),
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> Self {
+        HttpError::Io(err)
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::Url(url) => write!(f, "unsupported URL {url}"),
+            HttpError::Io(err) => write!(f, "{err}"),
+            HttpError::Status(status) => write!(f, "HTTP status {status}"),
+            HttpError::Response(reason) => write!(f, "invalid response: {reason}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Starts with `/`, including the query
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, HttpError> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| HttpError::Url(url.to_owned()))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| HttpError::Url(url.to_owned()))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(HttpError::Url(url.to_owned()));
+        }
+        Ok(Url {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+
+    /// `reference` may be absolute, absolute to the host (`/fw.bin`) or relative to this URL's directory
+    pub fn join(&self, reference: &str) -> Result<Url, HttpError> {
+        if reference.contains("://") {
+            return Url::parse(reference);
+        }
+        let path = match reference.starts_with('/') {
+            true => reference.to_owned(),
+            false => {
+                let dir = &self.path[..=self.path.rfind('/').unwrap_or_default()];
+                format!("{dir}{reference}")
+            }
+        };
+        Ok(Url {
+            path,
+            ..self.clone()
+        })
+    }
+}
+
+impl Display for Url {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Status line and headers, the body follows in `reader`
+pub fn read_head<R: BufRead>(reader: &mut R) -> Result<(u16, Option<usize>), HttpError> {
+    let mut line = String::new();
+    let mut read_line = |line: &mut String| -> Result<(), HttpError> {
+        line.clear();
+        reader.take(MAX_LINE as u64).read_line(line)?;
+        if !line.ends_with('\n') {
+            return Err(HttpError::Response(String::from(
+                "line too long or truncated",
+            )));
+        }
+        Ok(())
+    };
+
+    read_line(&mut line)?;
+    // e.g. "HTTP/1.0 200 OK"
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .filter(|_| line.starts_with("HTTP/"))
+        .ok_or_else(|| HttpError::Response(format!("status line {:?}", line.trim_end())))?;
+
+    let mut content_length = None;
+    for _ in 0..MAX_HEADERS {
+        read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            return match status {
+                200..=299 => Ok((status, content_length)),
+                status => Err(HttpError::Status(status)),
+            };
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(
+                    value
+                        .parse()
+                        .map_err(|_| HttpError::Response(format!("content length {value:?}")))?,
+                );
+            } else if name.eq_ignore_ascii_case("transfer-encoding")
+                && !value.eq_ignore_ascii_case("identity")
+            {
+                return Err(HttpError::Response(format!("transfer encoding {value}")));
+            }
+        }
+    }
+    Err(HttpError::Response(String::from("too many headers")))
+}
+
+pub struct Response {
+    pub content_length: Option<usize>,
+    reader: BufReader<TcpStream>,
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// `timeout` applies to connecting and to every single read
+pub fn get(url: &Url, timeout: Duration) -> Result<Response, HttpError> {
+    debug!("GET {}", url);
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| HttpError::Url(url.to_string()))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}/{}\r\nConnection: close\r\n\r\n",
+        url.path, url.host, NAME, VERSION
+    )?;
+
+    let mut reader = BufReader::new(stream);
+    let (_, content_length) = read_head(&mut reader)?;
+    Ok(Response {
+        content_length,
+        reader,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{net::TcpListener, thread::JoinHandle};
+
+    use super::*;
+
+    /// Answers one connection after the other with `responses`, returns the request heads
+    pub fn serve(responses: Vec<Vec<u8>>) -> (Url, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    if reader.read_line(&mut request).unwrap() == 0 {
+                        break;
+                    }
+                }
+                requests.push(request);
+                stream.write_all(&response).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    pub fn response(status: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.0 {status}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn url() {
+        let url = Url::parse("http://example.com:8080/fw/manifest.txt?v=1").unwrap();
+        assert_eq!(url.host, "example.com");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/fw/manifest.txt?v=1");
+        assert_eq!(
+            url.to_string(),
+            "http://example.com:8080/fw/manifest.txt?v=1"
+        );
+
+        let url = Url::parse("http://192.168.1.2").unwrap();
+        assert_eq!((url.port, url.path.as_str()), (80, "/"));
+
+        for url in [
+            "https://example.com/",
+            "http://:80/",
+            "http://example.com:http/",
+        ] {
+            assert!(matches!(Url::parse(url), Err(HttpError::Url(_))), "{url}");
+        }
+    }
+
+    #[test]
+    fn join() {
+        let url = Url::parse("http://example.com/fw/manifest.txt").unwrap();
+        assert_eq!(
+            url.join("image.bin").unwrap().to_string(),
+            "http://example.com:80/fw/image.bin"
+        );
+        assert_eq!(
+            url.join("/image.bin").unwrap().to_string(),
+            "http://example.com:80/image.bin"
+        );
+        assert_eq!(
+            url.join("http://mirror:8000/image.bin")
+                .unwrap()
+                .to_string(),
+            "http://mirror:8000/image.bin"
+        );
+    }
+
+    #[test]
+    fn head() {
+        let head = |text: &str| read_head(&mut text.as_bytes());
+
+        assert_eq!(
+            head("HTTP/1.1 200 OK\r\ncontent-length: 42\r\n\r\nbody").unwrap(),
+            (200, Some(42))
+        );
+        assert_eq!(head("HTTP/1.0 204 No Content\n\n").unwrap(), (204, None));
+        assert!(matches!(
+            head("HTTP/1.0 404 Not Found\r\n\r\n"),
+            Err(HttpError::Status(404))
+        ));
+        assert!(matches!(
+            head("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Err(HttpError::Response(_))
+        ));
+        assert!(matches!(
+            head("SSH-2.0-OpenSSH\r\n"),
+            Err(HttpError::Response(_))
+        ));
+        // the connection closed in the middle of the headers
+        assert!(matches!(
+            head("HTTP/1.0 200 OK\r\nContent-Length: 4"),
+            Err(HttpError::Response(_))
+        ));
+    }
+
+    #[test]
+    fn get_body() {
+        let (url, server) = serve(vec![response("200 OK", b"version = 0.0.8\n")]);
+        let url = url.join("fw/manifest.txt").unwrap();
+
+        let mut response = get(&url, Duration::from_secs(5)).unwrap();
+        assert_eq!(response.content_length, Some(16));
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "version = 0.0.8\n");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /fw/manifest.txt HTTP/1.0\r\n"));
+        assert!(requests[0].contains("\r\nHost: 127.0.0.1\r\n"));
+    }
+
+    #[test]
+    fn get_status() {
+        let (url, server) = serve(vec![response("404 Not Found", b"")]);
+        assert!(matches!(
+            get(&url, Duration::from_secs(5)),
+            Err(HttpError::Status(404))
+        ));
+        server.join().unwrap();
+    }
+}
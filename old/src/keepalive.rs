@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+
+// from ESPHome (api_connection.cpp)
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+/// 2.5 times the interval
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(150);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeepaliveAction {
+    /// Nothing to do until the given point in time
+    Wait(Instant),
+    /// Client was idle for too long, send a `PingRequest`
+    SendPing,
+    /// Client did not answer our ping, close the connection
+    Disconnect,
+}
+
+/// Keepalive policy of a single client connection
+///
+/// Does not read the clock itself, the current time is always passed in.
+/// This keeps the timing logic independent from the executor and allows driving it with a virtual clock.
+#[derive(Debug)]
+pub struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+
+    last_traffic: Instant,
+    ping_sent: bool,
+}
+
+impl Keepalive {
+    pub fn new(now: Instant) -> Self {
+        Self::with_timings(now, KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT)
+    }
+
+    pub fn with_timings(now: Instant, interval: Duration, timeout: Duration) -> Self {
+        Keepalive {
+            interval,
+            timeout,
+            last_traffic: now,
+            ping_sent: false,
+        }
+    }
+
+    /// Any received packet counts as a sign of life
+    pub fn on_traffic(&mut self, now: Instant) {
+        self.last_traffic = now;
+        self.ping_sent = false;
+    }
+
+    pub fn poll(&mut self, now: Instant) -> KeepaliveAction {
+        let idle = now.saturating_duration_since(self.last_traffic);
+
+        if idle >= self.timeout {
+            return KeepaliveAction::Disconnect;
+        }
+
+        if self.ping_sent {
+            return KeepaliveAction::Wait(self.last_traffic + self.timeout);
+        }
+
+        if idle >= self.interval {
+            self.ping_sent = true;
+            return KeepaliveAction::SendPing;
+        }
+
+        KeepaliveAction::Wait(self.last_traffic + self.interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(10);
+    const TIMEOUT: Duration = Duration::from_secs(25);
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn ping_then_disconnect() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::with_timings(start, INTERVAL, TIMEOUT);
+
+        assert_eq!(
+            keepalive.poll(start),
+            KeepaliveAction::Wait(start + INTERVAL)
+        );
+        assert_eq!(
+            keepalive.poll(start + secs(9)),
+            KeepaliveAction::Wait(start + INTERVAL)
+        );
+        assert_eq!(keepalive.poll(start + INTERVAL), KeepaliveAction::SendPing);
+        // only one ping per idle period
+        assert_eq!(
+            keepalive.poll(start + secs(11)),
+            KeepaliveAction::Wait(start + TIMEOUT)
+        );
+        assert_eq!(
+            keepalive.poll(start + secs(24)),
+            KeepaliveAction::Wait(start + TIMEOUT)
+        );
+        assert_eq!(keepalive.poll(start + TIMEOUT), KeepaliveAction::Disconnect);
+        assert_eq!(
+            keepalive.poll(start + secs(60)),
+            KeepaliveAction::Disconnect
+        );
+    }
+
+    #[test]
+    fn traffic_resets_ping() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::with_timings(start, INTERVAL, TIMEOUT);
+
+        keepalive.on_traffic(start + secs(8));
+        assert_eq!(
+            keepalive.poll(start + INTERVAL),
+            KeepaliveAction::Wait(start + secs(18))
+        );
+
+        assert_eq!(keepalive.poll(start + secs(18)), KeepaliveAction::SendPing);
+        // the client answered the ping
+        keepalive.on_traffic(start + secs(20));
+        assert_eq!(
+            keepalive.poll(start + secs(21)),
+            KeepaliveAction::Wait(start + secs(30))
+        );
+        assert_eq!(keepalive.poll(start + secs(30)), KeepaliveAction::SendPing);
+    }
+
+    #[test]
+    fn clock_before_traffic() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::with_timings(start + secs(5), INTERVAL, TIMEOUT);
+
+        assert_eq!(
+            keepalive.poll(start),
+            KeepaliveAction::Wait(start + secs(15))
+        );
+    }
+
+    #[test]
+    fn esphome_timings() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(start);
+
+        assert_eq!(
+            keepalive.poll(start + KEEPALIVE_INTERVAL),
+            KeepaliveAction::SendPing
+        );
+        assert_eq!(
+            keepalive.poll(start + KEEPALIVE_TIMEOUT),
+            KeepaliveAction::Disconnect
+        );
+    }
+}
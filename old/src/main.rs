@@ -5,12 +5,9 @@ use std::time::Duration;
 
 use anyhow::*;
 use async_net::Ipv4Addr;
-use consts::MessageTypes;
 // use esp_idf_svc::log::EspLogger;
 use log::*;
 
-use protobuf::Message;
-
 use smol;
 
 // use embedded_svc::anyerror::*;
@@ -23,7 +20,6 @@ use esp_idf_svc::ping::*;
 use esp_idf_svc::sysloop::*;
 use esp_idf_svc::wifi::*;
 
-const MAC: &str = "<MAC>"; // ESP32-C3-13
 // const SSID: &str = env!("RUST_ESP32_STD_HELLO_WIFI_SSID");
 // const PASS: &str = env!("RUST_ESP32_STD_HELLO_WIFI_PASS");
 const SSID: &str = "<SSID>";
@@ -38,15 +34,43 @@ const CLIENT_PW: &str = "test1234"; // empty for none
 
 const PORT: u16 = 6053;
 
+const OTA_PW: &str = "test1234"; // empty for none
+const OTA_PORT: u16 = 3232;
+
+// how often safe mode is logged
+const SAFE_MODE_REMINDER: Duration = Duration::from_secs(60);
+
+// NVS namespace of the component states
+const PREFERENCES_NAMESPACE: &str = "esphome";
+
+// ESPHome's API uses the node name, MQTT discovery optionally the MAC
+const UNIQUE_ID_GENERATOR: utils::UniqueIdGenerator = if cfg!(feature = "unique_id_mac") {
+    utils::UniqueIdGenerator::Mac
+} else {
+    utils::UniqueIdGenerator::NodeName
+};
+
 mod api;
+mod auth;
 mod client;
 mod components;
 mod consts;
+mod error;
+mod frame;
+mod http;
+mod keepalive;
+mod ota;
+mod preferences;
+mod safe_mode;
 
 mod server;
 mod utils;
 
-use components::ComponentManager;
+use auth::Authenticator;
+use components::{
+    entity::{EntityDescription, StatePublisher},
+    ComponentManager,
+};
 
 pub struct Device {
     pub mac: String,
@@ -57,9 +81,12 @@ pub struct Device {
     pub project_version: String,
     pub server_name: String,
 
-    pub password: String,
+    pub auth: Authenticator,
+
+    /// Why only the bare minimum is running, see [`safe_mode`]
+    pub safe_mode: Option<String>,
 
-    pub component_description: Vec<(MessageTypes, Arc<Box<dyn Message>>)>,
+    pub component_description: Vec<EntityDescription>,
 }
 
 fn main() -> Result<()> {
@@ -82,11 +109,39 @@ fn main() -> Result<()> {
     // // Bind the log crate to the ESP Logging facilities
     // EspLogger::initialize_default();
     components::logger::EspHomeLogger::initialize_default();
+    ota::log_boot();
+    safe_mode::install_panic_hook();
+
+    let last_reset = safe_mode::last_reset();
+    info!("reset reason: {}", last_reset);
 
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
     let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
+    // NVS is initialised by now
+    let backend: Box<dyn preferences::PreferenceBackend> =
+        match preferences::NvsBackend::open(PREFERENCES_NAMESPACE) {
+            Ok(backend) => Box::new(backend),
+            Err(err) => {
+                error!("failed to open preferences, nothing survives a reboot: {err}");
+                Box::new(preferences::MemoryBackend::new())
+            }
+        };
+    let preferences = preferences::Preferences::new(backend).shared();
+
+    let safe_mode = safe_mode::register_boot(
+        &mut preferences.lock().expect("lock poisened!"),
+        &last_reset,
+    )
+    .unwrap_or_else(|err| {
+        error!("failed to count the boot: {err}");
+        None
+    });
+    if let Some(reason) = &safe_mode {
+        warn!("starting in safe mode: {reason}");
+    }
+
     let wifi = wifi(
         netif_stack.clone(),
         sys_loop_stack.clone(),
@@ -118,21 +173,51 @@ fn main() -> Result<()> {
         }
     };
 
-    run_esphome(&ip);
+    run_esphome(&ip, preferences.clone(), safe_mode);
 
     drop(wifi);
     info!("Wifi stopped");
 
+    if ota::update_pending() {
+        // a new firmware gets all attempts
+        if let Err(err) = safe_mode::mark_stable(&mut preferences.lock().expect("lock poisened!")) {
+            warn!("failed to reset the boot counter: {err}");
+        }
+        info!("rebooting into the update");
+        unsafe { esp_idf_sys::esp_restart() };
+    }
+
     Ok(())
 }
 
-fn run_esphome(ip: &Ipv4Addr) {
-    // initialise components
-    let mut comp_mngr = Box::new(ComponentManager::new());
+/// Factory programmed base MAC
+pub fn get_mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    // only fails for a null pointer
+    unsafe { esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+    mac
+}
+
+fn run_esphome(
+    ip: &Ipv4Addr,
+    preferences: preferences::SharedPreferences,
+    safe_mode: Option<String>,
+) {
+    // server communication channels, components publish their states directly to the server
+    let (client_send, client_recv) = async_channel::unbounded();
+
+    // initialise components, none of them in safe mode
+    let comp_mngr = match safe_mode {
+        Some(_) => ComponentManager::empty(preferences.clone()),
+        None => ComponentManager::new(
+            StatePublisher::new(client_send.clone()),
+            preferences.clone(),
+        ),
+    };
 
     // create high level device
     let device = Arc::new(Device {
-        mac: String::from(MAC), // TODO
+        mac: utils::mac_to_string(&get_mac(), ":").to_uppercase(),
 
         model: String::from(MODEL),
         name: String::from(NAME),
@@ -140,7 +225,9 @@ fn run_esphome(ip: &Ipv4Addr) {
         project_version: String::from(VERSION),
         server_name: String::from(NAME) + " on " + MODEL,
 
-        password: String::from(CLIENT_PW),
+        auth: Authenticator::new(String::from(CLIENT_PW)),
+
+        safe_mode,
 
         component_description: comp_mngr.get_descriptions(),
     });
@@ -151,9 +238,40 @@ fn run_esphome(ip: &Ipv4Addr) {
         warn!("failed to setup mDNS: {}", err);
     }
 
+    let device_safe_mode = device.safe_mode.clone();
+
     // setup server
     smol::block_on(async {
-        let server = server::EspHomeApiServer::new(device, comp_mngr);
+        let server = server::EspHomeApiServer::new(device, comp_mngr, client_send, client_recv);
+
+        if let Err(err) = ota::spawn(String::from(OTA_PW), server.shutdown_handle()) {
+            error!("failed to start OTA: {}", err);
+        }
+        // a boot that made it this far is fine: a fresh update is kept (a reboot before rolls it back) and the
+        // failed boots are forgotten
+        smol::spawn(async move {
+            async_io::Timer::after(ota::CONFIRM_AFTER).await;
+            if let Err(err) = ota::mark_valid() {
+                warn!("failed to mark the update valid: {}", err);
+            }
+            if let Err(err) =
+                safe_mode::mark_stable(&mut preferences.lock().expect("lock poisened!"))
+            {
+                warn!("failed to reset the boot counter: {}", err);
+            }
+        })
+        .detach();
+        if let Some(reason) = device_safe_mode {
+            // repeated for whoever subscribes to the logs later
+            smol::spawn(async move {
+                loop {
+                    warn!("safe mode: {}", reason);
+                    async_io::Timer::after(SAFE_MODE_REMINDER).await;
+                }
+            })
+            .detach();
+        }
+
         let _server = Box::new(server).run_asyn().await;
     });
 }
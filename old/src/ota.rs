@@ -0,0 +1,696 @@
+//! ESPHome's native OTA protocol, so `esphome upload` (or the dashboard) can flash the device over WiFi
+//!
+//! Updates are written to the inactive `ota_0`/`ota_1` partition and booted once ESP-IDF validated the image.
+//! A fresh image starts as pending verification, [`mark_valid`] keeps it, a reboot before that rolls back.
+//! [`OtaSession`] only needs a stream and an [`OtaTarget`], so the protocol also runs against a local TCP client.
+//! Everything calling into ESP-IDF lives in the `esp` submodule, only built for the device.
+
+use std::{
+    fmt::{Debug, Display, Formatter},
+    io::{self, Read, Write},
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use log::*;
+
+use crate::auth::constant_time_eq;
+
+#[cfg(target_os = "espidf")]
+pub use esp::{log_boot, mark_valid, spawn, update_pending, EspOtaTarget};
+
+const MAGIC: [u8; 5] = [0x6c, 0x26, 0xf7, 0x5c, 0x45];
+const VERSION: u8 = 1;
+/// ESPHome reads and writes in chunks of this size
+const CHUNK_SIZE: usize = 1024;
+/// An update that runs this long without a reboot is kept
+pub const CONFIRM_AFTER: Duration = Duration::from_secs(60);
+
+// responses, from ESPHome's ota_component.h
+const RESPONSE_OK: u8 = 0x00;
+const RESPONSE_REQUEST_AUTH: u8 = 0x01;
+const RESPONSE_HEADER_OK: u8 = 0x40;
+const RESPONSE_AUTH_OK: u8 = 0x41;
+const RESPONSE_UPDATE_PREPARE_OK: u8 = 0x42;
+const RESPONSE_BIN_MD5_OK: u8 = 0x43;
+const RESPONSE_RECEIVE_OK: u8 = 0x44;
+const RESPONSE_UPDATE_END_OK: u8 = 0x45;
+
+const RESPONSE_ERROR_MAGIC: u8 = 0x80;
+const RESPONSE_ERROR_UPDATE_PREPARE: u8 = 0x81;
+const RESPONSE_ERROR_AUTH_INVALID: u8 = 0x82;
+const RESPONSE_ERROR_WRITING_FLASH: u8 = 0x83;
+const RESPONSE_ERROR_UPDATE_END: u8 = 0x84;
+const RESPONSE_ERROR_NOT_ENOUGH_SPACE: u8 = 0x89;
+const RESPONSE_ERROR_NO_UPDATE_PARTITION: u8 = 0x8a;
+const RESPONSE_ERROR_MD5_MISMATCH: u8 = 0x8b;
+const RESPONSE_ERROR_UNKNOWN: u8 = 0xff;
+
+/// Set while an update is written, see [`UpdateGuard`]
+static UPDATING: AtomicBool = AtomicBool::new(false);
+
+/// Keeps push and pull updates from writing the inactive partition at the same time
+pub struct UpdateGuard(());
+
+impl UpdateGuard {
+    /// `None` while another update runs
+    pub fn take() -> Option<Self> {
+        UPDATING
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| UpdateGuard(()))
+    }
+
+    /// Never releases the guard, a finished update must not be overwritten before the reboot
+    pub fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for UpdateGuard {
+    fn drop(&mut self) {
+        UPDATING.store(false, Ordering::Release);
+    }
+}
+
+/// Where an update goes
+pub trait OtaTarget {
+    type Error: Debug;
+
+    /// Prepares (erases) room for `size` bytes
+    fn begin(&mut self, size: usize) -> Result<(), OtaError<Self::Error>>;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Checks the image
+    fn finish(&mut self) -> Result<(), Self::Error>;
+
+    /// Version from the app description of the finished image
+    fn version(&self) -> Result<String, Self::Error>;
+
+    /// Boots the finished image next time
+    fn set_boot(&mut self) -> Result<(), Self::Error>;
+
+    /// Drops a partially written image
+    fn abort(&mut self);
+}
+
+#[derive(Debug)]
+pub enum OtaError<E> {
+    Io(io::Error),
+    /// Another update is being written
+    Busy,
+    /// Not an OTA client, e.g. a port scan
+    Magic,
+    Auth,
+    NoUpdatePartition,
+    NotEnoughSpace {
+        size: usize,
+        available: usize,
+    },
+    Prepare(E),
+    Write(E),
+    Md5Mismatch,
+    /// The image is broken or for another chip
+    End(E),
+}
+
+impl<E> OtaError<E> {
+    /// What the client is told before the connection closes
+    pub fn response(&self) -> u8 {
+        match self {
+            OtaError::Io(_) => RESPONSE_ERROR_UNKNOWN,
+            OtaError::Busy => RESPONSE_ERROR_UPDATE_PREPARE,
+            OtaError::Magic => RESPONSE_ERROR_MAGIC,
+            OtaError::Auth => RESPONSE_ERROR_AUTH_INVALID,
+            OtaError::NoUpdatePartition => RESPONSE_ERROR_NO_UPDATE_PARTITION,
+            OtaError::NotEnoughSpace { .. } => RESPONSE_ERROR_NOT_ENOUGH_SPACE,
+            OtaError::Prepare(_) => RESPONSE_ERROR_UPDATE_PREPARE,
+            OtaError::Write(_) => RESPONSE_ERROR_WRITING_FLASH,
+            OtaError::Md5Mismatch => RESPONSE_ERROR_MD5_MISMATCH,
+            OtaError::End(_) => RESPONSE_ERROR_UPDATE_END,
+        }
+    }
+}
+
+impl<E> From<io::Error> for OtaError<E> {
+    fn from(err: io::Error) -> Self {
+        OtaError::Io(err)
+    }
+}
+
+impl<E: Debug> Display for OtaError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OtaError::Io(err) => write!(f, "connection: {err}"),
+            OtaError::Busy => write!(f, "another update is in progress"),
+            OtaError::Magic => write!(f, "invalid magic bytes"),
+            OtaError::Auth => write!(f, "authentication failed"),
+            OtaError::NoUpdatePartition => write!(f, "no OTA partition to update"),
+            OtaError::NotEnoughSpace { size, available } => {
+                write!(
+                    f,
+                    "image of {size} bytes exceeds the {available} bytes available"
+                )
+            }
+            OtaError::Prepare(err) => write!(f, "failed to prepare the update: {err:?}"),
+            OtaError::Write(err) => write!(f, "failed to write: {err:?}"),
+            OtaError::Md5Mismatch => write!(f, "MD5 mismatch"),
+            OtaError::End(err) => write!(f, "image rejected: {err:?}"),
+        }
+    }
+}
+
+/// One upload, from the magic bytes to the final acknowledgement
+pub struct OtaSession<'a, S, T> {
+    stream: S,
+    target: &'a mut T,
+    /// Empty for none
+    password: &'a str,
+}
+
+impl<'a, S: Read + Write, T: OtaTarget> OtaSession<'a, S, T> {
+    pub fn new(stream: S, target: &'a mut T, password: &'a str) -> Self {
+        OtaSession {
+            stream,
+            target,
+            password,
+        }
+    }
+
+    /// `nonce` is the 32 hex digit auth challenge, returns the size of the image that will boot next
+    pub fn run(mut self, nonce: &str) -> Result<usize, OtaError<T::Error>> {
+        let result = self.update(nonce);
+        if let Err(err) = &result {
+            // the client may be gone already
+            let _ = self.stream.write_all(&[err.response()]);
+        }
+        result
+    }
+
+    fn update(&mut self, nonce: &str) -> Result<usize, OtaError<T::Error>> {
+        let mut magic = [0; 5];
+        self.stream.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(OtaError::Magic);
+        }
+        self.stream.write_all(&[RESPONSE_OK, VERSION])?;
+
+        // compression is the only feature, we don't support it
+        let mut features = [0; 1];
+        self.stream.read_exact(&mut features)?;
+        self.stream.write_all(&[RESPONSE_HEADER_OK])?;
+
+        self.authenticate(nonce)?;
+
+        let mut size = [0; 4];
+        self.stream.read_exact(&mut size)?;
+        let size = u32::from_be_bytes(size) as usize;
+        info!("receiving update of {} bytes", size);
+
+        self.target.begin(size)?;
+        let result = self.receive(size);
+        if result.is_err() {
+            self.target.abort();
+        }
+        result?;
+
+        // not fatal, the client may close right away
+        let mut ack = [0; 1];
+        if self.stream.read_exact(&mut ack).is_err() || ack[0] != RESPONSE_OK {
+            warn!("no final acknowledgement from the client");
+        }
+        Ok(size)
+    }
+
+    /// The client answers with its own nonce and `md5(password + nonce + cnonce)`, all as lowercase hex
+    fn authenticate(&mut self, nonce: &str) -> Result<(), OtaError<T::Error>> {
+        if self.password.is_empty() {
+            self.stream.write_all(&[RESPONSE_AUTH_OK])?;
+            return Ok(());
+        }
+
+        self.stream.write_all(&[RESPONSE_REQUEST_AUTH])?;
+        self.stream.write_all(nonce.as_bytes())?;
+
+        let mut cnonce = [0; 32];
+        self.stream.read_exact(&mut cnonce)?;
+        let mut result = [0; 32];
+        self.stream.read_exact(&mut result)?;
+
+        let mut expected = md5::Context::new();
+        expected.consume(self.password.as_bytes());
+        expected.consume(nonce.as_bytes());
+        expected.consume(cnonce);
+        let expected = format!("{:x}", expected.compute());
+        if !constant_time_eq(&result, expected.as_bytes()) {
+            warn!("OTA authentication failed");
+            return Err(OtaError::Auth);
+        }
+        self.stream.write_all(&[RESPONSE_AUTH_OK])?;
+        Ok(())
+    }
+
+    fn receive(&mut self, size: usize) -> Result<(), OtaError<T::Error>> {
+        self.stream.write_all(&[RESPONSE_UPDATE_PREPARE_OK])?;
+
+        let mut md5 = [0; 32];
+        self.stream.read_exact(&mut md5)?;
+        self.stream.write_all(&[RESPONSE_BIN_MD5_OK])?;
+
+        let mut digest = md5::Context::new();
+        let mut buf = [0; CHUNK_SIZE];
+        let mut received = 0;
+        let mut last_percent = 0;
+        while received < size {
+            let len = (size - received).min(CHUNK_SIZE);
+            let read = match self.stream.read(&mut buf[..len])? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                read => read,
+            };
+            digest.consume(&buf[..read]);
+            self.target.write(&buf[..read]).map_err(OtaError::Write)?;
+            received += read;
+
+            let percent = received * 100 / size;
+            if percent / 10 != last_percent / 10 {
+                info!("OTA progress {}%", percent);
+            }
+            last_percent = percent;
+        }
+        self.stream.write_all(&[RESPONSE_RECEIVE_OK])?;
+
+        if !md5.eq_ignore_ascii_case(format!("{:x}", digest.compute()).as_bytes()) {
+            return Err(OtaError::Md5Mismatch);
+        }
+        self.target.finish().map_err(OtaError::End)?;
+        self.target.set_boot().map_err(OtaError::End)?;
+        self.stream.write_all(&[RESPONSE_UPDATE_END_OK])?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use std::{
+        ffi::CStr,
+        io::{self, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        ptr, thread,
+        time::Duration,
+    };
+
+    use esp_idf_sys::*;
+    use log::*;
+
+    use super::{OtaError, OtaSession, OtaTarget, UpdateGuard};
+    use crate::{server::ShutdownHandle, OTA_PORT};
+
+    /// Longest pause of the client in the middle of a session
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Erasing the partition and the image check need more than the default 4k
+    const STACK_SIZE: usize = 8 * 1024;
+
+    /// The app partition that isn't running, through ESP-IDF's OTA API
+    pub struct EspOtaTarget {
+        partition: *const esp_partition_t,
+        handle: Option<esp_ota_handle_t>,
+    }
+
+    impl EspOtaTarget {
+        pub fn new() -> Result<Self, OtaError<EspError>> {
+            let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+            if partition.is_null() {
+                return Err(OtaError::NoUpdatePartition);
+            }
+            Ok(EspOtaTarget {
+                partition,
+                handle: None,
+            })
+        }
+    }
+
+    impl OtaTarget for EspOtaTarget {
+        type Error = EspError;
+
+        fn begin(&mut self, size: usize) -> Result<(), OtaError<EspError>> {
+            let available = unsafe { (*self.partition).size } as usize;
+            if size > available {
+                return Err(OtaError::NotEnoughSpace { size, available });
+            }
+
+            // erases as much as needed, which takes a few seconds
+            let mut handle = 0;
+            esp!(unsafe { esp_ota_begin(self.partition, size as _, &mut handle) })
+                .map_err(OtaError::Prepare)?;
+            self.handle = Some(handle);
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), EspError> {
+            let handle = self.handle.unwrap_or_default();
+            esp!(unsafe { esp_ota_write(handle, data.as_ptr() as *const _, data.len() as _) })
+        }
+
+        fn finish(&mut self) -> Result<(), EspError> {
+            let handle = self.handle.take().unwrap_or_default();
+            // validates the image, the handle is gone either way
+            esp!(unsafe { esp_ota_end(handle) })
+        }
+
+        fn version(&self) -> Result<String, EspError> {
+            let mut desc = esp_app_desc_t::default();
+            esp!(unsafe { esp_ota_get_partition_description(self.partition, &mut desc) })?;
+            Ok(unsafe { CStr::from_ptr(desc.version.as_ptr()) }
+                .to_string_lossy()
+                .into_owned())
+        }
+
+        fn set_boot(&mut self) -> Result<(), EspError> {
+            esp!(unsafe { esp_ota_set_boot_partition(self.partition) })
+        }
+
+        fn abort(&mut self) {
+            if let Some(handle) = self.handle.take() {
+                unsafe { esp_ota_abort(handle) };
+            }
+        }
+    }
+
+    impl Drop for EspOtaTarget {
+        fn drop(&mut self) {
+            self.abort();
+        }
+    }
+
+    fn partition_label(partition: *const esp_partition_t) -> String {
+        match partition.is_null() {
+            true => String::from("?"),
+            false => unsafe { CStr::from_ptr((*partition).label.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Logs which slot runs and whether an update was rolled back
+    pub fn log_boot() {
+        let running = unsafe { esp_ota_get_running_partition() };
+        info!("running from partition {}", partition_label(running));
+
+        let invalid = unsafe { esp_ota_get_last_invalid_partition() };
+        if !invalid.is_null() {
+            warn!(
+                "the update in partition {} failed to boot and was rolled back",
+                partition_label(invalid)
+            );
+        }
+    }
+
+    /// Keeps a freshly updated image, otherwise the bootloader rolls back on the next reboot
+    pub fn mark_valid() -> Result<(), EspError> {
+        let running = unsafe { esp_ota_get_running_partition() };
+        let mut state = 0;
+        // fails for the factory partition or without otadata, nothing to roll back then
+        if unsafe { esp_ota_get_state_partition(running, &mut state) } != ESP_OK as esp_err_t {
+            return Ok(());
+        }
+        if state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+            esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() })?;
+            info!("update marked valid");
+        }
+        Ok(())
+    }
+
+    /// Whether the next boot runs another image than the current one
+    pub fn update_pending() -> bool {
+        unsafe { esp_ota_get_boot_partition() != esp_ota_get_running_partition() }
+    }
+
+    /// Nonce for the auth challenge, like ESPHome an MD5 over a random number
+    fn nonce() -> String {
+        let random = unsafe { esp_random() };
+        format!("{:x}", md5::compute(format!("{random:08x}")))
+    }
+
+    fn handle(mut stream: TcpStream, password: &str) -> Result<usize, OtaError<EspError>> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let guard = match UpdateGuard::take() {
+            Some(guard) => guard,
+            None => {
+                let _ = stream.write_all(&[OtaError::<EspError>::Busy.response()]);
+                return Err(OtaError::Busy);
+            }
+        };
+        let mut target = EspOtaTarget::new()?;
+        let size = OtaSession::new(stream, &mut target, password).run(&nonce())?;
+        guard.keep();
+        Ok(size)
+    }
+
+    /// Serves one upload at a time on [`OTA_PORT`], a successful one shuts the server down for the reboot
+    pub fn spawn(password: String, shutdown: ShutdownHandle) -> io::Result<()> {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], OTA_PORT)))?;
+        info!("OTA listening on port {}", OTA_PORT);
+
+        thread::Builder::new()
+            .name(String::from("ota"))
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            warn!("failed to accept OTA connection: {err}");
+                            continue;
+                        }
+                    };
+                    info!("OTA connection from {:?}", stream.peer_addr());
+
+                    match handle(stream, &password) {
+                        Ok(size) => {
+                            info!("update of {} bytes done, rebooting", size);
+                            smol::block_on(shutdown.shutdown());
+                            break;
+                        }
+                        Err(err) => error!("OTA failed: {}", err),
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+
+    const NONCE: &str = "0123456789abcdef0123456789abcdef";
+    const CNONCE: &[u8; 32] = b"fedcba9876543210fedcba9876543210";
+
+    #[derive(Debug, Default)]
+    pub struct FakeTarget {
+        pub size: Option<usize>,
+        pub written: Vec<u8>,
+        pub finished: bool,
+        pub booted: bool,
+        pub aborted: bool,
+        /// Of the app description
+        pub version: String,
+    }
+
+    impl OtaTarget for FakeTarget {
+        type Error = ();
+
+        fn begin(&mut self, size: usize) -> Result<(), OtaError<()>> {
+            self.size = Some(size);
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+            self.written.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), ()> {
+            self.finished = true;
+            Ok(())
+        }
+
+        fn version(&self) -> Result<String, ()> {
+            Ok(self.version.clone())
+        }
+
+        fn set_boot(&mut self) -> Result<(), ()> {
+            self.booted = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.aborted = true;
+        }
+    }
+
+    /// Runs a session against `client` over a loopback connection
+    fn session(
+        password: &str,
+        client: impl FnOnce(TcpStream) + Send + 'static,
+    ) -> (Result<usize, OtaError<()>>, FakeTarget) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || client(TcpStream::connect(address).unwrap()));
+
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut target = FakeTarget::default();
+        let result = OtaSession::new(stream, &mut target, password).run(NONCE);
+        client.join().unwrap();
+        (result, target)
+    }
+
+    fn expect(stream: &mut TcpStream, expected: &[u8]) {
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    /// Like `espota2.py` up to the authentication
+    fn handshake(stream: &mut TcpStream) {
+        stream.write_all(&MAGIC).unwrap();
+        expect(stream, &[RESPONSE_OK, VERSION]);
+        // no compression
+        stream.write_all(&[0x00]).unwrap();
+        expect(stream, &[RESPONSE_HEADER_OK]);
+    }
+
+    fn authenticate(stream: &mut TcpStream, password: &str) {
+        expect(stream, &[RESPONSE_REQUEST_AUTH]);
+        expect(stream, NONCE.as_bytes());
+        let mut result = md5::Context::new();
+        result.consume(password.as_bytes());
+        result.consume(NONCE.as_bytes());
+        result.consume(CNONCE);
+        stream.write_all(CNONCE).unwrap();
+        stream
+            .write_all(format!("{:x}", result.compute()).as_bytes())
+            .unwrap();
+    }
+
+    /// Sends `image` with the MD5 of `md5_of`
+    fn upload(stream: &mut TcpStream, image: &[u8], md5_of: &[u8]) {
+        stream
+            .write_all(&(image.len() as u32).to_be_bytes())
+            .unwrap();
+        expect(stream, &[RESPONSE_UPDATE_PREPARE_OK]);
+        stream
+            .write_all(format!("{:x}", md5::compute(md5_of)).as_bytes())
+            .unwrap();
+        expect(stream, &[RESPONSE_BIN_MD5_OK]);
+        stream.write_all(image).unwrap();
+        expect(stream, &[RESPONSE_RECEIVE_OK]);
+    }
+
+    fn image() -> Vec<u8> {
+        // more than a chunk
+        (0..3000).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn without_password() {
+        let (result, target) = session("", |mut stream| {
+            handshake(&mut stream);
+            expect(&mut stream, &[RESPONSE_AUTH_OK]);
+            upload(&mut stream, &image(), &image());
+            expect(&mut stream, &[RESPONSE_UPDATE_END_OK]);
+            stream.write_all(&[RESPONSE_OK]).unwrap();
+        });
+        assert_eq!(result.unwrap(), 3000);
+        assert_eq!(target.size, Some(3000));
+        assert_eq!(target.written, image());
+        assert!(target.finished && target.booted);
+        assert!(!target.aborted);
+    }
+
+    #[test]
+    fn with_password() {
+        let (result, target) = session("secret", |mut stream| {
+            handshake(&mut stream);
+            authenticate(&mut stream, "secret");
+            expect(&mut stream, &[RESPONSE_AUTH_OK]);
+            upload(&mut stream, &image(), &image());
+            expect(&mut stream, &[RESPONSE_UPDATE_END_OK]);
+            // the final acknowledgement is optional
+        });
+        assert_eq!(result.unwrap(), 3000);
+        assert!(target.finished);
+    }
+
+    #[test]
+    fn wrong_password() {
+        let (result, target) = session("secret", |mut stream| {
+            handshake(&mut stream);
+            authenticate(&mut stream, "guessed");
+            expect(&mut stream, &[RESPONSE_ERROR_AUTH_INVALID]);
+        });
+        assert!(matches!(result, Err(OtaError::Auth)));
+        assert_eq!(target.size, None);
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let (result, target) = session("", |mut stream| {
+            stream.write_all(b"GET /").unwrap();
+            expect(&mut stream, &[RESPONSE_ERROR_MAGIC]);
+        });
+        assert!(matches!(result, Err(OtaError::Magic)));
+        assert_eq!(target.size, None);
+    }
+
+    #[test]
+    fn md5_mismatch() {
+        let (result, target) = session("", |mut stream| {
+            handshake(&mut stream);
+            expect(&mut stream, &[RESPONSE_AUTH_OK]);
+            upload(&mut stream, &image(), b"another image");
+            expect(&mut stream, &[RESPONSE_ERROR_MD5_MISMATCH]);
+        });
+        assert!(matches!(result, Err(OtaError::Md5Mismatch)));
+        assert!(!target.finished && !target.booted);
+        assert!(target.aborted);
+    }
+
+    #[test]
+    fn connection_lost() {
+        let (result, target) = session("", |mut stream| {
+            handshake(&mut stream);
+            expect(&mut stream, &[RESPONSE_AUTH_OK]);
+            stream.write_all(&3000u32.to_be_bytes()).unwrap();
+            expect(&mut stream, &[RESPONSE_UPDATE_PREPARE_OK]);
+            stream
+                .write_all(format!("{:x}", md5::compute(image())).as_bytes())
+                .unwrap();
+            expect(&mut stream, &[RESPONSE_BIN_MD5_OK]);
+            stream.write_all(&image()[..1000]).unwrap();
+        });
+        assert!(matches!(result, Err(OtaError::Io(_))));
+        assert_eq!(target.written, image()[..1000]);
+        assert!(target.aborted);
+    }
+
+    #[test]
+    fn guard() {
+        let guard = UpdateGuard::take().unwrap();
+        assert!(UpdateGuard::take().is_none());
+        drop(guard);
+        assert!(UpdateGuard::take().is_some());
+    }
+}
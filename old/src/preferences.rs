@@ -0,0 +1,469 @@
+//! Component state that survives a reboot
+//!
+//! Records are keyed by the entity key (the object_id hash), like ESPHome's `make_preference`.
+//! Flash wears out, so changes are only kept in RAM at first: repeated saves of a key coalesce into one write,
+//! unchanged records are never rewritten and everything that is pending goes out together every [`FLUSH_INTERVAL`]
+//! (and on shutdown). NVS spreads the remaining writes over its pages.
+//!
+//! Every record starts with a CRC-32 of the key and the data, a torn or foreign record is ignored instead of
+//! restoring garbage.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    ptr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys::*;
+use log::*;
+
+use crate::{error::Result, utils::crc32};
+
+/// ESPHome's default `flash_write_interval`
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type SharedPreferences = Arc<Mutex<Preferences>>;
+
+/// Where records end up, the data is opaque to the backend
+pub trait PreferenceBackend: Send {
+    /// `None` when nothing was written under `key`
+    fn read(&self, key: u32) -> Result<Option<Vec<u8>>>;
+
+    /// Does not need to be persistent before [`PreferenceBackend::commit`]
+    fn write(&mut self, key: u32, record: &[u8]) -> Result<()>;
+
+    fn commit(&mut self) -> Result<()>;
+}
+
+/// A value that can be stored, the encoding must stay the same across firmware updates
+pub trait Preference: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// `None` for data that does not make sense (anymore)
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! preference_le_bytes {
+    ($($ty:ty),*) => {
+        $(
+            impl Preference for $ty {
+                fn to_bytes(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn from_bytes(bytes: &[u8]) -> Option<Self> {
+                    Some(<$ty>::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+preference_le_bytes!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Preference for bool {
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Preference for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+pub struct Preferences {
+    backend: Box<dyn PreferenceBackend>,
+
+    /// Records waiting for the next flush, only the latest one per key
+    pending: HashMap<u32, Vec<u8>>,
+    /// CRC of every record the backend holds, to skip rewriting unchanged ones
+    stored: HashMap<u32, u32>,
+    /// Oldest change that was not flushed yet
+    dirty_since: Option<Instant>,
+}
+
+impl Preferences {
+    pub fn new(backend: Box<dyn PreferenceBackend>) -> Self {
+        Preferences {
+            backend,
+            pending: HashMap::new(),
+            stored: HashMap::new(),
+            dirty_since: None,
+        }
+    }
+
+    pub fn shared(self) -> SharedPreferences {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Latest saved value, even when it was not flushed yet
+    pub fn load<T: Preference>(&mut self, key: u32) -> Option<T> {
+        if let Some(record) = self.pending.get(&key) {
+            return T::from_bytes(&record[4..]);
+        }
+
+        let record = match self.backend.read(key) {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(err) => {
+                warn!("failed to load preference {:08x}: {}", key, err);
+                return None;
+            }
+        };
+        let crc = checksum(key, record.get(4..).unwrap_or_default());
+        if record.get(..4) != Some(&crc.to_le_bytes()[..]) {
+            warn!("preference {:08x} is corrupt, ignoring it", key);
+            return None;
+        }
+
+        self.stored.insert(key, crc);
+        T::from_bytes(&record[4..])
+    }
+
+    /// Only queues the value, see [`Preferences::flush_due`]
+    pub fn save<T: Preference>(&mut self, key: u32, value: &T, now: Instant) {
+        let data = value.to_bytes();
+        let crc = checksum(key, &data);
+
+        if self.stored.get(&key) == Some(&crc) {
+            // changed back before it was flushed
+            self.pending.remove(&key);
+            if self.pending.is_empty() {
+                self.dirty_since = None;
+            }
+            return;
+        }
+
+        let mut record = crc.to_le_bytes().to_vec();
+        record.extend(data);
+        self.pending.insert(key, record);
+        self.dirty_since.get_or_insert(now);
+    }
+
+    /// When [`Preferences::flush_due`] has something to do, `None` while nothing is pending
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.dirty_since.map(|since| since + FLUSH_INTERVAL)
+    }
+
+    pub fn flush_due(&mut self, now: Instant) -> Result<()> {
+        match self.next_flush() {
+            Some(at) if at <= now => {
+                let res = self.flush();
+                if res.is_err() {
+                    // try again next interval
+                    self.dirty_since = Some(now);
+                }
+                res
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes everything that is pending right away, e.g. before a reboot
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut keys: Vec<u32> = self.pending.keys().copied().collect();
+        keys.sort_unstable();
+        for key in keys {
+            let record = &self.pending[&key];
+            self.backend.write(key, record)?;
+
+            let crc = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+            self.stored.insert(key, crc);
+            self.pending.remove(&key);
+        }
+        self.backend.commit()?;
+
+        debug!("preferences flushed");
+        self.dirty_since = None;
+        Ok(())
+    }
+}
+
+/// Covers the key too, a record that ended up under another key is invalid
+fn checksum(key: u32, data: &[u8]) -> u32 {
+    let mut bytes = key.to_le_bytes().to_vec();
+    bytes.extend_from_slice(data);
+    crc32(&bytes)
+}
+
+/// Records as blobs in an NVS namespace
+pub struct NvsBackend {
+    handle: nvs_handle_t,
+}
+
+impl NvsBackend {
+    /// NVS must be initialised already, e.g. by `EspDefaultNvs`
+    pub fn open(namespace: &str) -> Result<Self> {
+        let namespace = CString::new(namespace).expect("namespace contains a nul byte");
+        let mut handle = 0;
+        esp!(unsafe {
+            nvs_open(
+                namespace.as_ptr(),
+                nvs_open_mode_t_NVS_READWRITE,
+                &mut handle,
+            )
+        })?;
+
+        Ok(NvsBackend { handle })
+    }
+
+    /// NVS keys are limited to 15 characters
+    fn name(key: u32) -> CString {
+        CString::new(format!("{:08x}", key)).expect("hex never contains a nul byte")
+    }
+}
+
+impl PreferenceBackend for NvsBackend {
+    fn read(&self, key: u32) -> Result<Option<Vec<u8>>> {
+        let name = Self::name(key);
+
+        // first call only returns the length
+        let mut len = 0;
+        let res = unsafe { nvs_get_blob(self.handle, name.as_ptr(), ptr::null_mut(), &mut len) };
+        if res == ESP_ERR_NVS_NOT_FOUND as esp_err_t {
+            return Ok(None);
+        }
+        esp!(res)?;
+
+        let mut data = vec![0u8; len as usize];
+        esp!(unsafe {
+            nvs_get_blob(
+                self.handle,
+                name.as_ptr(),
+                data.as_mut_ptr() as *mut _,
+                &mut len,
+            )
+        })?;
+        data.truncate(len as usize);
+
+        Ok(Some(data))
+    }
+
+    fn write(&mut self, key: u32, record: &[u8]) -> Result<()> {
+        let name = Self::name(key);
+
+        esp!(unsafe {
+            nvs_set_blob(
+                self.handle,
+                name.as_ptr(),
+                record.as_ptr() as *const _,
+                record.len() as _,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        esp!(unsafe { nvs_commit(self.handle) })?;
+        Ok(())
+    }
+}
+
+impl Drop for NvsBackend {
+    fn drop(&mut self) {
+        unsafe { nvs_close(self.handle) };
+    }
+}
+
+/// Nothing survives, the fallback when NVS cannot be opened
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    records: HashMap<u32, Vec<u8>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PreferenceBackend for MemoryBackend {
+    fn read(&self, key: u32) -> Result<Option<Vec<u8>>> {
+        Ok(self.records.get(&key).cloned())
+    }
+
+    fn write(&mut self, key: u32, record: &[u8]) -> Result<()> {
+        self.records.insert(key, record.to_vec());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{ComponentManager, ComponentUpdate};
+
+    /// Hands out the records and counts the writes while the preferences own the backend
+    #[derive(Clone, Default)]
+    struct SharedBackend(Arc<Mutex<(MemoryBackend, usize)>>);
+
+    impl SharedBackend {
+        fn writes(&self) -> usize {
+            self.0.lock().unwrap().1
+        }
+
+        fn corrupt(&self, key: u32) {
+            let mut inner = self.0.lock().unwrap();
+            let record = inner.0.records.get_mut(&key).unwrap();
+            record[4] ^= 0xff;
+        }
+    }
+
+    impl PreferenceBackend for SharedBackend {
+        fn read(&self, key: u32) -> Result<Option<Vec<u8>>> {
+            self.0.lock().unwrap().0.read(key)
+        }
+
+        fn write(&mut self, key: u32, record: &[u8]) -> Result<()> {
+            let mut inner = self.0.lock().unwrap();
+            inner.1 += 1;
+            inner.0.write(key, record)
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            self.0.lock().unwrap().0.commit()
+        }
+    }
+
+    fn preferences() -> (Preferences, SharedBackend) {
+        let backend = SharedBackend::default();
+        (Preferences::new(Box::new(backend.clone())), backend)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (mut preferences, backend) = preferences();
+        let now = Instant::now();
+        preferences.save(1, &42u32, now);
+        preferences.save(2, &"test".to_owned(), now);
+        preferences.save(3, &true, now);
+
+        // pending values are visible right away
+        assert_eq!(preferences.load(1), Some(42u32));
+        preferences.flush().unwrap();
+
+        let mut restored = Preferences::new(Box::new(backend));
+        assert_eq!(restored.load(1), Some(42u32));
+        assert_eq!(restored.load(2), Some("test".to_owned()));
+        assert_eq!(restored.load(3), Some(true));
+        assert_eq!(restored.load::<u32>(4), None);
+        // wrong type
+        assert_eq!(restored.load::<u8>(1), None);
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let (mut preferences, backend) = preferences();
+        preferences.save(1, &42u32, Instant::now());
+        preferences.flush().unwrap();
+
+        backend.corrupt(1);
+        let mut restored = Preferences::new(Box::new(backend));
+        assert_eq!(restored.load::<u32>(1), None);
+    }
+
+    #[test]
+    fn record_under_other_key() {
+        let (mut preferences, backend) = preferences();
+        preferences.save(1, &42u32, Instant::now());
+        preferences.flush().unwrap();
+
+        let record = backend.read(1).unwrap().unwrap();
+        let mut moved = backend.clone();
+        moved.write(2, &record).unwrap();
+        let mut restored = Preferences::new(Box::new(backend));
+        assert_eq!(restored.load::<u32>(2), None);
+    }
+
+    #[test]
+    fn writes_coalesce() {
+        let (mut preferences, backend) = preferences();
+        let start = Instant::now();
+        for value in 0..10u32 {
+            preferences.save(1, &value, start + Duration::from_secs(value as u64));
+        }
+
+        // the interval starts with the first change
+        assert_eq!(preferences.next_flush(), Some(start + FLUSH_INTERVAL));
+        preferences
+            .flush_due(start + FLUSH_INTERVAL - Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(backend.writes(), 0);
+
+        preferences.flush_due(start + FLUSH_INTERVAL).unwrap();
+        assert_eq!(backend.writes(), 1);
+        assert_eq!(preferences.next_flush(), None);
+        assert_eq!(preferences.load(1), Some(9u32));
+    }
+
+    #[test]
+    fn unchanged_records_are_not_rewritten() {
+        let (mut preferences, backend) = preferences();
+        let now = Instant::now();
+        preferences.save(1, &42u32, now);
+        preferences.flush().unwrap();
+        assert_eq!(backend.writes(), 1);
+
+        preferences.save(1, &42u32, now);
+        assert_eq!(preferences.next_flush(), None);
+
+        // changed and back before the flush
+        preferences.save(1, &43u32, now);
+        preferences.save(1, &42u32, now);
+        assert_eq!(preferences.next_flush(), None);
+        preferences.flush().unwrap();
+        assert_eq!(backend.writes(), 1);
+
+        // also after a reboot, once the record was loaded
+        let mut restored = Preferences::new(Box::new(backend.clone()));
+        assert_eq!(restored.load(1), Some(42u32));
+        restored.save(1, &42u32, now);
+        restored.flush().unwrap();
+        assert_eq!(backend.writes(), 1);
+    }
+
+    #[test]
+    fn flush_on_shutdown() {
+        let (mut preferences, backend) = preferences();
+        preferences.save(1, &42u32, Instant::now());
+        preferences.save(2, &43u32, Instant::now());
+        let preferences = preferences.shared();
+
+        // the next flush is a minute away, stopping the components writes everything right away
+        let (send, recv) = async_channel::unbounded();
+        let done = ComponentManager::empty(preferences.clone())
+            .spawn(recv)
+            .unwrap();
+        send.try_send(ComponentUpdate::Shutdown).unwrap();
+        assert!(smol::block_on(done.recv()).is_err());
+        assert_eq!(backend.writes(), 2);
+        assert_eq!(preferences.lock().unwrap().next_flush(), None);
+
+        let mut restored = Preferences::new(Box::new(backend));
+        assert_eq!(restored.load(2), Some(43u32));
+    }
+}
@@ -0,0 +1,145 @@
+//! Boot loop protection, like ESPHome's `safe_mode`
+//!
+//! Every boot counts as failed until the device ran for a while (see `main.rs`). After [`MAX_FAILED_BOOTS`] of them
+//! in a row the next boot skips all components and only brings up WiFi, the API server and OTA, so a broken firmware
+//! can still be replaced remotely. The counter lives in the preferences (NVS), power cycles and brownouts count too.
+//! Entering safe mode clears it, the boot after that tries the full firmware again.
+//!
+//! The message of a panic is kept in RTC memory, which survives the reset, to tell why the last boot failed.
+
+use std::{panic, ptr, time::Instant};
+
+use esp_idf_sys::*;
+use log::*;
+
+use crate::{
+    error::Result,
+    preferences::Preferences,
+    utils::{crc32, name_to_hash},
+};
+
+/// ESPHome's default `num_attempts`
+pub const MAX_FAILED_BOOTS: u32 = 10;
+
+const PANIC_MAGIC: u32 = 0x5041_4e43;
+const PANIC_MESSAGE_SIZE: usize = 120;
+
+/// Not touched by the bootloader or a reset, garbage after power on
+#[repr(C)]
+struct PanicRecord {
+    magic: u32,
+    crc: u32,
+    len: usize,
+    message: [u8; PANIC_MESSAGE_SIZE],
+}
+
+#[link_section = ".rtc_noinit"]
+static mut LAST_PANIC: PanicRecord = PanicRecord {
+    magic: 0,
+    crc: 0,
+    len: 0,
+    message: [0; PANIC_MESSAGE_SIZE],
+};
+
+fn counter_key() -> u32 {
+    name_to_hash("safe mode failed boots")
+}
+
+/// Counts this boot as failed until [`mark_stable`], returns the reason when it has to run in safe mode
+///
+/// `last_reset` tells how the previous boot ended, see [`last_reset`].
+pub fn register_boot(preferences: &mut Preferences, last_reset: &str) -> Result<Option<String>> {
+    let key = counter_key();
+    let failed = preferences.load::<u32>(key).unwrap_or_default();
+
+    let safe_mode = failed >= MAX_FAILED_BOOTS;
+    let next = match safe_mode {
+        true => 0,
+        false => failed + 1,
+    };
+    preferences.save(key, &next, Instant::now());
+    // a crash right after boot must not lose the count
+    preferences.flush()?;
+
+    if !safe_mode {
+        if failed > 0 {
+            info!("{} failed boot(s) in a row before this one", failed);
+        }
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{failed} failed boots in a row, last reset: {last_reset}"
+    )))
+}
+
+/// The firmware runs fine, resets the counter
+pub fn mark_stable(preferences: &mut Preferences) -> Result<()> {
+    preferences.save(counter_key(), &0u32, Instant::now());
+    preferences.flush()
+}
+
+/// Keeps the message of a panic for the next boot, the default hook still prints it
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        record_panic(&info.to_string());
+        default_hook(info);
+    }));
+}
+
+fn record_panic(message: &str) {
+    // cut at a char boundary
+    let mut len = message.len().min(PANIC_MESSAGE_SIZE);
+    while !message.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    // only written here and read once at boot, before any other thread runs
+    let record = unsafe { &mut *ptr::addr_of_mut!(LAST_PANIC) };
+    record.message[..len].copy_from_slice(&message.as_bytes()[..len]);
+    record.len = len;
+    record.crc = crc32(&record.message[..len]);
+    record.magic = PANIC_MAGIC;
+}
+
+/// How the previous boot ended, e.g. `brownout` or `panic: <message>`
+pub fn last_reset() -> String {
+    let reason = unsafe { esp_reset_reason() };
+    // a panic in a thread that was caught or did not take the device down is not the reason
+    match take_last_panic() {
+        Some(message) if reason == esp_reset_reason_t_ESP_RST_PANIC => format!("panic: {message}"),
+        _ => String::from(reset_reason_name(reason)),
+    }
+}
+
+/// Message of the last recorded panic, only once
+fn take_last_panic() -> Option<String> {
+    let record = unsafe { &mut *ptr::addr_of_mut!(LAST_PANIC) };
+    if record.magic != PANIC_MAGIC {
+        return None;
+    }
+    record.magic = 0;
+
+    let message = record.message.get(..record.len)?;
+    if crc32(message) != record.crc {
+        return None;
+    }
+    Some(String::from_utf8_lossy(message).into_owned())
+}
+
+fn reset_reason_name(reason: esp_reset_reason_t) -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match reason {
+        esp_reset_reason_t_ESP_RST_POWERON => "power on",
+        esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        esp_reset_reason_t_ESP_RST_SW => "software restart",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "SDIO",
+        _ => "unknown",
+    }
+}
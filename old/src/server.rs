@@ -1,133 +1,273 @@
 use async_channel::{Receiver, Sender};
 use async_io::Timer;
-use futures_lite::{self, StreamExt};
+use futures_lite::{self, future};
 use log::*;
-use smol::Async;
+use smol::{Async, Task};
 use std::{
     net::TcpListener,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    api::DisconnectRequest,
     client::EspHomeApiClient,
-    components::{logger::EspHomeLogger, ComponentManager, ComponentUpdate},
+    components::{ComponentManager, ComponentUpdate},
+    consts::MessageTypes,
+    error::{Error, Recovery, Result, TransportError},
     Device, PORT,
 };
 
+const BIND_RETRY: Duration = Duration::from_secs(5);
+
 pub struct Listener;
 
 impl Listener {
-    pub async fn run(send: Sender<ComponentUpdate>) -> smol::io::Result<()> {
-        let listener = Async::<TcpListener>::bind(([0, 0, 0, 0], PORT));
-        if listener.is_err() {
-            error!("failed to bind to socket!");
-            unreachable!();
-        } else {
-            info!("listener is ok");
-        }
-
-        let listener = listener.expect("failed to set up listener");
+    pub async fn run(send: Sender<ComponentUpdate>) -> Result<()> {
+        let listener = loop {
+            match Self::bind() {
+                Ok(listener) => break listener,
+                Err(err) if err.recovery() == Recovery::Retry => {
+                    error!("{err}, retrying in {}s", BIND_RETRY.as_secs());
+                    Timer::after(BIND_RETRY).await;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        info!("listener is ok");
 
-        while let Ok((socket, _addr)) = listener.accept().await {
-            // wrap stream in arc
-            send.send(ComponentUpdate::Connection(Arc::new(socket)))
-                .await
-                .expect("failed to sent to server");
+        loop {
+            match listener.accept().await {
+                Ok((socket, _addr)) => {
+                    // wrap stream in arc
+                    send.send(ComponentUpdate::Connection(Arc::new(socket)))
+                        .await?;
+                }
+                // a failed accept only affects that one client
+                Err(err) => warn!("failed to accept connection: {err}"),
+            }
         }
-        Ok(())
     }
-}
 
-const UPDATE_TICK: Duration = Duration::from_secs(10); // TODO tune this
+    fn bind() -> Result<Async<TcpListener>> {
+        Async::<TcpListener>::bind(([0, 0, 0, 0], PORT))
+            .map_err(|err| Error::Transport(TransportError::Bind(err)))
+    }
+}
 
-pub struct TickTimer;
+/// How long to wait for components to stop and for clients to answer our `DisconnectRequest`
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-impl TickTimer {
-    pub async fn run(send: Sender<ComponentUpdate>) {
-        let mut timer = Timer::interval(UPDATE_TICK);
+/// Allows other parts of the firmware (e.g. before a reboot or OTA) to stop the server in an orderly way
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    send: Sender<ComponentUpdate>,
+}
 
-        while let Some(_) = timer.next().await {
-            send.send(ComponentUpdate::Update)
-                .await
-                .expect("failed to sent to server");
+impl ShutdownHandle {
+    /// Returns once the server got the request, not when the shutdown is finished
+    pub async fn shutdown(&self) {
+        if self.send.send(ComponentUpdate::Shutdown).await.is_err() {
+            warn!("server is already gone");
         }
     }
 }
 
 pub struct EspHomeApiServer {
     device: Arc<Device>,
-    components: Mutex<Box<ComponentManager>>,
+    components: Sender<ComponentUpdate>,
 
     client_recv: Receiver<ComponentUpdate>,
     client_send: Sender<ComponentUpdate>,
     clients: Vec<Sender<ComponentUpdate>>,
+
+    listener: Option<Task<Result<()>>>,
+    /// Closed once the components thread is done
+    components_done: Option<Receiver<()>>,
 }
 
 impl EspHomeApiServer {
-    pub fn new(device: Arc<Device>, components: Box<ComponentManager>) -> Self {
+    /// `client_send` must be the sender the components publish their states to
+    pub fn new(
+        device: Arc<Device>,
+        components: ComponentManager,
+        client_send: Sender<ComponentUpdate>,
+        client_recv: Receiver<ComponentUpdate>,
+    ) -> Self {
         info!("setting up ...");
 
-        // server communication channels
-        let (client_send, client_recv) = async_channel::unbounded();
-
-        smol::spawn(Listener::run(client_send.clone())).detach();
+        let listener = smol::spawn(Listener::run(client_send.clone()));
         info!("listener running");
 
-        smol::spawn(TickTimer::run(client_send.clone())).detach();
-        info!("timer running");
-
-        // let logs = crate::components::logger::LOGGER.get_receiver();
-        // let logs = EspHomeLogger::new(client_send.clone());
-        EspHomeLogger::set_send(client_send.clone());
+        let (components_send, components_recv) = async_channel::unbounded();
+        let components_done = match components.spawn(components_recv) {
+            Ok(done) => {
+                info!("components running");
+                Some(done)
+            }
+            Err(err) => {
+                error!("failed to start components: {err}");
+                None
+            }
+        };
 
         EspHomeApiServer {
             device,
-            components: Mutex::new(components),
+            components: components_send,
             client_recv,
             client_send,
             clients: vec![],
+            listener: Some(listener),
+            components_done,
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            send: self.client_send.clone(),
         }
     }
 
     pub async fn run_asyn(&mut self) {
-        let mut msg_for_clients = vec![];
         loop {
-            msg_for_clients.clear();
             match self.client_recv.recv().await {
-                Ok(upd) => match upd {
-                    ComponentUpdate::Closing => (),
-                    ComponentUpdate::Connection(socket) => {
-                        // create new communication channels
-                        let (server_send, client_recv) = async_channel::unbounded();
-                        let client_send = self.client_send.clone();
-                        let device = self.device.to_owned();
-                        // unpack arc
-                        let socket = Arc::<Async<std::net::TcpStream>>::try_unwrap(socket)
-                            .expect("failed to get socket");
-                        EspHomeApiClient::new(socket, device, client_recv, client_send)
-                            .await
-                            .expect("failed to spawn client");
-
-                        self.clients.push(server_send);
-                    }
-                    ComponentUpdate::Log(msg) => msg_for_clients.push(ComponentUpdate::Log(msg)),
-                    upd @ _ => {
-                        msg_for_clients.append(
-                            &mut self.components.lock().expect("mutex poisoned").hanlde(&upd),
-                        );
+                Ok(ComponentUpdate::Shutdown) => break,
+                Ok(upd) => {
+                    let msg_for_clients = self.handle(upd).await;
+                    self.broadcast(&msg_for_clients);
+                }
+                Err(err) => warn!("{}", &err),
+            }
+        }
+
+        self.shutdown().await;
+    }
+
+    async fn handle(&mut self, upd: ComponentUpdate) -> Vec<ComponentUpdate> {
+        let mut msg_for_clients = vec![];
+
+        match upd {
+            ComponentUpdate::Closing => {
+                // drop clients whose queue has shut down
+                self.clients.retain(|client| !client.is_closed());
+            }
+            ComponentUpdate::Shutdown => warn!("shutdown is already in progress"),
+            ComponentUpdate::Connection(socket) => {
+                // create new communication channels
+                let (server_send, client_recv) = async_channel::unbounded();
+                let client_send = self.client_send.clone();
+                let device = self.device.to_owned();
+                // unpack arc
+                let socket = match Arc::<Async<std::net::TcpStream>>::try_unwrap(socket) {
+                    Ok(socket) => socket,
+                    Err(_) => {
+                        warn!("socket is still shared, dropping connection");
+                        return msg_for_clients;
                     }
+                };
+                match EspHomeApiClient::new(socket, device, client_recv, client_send).await {
+                    Ok(()) => self.clients.push(server_send),
+                    Err(err) => warn!("failed to spawn client: {err}"),
+                }
+            }
+            // states published by components go to all clients
+            upd @ ComponentUpdate::Response(_) => msg_for_clients.push(upd),
+            upd @ ComponentUpdate::Request(_) | upd @ ComponentUpdate::Command(_) => {
+                if self.components.send(upd).await.is_err() {
+                    warn!("components are gone");
+                }
+            }
+        }
+
+        msg_for_clients
+    }
+
+    fn broadcast(&mut self, msg_for_clients: &[ComponentUpdate]) {
+        // for now, send to all
+        // info!("{:?}", &msg_for_clients);
+        for resp in msg_for_clients {
+            self.clients.retain(|client| {
+                let res = smol::block_on(async { client.send(resp.to_owned()).await });
+                res.is_ok()
+            });
+        }
+    }
+
+    /// Orderly shutdown
+    ///
+    /// 1. stop accepting connections and shut down all components
+    /// 2. flush the states that are still queued
+    /// 3. ask all clients to disconnect and wait (with timeout) for them to do so
+    async fn shutdown(&mut self) {
+        info!("shutting down ...");
+
+        if let Some(listener) = self.listener.take() {
+            listener.cancel().await;
+        }
+        if let Some(components_done) = self.components_done.take() {
+            let _ = self.components.send(ComponentUpdate::Shutdown).await;
+
+            let timeout = async {
+                Timer::after(SHUTDOWN_TIMEOUT).await;
+                false
+            };
+            if !future::or(
+                async {
+                    // never sent, only closed
+                    let _ = components_done.recv().await;
+                    true
                 },
-                Err(err) => warn!("{}", &err),
+                timeout,
+            )
+            .await
+            {
+                warn!("components did not stop in time");
+            }
+        }
+
+        // flush pending updates
+        while let Ok(upd) = self.client_recv.try_recv() {
+            match upd {
+                // nobody will serve new connections anymore
+                ComponentUpdate::Connection(_) | ComponentUpdate::Shutdown => {}
+                upd => {
+                    let msg_for_clients = self.handle(upd).await;
+                    self.broadcast(&msg_for_clients);
+                }
             }
-            // for now, send to all
-            // info!("{:?}", &msg_for_clients);
-            for resp in &msg_for_clients {
-                self.clients.retain(|client| {
-                    let res = smol::block_on(async { client.send(resp.to_owned()).await });
-                    res.is_ok()
-                });
+        }
+
+        // ask clients to disconnect, they close their connection once the `DisconnectResponse` arrives
+        let req = ComponentUpdate::Response((
+            MessageTypes::DisconnectRequest,
+            Arc::new(Box::new(DisconnectRequest::new())),
+        ));
+        self.broadcast(&[req]);
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while !self.clients.is_empty() {
+            let timeout = async {
+                Timer::at(deadline).await;
+                None
+            };
+            match future::or(async { self.client_recv.recv().await.ok() }, timeout).await {
+                Some(ComponentUpdate::Closing) => {
+                    self.clients.retain(|client| !client.is_closed());
+                }
+                Some(_) => {}
+                None => {
+                    warn!(
+                        "{} client(s) did not disconnect in time, dropping them",
+                        self.clients.len()
+                    );
+                    break;
+                }
             }
         }
+
+        // closing the channels terminates the remaining client queues
+        self.clients.clear();
+
+        info!("shutdown complete");
     }
 }
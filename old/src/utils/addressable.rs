@@ -0,0 +1,376 @@
+//! Pixels and effects of addressable LED strips
+//!
+//! Everything renders into a plain slice of [`Color`]s, the strip driver only gets to see the final framebuffer.
+//! Like the light engine, effects never read the clock, the current time is passed in.
+
+use std::time::{Duration, Instant};
+
+use super::{
+    light_color::gamma_correct,
+    light_engine::{hue_to_rgb, Rng},
+};
+
+/// One pixel, `w` is only sent to RGBW chipsets
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::new(0, 0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8, w: u8) -> Self {
+        Color { r, g, b, w }
+    }
+
+    /// From levels in `0.0..=1.0`
+    pub fn from_levels(r: f32, g: f32, b: f32, w: f32) -> Self {
+        let to_u8 = |level: f32| (level.clamp(0., 1.) * 255.).round() as u8;
+        Color::new(to_u8(r), to_u8(g), to_u8(b), to_u8(w))
+    }
+
+    /// `scale` 255 keeps the colour, 0 is black
+    pub fn scale8(&self, scale: u8) -> Self {
+        let scale8 = |c: u8| ((c as u16 * (scale as u16 + 1)) >> 8) as u8;
+        Color::new(
+            scale8(self.r),
+            scale8(self.g),
+            scale8(self.b),
+            scale8(self.w),
+        )
+    }
+
+    /// Average of the three, used to spread fireworks sparks
+    fn blend3(left: Color, center: Color, right: Color) -> Self {
+        let avg = |l: u8, c: u8, r: u8| ((l as u16 + 2 * c as u16 + r as u16) / 4) as u8;
+        Color::new(
+            avg(left.r, center.r, right.r),
+            avg(left.g, center.g, right.g),
+            avg(left.b, center.b, right.b),
+            avg(left.w, center.w, right.w),
+        )
+    }
+}
+
+/// Gamma lookup, calculated once per strip instead of a `powf` per channel and pixel
+#[derive(Debug, Clone)]
+pub struct GammaTable([u8; 256]);
+
+impl GammaTable {
+    pub fn new(gamma: f32) -> Self {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = (gamma_correct(i as f32 / 255., gamma) * 255.).round() as u8;
+        }
+        GammaTable(table)
+    }
+
+    /// Scales `color` by `brightness` (`0.0..=1.0`, perceived) and gamma corrects it
+    pub fn apply(&self, color: Color, brightness: f32) -> Color {
+        let scale = (brightness.clamp(0., 1.) * 255.).round() as u8;
+        let color = color.scale8(scale);
+        Color::new(
+            self.0[color.r as usize],
+            self.0[color.g as usize],
+            self.0[color.b as usize],
+            self.0[color.w as usize],
+        )
+    }
+}
+
+// ESPHome's defaults
+const RAINBOW_PERIOD: Duration = Duration::from_secs(5);
+/// Pixels per full hue cycle
+const RAINBOW_WIDTH: usize = 50;
+const COLOR_WIPE_INTERVAL: Duration = Duration::from_millis(100);
+/// New colour after this many pixels
+const COLOR_WIPE_BLOCK: usize = 10;
+const TWINKLE_INTERVAL: Duration = Duration::from_millis(32);
+/// Per pixel and step, out of 1000
+const TWINKLE_PROBABILITY: u32 = 50;
+const FIREWORKS_INTERVAL: Duration = Duration::from_millis(32);
+/// Per step, out of 1000
+const FIREWORKS_SPARK_PROBABILITY: u32 = 100;
+const FIREWORKS_FADE_OUT: u8 = 120;
+/// Never catch up more steps than this, e.g. after the light was off
+const MAX_STEPS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressableEffect {
+    /// Hue changes along the strip and over time
+    Rainbow,
+    /// Pushes new colours in from the start of the strip
+    ColorWipe,
+    /// Random pixels fade in and out
+    Twinkle,
+    /// Random sparks that spread and fade
+    Fireworks,
+}
+
+impl AddressableEffect {
+    pub const ALL: [AddressableEffect; 4] = [
+        AddressableEffect::Rainbow,
+        AddressableEffect::ColorWipe,
+        AddressableEffect::Twinkle,
+        AddressableEffect::Fireworks,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AddressableEffect::Rainbow => "Rainbow",
+            AddressableEffect::ColorWipe => "Color Wipe",
+            AddressableEffect::Twinkle => "Twinkle",
+            AddressableEffect::Fireworks => "Fireworks",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        AddressableEffect::ALL
+            .iter()
+            .copied()
+            .find(|effect| effect.name() == name)
+    }
+}
+
+/// A running addressable effect on a range of pixels
+#[derive(Debug, Clone)]
+pub struct EffectRenderer {
+    effect: AddressableEffect,
+    started: Instant,
+    last_step: Instant,
+    rng: Rng,
+
+    /// Color wipe: current colour and pixels pushed with it
+    wipe_color: Color,
+    wipe_count: usize,
+    /// Twinkle: progress per pixel, 0 is idle
+    twinkle: Vec<u8>,
+}
+
+impl EffectRenderer {
+    pub fn new(effect: AddressableEffect, len: usize, now: Instant, seed: u32) -> Self {
+        EffectRenderer {
+            effect,
+            started: now,
+            last_step: now,
+            rng: Rng::new(seed),
+            wipe_color: Color::BLACK,
+            wipe_count: COLOR_WIPE_BLOCK,
+            twinkle: vec![0; len],
+        }
+    }
+
+    pub fn effect(&self) -> AddressableEffect {
+        self.effect
+    }
+
+    /// Renders the frame at `now` into `pixels`, `base` is the colour of the light
+    ///
+    /// Stepped effects keep their previous frame in `pixels`, so always pass the same slice.
+    pub fn render(&mut self, pixels: &mut [Color], base: Color, now: Instant) {
+        match self.effect {
+            AddressableEffect::Rainbow => {
+                let elapsed = now.saturating_duration_since(self.started);
+                let offset = (elapsed.as_millis() % RAINBOW_PERIOD.as_millis()) as f32
+                    / RAINBOW_PERIOD.as_millis() as f32;
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let hue = offset + (i % RAINBOW_WIDTH) as f32 / RAINBOW_WIDTH as f32;
+                    let (r, g, b) = hue_to_rgb(hue);
+                    *pixel = Color::from_levels(r, g, b, 0.);
+                }
+            }
+            AddressableEffect::ColorWipe => {
+                for _ in 0..self.steps(now, COLOR_WIPE_INTERVAL) {
+                    if self.wipe_count >= COLOR_WIPE_BLOCK {
+                        let (r, g, b) = hue_to_rgb(self.rng.next_f32());
+                        self.wipe_color = Color::from_levels(r, g, b, 0.);
+                        self.wipe_count = 0;
+                    }
+                    if !pixels.is_empty() {
+                        pixels.rotate_right(1);
+                        pixels[0] = self.wipe_color;
+                    }
+                    self.wipe_count += 1;
+                }
+            }
+            AddressableEffect::Twinkle => {
+                self.twinkle.resize(pixels.len(), 0);
+                for _ in 0..self.steps(now, TWINKLE_INTERVAL) {
+                    for progress in self.twinkle.iter_mut() {
+                        if *progress > 0 {
+                            *progress = progress.saturating_add(8);
+                            if *progress == u8::MAX {
+                                *progress = 0;
+                            }
+                        } else if self.rng.next_u32() % 1000 < TWINKLE_PROBABILITY {
+                            *progress = 1;
+                        }
+                    }
+                }
+                for (pixel, progress) in pixels.iter_mut().zip(&self.twinkle) {
+                    // rise for the first half, fall for the second
+                    let level = if *progress < 128 {
+                        *progress * 2
+                    } else {
+                        (255 - *progress) * 2
+                    };
+                    *pixel = base.scale8(level);
+                }
+            }
+            AddressableEffect::Fireworks => {
+                for _ in 0..self.steps(now, FIREWORKS_INTERVAL) {
+                    let previous = pixels.to_vec();
+                    for (i, pixel) in pixels.iter_mut().enumerate() {
+                        let left = if i > 0 { previous[i - 1] } else { Color::BLACK };
+                        let right = previous.get(i + 1).copied().unwrap_or(Color::BLACK);
+                        *pixel = Color::blend3(left, previous[i], right)
+                            .scale8(u8::MAX - FIREWORKS_FADE_OUT);
+                    }
+                    if !pixels.is_empty()
+                        && self.rng.next_u32() % 1000 < FIREWORKS_SPARK_PROBABILITY
+                    {
+                        let pos = self.rng.next_u32() as usize % pixels.len();
+                        pixels[pos] = base;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of fixed size steps since the last call
+    fn steps(&mut self, now: Instant, interval: Duration) -> u32 {
+        let elapsed = now.saturating_duration_since(self.last_step);
+        let steps = (elapsed.as_millis() / interval.as_millis()) as u32;
+        // keep the remainder, the next frame may be due earlier than a full interval
+        self.last_step += interval * steps;
+        steps.min(MAX_STEPS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = Color::new(255, 0, 0, 0);
+
+    #[test]
+    fn colors() {
+        assert_eq!(
+            Color::from_levels(1., 0.5, 0., 2.),
+            Color::new(255, 128, 0, 255)
+        );
+        assert_eq!(RED.scale8(255), RED);
+        assert_eq!(RED.scale8(127), Color::new(127, 0, 0, 0));
+        assert_eq!(RED.scale8(0), Color::BLACK);
+        assert_eq!(
+            Color::blend3(Color::BLACK, RED, Color::BLACK),
+            Color::new(127, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn gamma() {
+        let linear = GammaTable::new(1.);
+        assert_eq!(linear.apply(RED, 1.), RED);
+        assert_eq!(linear.apply(RED, 0.5), Color::new(128, 0, 0, 0));
+        assert_eq!(linear.apply(RED, 0.), Color::BLACK);
+
+        let table = GammaTable::new(2.8);
+        assert_eq!(table.apply(RED, 1.), RED);
+        // dark values get darker
+        assert!(table.apply(RED, 0.5).r < 128);
+    }
+
+    #[test]
+    fn names() {
+        for effect in AddressableEffect::ALL {
+            assert_eq!(AddressableEffect::from_name(effect.name()), Some(effect));
+        }
+        assert_eq!(AddressableEffect::from_name("Pulse"), None);
+    }
+
+    #[test]
+    fn rainbow() {
+        let now = Instant::now();
+        let mut renderer = EffectRenderer::new(AddressableEffect::Rainbow, 60, now, 1);
+        let mut pixels = vec![Color::BLACK; 60];
+
+        renderer.render(&mut pixels, RED, now);
+        assert_eq!(pixels[0], RED);
+        assert_eq!(pixels[RAINBOW_WIDTH / 3].g, 255);
+        // repeats after its width
+        assert_eq!(pixels[RAINBOW_WIDTH], pixels[0]);
+
+        // and moves over time
+        renderer.render(&mut pixels, RED, now + RAINBOW_PERIOD / 3);
+        assert_eq!(pixels[0].g, 255);
+    }
+
+    #[test]
+    fn color_wipe() {
+        let now = Instant::now();
+        let mut renderer = EffectRenderer::new(AddressableEffect::ColorWipe, 30, now, 1);
+        let mut pixels = vec![Color::BLACK; 30];
+
+        // nothing is due yet
+        renderer.render(&mut pixels, RED, now);
+        assert!(pixels.iter().all(|pixel| *pixel == Color::BLACK));
+
+        renderer.render(&mut pixels, RED, now + COLOR_WIPE_INTERVAL * 3);
+        assert_ne!(pixels[0], Color::BLACK);
+        assert!(pixels[..3].iter().all(|pixel| *pixel == pixels[0]));
+        assert!(pixels[3..].iter().all(|pixel| *pixel == Color::BLACK));
+
+        // one block per colour
+        let end = now + COLOR_WIPE_INTERVAL * (COLOR_WIPE_BLOCK as u32 + 3);
+        renderer.render(&mut pixels, RED, end);
+        assert_eq!(pixels[COLOR_WIPE_BLOCK + 2], pixels[COLOR_WIPE_BLOCK]);
+        assert_ne!(pixels[0], pixels[COLOR_WIPE_BLOCK]);
+    }
+
+    #[test]
+    fn catch_up_is_limited() {
+        let now = Instant::now();
+        let mut renderer = EffectRenderer::new(AddressableEffect::ColorWipe, 30, now, 1);
+        let mut pixels = vec![Color::BLACK; 30];
+
+        renderer.render(&mut pixels, RED, now + COLOR_WIPE_INTERVAL * 100);
+        let lit = pixels
+            .iter()
+            .filter(|pixel| **pixel != Color::BLACK)
+            .count();
+        assert_eq!(lit, MAX_STEPS as usize);
+    }
+
+    #[test]
+    fn twinkle() {
+        let now = Instant::now();
+        let mut renderer = EffectRenderer::new(AddressableEffect::Twinkle, 100, now, 1);
+        let mut pixels = vec![Color::BLACK; 100];
+
+        renderer.render(&mut pixels, RED, now + TWINKLE_INTERVAL * 8);
+        // only shades of the base colour
+        assert!(pixels.iter().any(|pixel| *pixel != Color::BLACK));
+        assert!(pixels
+            .iter()
+            .all(|pixel| pixel.g == 0 && pixel.b == 0 && pixel.w == 0));
+    }
+
+    #[test]
+    fn fireworks() {
+        let now = Instant::now();
+        let mut renderer = EffectRenderer::new(AddressableEffect::Fireworks, 10, now, 1);
+        let mut pixels = vec![Color::BLACK; 10];
+        pixels[5] = RED;
+
+        renderer.render(&mut pixels, Color::BLACK, now + FIREWORKS_INTERVAL);
+        // the spark spreads to its neighbours and fades
+        assert!(pixels[5].r < RED.r);
+        assert_eq!(pixels[4], pixels[6]);
+        assert!(pixels[4].r > 0);
+        assert_eq!(pixels[0], Color::BLACK);
+    }
+}
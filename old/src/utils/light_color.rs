@@ -1,142 +1,650 @@
-#[derive(Debug, Clone, Copy)]
-pub enum LightColor {
-    Monochromatic(f32),
-    Rgb(f32, f32, f32),
-    Rgbw(f32, f32, f32, f32),
-    Rgbww(f32, f32, f32, f32, f32),
+//! Colour handling of lights, modelled after ESPHome's `LightColorValues`
+//!
+//! Only does math, the hardware is driven by `components::light`.
+
+use log::*;
+
+use protobuf::ProtobufEnum;
+
+use crate::{
+    api::{ColorMode, LightCommandRequest, LightStateResponse},
+    preferences::Preference,
+};
+
+/// ESPHome's default, LEDs look linear to the eye with it
+pub const DEFAULT_GAMMA: f32 = 2.8;
+
+/// Building blocks of a [`ColorMode`], like ESPHome's `ColorCapability`
+pub mod capability {
+    pub const ON_OFF: u8 = 1 << 0;
+    pub const BRIGHTNESS: u8 = 1 << 1;
+    pub const WHITE: u8 = 1 << 2;
+    pub const COLOR_TEMPERATURE: u8 = 1 << 3;
+    pub const COLD_WARM_WHITE: u8 = 1 << 4;
+    pub const RGB: u8 = 1 << 5;
 }
 
-#[allow(dead_code)]
-impl LightColor {
-    pub fn scale(&self, factor: f32) -> Self {
-        // create a copy!
-        match self {
-            LightColor::Monochromatic(w) => (w * factor).into(),
-            LightColor::Rgb(r, g, b) => (r * factor, g * factor, b * factor).into(),
-            LightColor::Rgbw(r, g, b, w) => (r * factor, g * factor, b * factor, w * factor).into(),
-            LightColor::Rgbww(r, g, b, w, ww) => {
-                (r * factor, g * factor, b * factor, w * factor, ww * factor).into()
-            }
+pub fn capabilities(mode: ColorMode) -> u8 {
+    use capability::*;
+
+    // api.proto does not encode ON_OFF into BRIGHTNESS, so spell them out
+    match mode {
+        ColorMode::COLOR_MODE_UNKNOWN => 0,
+        ColorMode::COLOR_MODE_ON_OFF => ON_OFF,
+        ColorMode::COLOR_MODE_BRIGHTNESS => ON_OFF | BRIGHTNESS,
+        ColorMode::COLOR_MODE_WHITE => ON_OFF | BRIGHTNESS | WHITE,
+        ColorMode::COLOR_MODE_COLOR_TEMPERATURE => ON_OFF | BRIGHTNESS | COLOR_TEMPERATURE,
+        ColorMode::COLOR_MODE_COLD_WARM_WHITE => ON_OFF | BRIGHTNESS | COLD_WARM_WHITE,
+        ColorMode::COLOR_MODE_RGB => ON_OFF | BRIGHTNESS | RGB,
+        ColorMode::COLOR_MODE_RGB_WHITE => ON_OFF | BRIGHTNESS | RGB | WHITE,
+        ColorMode::COLOR_MODE_RGB_COLOR_TEMPERATURE => {
+            ON_OFF | BRIGHTNESS | RGB | COLOR_TEMPERATURE
         }
+        ColorMode::COLOR_MODE_RGB_COLD_WARM_WHITE => ON_OFF | BRIGHTNESS | RGB | COLD_WARM_WHITE,
     }
+}
 
-    // getters
-    pub fn get_red(&self) -> f32 {
-        match self {
-            LightColor::Monochromatic(_) => unreachable!("light has no \"red\""),
-            LightColor::Rgb(r, _, _)
-            | LightColor::Rgbw(r, _, _, _)
-            | LightColor::Rgbww(r, _, _, _, _) => *r,
+/// What a light is capable of
+#[derive(Debug, Clone)]
+pub struct LightTraits {
+    pub supported_color_modes: Vec<ColorMode>,
+    /// Coldest colour temperature, only used by modes with colour temperature or cold/warm white
+    pub min_mireds: f32,
+    /// Warmest colour temperature
+    pub max_mireds: f32,
+}
+
+impl LightTraits {
+    pub fn new(supported_color_modes: Vec<ColorMode>) -> Self {
+        LightTraits {
+            supported_color_modes,
+            min_mireds: 0.,
+            max_mireds: 0.,
         }
     }
 
-    pub fn get_green(&self) -> f32 {
-        match self {
-            LightColor::Monochromatic(_) => unreachable!("light has no \"green\""),
-            LightColor::Rgb(_, g, _)
-            | LightColor::Rgbw(_, g, _, _)
-            | LightColor::Rgbww(_, g, _, _, _) => *g,
+    pub fn with_mireds(mut self, min_mireds: f32, max_mireds: f32) -> Self {
+        self.min_mireds = min_mireds;
+        self.max_mireds = max_mireds;
+        self
+    }
+
+    pub fn supports(&self, mode: ColorMode) -> bool {
+        self.supported_color_modes.contains(&mode)
+    }
+
+    /// `true` when any of the supported modes has all of `caps`
+    pub fn supports_capability(&self, caps: u8) -> bool {
+        self.mode_with(caps).is_some()
+    }
+
+    /// First supported mode that has all of `caps`
+    pub fn mode_with(&self, caps: u8) -> Option<ColorMode> {
+        self.supported_color_modes
+            .iter()
+            .copied()
+            .find(|mode| capabilities(*mode) & caps == caps)
+    }
+
+    fn has_mireds(&self) -> bool {
+        self.max_mireds > self.min_mireds
+    }
+}
+
+/// State of a light, all values are in `0.0..=1.0` except the colour temperature (mireds)
+///
+/// Values that the current colour mode does not use are kept, switching back to a mode restores them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightColorValues {
+    pub color_mode: ColorMode,
+    /// `0.0` off, `1.0` on, in between during transitions
+    pub state: f32,
+    pub brightness: f32,
+    pub color_brightness: f32,
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+    pub white: f32,
+    pub color_temperature: f32,
+    pub cold_white: f32,
+    pub warm_white: f32,
+}
+
+impl LightColorValues {
+    /// Light is off, everything else at full
+    pub fn new(traits: &LightTraits) -> Self {
+        LightColorValues {
+            color_mode: traits
+                .supported_color_modes
+                .first()
+                .copied()
+                .unwrap_or(ColorMode::COLOR_MODE_UNKNOWN),
+            state: 0.,
+            brightness: 1.,
+            color_brightness: 1.,
+            red: 1.,
+            green: 1.,
+            blue: 1.,
+            white: 1.,
+            color_temperature: traits.min_mireds,
+            cold_white: 1.,
+            warm_white: 1.,
         }
     }
 
-    pub fn get_blue(&self) -> f32 {
-        match self {
-            LightColor::Monochromatic(_) => unreachable!("light has no \"blue\""),
-            LightColor::Rgb(_, _, b)
-            | LightColor::Rgbw(_, _, b, _)
-            | LightColor::Rgbww(_, _, b, _, _) => *b,
+    fn has(&self, caps: u8) -> bool {
+        capabilities(self.color_mode) & caps == caps
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.state > 0.
+    }
+
+    /// Linear interpolation towards `end`, `t` in `0.0..=1.0`, the colour mode is the one of `end`
+    pub fn lerp(&self, end: &Self, t: f32) -> Self {
+        let lerp = |start: f32, end: f32| start + (end - start) * t;
+
+        LightColorValues {
+            color_mode: end.color_mode,
+            state: lerp(self.state, end.state),
+            brightness: lerp(self.brightness, end.brightness),
+            color_brightness: lerp(self.color_brightness, end.color_brightness),
+            red: lerp(self.red, end.red),
+            green: lerp(self.green, end.green),
+            blue: lerp(self.blue, end.blue),
+            white: lerp(self.white, end.white),
+            color_temperature: lerp(self.color_temperature, end.color_temperature),
+            cold_white: lerp(self.cold_white, end.cold_white),
+            warm_white: lerp(self.warm_white, end.warm_white),
         }
     }
 
-    pub fn get_white(&self) -> f32 {
-        match self {
-            LightColor::Rgb(_, _, _) => {
-                unreachable!("light has no \"white\"")
+    /// Applies a `LightCommandRequest`, fields the light cannot handle are ignored
+    pub fn apply_command(&self, req: &LightCommandRequest, traits: &LightTraits) -> Self {
+        use capability::*;
+
+        let mut values = *self;
+        let mut ignored = vec![];
+
+        if req.get_has_state() {
+            values.state = if req.get_state() { 1. } else { 0. };
+        }
+
+        // capabilities the client asks for, used to pick a mode when it did not send one
+        let mut wanted = 0;
+        if req.get_has_brightness() {
+            wanted |= BRIGHTNESS;
+        }
+        if req.get_has_rgb() || req.get_has_color_brightness() {
+            wanted |= RGB;
+        }
+        if req.get_has_white() {
+            wanted |= WHITE;
+        }
+        if req.get_has_cold_white() || req.get_has_warm_white() {
+            wanted |= COLD_WARM_WHITE;
+        }
+        if req.get_has_color_temperature() {
+            // cold/warm white lights emulate the colour temperature
+            wanted |= if traits.supports_capability(COLOR_TEMPERATURE) {
+                COLOR_TEMPERATURE
+            } else {
+                COLD_WARM_WHITE
+            };
+        }
+
+        if req.get_has_color_mode() && traits.supports(req.get_color_mode()) {
+            values.color_mode = req.get_color_mode();
+        } else {
+            if req.get_has_color_mode() {
+                ignored.push("color_mode");
+            }
+            if !values.has(wanted) {
+                if let Some(mode) = traits.mode_with(wanted) {
+                    values.color_mode = mode;
+                }
             }
-            LightColor::Monochromatic(w)
-            | LightColor::Rgbw(_, _, _, w)
-            | LightColor::Rgbww(_, _, _, w, _) => *w,
         }
-    }
 
-    pub fn get_warm_white(&self) -> f32 {
-        match self {
-            LightColor::Monochromatic(_)
-            | LightColor::Rgb(_, _, _)
-            | LightColor::Rgbw(_, _, _, _) => unreachable!("light has no \"warm white\""),
-            LightColor::Rgbww(_, _, _, _, ww) => *ww,
+        if req.get_has_brightness() {
+            let brightness = clamp(req.get_brightness());
+            if !values.has(BRIGHTNESS) {
+                ignored.push("brightness");
+            } else if brightness == 0. {
+                // like ESPHome, zero brightness turns the light off and keeps the last brightness
+                values.state = 0.;
+            } else {
+                values.brightness = brightness;
+            }
         }
-    }
 
-    // setters
-    pub fn set_red(&mut self, r_new: f32) {
-        match self {
-            LightColor::Monochromatic(_) => unreachable!("light has no \"red\""),
-            LightColor::Rgb(r, _, _)
-            | LightColor::Rgbw(r, _, _, _)
-            | LightColor::Rgbww(r, _, _, _, _) => *r = r_new,
+        if req.get_has_color_brightness() {
+            if values.has(RGB) {
+                values.color_brightness = clamp(req.get_color_brightness());
+            } else {
+                ignored.push("color_brightness");
+            }
+        }
+
+        if req.get_has_rgb() {
+            if values.has(RGB) {
+                let (red, green, blue) = (
+                    clamp(req.get_red()),
+                    clamp(req.get_green()),
+                    clamp(req.get_blue()),
+                );
+                // brightness is carried by `color_brightness`, keep the colour normalised
+                let max = red.max(green).max(blue);
+                if max > 0. {
+                    values.red = red / max;
+                    values.green = green / max;
+                    values.blue = blue / max;
+                } else {
+                    values.color_brightness = 0.;
+                }
+            } else {
+                ignored.push("rgb");
+            }
+        }
+
+        if req.get_has_white() {
+            if values.has(WHITE) {
+                values.white = clamp(req.get_white());
+            } else {
+                ignored.push("white");
+            }
+        }
+
+        if req.get_has_color_temperature() {
+            let color_temperature = if traits.has_mireds() {
+                req.get_color_temperature()
+                    .clamp(traits.min_mireds, traits.max_mireds)
+            } else {
+                req.get_color_temperature()
+            };
+
+            if values.has(COLOR_TEMPERATURE) {
+                values.color_temperature = color_temperature;
+            } else if values.has(COLD_WARM_WHITE) && traits.has_mireds() {
+                // cold/warm white lights emulate the temperature by mixing both channels
+                values.color_temperature = color_temperature;
+                if !req.get_has_cold_white() && !req.get_has_warm_white() {
+                    let warm = (color_temperature - traits.min_mireds)
+                        / (traits.max_mireds - traits.min_mireds);
+                    let cold = 1. - warm;
+                    let max = cold.max(warm);
+                    values.cold_white = cold / max;
+                    values.warm_white = warm / max;
+                }
+            } else {
+                ignored.push("color_temperature");
+            }
+        }
+
+        if req.get_has_cold_white() || req.get_has_warm_white() {
+            if values.has(COLD_WARM_WHITE) {
+                if req.get_has_cold_white() {
+                    values.cold_white = clamp(req.get_cold_white());
+                }
+                if req.get_has_warm_white() {
+                    values.warm_white = clamp(req.get_warm_white());
+                }
+            } else {
+                ignored.push("cold_white/warm_white");
+            }
         }
-    }
 
-    pub fn set_green(&mut self, g_new: f32) {
-        match self {
-            LightColor::Monochromatic(_) => unreachable!("light has no \"green\""),
-            LightColor::Rgb(_, g, _)
-            | LightColor::Rgbw(_, g, _, _)
-            | LightColor::Rgbww(_, g, _, _, _) => *g = g_new,
+        if !ignored.is_empty() {
+            debug!(
+                "light in mode {:?} ignores unsupported {:?}",
+                values.color_mode, ignored
+            );
         }
+
+        values
+    }
+
+    pub fn fill_state_response(&self, resp: &mut LightStateResponse) {
+        resp.set_state(self.is_on());
+        resp.set_color_mode(self.color_mode);
+        resp.set_brightness(self.brightness);
+        resp.set_color_brightness(self.color_brightness);
+        resp.set_red(self.red);
+        resp.set_green(self.green);
+        resp.set_blue(self.blue);
+        resp.set_white(self.white);
+        resp.set_color_temperature(self.color_temperature);
+        resp.set_cold_white(self.cold_white);
+        resp.set_warm_white(self.warm_white);
+    }
+
+    /// Everything but the colour mode, in the order it is persisted
+    const LEVELS: usize = 10;
+
+    fn levels(&self) -> [f32; Self::LEVELS] {
+        [
+            self.state,
+            self.brightness,
+            self.color_brightness,
+            self.red,
+            self.green,
+            self.blue,
+            self.white,
+            self.color_temperature,
+            self.cold_white,
+            self.warm_white,
+        ]
+    }
+
+    // output values, channels the current mode does not use are 0
+
+    pub fn as_binary(&self) -> bool {
+        self.state == 1.
+    }
+
+    pub fn as_brightness(&self) -> f32 {
+        self.state * self.brightness
     }
 
-    pub fn set_blue(&mut self, b_new: f32) {
-        match self {
-            LightColor::Monochromatic(_) => unreachable!("light has no \"blue\""),
-            LightColor::Rgb(_, _, b)
-            | LightColor::Rgbw(_, _, b, _)
-            | LightColor::Rgbww(_, _, b, _, _) => *b = b_new,
+    pub fn as_rgb(&self) -> (f32, f32, f32) {
+        if !self.has(capability::RGB) {
+            return (0., 0., 0.);
         }
+
+        let factor = self.state * self.brightness * self.color_brightness;
+        (self.red * factor, self.green * factor, self.blue * factor)
     }
 
-    pub fn set_white(&mut self, w_new: f32) {
-        match self {
-            LightColor::Rgb(_, _, _) => {
-                unreachable!("light has no \"white\"")
-            }
-            LightColor::Monochromatic(w)
-            | LightColor::Rgbw(_, _, _, w)
-            | LightColor::Rgbww(_, _, _, w, _) => *w = w_new,
+    pub fn as_rgbw(&self) -> (f32, f32, f32, f32) {
+        let (red, green, blue) = self.as_rgb();
+        let white = if self.has(capability::WHITE) {
+            self.state * self.brightness * self.white
+        } else {
+            0.
+        };
+
+        (red, green, blue, white)
+    }
+
+    pub fn as_rgbww(&self, constant_brightness: bool) -> (f32, f32, f32, f32, f32) {
+        let (red, green, blue) = self.as_rgb();
+        let (cold_white, warm_white) = self.as_cwww(constant_brightness);
+
+        (red, green, blue, cold_white, warm_white)
+    }
+
+    /// With `constant_brightness` the sum of both channels never exceeds the brightest one
+    pub fn as_cwww(&self, constant_brightness: bool) -> (f32, f32) {
+        if !self.has(capability::COLD_WARM_WHITE) {
+            return (0., 0.);
         }
+
+        let white_level = self.state * self.brightness;
+        if !constant_brightness {
+            return (white_level * self.cold_white, white_level * self.warm_white);
+        }
+
+        let (cold, warm) = (self.cold_white, self.warm_white);
+        let sum = if cold > 0. || warm > 0. {
+            cold + warm
+        } else {
+            1.
+        };
+        let max = cold.max(warm);
+        (
+            white_level * max * cold / sum,
+            white_level * max * warm / sum,
+        )
     }
 
-    pub fn set_warm_white(&mut self, ww_new: f32) {
-        match self {
-            LightColor::Monochromatic(_)
-            | LightColor::Rgb(_, _, _)
-            | LightColor::Rgbw(_, _, _, _) => unreachable!("light has no \"warm white\""),
-            LightColor::Rgbww(_, _, _, _, ww) => *ww = ww_new,
+    /// Colour temperature as fraction between the cold (`0.0`) and the warm (`1.0`) end of `mireds`
+    /// and the white brightness
+    pub fn as_ct(&self, mireds: (f32, f32)) -> (f32, f32) {
+        let (cold, warm) = mireds;
+        if !self.has(capability::COLOR_TEMPERATURE) || warm <= cold {
+            return (0., 0.);
         }
+
+        let color_temperature = clamp((self.color_temperature - cold) / (warm - cold));
+        (color_temperature, self.state * self.brightness)
     }
 }
 
-impl From<(f32, f32, f32)> for LightColor {
-    fn from(rgb: (f32, f32, f32)) -> Self {
-        LightColor::Rgb(rgb.0, rgb.1, rgb.2)
+/// Maps a perceived brightness onto the (linear) output level
+pub fn gamma_correct(value: f32, gamma: f32) -> f32 {
+    if value <= 0. {
+        return 0.;
     }
+    if gamma <= 0. {
+        return value;
+    }
+    value.powf(gamma)
 }
 
-impl From<(f32, f32, f32, f32)> for LightColor {
-    fn from(rgbw: (f32, f32, f32, f32)) -> Self {
-        LightColor::Rgbw(rgbw.0, rgbw.1, rgbw.2, rgbw.3)
-    }
+fn clamp(value: f32) -> f32 {
+    value.clamp(0., 1.)
 }
 
-impl From<(f32, f32, f32, f32, f32)> for LightColor {
-    fn from(rgbww: (f32, f32, f32, f32, f32)) -> Self {
-        LightColor::Rgbww(rgbww.0, rgbww.1, rgbww.2, rgbww.3, rgbww.4)
+/// Little endian colour mode followed by all levels
+///
+/// The colour mode is not checked against any traits, the light has to do that.
+impl Preference for LightColorValues {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.color_mode.value().to_le_bytes().to_vec();
+        for level in self.levels() {
+            bytes.extend_from_slice(&level.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 4 * (1 + Self::LEVELS) {
+            return None;
+        }
+        let mut words = bytes
+            .chunks_exact(4)
+            .map(|word| [word[0], word[1], word[2], word[3]]);
+
+        let color_mode = ColorMode::from_i32(i32::from_le_bytes(words.next()?))?;
+        let mut next = || words.next().map(f32::from_le_bytes);
+        let values = LightColorValues {
+            color_mode,
+            state: next()?,
+            brightness: next()?,
+            color_brightness: next()?,
+            red: next()?,
+            green: next()?,
+            blue: next()?,
+            white: next()?,
+            color_temperature: next()?,
+            cold_white: next()?,
+            warm_white: next()?,
+        };
+
+        values
+            .levels()
+            .iter()
+            .all(|level| level.is_finite())
+            .then_some(values)
     }
 }
 
-impl From<f32> for LightColor {
-    fn from(w: f32) -> Self {
-        LightColor::Monochromatic(w)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traits(mode: ColorMode) -> LightTraits {
+        LightTraits {
+            supported_color_modes: vec![mode],
+            min_mireds: 153.,
+            max_mireds: 370.,
+        }
+    }
+
+    fn on(traits: &LightTraits) -> LightColorValues {
+        let mut values = LightColorValues::new(traits);
+        values.state = 1.;
+        values
+    }
+
+    #[test]
+    fn mode_with() {
+        let traits = LightTraits::new(vec![
+            ColorMode::COLOR_MODE_COLD_WARM_WHITE,
+            ColorMode::COLOR_MODE_RGB,
+        ]);
+        assert_eq!(
+            traits.mode_with(capability::RGB),
+            Some(ColorMode::COLOR_MODE_RGB)
+        );
+        assert_eq!(
+            traits.mode_with(capability::BRIGHTNESS),
+            Some(ColorMode::COLOR_MODE_COLD_WARM_WHITE)
+        );
+        assert_eq!(traits.mode_with(capability::WHITE), None);
+        assert!(!traits.supports_capability(capability::RGB | capability::WHITE));
+    }
+
+    #[test]
+    fn rgb_is_normalised() {
+        let traits = traits(ColorMode::COLOR_MODE_RGB);
+        let mut req = LightCommandRequest::new();
+        req.set_has_rgb(true);
+        req.set_red(0.5);
+        req.set_green(0.25);
+        req.set_blue(0.);
+
+        let values = on(&traits).apply_command(&req, &traits);
+        assert_eq!((values.red, values.green, values.blue), (1., 0.5, 0.));
+        assert_eq!(values.as_rgb(), (1., 0.5, 0.));
+
+        req.set_has_color_brightness(true);
+        req.set_color_brightness(0.5);
+        let values = values.apply_command(&req, &traits);
+        assert_eq!(values.as_rgb(), (0.5, 0.25, 0.));
+    }
+
+    #[test]
+    fn unsupported_fields_are_ignored() {
+        let traits = traits(ColorMode::COLOR_MODE_RGB);
+        let before = on(&traits);
+
+        let mut req = LightCommandRequest::new();
+        req.set_has_white(true);
+        req.set_white(0.5);
+        req.set_has_cold_white(true);
+        req.set_cold_white(0.5);
+        req.set_has_color_mode(true);
+        req.set_color_mode(ColorMode::COLOR_MODE_WHITE);
+
+        assert_eq!(before.apply_command(&req, &traits), before);
+    }
+
+    #[test]
+    fn zero_brightness_turns_off() {
+        let traits = traits(ColorMode::COLOR_MODE_BRIGHTNESS);
+        let mut req = LightCommandRequest::new();
+        req.set_has_brightness(true);
+        req.set_brightness(0.);
+
+        let values = on(&traits).apply_command(&req, &traits);
+        assert!(!values.is_on());
+        assert_eq!(values.brightness, 1.);
+        assert_eq!(values.as_brightness(), 0.);
+    }
+
+    #[test]
+    fn picks_mode_for_fields() {
+        let traits = LightTraits::new(vec![
+            ColorMode::COLOR_MODE_RGB,
+            ColorMode::COLOR_MODE_RGB_WHITE,
+        ]);
+        let mut req = LightCommandRequest::new();
+        req.set_has_white(true);
+        req.set_white(0.5);
+
+        let values = on(&traits).apply_command(&req, &traits);
+        assert_eq!(values.color_mode, ColorMode::COLOR_MODE_RGB_WHITE);
+        assert_eq!(values.white, 0.5);
+    }
+
+    #[test]
+    fn cold_warm_white_from_temperature() {
+        let traits = traits(ColorMode::COLOR_MODE_COLD_WARM_WHITE);
+        let mut req = LightCommandRequest::new();
+        req.set_has_color_temperature(true);
+        req.set_color_temperature(370.);
+
+        let values = on(&traits).apply_command(&req, &traits);
+        assert_eq!((values.cold_white, values.warm_white), (0., 1.));
+
+        // out of range is clamped
+        req.set_color_temperature(1000.);
+        assert_eq!(values.apply_command(&req, &traits), values);
+
+        // half way both run at full
+        req.set_color_temperature((153. + 370.) / 2.);
+        let values = values.apply_command(&req, &traits);
+        assert_eq!((values.cold_white, values.warm_white), (1., 1.));
+    }
+
+    #[test]
+    fn color_temperature_picks_mode() {
+        let mut req = LightCommandRequest::new();
+        req.set_has_color_temperature(true);
+        req.set_color_temperature(200.);
+
+        for mode in [
+            ColorMode::COLOR_MODE_COLOR_TEMPERATURE,
+            ColorMode::COLOR_MODE_COLD_WARM_WHITE,
+        ] {
+            let mut traits = traits(ColorMode::COLOR_MODE_RGB);
+            traits.supported_color_modes.push(mode);
+
+            let values = on(&traits).apply_command(&req, &traits);
+            assert_eq!(values.color_mode, mode);
+            assert_eq!(values.color_temperature, 200.);
+        }
+    }
+
+    #[test]
+    fn persisted() {
+        let traits = traits(ColorMode::COLOR_MODE_RGB);
+        let mut values = on(&traits);
+        values.red = 0.25;
+
+        let bytes = values.to_bytes();
+        assert_eq!(LightColorValues::from_bytes(&bytes), Some(values));
+        assert_eq!(LightColorValues::from_bytes(&bytes[1..]), None);
+
+        // unknown colour modes and NaNs are not restored
+        let mut unknown = bytes.clone();
+        unknown[0] = 0xff;
+        assert_eq!(LightColorValues::from_bytes(&unknown), None);
+        let mut nan = bytes;
+        nan[4..8].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert_eq!(LightColorValues::from_bytes(&nan), None);
+    }
+
+    #[test]
+    fn cwww_constant_brightness() {
+        let traits = traits(ColorMode::COLOR_MODE_COLD_WARM_WHITE);
+        let values = on(&traits);
+
+        assert_eq!(values.as_cwww(false), (1., 1.));
+        assert_eq!(values.as_cwww(true), (0.5, 0.5));
+    }
+
+    #[test]
+    fn color_temperature() {
+        let traits = traits(ColorMode::COLOR_MODE_COLOR_TEMPERATURE);
+        let mut values = on(&traits);
+        values.brightness = 0.5;
+
+        assert_eq!(values.as_ct((153., 370.)), (0., 0.5));
+        values.color_temperature = 370.;
+        assert_eq!(values.as_ct((153., 370.)), (1., 0.5));
+        // no range, no output
+        assert_eq!(values.as_ct((0., 0.)), (0., 0.));
     }
 }
@@ -0,0 +1,573 @@
+//! Transitions, flashes and effects of lights
+//!
+//! Like the keepalive, the engine never reads the clock itself, the current time is always passed in.
+//! `components::light` polls it while [`LightEngine::is_animating`] and writes the result to the hardware.
+
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::api::LightCommandRequest;
+
+use super::light_color::{capability, LightColorValues, LightTraits};
+
+/// Used when the client does not send a transition length, ESPHome's `default_transition_length`
+pub const DEFAULT_TRANSITION_LENGTH: Duration = Duration::from_secs(1);
+/// Time between two outputs while a transition or effect is running
+pub const ANIMATION_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Name of the "no effect" entry, always the first one in the effects list
+pub const EFFECT_NONE: &str = "None";
+
+const PULSE_PERIOD: Duration = Duration::from_secs(2);
+const STROBE_PERIOD: Duration = Duration::from_millis(250);
+const RANDOM_PERIOD: Duration = Duration::from_secs(10);
+const RANDOM_TRANSITION: Duration = Duration::from_millis(7500);
+const RAINBOW_PERIOD: Duration = Duration::from_secs(5);
+// ESPHome's flicker defaults
+const FLICKER_ALPHA: f32 = 0.95;
+const FLICKER_INTENSITY: f32 = 0.015;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightEffect {
+    /// Fades between off and the set brightness
+    Pulse,
+    /// Switches between on and off
+    Strobe,
+    /// Slowly fades to a new random colour
+    Random,
+    /// Small random brightness changes, like a candle
+    Flicker,
+    /// Cycles through all hues
+    Rainbow,
+}
+
+impl LightEffect {
+    const ALL: [LightEffect; 5] = [
+        LightEffect::Pulse,
+        LightEffect::Strobe,
+        LightEffect::Random,
+        LightEffect::Flicker,
+        LightEffect::Rainbow,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightEffect::Pulse => "Pulse",
+            LightEffect::Strobe => "Strobe",
+            LightEffect::Random => "Random",
+            LightEffect::Flicker => "Flicker",
+            LightEffect::Rainbow => "Rainbow",
+        }
+    }
+
+    /// Effects that make sense for a light with the given traits
+    pub fn available(traits: &LightTraits) -> Vec<LightEffect> {
+        LightEffect::ALL
+            .iter()
+            .copied()
+            .filter(|effect| match effect {
+                LightEffect::Strobe => true,
+                LightEffect::Pulse | LightEffect::Flicker => {
+                    traits.supports_capability(capability::BRIGHTNESS)
+                }
+                LightEffect::Random | LightEffect::Rainbow => {
+                    traits.supports_capability(capability::RGB)
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Transformer {
+    Transition {
+        start: LightColorValues,
+        end: LightColorValues,
+        started: Instant,
+        length: Duration,
+    },
+    /// Shows `values` until `until`, then returns to the remote values
+    Flash {
+        values: LightColorValues,
+        until: Instant,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RunningEffect {
+    effect: LightEffect,
+    started: Instant,
+    /// Random: colour faded from and to, Flicker: last output
+    from: LightColorValues,
+    to: LightColorValues,
+    changed: Instant,
+}
+
+/// xorshift32, good enough for effects and does not need a dependency
+#[derive(Debug, Clone, Copy)]
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // xorshift gets stuck on 0
+        Rng(seed | 1)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// `0.0..=1.0`
+    pub fn next_f32(&mut self) -> f32 {
+        self.next_u32() as f32 / u32::MAX as f32
+    }
+}
+
+/// Drives the values of a single light
+#[derive(Debug)]
+pub struct LightEngine {
+    traits: LightTraits,
+    default_transition_length: Duration,
+    effects: Vec<LightEffect>,
+
+    /// What clients asked for, this is what gets reported
+    remote: LightColorValues,
+    /// What the hardware shows right now
+    current: LightColorValues,
+
+    transformer: Option<Transformer>,
+    effect: Option<RunningEffect>,
+    rng: Rng,
+}
+
+impl LightEngine {
+    /// `seed` feeds the random effects, e.g. the entity key
+    pub fn new(traits: LightTraits, seed: u32) -> Self {
+        let values = LightColorValues::new(&traits);
+        // ESPHome does not fade binary lights either
+        let default_transition_length = if traits.supports_capability(capability::BRIGHTNESS) {
+            DEFAULT_TRANSITION_LENGTH
+        } else {
+            Duration::ZERO
+        };
+
+        LightEngine {
+            effects: LightEffect::available(&traits),
+            traits,
+            default_transition_length,
+            remote: values,
+            current: values,
+            transformer: None,
+            effect: None,
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Restricts the built-in effects, e.g. when a platform brings its own
+    #[cfg(feature = "has_led_strip")]
+    pub fn with_effects(mut self, effects: &[LightEffect]) -> Self {
+        self.effects.retain(|effect| effects.contains(effect));
+        self
+    }
+
+    pub fn traits(&self) -> &LightTraits {
+        &self.traits
+    }
+
+    pub fn remote_values(&self) -> &LightColorValues {
+        &self.remote
+    }
+
+    pub fn effects(&self) -> &[LightEffect] {
+        &self.effects
+    }
+
+    pub fn effect_name(&self) -> &'static str {
+        self.effect
+            .map(|running| running.effect.name())
+            .unwrap_or(EFFECT_NONE)
+    }
+
+    /// `true` while the output changes over time and [`LightEngine::poll`] must be called regularly
+    pub fn is_animating(&self) -> bool {
+        self.transformer.is_some() || self.effect.is_some()
+    }
+
+    /// Jumps to `values` without a transition, e.g. the state before a reboot
+    pub fn restore(&mut self, values: LightColorValues) {
+        self.remote = values;
+        self.current = values;
+        self.transformer = None;
+        self.effect = None;
+    }
+
+    pub fn apply_command(&mut self, req: &LightCommandRequest, now: Instant) {
+        let target = self.remote.apply_command(req, &self.traits);
+
+        // a flash does not change the remote values, it only overlays them for a while
+        if req.get_has_flash_length() && req.get_flash_length() > 0 {
+            self.transformer = Some(Transformer::Flash {
+                values: target,
+                until: now + Duration::from_millis(req.get_flash_length() as u64),
+            });
+            return;
+        }
+
+        if req.get_has_effect() {
+            let name = req.get_effect();
+            match self.effects.iter().find(|effect| effect.name() == name) {
+                Some(effect) => self.start_effect(*effect, target, now),
+                None if name == EFFECT_NONE => self.effect = None,
+                None => debug!("ignoring unknown effect {}", name),
+            }
+        }
+        // turning a light off ends the effect
+        if !target.is_on() {
+            self.effect = None;
+        }
+
+        let length = if req.get_has_transition_length() {
+            Duration::from_millis(req.get_transition_length() as u64)
+        } else {
+            self.default_transition_length
+        };
+        self.set_values(target, length, now);
+    }
+
+    fn set_values(&mut self, target: LightColorValues, length: Duration, now: Instant) {
+        self.remote = target;
+
+        if length.is_zero() || self.current == target || self.effect.is_some() {
+            self.transformer = None;
+            self.current = target;
+        } else {
+            self.transformer = Some(Transformer::Transition {
+                start: self.current,
+                end: target,
+                started: now,
+                length,
+            });
+        }
+    }
+
+    fn start_effect(&mut self, effect: LightEffect, target: LightColorValues, now: Instant) {
+        let mut values = target;
+        if effect == LightEffect::Random || effect == LightEffect::Rainbow {
+            // random colours need a mode with RGB
+            if let Some(mode) = self.traits.mode_with(capability::RGB) {
+                values.color_mode = mode;
+            }
+        }
+
+        let to = if effect == LightEffect::Random {
+            self.random_color(values)
+        } else {
+            values
+        };
+
+        self.effect = Some(RunningEffect {
+            effect,
+            started: now,
+            from: values,
+            to,
+            changed: now,
+        });
+    }
+
+    /// Values the hardware should show at `now`
+    pub fn poll(&mut self, now: Instant) -> LightColorValues {
+        match self.transformer {
+            Some(Transformer::Transition {
+                start,
+                end,
+                started,
+                length,
+            }) => {
+                let progress =
+                    now.saturating_duration_since(started).as_secs_f32() / length.as_secs_f32();
+                if progress < 1. {
+                    self.current = start.lerp(&end, smoothed_progress(progress));
+                    return self.current;
+                }
+                self.transformer = None;
+            }
+            Some(Transformer::Flash { values, until }) => {
+                if now < until {
+                    return values;
+                }
+                self.transformer = None;
+            }
+            None => {}
+        }
+
+        self.current = match self.effect {
+            Some(running) => self.apply_effect(running, now),
+            None => self.remote,
+        };
+        self.current
+    }
+
+    fn apply_effect(&mut self, mut running: RunningEffect, now: Instant) -> LightColorValues {
+        let elapsed = now.saturating_duration_since(running.started);
+        let mut values = self.remote;
+
+        match running.effect {
+            LightEffect::Pulse => {
+                let phase = fraction(elapsed, PULSE_PERIOD);
+                let level = if phase < 0.5 {
+                    phase * 2.
+                } else {
+                    2. - phase * 2.
+                };
+                values.brightness = self.remote.brightness * level;
+            }
+            LightEffect::Strobe => {
+                let on = fraction(elapsed, STROBE_PERIOD * 2) < 0.5;
+                values.state = if on { self.remote.state } else { 0. };
+            }
+            LightEffect::Random => {
+                if now.saturating_duration_since(running.changed) >= RANDOM_PERIOD {
+                    running.from = running.to;
+                    running.to = self.random_color(running.to);
+                    running.changed = now;
+                }
+                let progress = now.saturating_duration_since(running.changed).as_secs_f32()
+                    / RANDOM_TRANSITION.as_secs_f32();
+                let color = running
+                    .from
+                    .lerp(&running.to, smoothed_progress(progress.min(1.)));
+                values.color_mode = running.to.color_mode;
+                values.red = color.red;
+                values.green = color.green;
+                values.blue = color.blue;
+            }
+            LightEffect::Flicker => {
+                let target =
+                    self.remote.brightness * (1. - FLICKER_INTENSITY * self.rng.next_f32());
+                values.brightness =
+                    running.from.brightness * FLICKER_ALPHA + target * (1. - FLICKER_ALPHA);
+                running.from = values;
+            }
+            LightEffect::Rainbow => {
+                let (red, green, blue) = hue_to_rgb(fraction(elapsed, RAINBOW_PERIOD));
+                values.color_mode = running.to.color_mode;
+                values.red = red;
+                values.green = green;
+                values.blue = blue;
+            }
+        }
+
+        self.effect = Some(running);
+        values
+    }
+
+    fn random_color(&mut self, base: LightColorValues) -> LightColorValues {
+        let mut values = base;
+        let (red, green, blue) = (
+            self.rng.next_f32(),
+            self.rng.next_f32(),
+            self.rng.next_f32(),
+        );
+        // same normalisation as a client command
+        let max = red.max(green).max(blue).max(f32::EPSILON);
+        values.red = red / max;
+        values.green = green / max;
+        values.blue = blue / max;
+        values
+    }
+}
+
+/// ESPHome's `LightTransitionTransformer::smoothed_progress` (smootherstep)
+pub fn smoothed_progress(x: f32) -> f32 {
+    let x = x.clamp(0., 1.);
+    x * x * x * (x * (x * 6. - 15.) + 10.)
+}
+
+/// Position in the current period, `0.0..1.0`
+fn fraction(elapsed: Duration, period: Duration) -> f32 {
+    (elapsed.as_millis() % period.as_millis()) as f32 / period.as_millis() as f32
+}
+
+/// Full saturation and value, `hue` in `0.0..1.0`
+pub fn hue_to_rgb(hue: f32) -> (f32, f32, f32) {
+    let h = (hue.fract() * 6.).clamp(0., 6.);
+    let x = 1. - (h % 2. - 1.).abs();
+
+    match h as u32 {
+        0 => (1., x, 0.),
+        1 => (x, 1., 0.),
+        2 => (0., 1., x),
+        3 => (0., x, 1.),
+        4 => (x, 0., 1.),
+        _ => (1., 0., x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ColorMode;
+
+    fn engine(mode: ColorMode) -> LightEngine {
+        LightEngine::new(LightTraits::new(vec![mode]), 42)
+    }
+
+    fn turn_on(brightness: f32) -> LightCommandRequest {
+        let mut req = LightCommandRequest::new();
+        req.set_has_state(true);
+        req.set_state(true);
+        req.set_has_brightness(true);
+        req.set_brightness(brightness);
+        req
+    }
+
+    #[test]
+    fn smoothing() {
+        assert_eq!(smoothed_progress(-1.), 0.);
+        assert_eq!(smoothed_progress(0.5), 0.5);
+        assert_eq!(smoothed_progress(2.), 1.);
+        assert!(smoothed_progress(0.25) < 0.25);
+        assert!(smoothed_progress(0.75) > 0.75);
+    }
+
+    #[test]
+    fn transition() {
+        let mut engine = engine(ColorMode::COLOR_MODE_BRIGHTNESS);
+        let start = Instant::now();
+        let mut req = turn_on(0.5);
+        req.set_has_transition_length(true);
+        req.set_transition_length(1000);
+        engine.apply_command(&req, start);
+
+        // reported right away, the hardware follows
+        assert!(engine.remote_values().is_on());
+        assert!(engine.is_animating());
+        assert_eq!(engine.poll(start).as_brightness(), 0.);
+
+        let half = engine.poll(start + Duration::from_millis(500));
+        assert_eq!(half.state, 0.5);
+        assert_eq!(half.brightness, 0.75);
+
+        let end = engine.poll(start + Duration::from_millis(1000));
+        assert_eq!(&end, engine.remote_values());
+        assert_eq!(end.as_brightness(), 0.5);
+        assert!(!engine.is_animating());
+    }
+
+    #[test]
+    fn transition_from_current() {
+        let mut engine = engine(ColorMode::COLOR_MODE_BRIGHTNESS);
+        let start = Instant::now();
+        engine.apply_command(&turn_on(1.), start);
+        let middle = engine.poll(start + Duration::from_millis(500));
+
+        // a new command starts from what is shown, not from the last target
+        let mut req = LightCommandRequest::new();
+        req.set_has_state(true);
+        req.set_state(false);
+        engine.apply_command(&req, start + Duration::from_millis(500));
+        assert_eq!(engine.poll(start + Duration::from_millis(500)), middle);
+        assert!(!engine.poll(start + Duration::from_millis(1500)).is_on());
+    }
+
+    #[test]
+    fn binary_does_not_fade() {
+        let mut engine = engine(ColorMode::COLOR_MODE_ON_OFF);
+        let now = Instant::now();
+        let mut req = LightCommandRequest::new();
+        req.set_has_state(true);
+        req.set_state(true);
+        engine.apply_command(&req, now);
+
+        assert!(!engine.is_animating());
+        assert!(engine.poll(now).is_on());
+    }
+
+    #[test]
+    fn flash() {
+        let mut engine = engine(ColorMode::COLOR_MODE_BRIGHTNESS);
+        let now = Instant::now();
+        let mut req = turn_on(1.);
+        req.set_has_flash_length(true);
+        req.set_flash_length(100);
+        engine.apply_command(&req, now);
+
+        // the remote values stay off
+        assert!(!engine.remote_values().is_on());
+        assert!(engine.poll(now).is_on());
+        assert!(!engine.poll(now + Duration::from_millis(100)).is_on());
+        assert!(!engine.is_animating());
+    }
+
+    #[test]
+    fn effects() {
+        let mut engine = engine(ColorMode::COLOR_MODE_BRIGHTNESS);
+        assert_eq!(
+            engine.effects(),
+            &[
+                LightEffect::Pulse,
+                LightEffect::Strobe,
+                LightEffect::Flicker
+            ]
+        );
+
+        let now = Instant::now();
+        let mut req = turn_on(1.);
+        req.set_has_effect(true);
+        req.set_effect("Strobe".to_owned());
+        engine.apply_command(&req, now);
+        assert_eq!(engine.effect_name(), "Strobe");
+        assert!(engine.poll(now).is_on());
+        assert!(!engine.poll(now + STROBE_PERIOD).is_on());
+        assert!(engine.poll(now + STROBE_PERIOD * 2).is_on());
+
+        // unknown effects are ignored, turning off ends the effect
+        req.set_effect("Fireworks".to_owned());
+        engine.apply_command(&req, now);
+        assert_eq!(engine.effect_name(), "Strobe");
+        let mut req = LightCommandRequest::new();
+        req.set_has_state(true);
+        req.set_state(false);
+        engine.apply_command(&req, now);
+        assert_eq!(engine.effect_name(), EFFECT_NONE);
+    }
+
+    #[test]
+    fn pulse() {
+        let mut engine = engine(ColorMode::COLOR_MODE_BRIGHTNESS);
+        let now = Instant::now();
+        let mut req = turn_on(1.);
+        req.set_has_effect(true);
+        req.set_effect("Pulse".to_owned());
+        engine.apply_command(&req, now);
+
+        assert_eq!(engine.poll(now).brightness, 0.);
+        assert_eq!(engine.poll(now + PULSE_PERIOD / 4).brightness, 0.5);
+        assert_eq!(engine.poll(now + PULSE_PERIOD / 2).brightness, 1.);
+        // only the output pulses
+        assert_eq!(engine.remote_values().brightness, 1.);
+    }
+
+    #[test]
+    fn hues() {
+        assert_eq!(hue_to_rgb(0.), (1., 0., 0.));
+        assert_eq!(hue_to_rgb(1. / 3.), (0., 1., 0.));
+        assert_eq!(hue_to_rgb(2. / 3.), (0., 0., 1.));
+        assert_eq!(hue_to_rgb(1.), (1., 0., 0.));
+        assert_eq!(hue_to_rgb(0.5 / 6.), (1., 0.5, 0.));
+    }
+
+    #[test]
+    fn rng() {
+        let mut rng = Rng::new(0);
+        let values: Vec<_> = (0..100).map(|_| rng.next_f32()).collect();
+        assert!(values.iter().all(|value| (0. ..=1.).contains(value)));
+        assert!(values.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+}
@@ -1,11 +1,25 @@
-use convert_case::{Case::Snake, Casing};
-
+#[cfg(feature = "has_led_strip")]
+pub mod addressable;
 pub mod light_color;
+pub mod light_engine;
+#[cfg(feature = "has_pulse_counter")]
+pub mod pulse;
+
+/// How `unique_id`s are generated, mirrors ESPHome's `unique_id_generator`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UniqueIdGenerator {
+    /// `<node name><component type><object_id>`, used by ESPHome's native API
+    NodeName,
+    /// `<mac>-<component type>-<fnv1 hash of the name as hex>`, ESPHome's `mac` generator
+    Mac,
+}
 
-fn fnv1_hash(input: &str) -> u32 {
+// https://github.com/esphome/esphome/blob/2022.3.0/esphome/core/helpers.cpp#L48
+pub fn fnv1_hash(input: &str) -> u32 {
     let mut state = 2166136261u32;
 
-    for c in input.chars() {
+    // byte wise, like the C++ implementation iterating over `char`s
+    for c in input.bytes() {
         state = state.wrapping_mul(16777619);
         state ^= c as u32;
     }
@@ -13,15 +27,209 @@ fn fnv1_hash(input: &str) -> u32 {
     state
 }
 
+// https://github.com/esphome/esphome/blob/2022.3.0/esphome/core/helpers.cpp#L219
+fn str_snake_case(input: &str) -> String {
+    // only ASCII is touched, everything else is kept as is (and replaced byte wise by `str_sanitize` later)
+    input
+        .chars()
+        .map(|c| match c {
+            ' ' => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+// https://github.com/esphome/esphome/blob/2022.3.0/esphome/core/helpers.cpp#L226
+fn str_sanitize(input: &str) -> String {
+    // every byte that is not allowed is replaced, a multi byte UTF-8 character results in multiple '_'
+    input
+        .bytes()
+        .map(|c| match c {
+            b'-' | b'_' | b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' => c as char,
+            _ => '_',
+        })
+        .collect()
+}
+
 pub fn name_to_object(name: &str) -> String {
-    name.to_case(Snake)
+    str_sanitize(&str_snake_case(name))
 }
 
+/// The entity key is the hash of the object_id, not of the name
 pub fn name_to_hash(name: &str) -> u32 {
-    fnv1_hash(name)
+    fnv1_hash(&name_to_object(name))
+}
+
+/// `unique_id` of an entity, using the generator and node configured in `main.rs`
+pub fn name_to_unique(name: &str, component_type: &str) -> String {
+    make_unique_id(
+        crate::UNIQUE_ID_GENERATOR,
+        crate::NAME,
+        &mac_to_string(&crate::get_mac(), ""),
+        component_type,
+        name,
+    )
 }
 
-pub fn name_to_unique(name: &str, ty: &str) -> String {
-    // TODO
-    String::from(ty) + name
+/// `mac` is expected as lower case hex without separators, like ESPHome's `get_mac_address()`
+pub fn make_unique_id(
+    generator: UniqueIdGenerator,
+    node_name: &str,
+    mac: &str,
+    component_type: &str,
+    name: &str,
+) -> String {
+    match generator {
+        // https://github.com/esphome/esphome/blob/2022.3.0/esphome/components/api/api_connection.cpp#L1004
+        UniqueIdGenerator::NodeName => {
+            String::from(node_name) + component_type + &name_to_object(name)
+        }
+        // https://github.com/esphome/esphome/blob/2022.3.0/esphome/components/mqtt/mqtt_component.cpp#L95
+        UniqueIdGenerator::Mac => {
+            format!("{}-{}-{:08x}", mac, component_type, fnv1_hash(name))
+        }
+    }
+}
+
+/// Lower case hex, `sep` goes between the octets
+pub fn mac_to_string(mac: &[u8; 6], sep: &str) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+/// CRC-32 (IEEE 802.3, reflected, the one of zlib), bitwise to save the table's flash
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Delay for embedded-hal 1.0 drivers, sleeps instead of spinning so other threads can run
+///
+/// Blocks the calling thread, only use it on the components thread (see [`crate::components::ComponentManager`]),
+/// never on the executor.
+#[cfg(any(
+    feature = "has_bme280",
+    feature = "has_bme680",
+    feature = "has_ccs811",
+    feature = "has_sgp30"
+))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadDelay;
+
+#[cfg(any(
+    feature = "has_bme280",
+    feature = "has_bme680",
+    feature = "has_ccs811",
+    feature = "has_sgp30"
+))]
+impl embedded_hal_1::delay::DelayNs for ThreadDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        std::thread::sleep(std::time::Duration::from_nanos(ns as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1() {
+        // reference vectors of FNV-1 (not FNV-1a)
+        assert_eq!(fnv1_hash(""), 0x811c9dc5);
+        assert_eq!(fnv1_hash("a"), 0x050c5d7e);
+        assert_eq!(fnv1_hash("foobar"), 0x31f0b262);
+    }
+
+    #[test]
+    fn snake_case() {
+        assert_eq!(str_snake_case("Rusty old LED blue"), "rusty_old_led_blue");
+        assert_eq!(str_snake_case("Temp. (°C)"), "temp._(°c)");
+        // only ASCII is lowered, like `tolower()` in the C locale
+        assert_eq!(str_snake_case("ÄÖÜ"), "ÄÖÜ");
+    }
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(str_sanitize("my-sensor_2"), "my-sensor_2");
+        // replaced, not dropped
+        assert_eq!(str_sanitize("a.b(c)"), "a_b_c_");
+        // one '_' per byte
+        assert_eq!(str_sanitize("°"), "__");
+        assert_eq!(str_sanitize("€"), "___");
+    }
+
+    #[test]
+    fn object_id_and_key() {
+        for (name, object_id, key) in [
+            ("Rusty old LED blue", "rusty_old_led_blue", 0x33ef18a3),
+            ("Outdoor Temperature", "outdoor_temperature", 0x792d9f34),
+            ("Temp. (°C)", "temp_____c_", 0x5b7b17d8),
+            ("Küche Licht", "k__che_licht", 0x7ba15ea5),
+            ("my-sensor_2", "my-sensor_2", 0x79773fe1),
+        ] {
+            assert_eq!(name_to_object(name), object_id, "{name}");
+            assert_eq!(name_to_hash(name), key, "{name}");
+        }
+    }
+
+    #[test]
+    fn unique_id() {
+        assert_eq!(
+            make_unique_id(
+                UniqueIdGenerator::NodeName,
+                "esphome-rs-poc",
+                "a0b1c2d3e4f5",
+                "light",
+                "Rusty old LED blue",
+            ),
+            "esphome-rs-poclightrusty_old_led_blue"
+        );
+        // the hash is taken from the name, not from the object_id
+        assert_eq!(
+            make_unique_id(
+                UniqueIdGenerator::Mac,
+                "esphome-rs-poc",
+                "a0b1c2d3e4f5",
+                "light",
+                "Rusty old LED blue",
+            ),
+            "a0b1c2d3e4f5-light-68da6a1c"
+        );
+        assert_eq!(
+            make_unique_id(
+                UniqueIdGenerator::Mac,
+                "esphome-rs-poc",
+                "a0b1c2d3e4f5",
+                "sensor",
+                "Temp. (°C)",
+            ),
+            "a0b1c2d3e4f5-sensor-01a65f9d"
+        );
+    }
+
+    #[test]
+    fn mac() {
+        let mac = [0xa0, 0xb1, 0xc2, 0xd3, 0xe4, 0x05];
+        assert_eq!(mac_to_string(&mac, ""), "a0b1c2d3e405");
+        assert_eq!(mac_to_string(&mac, ":"), "a0:b1:c2:d3:e4:05");
+    }
+
+    #[test]
+    fn crc() {
+        // the check value of CRC-32
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
 }
@@ -0,0 +1,183 @@
+//! Rates and totals of pulse sensors
+//!
+//! Like the light engine, nothing in here reads the clock or touches the hardware. `components::pulse_counter`
+//! passes in what its interrupt counted and the current time.
+
+use std::time::{Duration, Instant};
+
+const MICROS_PER_MINUTE: f32 = 60_000_000.;
+
+/// Whether an edge at `now_us` starts a new pulse, timestamps are µs and wrap after about 71 minutes
+pub fn debounced(last_us: u32, now_us: u32, min_us: u32) -> bool {
+    now_us.wrapping_sub(last_us) >= min_us
+}
+
+/// What the interrupt counted since boot
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pulses {
+    pub count: u32,
+    /// µs between the last two pulses, 0 before the second one
+    pub period_us: u32,
+}
+
+/// How the rate is derived
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateMode {
+    /// Pulses over the update interval, like ESPHome's `pulse_counter`
+    Counter,
+    /// From the time between the last two pulses, like ESPHome's `pulse_meter`
+    ///
+    /// Drops to 0 when no pulse arrived for `timeout`.
+    Meter { timeout: Duration },
+}
+
+#[derive(Debug, Clone)]
+pub struct PulseMath {
+    mode: RateMode,
+    /// Starts with the total from before the last reboot
+    total: u64,
+    /// Count of the previous [`PulseMath::total`] call
+    counted: u32,
+    /// Count and time of the previous [`PulseMath::rate`] call
+    last: Option<(u32, Instant)>,
+    /// When the count was first seen changing
+    last_pulse: Option<Instant>,
+}
+
+impl PulseMath {
+    pub fn new(mode: RateMode, offset: u64) -> Self {
+        PulseMath {
+            mode,
+            total: offset,
+            counted: 0,
+            last: None,
+            last_pulse: None,
+        }
+    }
+
+    /// Adds the pulses since the previous call, the count may have wrapped in between
+    pub fn total(&mut self, pulses: Pulses) -> u64 {
+        self.total += pulses.count.wrapping_sub(self.counted) as u64;
+        self.counted = pulses.count;
+        self.total
+    }
+
+    /// Pulses per minute, `None` when there is nothing to tell yet
+    pub fn rate(&mut self, pulses: Pulses, now: Instant) -> Option<f32> {
+        let last = self.last.replace((pulses.count, now));
+        if let Some((count, _)) = last {
+            if count != pulses.count {
+                self.last_pulse = Some(now);
+            }
+        }
+
+        match self.mode {
+            RateMode::Counter => {
+                let (count, at) = last?;
+                let elapsed = now.checked_duration_since(at)?.as_secs_f32();
+                if elapsed <= 0. {
+                    return None;
+                }
+                // the count wraps after 2^32 pulses, which is still a single step
+                Some(pulses.count.wrapping_sub(count) as f32 * 60. / elapsed)
+            }
+            RateMode::Meter { timeout } => {
+                let recent = self
+                    .last_pulse
+                    .is_some_and(|at| now.saturating_duration_since(at) < timeout);
+                match (recent, pulses.period_us) {
+                    (true, period_us) if period_us > 0 => {
+                        Some(MICROS_PER_MINUTE / period_us as f32)
+                    }
+                    // only a single pulse so far
+                    (true, _) => None,
+                    (false, _) => Some(0.),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulses(count: u32) -> Pulses {
+        Pulses {
+            count,
+            period_us: 0,
+        }
+    }
+
+    #[test]
+    fn debounce() {
+        assert!(debounced(0, 10, 10));
+        assert!(!debounced(0, 9, 10));
+        // across the µs wrap
+        assert!(debounced(u32::MAX - 4, 5, 10));
+        assert!(!debounced(u32::MAX - 4, 4, 10));
+    }
+
+    #[test]
+    fn total() {
+        let mut math = PulseMath::new(RateMode::Counter, 1000);
+        assert_eq!(math.total(pulses(0)), 1000);
+        assert_eq!(math.total(pulses(5)), 1005);
+        assert_eq!(math.total(pulses(5)), 1005);
+        assert_eq!(math.total(pulses(7)), 1007);
+    }
+
+    #[test]
+    fn total_wrap() {
+        let mut math = PulseMath::new(RateMode::Counter, 0);
+        assert_eq!(math.total(pulses(u32::MAX - 1)), u32::MAX as u64 - 1);
+        // 4 pulses later the count wrapped, the total keeps counting
+        assert_eq!(math.total(pulses(2)), u32::MAX as u64 + 3);
+    }
+
+    #[test]
+    fn counter_rate() {
+        let mut math = PulseMath::new(RateMode::Counter, 0);
+        let start = Instant::now();
+        assert_eq!(math.rate(pulses(0), start), None);
+        // no time passed
+        assert_eq!(math.rate(pulses(0), start), None);
+        let rate = math.rate(pulses(30), start + Duration::from_secs(60));
+        assert_eq!(rate, Some(30.));
+        let rate = math.rate(pulses(40), start + Duration::from_secs(90));
+        assert_eq!(rate, Some(20.));
+        let rate = math.rate(pulses(40), start + Duration::from_secs(150));
+        assert_eq!(rate, Some(0.));
+    }
+
+    #[test]
+    fn counter_rate_wrap() {
+        let mut math = PulseMath::new(RateMode::Counter, 0);
+        let start = Instant::now();
+        assert_eq!(math.rate(pulses(u32::MAX - 1), start), None);
+        let rate = math.rate(pulses(2), start + Duration::from_secs(60));
+        assert_eq!(rate, Some(4.));
+    }
+
+    #[test]
+    fn meter_rate() {
+        let timeout = Duration::from_secs(60);
+        let mut math = PulseMath::new(RateMode::Meter { timeout }, 0);
+        let start = Instant::now();
+        assert_eq!(math.rate(pulses(0), start), Some(0.));
+
+        // a single pulse has no period yet
+        let first = start + Duration::from_secs(1);
+        assert_eq!(math.rate(pulses(1), first), None);
+
+        let pulse = Pulses {
+            count: 2,
+            period_us: 500_000,
+        };
+        let second = first + Duration::from_secs(1);
+        assert_eq!(math.rate(pulse, second), Some(120.));
+        // still within the timeout
+        assert_eq!(math.rate(pulse, second + timeout / 2), Some(120.));
+        assert_eq!(math.rate(pulse, second + timeout), Some(0.));
+    }
+}
@@ -48,10 +48,14 @@ fn main() -> anyhow::Result<()> {
 
 async fn connect_wifi(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<()> {
     let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: SSID.try_into().unwrap(),
+        ssid: SSID
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("WiFi SSID is too long"))?,
         bssid: None,
         auth_method: AuthMethod::WPA2Personal,
-        password: PASSWORD.try_into().unwrap(),
+        password: PASSWORD
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("WiFi password is too long"))?,
         channel: None,
     });
 